@@ -0,0 +1,64 @@
+//!
+//! Encode/decode overhead of the typed messages, run with `cargo bench`.
+//!
+use bevy_client_server_events::{codec, NetworkConfig};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Ping;
+
+#[derive(Serialize, Deserialize)]
+struct PlayerInput {
+    tick: u32,
+    movement: [f32; 2],
+    jumping: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    positions: Vec<[f32; 3]>,
+}
+
+fn bench_message<T: Serialize + for<'de> Deserialize<'de>>(
+    c: &mut Criterion,
+    name: &str,
+    content: T,
+    config: &NetworkConfig,
+) {
+    c.bench_function(&format!("encode {}", name), |b| {
+        b.iter(|| codec::encode(config, black_box(&content)))
+    });
+    let payload = codec::encode(config, &content);
+    c.bench_function(&format!("decode {}", name), |b| {
+        b.iter(|| codec::decode::<T>(config, black_box(&payload)).ok())
+    });
+}
+
+fn codec_benchmarks(c: &mut Criterion) {
+    let config = NetworkConfig::default();
+    let timestamped = NetworkConfig {
+        timestamps: true,
+        ..Default::default()
+    };
+    let input = || PlayerInput {
+        tick: 1234,
+        movement: [0.5, -1.0],
+        jumping: true,
+    };
+    bench_message(c, "unit", Ping, &config);
+    bench_message(c, "unit timestamped", Ping, &timestamped);
+    bench_message(c, "input", input(), &config);
+    bench_message(c, "input timestamped", input(), &timestamped);
+    bench_message(
+        c,
+        "snapshot",
+        Snapshot {
+            positions: vec![[1.0, 2.0, 3.0]; 256],
+        },
+        &config,
+    );
+}
+
+criterion_group!(benches, codec_benchmarks);
+criterion_main!(benches);
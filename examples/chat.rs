@@ -128,6 +128,7 @@ fn update_server(
     for ReceiveFromClient {
         client_id,
         content: Message(message),
+        ..
     } in client_messages.read()
     {
         println!("{} sent: {}", client_id, message);
@@ -135,7 +136,7 @@ fn update_server(
             content: Message(format!("> {}: {}", client_id, message)),
         });
     }
-    for ClientConnected { client_id } in client_connected.read() {
+    for ClientConnected { client_id, .. } in client_connected.read() {
         println!("{} has connected", client_id);
         server_messages.send(SendToClients {
             content: Message(format!("> {} has joined the chat!", client_id)),
@@ -159,6 +160,7 @@ fn receive_messages(
 ) {
     for ReceiveFromServer {
         content: Message(message),
+        ..
     } in server_messages.read()
     {
         let current_text = &chat_area.single_mut().sections[0].value.clone();
@@ -6,7 +6,7 @@
 ///
 /// The server and client will use encryption to communicate.
 ///
-/// Every 500 frames the server will broadcast a message of it's frame count.
+/// Every 10 seconds the server will broadcast a numbered message.
 ///
 /// With focus on the server window:
 ///     Hit 'ESC' to stop the server
@@ -21,13 +21,16 @@
 ///
 use bevy::prelude::*;
 use bevy_client_server_events::{
+    broadcast::ScheduledBroadcasts,
     client::{ConnectToServer, DisconnectFromServer, ReceiveFromServer, SendToServer},
     client_server_events_plugin,
+    error::NetworkError,
+    keys::derive_key,
     server::{
-        ClientConnected, ClientDisconnected, ReceiveFromClient, SendToClient, SendToClients,
-        StartServer, StopServer,
+        ClientConnected, ClientDisconnected, ReceiveFromClient, SendToClient, StartServer,
+        StopServer,
     },
-    string_to_key, NetcodeTransportError, NetworkConfig,
+    NetworkConfig,
 };
 use renet::SendType;
 use serde::{Deserialize, Serialize};
@@ -50,6 +53,7 @@ pub struct BroadcastMessage {
 }
 
 const SHARED_KEY: &str = "secret";
+const KEY_SALT: &str = "features example";
 
 fn main() {
     let mut args = env::args();
@@ -58,26 +62,22 @@ fn main() {
     let mut app = App::new();
     client_server_events_plugin!(
         app,
-        PlayerMovement => NetworkConfig::default(),
+        PlayerMovement => NetworkConfig::unreliable_sequenced(),
         ServerResponse => NetworkConfig::default(),
         BroadcastMessage => NetworkConfig {
             max_memory_usage_bytes: 5 * 1024 * 1024,
             send_type: SendType::ReliableOrdered {
                 resend_time: Duration::from_millis(1000),
-            }
+            },
+            ..Default::default()
         }
     );
     if is_server {
         app.add_plugins(DefaultPlugins)
-            .add_systems(Startup, setup_server)
+            .add_systems(Startup, (setup_server, schedule_server_broadcast))
             .add_systems(
                 Update,
-                (
-                    update_server,
-                    periodic_server_broadcast,
-                    log_connections_on_server,
-                    handle_errors,
-                ),
+                (update_server, log_connections_on_server, handle_errors),
             )
             .run();
     } else {
@@ -89,7 +89,7 @@ fn main() {
 }
 
 fn setup_server(mut start_server: EventWriter<StartServer>) {
-    let key = string_to_key(SHARED_KEY);
+    let key = derive_key(SHARED_KEY, KEY_SALT);
     start_server.send(StartServer {
         private_key: Some(key),
         ..Default::default()
@@ -97,7 +97,7 @@ fn setup_server(mut start_server: EventWriter<StartServer>) {
 }
 
 fn setup_client(mut connect_to_server: EventWriter<ConnectToServer>) {
-    let key = string_to_key(SHARED_KEY);
+    let key = derive_key(SHARED_KEY, KEY_SALT);
     connect_to_server.send(ConnectToServer {
         private_key: Some(key),
         ..Default::default()
@@ -115,7 +115,7 @@ fn update_server(
         stop_server_events.send(StopServer);
         println!("Stopping server");
     } else if input.just_pressed(KeyCode::Enter) {
-        let key = string_to_key(SHARED_KEY);
+        let key = derive_key(SHARED_KEY, KEY_SALT);
         start_server_events.send(StartServer {
             private_key: Some(key),
             ..Default::default()
@@ -123,7 +123,10 @@ fn update_server(
         println!("Starting server");
     }
 
-    for ReceiveFromClient { client_id, content } in player_movement_events.read() {
+    for ReceiveFromClient {
+        client_id, content, ..
+    } in player_movement_events.read()
+    {
         println!(
             "Player Movement Received from Client {}: {:?}",
             *client_id, content
@@ -137,18 +140,14 @@ fn update_server(
     }
 }
 
-fn periodic_server_broadcast(
-    mut broadcast_events: EventWriter<SendToClients<BroadcastMessage>>,
-    mut frames: Local<u64>,
-) {
-    *frames += 1;
-    if *frames % 500 == 0 {
-        broadcast_events.send(SendToClients {
-            content: BroadcastMessage {
-                message: format!("Broadcast: Server has been running for {} frames", *frames),
-            },
-        });
-    }
+fn schedule_server_broadcast(mut broadcasts: ResMut<ScheduledBroadcasts<BroadcastMessage>>) {
+    let mut count = 0;
+    broadcasts.every(Duration::from_secs(10), move || {
+        count += 1;
+        BroadcastMessage {
+            message: format!("Broadcast #{}: sent by the server every 10 seconds", count),
+        }
+    });
 }
 
 fn log_connections_on_server(
@@ -180,7 +179,7 @@ fn update_client(
         disconnect_events.send(DisconnectFromServer);
         println!("Disconnecting from server");
     } else if input.just_pressed(KeyCode::Enter) {
-        let key = string_to_key(SHARED_KEY);
+        let key = derive_key(SHARED_KEY, KEY_SALT);
         connect_events.send(ConnectToServer {
             private_key: Some(key),
             ..Default::default()
@@ -197,8 +196,8 @@ fn update_client(
     }
 }
 
-fn handle_errors(mut errors: EventReader<NetcodeTransportError>) {
+fn handle_errors(mut errors: EventReader<NetworkError>) {
     for error in errors.read() {
-        println!("Networking Error: {:?}", error);
+        println!("Networking Error: {}", error);
     }
 }
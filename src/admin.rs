@@ -0,0 +1,432 @@
+//!
+//! Remote admin console (rcon), enabled with the `admin` feature.
+//!
+//! Clients log in with `AdminLogin` using the password from the server's `AdminConfig`, then send
+//! `AdminCommand`s and receive an `AdminResponse` for each one. The server runs the handler
+//! registered for the command's first word through `AdminAppExt::add_admin_command`.
+//!
+//! The password and commands travel in clear unless the server has a `StartServer::private_key`
+//! (or `private_key_path`), so the console should only be enabled on secure servers. Clients are
+//! disconnected after `MAX_FAILED_LOGINS` wrong passwords, and logins from the same IP address or
+//! player are refused for `LOGIN_COOLDOWN`, so that reconnecting with another client id doesn't
+//! give more attempts.
+//!
+use bevy::ecs::system::SystemId;
+use bevy::prelude::{
+    resource_exists, resource_removed, App, Event, EventReader, EventWriter, IntoSystem,
+    IntoSystemConfigs, Mut, PostUpdate, PreUpdate, ResMut, Resource, Time, World,
+};
+use bevy_renet::renet::{transport::NetcodeServerTransport, RenetClient, RenetServer};
+use bevy_renet::RenetReceive;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::{
+    server::{
+        server_tracks_connected_and_disconnected_clients, ClientDisconnected, ConnectedClients,
+        DisconnectClient,
+    },
+    ClientId, DisconnectReason, PlayerId, ADMIN_CHANNEL_ID,
+};
+
+/// Wrong passwords a client may send before being disconnected with `DisconnectReason::Kicked`.
+pub const MAX_FAILED_LOGINS: u32 = 3;
+
+/// How long logins stay refused after `MAX_FAILED_LOGINS` wrong passwords from the same IP address
+/// or player, wrong passwords older than this are forgotten.
+pub const LOGIN_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// Upper bound on the memory decoding an admin message may claim, so that a crafted length prefix
+/// can't make the server allocate huge buffers.
+const MAX_ADMIN_MESSAGE_BYTES: usize = 1 << 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum AdminMessage {
+    Login(String),
+    Command(String),
+    Response(String),
+}
+
+impl AdminMessage {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard().with_limit::<MAX_ADMIN_MESSAGE_BYTES>();
+        bincode::serde::decode_from_slice(bytes, config)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+///
+/// Send this event from a client to authenticate as an admin.
+///
+#[derive(Debug, Event)]
+pub struct AdminLogin {
+    pub password: String,
+}
+
+///
+/// Send this event from an authenticated client to run a command on the server.
+///
+#[derive(Debug, Event)]
+pub struct AdminCommand(pub String);
+
+///
+/// Received by the client for every `AdminLogin` and `AdminCommand` it sent.
+///
+#[derive(Debug, Event)]
+pub struct AdminResponse(pub String);
+
+///
+/// Server side admin settings, the admin console is disabled while `password` is `None`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct AdminConfig {
+    pub password: Option<String>,
+}
+
+///
+/// Where failed logins are counted: client ids are chosen by unsecure clients, so they are only
+/// used when the IP address isn't known (e.g. local clients).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum LoginSource {
+    Ip(IpAddr),
+    Player(PlayerId),
+    Client(ClientId),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedLogins {
+    count: u32,
+    /// Time of the latest wrong password, see `Time::elapsed`.
+    last: Duration,
+}
+
+///
+/// Clients that successfully logged in as admins, and the recent failed logins.
+///
+#[derive(Debug, Default, Resource)]
+pub struct AdminSessions {
+    admins: HashSet<ClientId>,
+    failed_logins: HashMap<LoginSource, FailedLogins>,
+}
+
+impl AdminSessions {
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.admins.contains(&client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ClientId> {
+        self.admins.iter()
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.admins.iter().copied()
+    }
+
+    fn is_locked_out(&self, sources: &[LoginSource]) -> bool {
+        sources.iter().any(|source| {
+            self.failed_logins
+                .get(source)
+                .is_some_and(|failed_logins| failed_logins.count >= MAX_FAILED_LOGINS)
+        })
+    }
+}
+
+///
+/// Input given to admin command handlers.
+///
+#[derive(Debug)]
+pub struct AdminRequest {
+    pub client_id: ClientId,
+    /// The words following the command name.
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Default, Resource)]
+pub struct AdminHandlers(HashMap<String, SystemId<AdminRequest, String>>);
+
+pub trait AdminAppExt {
+    ///
+    /// Registers a system run whenever an admin sends a command starting with `name`.
+    /// The system receives the request through `In<AdminRequest>` and returns the response.
+    ///
+    fn add_admin_command<M>(
+        &mut self,
+        name: impl Into<String>,
+        system: impl IntoSystem<AdminRequest, String, M> + 'static,
+    ) -> &mut Self;
+}
+
+impl AdminAppExt for App {
+    fn add_admin_command<M>(
+        &mut self,
+        name: impl Into<String>,
+        system: impl IntoSystem<AdminRequest, String, M> + 'static,
+    ) -> &mut Self {
+        let system_id = self.world_mut().register_system(system);
+        self.world_mut()
+            .get_resource_or_insert_with(AdminHandlers::default)
+            .0
+            .insert(name.into(), system_id);
+        self
+    }
+}
+
+pub fn server_runs_admin_commands(world: &mut World) {
+    let mut requests = Vec::new();
+    world.resource_scope(|world, mut server: Mut<RenetServer>| {
+        let connected_clients = world.resource::<ConnectedClients>();
+        for client_id in server.clients_id() {
+            while let Some(message) = server.receive_message(client_id, ADMIN_CHANNEL_ID) {
+                if connected_clients.get(client_id.into()).is_none() {
+                    continue;
+                }
+                if let Some(message) = AdminMessage::decode(&message) {
+                    requests.push((client_id, message));
+                }
+            }
+        }
+    });
+    for (client_id, message) in requests {
+        let response = match message {
+            AdminMessage::Login(password) => admin_login(world, client_id.into(), password),
+            AdminMessage::Command(line) => admin_command(world, client_id.into(), &line),
+            AdminMessage::Response(_) => continue,
+        };
+        world.resource_mut::<RenetServer>().send_message(
+            client_id,
+            ADMIN_CHANNEL_ID,
+            AdminMessage::Response(response).encode(),
+        );
+    }
+}
+
+fn login_sources(world: &World, client_id: ClientId) -> Vec<LoginSource> {
+    let addr = world
+        .get_resource::<NetcodeServerTransport>()
+        .and_then(|transport| transport.client_addr(client_id.into()));
+    let mut sources = vec![match addr {
+        Some(addr) => LoginSource::Ip(addr.ip().to_canonical()),
+        None => LoginSource::Client(client_id),
+    }];
+    if let Some(player_id) = world.resource::<ConnectedClients>().player_id(client_id) {
+        sources.push(LoginSource::Player(player_id));
+    }
+    sources
+}
+
+fn admin_login(world: &mut World, client_id: ClientId, password: String) -> String {
+    let matches = match &world.resource::<AdminConfig>().password {
+        None => return "Admin console is disabled".to_string(),
+        Some(expected) => password_matches(expected, &password),
+    };
+    let sources = login_sources(world, client_id);
+    let now = world.resource::<Time>().elapsed();
+    let mut sessions = world.resource_mut::<AdminSessions>();
+    sessions
+        .failed_logins
+        .retain(|_, failed_logins| now.saturating_sub(failed_logins.last) < LOGIN_COOLDOWN);
+    // Locked out until the cooldown is over, even with the right password.
+    if !sessions.is_locked_out(&sources) {
+        if matches {
+            for source in &sources {
+                sessions.failed_logins.remove(source);
+            }
+            sessions.admins.insert(client_id);
+            return "Logged in".to_string();
+        }
+        for source in &sources {
+            let failed_logins = sessions
+                .failed_logins
+                .entry(*source)
+                .or_insert(FailedLogins {
+                    count: 0,
+                    last: now,
+                });
+            failed_logins.count += 1;
+            failed_logins.last = now;
+        }
+        if !sessions.is_locked_out(&sources) {
+            return "Invalid password".to_string();
+        }
+    }
+    world.send_event(DisconnectClient {
+        client_id,
+        reason: DisconnectReason::Kicked,
+    });
+    "Too many failed logins".to_string()
+}
+
+/// Compares the digests of the passwords in constant time, so that response times don't tell how
+/// much of the password was right.
+fn password_matches(expected: &str, password: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let password = Sha256::digest(password.as_bytes());
+    expected
+        .iter()
+        .zip(password.iter())
+        .fold(0, |difference, (expected, given)| {
+            difference | (expected ^ given)
+        })
+        == 0
+}
+
+fn admin_command(world: &mut World, client_id: ClientId, line: &str) -> String {
+    if !world.resource::<AdminSessions>().contains(client_id) {
+        return "Not logged in".to_string();
+    }
+    let mut words = line.split_whitespace().map(str::to_string);
+    let Some(name) = words.next() else {
+        return "Empty command".to_string();
+    };
+    let Some(&system_id) = world.resource::<AdminHandlers>().0.get(&name) else {
+        return format!("Unknown command: {}", name);
+    };
+    let request = AdminRequest {
+        client_id,
+        args: words.collect(),
+    };
+    world
+        .run_system_with_input(system_id, request)
+        .unwrap_or_else(|error| format!("Command {} failed: {}", name, error))
+}
+
+pub fn server_forgets_admin_sessions(
+    mut sessions: ResMut<AdminSessions>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+) {
+    // Failed logins are kept, so that reconnecting doesn't reset them.
+    for client_disconnected in client_disconnected_events.read() {
+        sessions.admins.remove(&client_disconnected.client_id);
+    }
+}
+
+pub fn cleanup_admin_sessions(mut sessions: ResMut<AdminSessions>) {
+    sessions.admins.clear();
+    sessions.failed_logins.clear();
+}
+
+pub fn client_sends_admin_messages(
+    mut client: ResMut<RenetClient>,
+    mut admin_login_events: EventReader<AdminLogin>,
+    mut admin_command_events: EventReader<AdminCommand>,
+) {
+    for AdminLogin { password } in admin_login_events.read() {
+        client.send_message(
+            ADMIN_CHANNEL_ID,
+            AdminMessage::Login(password.clone()).encode(),
+        );
+    }
+    for AdminCommand(line) in admin_command_events.read() {
+        client.send_message(
+            ADMIN_CHANNEL_ID,
+            AdminMessage::Command(line.clone()).encode(),
+        );
+    }
+}
+
+pub fn client_receives_admin_responses(
+    mut client: ResMut<RenetClient>,
+    mut admin_response_events: EventWriter<AdminResponse>,
+) {
+    while let Some(message) = client.receive_message(ADMIN_CHANNEL_ID) {
+        if let Some(AdminMessage::Response(response)) = AdminMessage::decode(&message) {
+            admin_response_events.send(AdminResponse(response));
+        }
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<AdminConfig>()
+        .init_resource::<AdminSessions>()
+        .init_resource::<AdminHandlers>()
+        .add_event::<AdminLogin>()
+        .add_event::<AdminCommand>()
+        .add_event::<AdminResponse>()
+        .add_systems(
+            PreUpdate,
+            (server_forgets_admin_sessions, server_runs_admin_commands)
+                .chain()
+                .after(RenetReceive)
+                .after(server_tracks_connected_and_disconnected_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PreUpdate,
+            cleanup_admin_sessions.run_if(resource_removed::<RenetServer>()),
+        )
+        .add_systems(
+            PostUpdate,
+            (client_sends_admin_messages, client_receives_admin_responses)
+                .run_if(resource_exists::<RenetClient>),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Events;
+
+    use super::*;
+    use crate::{server::ConnectedClient, ConnectionMode};
+
+    fn connect(world: &mut World, client_id: ClientId, player_id: PlayerId) {
+        world.resource_mut::<ConnectedClients>().insert(
+            client_id,
+            ConnectedClient {
+                mode: ConnectionMode::Player,
+                player_id: Some(player_id),
+            },
+        );
+    }
+
+    fn login(world: &mut World, client_id: ClientId, password: &str) -> String {
+        admin_login(world, client_id, password.to_string())
+    }
+
+    #[test]
+    fn lockout_follows_the_player_and_expires() {
+        let mut world = World::new();
+        world.insert_resource(AdminConfig {
+            password: Some("secret".to_string()),
+        });
+        world.init_resource::<AdminSessions>();
+        world.init_resource::<ConnectedClients>();
+        world.init_resource::<Events<DisconnectClient>>();
+        world.init_resource::<Time>();
+        let player_id = PlayerId(7);
+        connect(&mut world, ClientId(1), player_id);
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert_eq!(login(&mut world, ClientId(1), "guess"), "Invalid password");
+        }
+        assert_eq!(
+            login(&mut world, ClientId(1), "guess"),
+            "Too many failed logins"
+        );
+        // Reconnecting with another client id doesn't give more attempts.
+        connect(&mut world, ClientId(2), player_id);
+        assert_eq!(
+            login(&mut world, ClientId(2), "secret"),
+            "Too many failed logins"
+        );
+        assert!(!world.resource::<AdminSessions>().contains(ClientId(2)));
+        assert_eq!(world.resource::<Events<DisconnectClient>>().len(), 2);
+        world.resource_mut::<Time>().advance_by(LOGIN_COOLDOWN);
+        assert_eq!(login(&mut world, ClientId(2), "secret"), "Logged in");
+    }
+
+    #[test]
+    fn crafted_length_prefix_is_rejected() {
+        let mut bytes = vec![0, 253];
+        bytes.extend_from_slice(&(1u64 << 62).to_le_bytes());
+        assert!(AdminMessage::decode(&bytes).is_none());
+    }
+}
@@ -0,0 +1,282 @@
+//!
+//! Periodic audit of the server's per-client state, to catch state that outlives its client
+//! (e.g. in long running soak tests). Disabled by default, set `ClientStateAudit::interval`.
+//!
+//! Every audit compares the clients known to the server (connected through renet, connected,
+//! denied, being disconnected or suspended) with the clients each piece of per-client state
+//! refers to. State is reported with `LeakedClientState` once it refers to an unknown client in
+//! two audits in a row, so state forgotten a tick after its client's disconnection isn't
+//! reported. Bans, chat mutes and relay blocks persist across reconnections on purpose and
+//! aren't audited.
+//!
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PreUpdate, Res,
+    ResMut, Resource, Time,
+};
+use bevy::utils::HashSet;
+use bevy_renet::renet::RenetServer;
+
+use std::time::Duration;
+
+#[cfg(feature = "admin")]
+use crate::admin::AdminSessions;
+use crate::{
+    handshake::ClientCapabilities,
+    matchmaking::MatchmakingQueues,
+    mute::MutedClientEvents,
+    negotiation::Negotiation,
+    server::{
+        server_forgets_disconnected_clients, ClientBandwidth, ClientWarmUp, ConnectedClients,
+        DeniedClients, DisconnectingClients, ReceiveBacklog, ServerDeltaBaselines, ServerOutbox,
+        ServerSequences, ServerStopped, SuspendedClients,
+    },
+    sessions::Sessions,
+    streams::ServerStreams,
+    teams::Teams,
+    telemetry::ConnectionTelemetry,
+    ClientId,
+};
+
+///
+/// How often the server's per-client state is audited, see the module documentation.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientStateAudit {
+    /// `None` disables the audit.
+    pub interval: Option<Duration>,
+    next_audit: Option<Duration>,
+    suspects: HashSet<(ClientId, &'static str)>,
+    reported: HashSet<(ClientId, &'static str)>,
+    leaks: u64,
+}
+
+impl ClientStateAudit {
+    pub fn every(interval: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            ..Default::default()
+        }
+    }
+
+    /// Number of leaks reported so far, across server restarts. Soak tests can assert it stays 0.
+    pub fn leaks(&self) -> u64 {
+        self.leaks
+    }
+}
+
+///
+/// Received on the server when `state` still refers to a client the server no longer knows,
+/// reported once per client and state.
+///
+#[derive(Debug, Clone, Event)]
+pub struct LeakedClientState {
+    pub client_id: ClientId,
+    /// The leaking state, e.g. "outbox" or "teams".
+    pub state: &'static str,
+}
+
+///
+/// The per-client state checked by `server_audits_client_state`.
+///
+#[derive(SystemParam)]
+pub struct AuditedState<'w> {
+    server: Res<'w, RenetServer>,
+    connected: Res<'w, ConnectedClients>,
+    denied: Res<'w, DeniedClients>,
+    disconnecting: Res<'w, DisconnectingClients>,
+    suspended: Res<'w, SuspendedClients>,
+    negotiation: Res<'w, Negotiation>,
+    outbox: Res<'w, ServerOutbox>,
+    delta_baselines: Res<'w, ServerDeltaBaselines>,
+    sequences: Res<'w, ServerSequences>,
+    receive_backlog: Res<'w, ReceiveBacklog>,
+    warm_up: Res<'w, ClientWarmUp>,
+    bandwidth: Res<'w, ClientBandwidth>,
+    teams: Res<'w, Teams>,
+    sessions: Res<'w, Sessions>,
+    matchmaking_queues: Res<'w, MatchmakingQueues>,
+    muted_client_events: Res<'w, MutedClientEvents>,
+    telemetry: Res<'w, ConnectionTelemetry>,
+    streams: Res<'w, ServerStreams>,
+    capabilities: Res<'w, ClientCapabilities>,
+    #[cfg(feature = "admin")]
+    admin_sessions: Res<'w, AdminSessions>,
+}
+
+impl AuditedState<'_> {
+    /// Every (client, state) pair whose client isn't known to the server.
+    fn unknown_clients(&self) -> HashSet<(ClientId, &'static str)> {
+        let mut unknown = HashSet::new();
+        let transport: HashSet<ClientId> = self
+            .server
+            .clients_id()
+            .into_iter()
+            .map(ClientId::from)
+            .collect();
+        // The bookkeeping of renet's connections only needs renet's own view.
+        let connected = || self.connected.iter().map(|(client_id, _)| *client_id);
+        check(&mut unknown, &transport, "connected clients", connected());
+        check(
+            &mut unknown,
+            &transport,
+            "denied clients",
+            self.denied.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &transport,
+            "disconnecting clients",
+            self.disconnecting.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &transport,
+            "negotiating clients",
+            self.negotiation.client_ids(),
+        );
+
+        let mut known = transport;
+        known.extend(connected());
+        known.extend(self.denied.client_ids());
+        known.extend(self.disconnecting.client_ids());
+        known.extend(self.suspended.ids().copied());
+        check(&mut unknown, &known, "outbox", self.outbox.client_ids());
+        check(
+            &mut unknown,
+            &known,
+            "delta baselines",
+            self.delta_baselines.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "sequences",
+            self.sequences.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "receive backlog",
+            self.receive_backlog.client_ids(),
+        );
+        check(&mut unknown, &known, "warm-up", self.warm_up.client_ids());
+        check(
+            &mut unknown,
+            &known,
+            "bandwidth",
+            self.bandwidth.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "teams",
+            self.teams.iter().map(|(client_id, _)| *client_id),
+        );
+        check(&mut unknown, &known, "sessions", self.sessions.client_ids());
+        check(
+            &mut unknown,
+            &known,
+            "matchmaking queues",
+            self.matchmaking_queues.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "muted client events",
+            self.muted_client_events.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "connection telemetry",
+            self.telemetry.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "resumable streams",
+            self.streams.client_ids(),
+        );
+        check(
+            &mut unknown,
+            &known,
+            "capabilities",
+            self.capabilities.client_ids(),
+        );
+        #[cfg(feature = "admin")]
+        check(
+            &mut unknown,
+            &known,
+            "admin sessions",
+            self.admin_sessions.client_ids(),
+        );
+        unknown
+    }
+}
+
+fn check(
+    unknown: &mut HashSet<(ClientId, &'static str)>,
+    known: &HashSet<ClientId>,
+    state: &'static str,
+    client_ids: impl Iterator<Item = ClientId>,
+) {
+    unknown.extend(
+        client_ids
+            .filter(|client_id| !known.contains(client_id))
+            .map(|client_id| (client_id, state)),
+    );
+}
+
+pub fn server_audits_client_state(
+    time: Res<Time>,
+    audited_state: AuditedState,
+    mut audit: ResMut<ClientStateAudit>,
+    mut leaked_client_state_events: EventWriter<LeakedClientState>,
+) {
+    let Some(interval) = audit.interval else {
+        return;
+    };
+    let now = time.elapsed();
+    if audit.next_audit.is_some_and(|next_audit| now < next_audit) {
+        return;
+    }
+    audit.next_audit = Some(now + interval);
+    let unknown = audited_state.unknown_clients();
+    let audit = &mut *audit;
+    for leak in unknown.intersection(&audit.suspects) {
+        if audit.reported.insert(*leak) {
+            audit.leaks += 1;
+            leaked_client_state_events.send(LeakedClientState {
+                client_id: leak.0,
+                state: leak.1,
+            });
+        }
+    }
+    // Leaks that got cleaned up after all can be reported again if they come back.
+    audit.reported.retain(|leak| unknown.contains(leak));
+    audit.suspects = unknown;
+}
+
+pub fn server_resets_client_state_audit(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut audit: ResMut<ClientStateAudit>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        audit.next_audit = None;
+        audit.suspects.clear();
+        audit.reported.clear();
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ClientStateAudit>()
+        .add_event::<LeakedClientState>()
+        .add_systems(
+            PreUpdate,
+            server_audits_client_state
+                .after(server_forgets_disconnected_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PreUpdate, server_resets_client_state_audit);
+}
@@ -0,0 +1,153 @@
+//!
+//! Periodic broadcasts from the server, driven by timers rather than frame counts so they don't
+//! depend on the server's frame rate.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource, Time, Timer, TimerMode,
+};
+use bevy_renet::renet::RenetServer;
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::time::Duration;
+
+use crate::{
+    server::{SendToClients, ServerStopped},
+    NetworkSet,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScheduledBroadcastId(u32);
+
+type BroadcastContent<T> = Box<dyn FnMut() -> T + Send + Sync>;
+
+struct ScheduledBroadcast<T> {
+    id: ScheduledBroadcastId,
+    timer: Timer,
+    content: BroadcastContent<T>,
+}
+
+///
+/// Messages of type T the server sends to every connected client at a fixed interval.
+/// Timers only advance while the server is running, and restart when it stops.
+///
+/// ```rust,ignore
+/// fn setup_server(mut broadcasts: ResMut<ScheduledBroadcasts<ServerStatus>>) {
+///     broadcasts.every_default(Duration::from_secs(1));
+///     let mut count = 0;
+///     broadcasts.every(Duration::from_secs(10), move || {
+///         count += 1;
+///         ServerStatus::announcement(count)
+///     });
+/// }
+/// ```
+///
+#[derive(Resource)]
+pub struct ScheduledBroadcasts<T: Event + Serialize + DeserializeOwned> {
+    broadcasts: Vec<ScheduledBroadcast<T>>,
+    next_id: u32,
+}
+
+impl<T: Event + Serialize + DeserializeOwned> Default for ScheduledBroadcasts<T> {
+    fn default() -> Self {
+        Self {
+            broadcasts: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<T: Event + Serialize + DeserializeOwned> ScheduledBroadcasts<T> {
+    /// Broadcasts the message built by `content` every `interval`, starting one interval from now.
+    pub fn every(
+        &mut self,
+        interval: Duration,
+        content: impl FnMut() -> T + Send + Sync + 'static,
+    ) -> ScheduledBroadcastId {
+        let id = ScheduledBroadcastId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.broadcasts.push(ScheduledBroadcast {
+            id,
+            timer: Timer::new(interval, TimerMode::Repeating),
+            content: Box::new(content),
+        });
+        id
+    }
+
+    /// Broadcasts `T::default()` every `interval`.
+    pub fn every_default(&mut self, interval: Duration) -> ScheduledBroadcastId
+    where
+        T: Default,
+    {
+        self.every(interval, T::default)
+    }
+
+    pub fn cancel(&mut self, id: ScheduledBroadcastId) {
+        self.broadcasts.retain(|broadcast| broadcast.id != id);
+    }
+
+    pub fn clear(&mut self) {
+        self.broadcasts.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.broadcasts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.broadcasts.is_empty()
+    }
+}
+
+impl<T: Event + Serialize + DeserializeOwned> std::fmt::Debug for ScheduledBroadcasts<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScheduledBroadcasts")
+            .field(
+                "intervals",
+                &self
+                    .broadcasts
+                    .iter()
+                    .map(|broadcast| broadcast.timer.duration())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+pub fn server_sends_scheduled_broadcasts<T: Event + Serialize + DeserializeOwned>(
+    time: Res<Time>,
+    mut scheduled_broadcasts: ResMut<ScheduledBroadcasts<T>>,
+    mut send_to_clients_events: EventWriter<SendToClients<T>>,
+) {
+    for broadcast in scheduled_broadcasts.broadcasts.iter_mut() {
+        broadcast.timer.tick(time.delta());
+        // A long frame may cover several intervals, catch up on all of them.
+        for _ in 0..broadcast.timer.times_finished_this_tick() {
+            send_to_clients_events.send(SendToClients {
+                content: (broadcast.content)(),
+            });
+        }
+    }
+}
+
+pub fn server_resets_scheduled_broadcasts<T: Event + Serialize + DeserializeOwned>(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut scheduled_broadcasts: ResMut<ScheduledBroadcasts<T>>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        for broadcast in scheduled_broadcasts.broadcasts.iter_mut() {
+            broadcast.timer.reset();
+        }
+    }
+}
+
+pub(crate) fn add_scheduled_broadcasts<T: Event + Serialize + DeserializeOwned>(app: &mut App) {
+    app.init_resource::<ScheduledBroadcasts<T>>()
+        .add_systems(
+            PostUpdate,
+            server_sends_scheduled_broadcasts::<T>
+                .before(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_resets_scheduled_broadcasts::<T>);
+}
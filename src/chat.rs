@@ -0,0 +1,525 @@
+//!
+//! Ready-made text chat, enabled with the `chat` feature.
+//!
+//! Clients send `SendChatMessage` events, the server runs them through the `ChatFilter` resource
+//! (mute lists and filter callback) and relays the accepted ones to every connected client as a
+//! `ChatMessage` event. The server receives the same `ChatMessage` events, and can send its own
+//! messages with `BroadcastChatMessage`.
+//!
+//! Listen servers get every message twice as `ChatMessage`, once accepted by the server and once
+//! delivered to the client, `ServerChatMessage` and `ClientChatMessage` tell them apart. Only the
+//! messages the server accepted or sent are relayed.
+//!
+//! Messages are scoped with `ChatScope`: global messages go to every connected client, team and
+//! session messages only to the clients in the sender's team (`Teams`) or session (`Sessions`).
+//! Scoped messages from a client outside any team or session are dropped. `BroadcastChatMessage`
+//! always uses the global scope.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate,
+    PreUpdate, Res, ResMut, Resource,
+};
+use bevy_renet::renet::{Bytes, RenetClient, RenetServer};
+use bevy_renet::RenetReceive;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+
+use crate::{
+    server::{server_tracks_connected_and_disconnected_clients, ConnectedClients},
+    sessions::Sessions,
+    teams::Teams,
+    ClientId, PlayerId, CHAT_CHANNEL_ID,
+};
+
+/// Upper bound on the memory decoding a chat message may claim, so that a crafted length prefix
+/// can't make the server allocate huge buffers. Longer messages are dropped.
+const MAX_CHAT_MESSAGE_BYTES: usize = 1 << 12;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ChatPayload {
+    Send {
+        text: String,
+        scope: ChatScope,
+    },
+    Deliver {
+        sender: Option<ClientId>,
+        text: String,
+        scope: ChatScope,
+    },
+}
+
+impl ChatPayload {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard().with_limit::<MAX_CHAT_MESSAGE_BYTES>();
+        bincode::serde::decode_from_slice(bytes, config)
+            .ok()
+            .map(|(payload, _)| payload)
+    }
+}
+
+///
+/// Who a chat message is delivered to.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChatScope {
+    /// Every connected client.
+    #[default]
+    Global,
+    /// The clients in the sender's team.
+    Team,
+    /// The clients in the sender's session.
+    Session,
+}
+
+///
+/// Send this event from a client to post a chat message.
+///
+#[derive(Debug, Event)]
+pub struct SendChatMessage {
+    pub text: String,
+    pub scope: ChatScope,
+}
+
+///
+/// Send this event from the server to post a chat message to every connected client.
+///
+#[derive(Debug, Event)]
+pub struct BroadcastChatMessage {
+    pub text: String,
+}
+
+///
+/// A chat message accepted by the server, received on both the server and the clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ChatMessage {
+    /// The client that sent the message, `None` for messages sent by the server.
+    pub sender: Option<ClientId>,
+    pub text: String,
+    pub scope: ChatScope,
+}
+
+///
+/// A `ChatMessage` accepted or sent by the server (including the server of a listen server), the
+/// messages relayed to the clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ServerChatMessage(pub ChatMessage);
+
+///
+/// A `ChatMessage` delivered to the client (including the client of a listen server).
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClientChatMessage(pub ChatMessage);
+
+type ChatFilterCallback = Box<dyn Fn(ClientId, &str) -> Option<String> + Send + Sync>;
+
+///
+/// Server side moderation of chat messages sent by clients.
+///
+#[derive(Default, Resource)]
+pub struct ChatFilter {
+    muted: HashSet<ClientId>,
+    muted_players: HashSet<PlayerId>,
+    max_length: Option<usize>,
+    callback: Option<ChatFilterCallback>,
+}
+
+impl ChatFilter {
+    /// Drops every message from this client until unmuted, persists across reconnections.
+    pub fn mute(&mut self, client_id: ClientId) {
+        self.muted.insert(client_id);
+    }
+
+    pub fn unmute(&mut self, client_id: ClientId) {
+        self.muted.remove(&client_id);
+    }
+
+    pub fn is_muted(&self, client_id: ClientId) -> bool {
+        self.muted.contains(&client_id)
+    }
+
+    /// Drops every message from this player (see `ConnectToServer::player_id`) until unmuted,
+    /// whatever client it connects from.
+    pub fn mute_player(&mut self, player_id: PlayerId) {
+        self.muted_players.insert(player_id);
+    }
+
+    pub fn unmute_player(&mut self, player_id: PlayerId) {
+        self.muted_players.remove(&player_id);
+    }
+
+    pub fn is_player_muted(&self, player_id: PlayerId) -> bool {
+        self.muted_players.contains(&player_id)
+    }
+
+    /// Drops messages longer than `max_length` characters, `None` only drops the ones over 4 KiB.
+    pub fn set_max_length(&mut self, max_length: Option<usize>) {
+        self.max_length = max_length;
+    }
+
+    pub fn max_length(&self) -> Option<usize> {
+        self.max_length
+    }
+
+    ///
+    /// Sets a callback run on every message from a client that isn't muted.
+    /// Returning `None` drops the message, returning `Some` relays the (possibly rewritten) text,
+    /// which is where profanity filtering or rate checks go.
+    ///
+    pub fn set_callback(
+        &mut self,
+        callback: impl Fn(ClientId, &str) -> Option<String> + Send + Sync + 'static,
+    ) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    pub fn clear_callback(&mut self) {
+        self.callback = None;
+    }
+
+    fn apply(
+        &self,
+        client_id: ClientId,
+        player_id: Option<PlayerId>,
+        text: String,
+    ) -> Option<String> {
+        if self.is_muted(client_id)
+            || player_id.is_some_and(|player_id| self.is_player_muted(player_id))
+            || self
+                .max_length
+                .is_some_and(|max_length| text.chars().count() > max_length)
+        {
+            return None;
+        }
+        match &self.callback {
+            Some(callback) => callback(client_id, &text),
+            None => Some(text),
+        }
+    }
+}
+
+impl std::fmt::Debug for ChatFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChatFilter")
+            .field("muted", &self.muted)
+            .field("muted_players", &self.muted_players)
+            .field("max_length", &self.max_length)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
+
+pub fn server_receives_chat_messages(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    chat_filter: Res<ChatFilter>,
+    mut chat_message_events: EventWriter<ChatMessage>,
+    mut server_chat_message_events: EventWriter<ServerChatMessage>,
+) {
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, CHAT_CHANNEL_ID) {
+            let Some(connected_client) = connected_clients.get(client_id.into()) else {
+                continue;
+            };
+            let Some(ChatPayload::Send { text, scope }) = ChatPayload::decode(&message) else {
+                continue;
+            };
+            if let Some(text) =
+                chat_filter.apply(client_id.into(), connected_client.player_id, text)
+            {
+                let chat_message = ChatMessage {
+                    sender: Some(client_id.into()),
+                    text,
+                    scope,
+                };
+                server_chat_message_events.send(ServerChatMessage(chat_message.clone()));
+                chat_message_events.send(chat_message);
+            }
+        }
+    }
+}
+
+pub fn server_sends_chat_messages(
+    mut broadcast_chat_message_events: EventReader<BroadcastChatMessage>,
+    mut chat_message_events: EventWriter<ChatMessage>,
+    mut server_chat_message_events: EventWriter<ServerChatMessage>,
+) {
+    for BroadcastChatMessage { text } in broadcast_chat_message_events.read() {
+        let chat_message = ChatMessage {
+            sender: None,
+            text: text.clone(),
+            scope: ChatScope::Global,
+        };
+        server_chat_message_events.send(ServerChatMessage(chat_message.clone()));
+        chat_message_events.send(chat_message);
+    }
+}
+
+pub fn server_relays_chat_messages(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    teams: Res<Teams>,
+    sessions: Res<Sessions>,
+    mut server_chat_message_events: EventReader<ServerChatMessage>,
+) {
+    for ServerChatMessage(ChatMessage {
+        sender,
+        text,
+        scope,
+    }) in server_chat_message_events.read()
+    {
+        let recipients: Vec<ClientId> = match (scope, sender) {
+            (ChatScope::Global, _) | (_, None) => connected_clients
+                .iter()
+                .map(|(client_id, _)| *client_id)
+                .collect(),
+            (ChatScope::Team, Some(sender)) => match teams.team_of(*sender) {
+                Some(team) => teams.members(team).collect(),
+                None => continue,
+            },
+            (ChatScope::Session, Some(sender)) => match sessions.session_of(*sender) {
+                Some(session) => sessions.members(session).collect(),
+                None => continue,
+            },
+        };
+        let payload = Bytes::from(
+            ChatPayload::Deliver {
+                sender: *sender,
+                text: text.clone(),
+                scope: *scope,
+            }
+            .encode(),
+        );
+        for client_id in recipients {
+            if connected_clients.get(client_id).is_some() {
+                server.send_message(client_id.into(), CHAT_CHANNEL_ID, payload.clone());
+            }
+        }
+    }
+}
+
+pub fn client_sends_chat_messages(
+    mut client: ResMut<RenetClient>,
+    mut send_chat_message_events: EventReader<SendChatMessage>,
+) {
+    for SendChatMessage { text, scope } in send_chat_message_events.read() {
+        let payload = ChatPayload::Send {
+            text: text.clone(),
+            scope: *scope,
+        };
+        client.send_message(CHAT_CHANNEL_ID, payload.encode());
+    }
+}
+
+pub fn client_receives_chat_messages(
+    mut client: ResMut<RenetClient>,
+    mut chat_message_events: EventWriter<ChatMessage>,
+    mut client_chat_message_events: EventWriter<ClientChatMessage>,
+) {
+    while let Some(message) = client.receive_message(CHAT_CHANNEL_ID) {
+        if let Some(ChatPayload::Deliver {
+            sender,
+            text,
+            scope,
+        }) = ChatPayload::decode(&message)
+        {
+            let chat_message = ChatMessage {
+                sender,
+                text,
+                scope,
+            };
+            client_chat_message_events.send(ClientChatMessage(chat_message.clone()));
+            chat_message_events.send(chat_message);
+        }
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ChatFilter>()
+        .add_event::<SendChatMessage>()
+        .add_event::<BroadcastChatMessage>()
+        .add_event::<ChatMessage>()
+        .add_event::<ServerChatMessage>()
+        .add_event::<ClientChatMessage>()
+        .add_systems(
+            PreUpdate,
+            server_receives_chat_messages
+                .after(RenetReceive)
+                .after(server_tracks_connected_and_disconnected_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (server_sends_chat_messages, server_relays_chat_messages)
+                .chain()
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (client_sends_chat_messages, client_receives_chat_messages)
+                .run_if(resource_exists::<RenetClient>),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{Mut, Update};
+    use renet::{ChannelConfig, ConnectionConfig, SendType};
+
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{server::ConnectedClient, sessions::SessionId, teams::TeamId, ConnectionMode};
+
+    #[derive(Default, Resource)]
+    struct Received {
+        server: usize,
+        client: usize,
+    }
+
+    fn count_received(
+        mut server_chat_message_events: EventReader<ServerChatMessage>,
+        mut client_chat_message_events: EventReader<ClientChatMessage>,
+        mut received: ResMut<Received>,
+    ) {
+        received.server += server_chat_message_events.read().count();
+        received.client += client_chat_message_events.read().count();
+    }
+
+    fn server_app(client_ids: &[ClientId]) -> (App, Vec<RenetClient>) {
+        let channels_config = vec![ChannelConfig {
+            channel_id: CHAT_CHANNEL_ID,
+            max_memory_usage_bytes: 1 << 20,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_millis(300),
+            },
+        }];
+        let mut server = RenetServer::new(ConnectionConfig {
+            server_channels_config: channels_config.clone(),
+            client_channels_config: channels_config,
+            ..Default::default()
+        });
+        let mut connected_clients = ConnectedClients::default();
+        let mut clients = Vec::new();
+        for client_id in client_ids {
+            clients.push(server.new_local_client((*client_id).into()));
+            connected_clients.insert(
+                *client_id,
+                ConnectedClient {
+                    mode: ConnectionMode::Player,
+                    player_id: None,
+                },
+            );
+        }
+        let mut app = App::new();
+        app.init_resource::<Teams>()
+            .init_resource::<Sessions>()
+            .insert_resource(connected_clients)
+            .insert_resource(server);
+        build(&mut app);
+        (app, clients)
+    }
+
+    #[test]
+    fn listen_server_relays_messages_once() {
+        let client_id = ClientId(1);
+        let (mut app, mut clients) = server_app(&[client_id]);
+        app.init_resource::<Received>()
+            .insert_resource(clients.pop().unwrap())
+            .add_systems(Update, count_received);
+        app.world_mut().send_event(SendChatMessage {
+            text: "hello".to_string(),
+            scope: ChatScope::Global,
+        });
+        for _ in 0..10 {
+            app.update();
+            app.world_mut()
+                .resource_scope(|world, mut server: Mut<RenetServer>| {
+                    let mut client = world.resource_mut::<RenetClient>();
+                    server.process_local_client(client_id.into(), &mut client)
+                })
+                .unwrap();
+        }
+        let received = app.world().resource::<Received>();
+        assert_eq!(received.server, 1);
+        assert_eq!(received.client, 1);
+    }
+
+    #[test]
+    fn scoped_messages_reach_the_sender_team_or_session() {
+        let client_ids = [ClientId(1), ClientId(2), ClientId(3)];
+        let (mut app, mut clients) = server_app(&client_ids);
+        let mut teams = app.world_mut().resource_mut::<Teams>();
+        teams.assign(ClientId(1), TeamId(1));
+        teams.assign(ClientId(2), TeamId(1));
+        teams.assign(ClientId(3), TeamId(2));
+        let mut sessions = app.world_mut().resource_mut::<Sessions>();
+        sessions.join(ClientId(1), SessionId(1));
+        sessions.join(ClientId(3), SessionId(1));
+        for (scope, expected) in [
+            (ChatScope::Global, [true, true, true]),
+            (ChatScope::Team, [true, true, false]),
+            (ChatScope::Session, [true, false, true]),
+        ] {
+            app.world_mut().send_event(ServerChatMessage(ChatMessage {
+                sender: Some(ClientId(1)),
+                text: "hello".to_string(),
+                scope,
+            }));
+            app.update();
+            let mut server = app.world_mut().resource_mut::<RenetServer>();
+            for ((client_id, client), expected) in client_ids.iter().zip(&mut clients).zip(expected)
+            {
+                server
+                    .process_local_client((*client_id).into(), client)
+                    .unwrap();
+                let received = client.receive_message(CHAT_CHANNEL_ID).is_some();
+                assert_eq!(received, expected, "{scope:?} to {client_id:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn scoped_messages_outside_a_team_are_dropped() {
+        let (mut app, mut clients) = server_app(&[ClientId(1)]);
+        app.world_mut().send_event(ServerChatMessage(ChatMessage {
+            sender: Some(ClientId(1)),
+            text: "hello".to_string(),
+            scope: ChatScope::Team,
+        }));
+        app.update();
+        let mut server = app.world_mut().resource_mut::<RenetServer>();
+        server
+            .process_local_client(ClientId(1).into(), &mut clients[0])
+            .unwrap();
+        assert!(clients[0].receive_message(CHAT_CHANNEL_ID).is_none());
+    }
+
+    #[test]
+    fn crafted_length_prefix_is_rejected() {
+        let mut bytes = vec![0, 253];
+        bytes.extend_from_slice(&(1u64 << 62).to_le_bytes());
+        assert!(ChatPayload::decode(&bytes).is_none());
+    }
+
+    #[test]
+    fn long_messages_are_dropped() {
+        let mut chat_filter = ChatFilter::default();
+        let client_id = ClientId(1);
+        chat_filter.set_max_length(Some(3));
+        assert_eq!(
+            chat_filter.apply(client_id, None, "héé".to_string()),
+            Some("héé".to_string())
+        );
+        assert_eq!(
+            chat_filter.apply(client_id, None, "hello".to_string()),
+            None
+        );
+    }
+}
@@ -1,13 +1,49 @@
-use bevy::prelude::{Commands, Event, EventReader, EventWriter, Res, ResMut};
+use bevy::ecs::system::SystemParam;
+use bevy::log::error;
+use bevy::prelude::{
+    Commands, DetectChanges, Event, EventReader, EventWriter, Events, Local, Res, ResMut, Resource,
+    Time,
+};
 use bevy_renet::renet::{transport::ClientAuthentication, ConnectionConfig, RenetClient};
-use renet::transport::{ConnectToken, NetcodeClientTransport, NETCODE_USER_DATA_BYTES};
+use renet::transport::{
+    ConnectToken, NetcodeClientTransport, NetcodeDisconnectReason, NETCODE_USER_DATA_BYTES,
+};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use std::net::UdpSocket;
-use std::time::SystemTime;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime};
 
-use crate::NetworkConfigs;
+use crate::{
+    codec,
+    control::ControlMessage,
+    delivery::ClientPendingDeliveries,
+    delta::Baselines,
+    error::{ClientNetworkError, ConfigError, NetworkError, SendErrorReason},
+    handshake::Handshake,
+    health::ChannelHealth,
+    matchmaking::MatchFound,
+    migration::ServerMigrating,
+    negotiation::{ClientNegotiation, Negotiation},
+    outbox::{Coalescer, QueuedMessage},
+    ready_check::ClientReadyChecks,
+    redirect::Redirecting,
+    sequence::{self, Arrival, Sequences},
+    sessions::{SessionEvents, Sessions},
+    streams::ClientStreams,
+    teams::{ClientTeamChanged, Teams},
+    user_data::{
+        password_hash, write_channels_fingerprint, write_connection_mode, write_password_hash,
+        write_player_id, write_redirect_token, RESERVED_BYTES,
+    },
+    ChannelRegistry, ChannelsFingerprint, ClientId, ConnectionMode, DecodeErrorKind, DenialReason,
+    DeterministicOrdering, DisconnectReason, MessageDecodeError, NetworkConfig, NetworkConfigs,
+    PlayerId, SocketOptions, UnknownChannelMessage, CONTROL_CHANNEL_ID,
+};
+
+/// Trailing bytes of `ConnectToServer::user_data` used by the crate, whatever the connection.
+pub const RESERVED_USER_DATA_BYTES: usize = RESERVED_BYTES;
 
 #[derive(Debug, Event)]
 pub struct ConnectToServer {
@@ -15,11 +51,29 @@ pub struct ConnectToServer {
     pub server_port: u16,
     pub protocol_id: u64,
     pub available_bytes_per_tick: u64,
-    pub client_id: Option<u64>,
+    pub client_id: Option<ClientId>,
+    /// The last `RESERVED_USER_DATA_BYTES` are reserved for connection metadata (connection mode,
+    /// registered types, password, player id, redirect token), only the leading bytes are the app's.
     pub user_data: Option<[u8; NETCODE_USER_DATA_BYTES]>,
     pub expire_seconds: Option<u64>,
     pub timeout_seconds: Option<i32>,
     pub private_key: Option<[u8; 32]>,
+    /// Password of servers started with `StartServer::password`.
+    pub password: Option<String>,
+    pub mode: ConnectionMode,
+    /// An already bound (and configured) socket to use instead of binding an ephemeral port.
+    pub socket: Option<UdpSocket>,
+    pub socket_options: SocketOptions,
+    /// Reuse the client id of the previous connection (unless `client_id` is set), so that the
+    /// server can resume the session (see `SessionResumption`).
+    pub resume: bool,
+    /// Servers tried in order when the previous one can't be reached, see `FailedOverTo`.
+    pub fallback_addrs: Vec<SocketAddr>,
+    /// Handed to the server in the reserved bytes of `user_data`, see `RedirectClient`.
+    pub redirect_token: Option<Vec<u8>>,
+    /// Persistent identity of the player, see `PlayerId`. Clients can claim any player id, servers
+    /// should check it (e.g. against a `redirect_token` issued by a login service).
+    pub player_id: Option<PlayerId>,
 }
 
 impl Default for ConnectToServer {
@@ -34,68 +88,415 @@ impl Default for ConnectToServer {
             expire_seconds: None,
             timeout_seconds: None,
             private_key: None,
+            password: None,
+            mode: ConnectionMode::Player,
+            socket: None,
+            socket_options: SocketOptions::default(),
+            resume: false,
+            fallback_addrs: Vec::new(),
+            redirect_token: None,
+            player_id: None,
         }
     }
 }
 
 impl ConnectToServer {
-    fn get_client_and_transport(
+    pub(crate) fn server_addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.server_ip
+            .parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, self.server_port))
+            .map_err(|_| {
+                ConfigError::InvalidAddress(format!("{}:{}", self.server_ip, self.server_port))
+            })
+    }
+
+    pub(crate) fn get_client_and_transport(
         &self,
+        server_addr: SocketAddr,
         channel_configs: NetworkConfigs,
-    ) -> (RenetClient, NetcodeClientTransport) {
+        channels_fingerprint: ChannelsFingerprint,
+        last_client_id: Option<ClientId>,
+    ) -> Result<
+        (
+            RenetClient,
+            NetcodeClientTransport,
+            LocalClientId,
+            ServerEndpoint,
+        ),
+        ConfigError,
+    > {
         let client = RenetClient::new(ConnectionConfig {
             available_bytes_per_tick: self.available_bytes_per_tick,
             server_channels_config: channel_configs.clone().into(),
             client_channels_config: channel_configs.into(),
         });
-        let server_addr = format!("{}:{}", self.server_ip, self.server_port)
-            .parse()
-            .unwrap();
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let socket = match &self.socket {
+            Some(socket) => socket.try_clone()?,
+            None => UdpSocket::bind("0.0.0.0:0")?,
+        };
+        self.socket_options.apply(&socket)?;
         let current_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap();
-        let client_id = self.client_id.unwrap_or(current_time.as_millis() as u64);
+        let client_id = self
+            .client_id
+            .or(last_client_id.filter(|_| self.resume))
+            .unwrap_or(ClientId(current_time.as_millis() as u64));
+        let mut user_data = self.user_data.unwrap_or([0; NETCODE_USER_DATA_BYTES]);
+        if let Some(token) = &self.redirect_token {
+            if !write_redirect_token(&mut user_data, token) {
+                return Err(ConfigError::RedirectTokenTooLong(token.len()));
+            }
+        }
+        if let Some(player_id) = self.player_id {
+            write_player_id(&mut user_data, player_id);
+        }
+        write_connection_mode(&mut user_data, self.mode);
+        write_channels_fingerprint(&mut user_data, channels_fingerprint);
+        if let Some(password) = &self.password {
+            write_password_hash(&mut user_data, password_hash(password, self.protocol_id));
+        }
         let authentication = if let Some(private_key) = self.private_key {
-            let ud;
-            let user_data = if self.user_data.is_some() {
-                ud = self.user_data.unwrap();
-                Some(&ud)
-            } else {
-                None
-            };
             ClientAuthentication::Secure {
                 connect_token: ConnectToken::generate(
                     current_time,
                     self.protocol_id,
                     self.expire_seconds.unwrap_or(86_400), // 1 day by default
-                    client_id,
+                    client_id.raw(),
                     self.timeout_seconds.unwrap_or(-1), // No timeout by default
                     vec![server_addr],
-                    user_data,
+                    Some(&user_data),
                     &private_key,
                 )
-                .unwrap(),
+                .map_err(|error| ConfigError::Transport(error.to_string()))?,
             }
         } else {
             ClientAuthentication::Unsecure {
-                client_id,
+                client_id: client_id.raw(),
                 protocol_id: self.protocol_id,
                 server_addr,
-                user_data: self.user_data,
+                user_data: Some(user_data),
             }
         };
-        let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
-        (client, transport)
+        let transport = NetcodeClientTransport::new(current_time, authentication, socket)
+            .map_err(|error| ConfigError::Transport(error.to_string()))?;
+        let server_endpoint = ServerEndpoint {
+            addr: server_addr,
+            protocol_id: self.protocol_id,
+        };
+        Ok((client, transport, LocalClientId(client_id), server_endpoint))
+    }
+}
+
+///
+/// The client id used for the current connection.
+/// Inserted when connecting to a server and removed when disconnecting.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Resource)]
+pub struct LocalClientId(pub ClientId);
+
+///
+/// The server the client is connected (or connecting) to.
+/// Inserted when connecting to a server and removed when disconnecting.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct ServerEndpoint {
+    pub addr: SocketAddr,
+    pub protocol_id: u64,
+}
+
+///
+/// Sent when the server couldn't be reached and the client moved on to the next of
+/// `ConnectToServer::fallback_addrs`.
+///
+#[derive(Debug, Clone, Event)]
+pub struct FailedOverTo {
+    pub addr: SocketAddr,
+}
+
+///
+/// The stages of a connection attempt, sent in order as the client goes through them, for connect
+/// screens to show what the client is waiting for. A new attempt (including a failover or a
+/// migration) starts over from `SocketBound`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Event)]
+pub enum ConnectionProgress {
+    /// The client's socket is bound to `local_addr`, the connect token goes out next.
+    SocketBound {
+        local_addr: SocketAddr,
+    },
+    /// The connect token was sent to `server_addr`, waiting for the server's challenge.
+    TokenSent {
+        server_addr: SocketAddr,
+    },
+    /// The server answered with a challenge, the client is responding to it.
+    ChallengeReceived,
+    Connected,
+    /// The attempt ended before the connection was established.
+    Failed {
+        reason: DisconnectReason,
+    },
+}
+
+///
+/// Received when an established connection to the server ends without a `DisconnectFromServer`,
+/// with the reason the server gave (see `DisconnectClient`) or the transport's. Send a
+/// `DisconnectFromServer` to tear the client down.
+///
+#[derive(Debug, Clone, Event)]
+pub struct DisconnectedFromServer {
+    pub reason: DisconnectReason,
+}
+
+///
+/// The reason the server gave for disconnecting the client, once it did.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerDisconnectReason(Option<DisconnectReason>);
+
+impl ServerDisconnectReason {
+    pub fn get(&self) -> Option<DisconnectReason> {
+        self.0
+    }
+}
+
+///
+/// The latest `ConnectionProgress` of the current (or last) connection attempt.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientConnectionProgress {
+    latest: Option<ConnectionProgress>,
+    last_received: Duration,
+}
+
+impl ClientConnectionProgress {
+    pub fn latest(&self) -> Option<ConnectionProgress> {
+        self.latest
+    }
+}
+
+///
+/// The `ConnectToServer` the current connection was made with, its `fallback_addrs` only holding
+/// the servers not tried yet. Cleared when disconnecting.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ActiveConnection(pub(crate) Option<ConnectToServer>);
+
+impl ActiveConnection {
+    /// The fallback servers not tried yet.
+    pub fn fallback_addrs(&self) -> &[SocketAddr] {
+        self.0
+            .as_ref()
+            .map_or(&[], |connect_to_server| &connect_to_server.fallback_addrs)
+    }
+
+    /// Whether the transport couldn't reach the server and a fallback server is left to try.
+    pub(crate) fn fails_over(&self, transport: &NetcodeClientTransport) -> bool {
+        let unreachable = matches!(
+            transport.disconnect_reason(),
+            Some(
+                NetcodeDisconnectReason::ConnectionRequestTimedOut
+                    | NetcodeDisconnectReason::ConnectionResponseTimedOut
+            )
+        );
+        unreachable && !self.fallback_addrs().is_empty()
     }
 }
 
+///
+/// The client id of the last connection, kept after disconnecting for `ConnectToServer::resume`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct LastClientId(pub Option<ClientId>);
+
 #[derive(Debug, Event)]
 pub struct DisconnectFromServer;
 
+///
+/// Sent once the client and its transport are torn down after a `DisconnectFromServer`.
+///
+#[derive(Debug, Event)]
+pub struct ClientStopped;
+
+///
+/// Tracks the server's heartbeats to notice quickly when it stops responding, well before the
+/// transport times out (or never does, with a `timeout_seconds` of -1).
+///
+#[derive(Debug, Resource)]
+pub struct ServerLiveness {
+    /// How long without hearing from the server before `ServerUnreachable` is emitted.
+    pub timeout: Duration,
+    last_heard: Option<Duration>,
+    unreachable: bool,
+}
+
+impl Default for ServerLiveness {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(1500),
+            last_heard: None,
+            unreachable: false,
+        }
+    }
+}
+
+impl ServerLiveness {
+    /// When the server was last heard from, as `Time::elapsed`.
+    pub fn last_heard(&self) -> Option<Duration> {
+        self.last_heard
+    }
+
+    pub fn is_unreachable(&self) -> bool {
+        self.unreachable
+    }
+
+    fn heard(&mut self, now: Duration) {
+        self.last_heard = Some(now);
+    }
+
+    fn reset(&mut self) {
+        self.last_heard = None;
+        self.unreachable = false;
+    }
+}
+
+///
+/// The server hasn't been heard from for `ServerLiveness::timeout` while still connected.
+/// The connection is kept, `ServerReachable` follows if the server comes back.
+///
+#[derive(Debug, Event)]
+pub struct ServerUnreachable {
+    /// When the server was last heard from, as `Time::elapsed`.
+    pub last_heard: Duration,
+}
+
+///
+/// The server was heard from again after `ServerUnreachable`.
+///
+#[derive(Debug, Event)]
+pub struct ServerReachable;
+
+///
+/// Received when the server refuses the connection, the client disconnects right after.
+///
+#[derive(Debug, Event)]
+pub struct ConnectionDenied {
+    pub reason: DenialReason,
+}
+
+///
+/// Delta encoding baselines per channel, see `NetworkConfig::delta`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientDeltaBaselines(Baselines<u8>);
+
+///
+/// Sequence numbers received from and sent to the server per channel, see
+/// `NetworkConfig::sequenced`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientSequences(Sequences<u8>);
+
+///
+/// Encoded messages waiting to be handed to renet.
+/// Filled by the typed send systems and drained by `client_flushes_outbox`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientOutbox {
+    queue: VecDeque<QueuedMessage>,
+    coalescer: Coalescer<u8>,
+}
+
+impl ClientOutbox {
+    fn push(&mut self, config: &NetworkConfig, message: QueuedMessage) {
+        if config.send_interval().is_some() {
+            self.coalescer.offer(message.channel_id, message);
+        } else {
+            self.queue.push_back(message);
+        }
+    }
+
+    /// Number of messages held back by `NetworkConfig::max_send_rate`.
+    pub fn coalesced(&self) -> usize {
+        self.coalescer.pending()
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.coalescer.clear();
+    }
+}
+
 #[derive(Debug, Event)]
 pub struct ReceiveFromServer<T: Event + Serialize + DeserializeOwned> {
     pub content: T,
+    /// When the server sent the message (since the UNIX epoch, by the server's clock).
+    /// Only available when `NetworkConfig::timestamps` is enabled for this type.
+    pub sent_at: Option<Duration>,
+    /// When the message was received (since the UNIX epoch, by the client's clock).
+    pub received_at: Duration,
+}
+
+///
+/// The newest message of type `T` received from the server this tick, kept instead of
+/// `ReceiveFromServer<T>` events for types with `NetworkConfig::latest_only` enabled.
+///
+#[derive(Debug, Resource)]
+pub struct LatestFromServer<T: Event + Serialize + DeserializeOwned>(Option<ReceiveFromServer<T>>);
+
+impl<T: Event + Serialize + DeserializeOwned> Default for LatestFromServer<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<T: Event + Serialize + DeserializeOwned> LatestFromServer<T> {
+    pub fn get(&self) -> Option<&ReceiveFromServer<T>> {
+        self.0.as_ref()
+    }
+
+    /// Takes the message, so that it is only handled once.
+    pub fn take(&mut self) -> Option<ReceiveFromServer<T>> {
+        self.0.take()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+///
+/// Messages of type `T` received from the server, kept until drained instead of
+/// `ReceiveFromServer<T>` events for types with `NetworkConfig::queued` enabled.
+///
+#[derive(Debug, Resource)]
+pub struct QueuedFromServer<T: Event + Serialize + DeserializeOwned>(
+    VecDeque<ReceiveFromServer<T>>,
+);
+
+impl<T: Event + Serialize + DeserializeOwned> Default for QueuedFromServer<T> {
+    fn default() -> Self {
+        Self(VecDeque::new())
+    }
+}
+
+impl<T: Event + Serialize + DeserializeOwned> QueuedFromServer<T> {
+    pub fn iter(&self) -> impl Iterator<Item = &ReceiveFromServer<T>> {
+        self.0.iter()
+    }
+
+    /// Takes the messages in the order they were received.
+    pub fn drain(&mut self) -> impl Iterator<Item = ReceiveFromServer<T>> + '_ {
+        self.0.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Debug, Event)]
@@ -103,57 +504,601 @@ pub struct SendToServer<T: Event + Serialize + DeserializeOwned> {
     pub content: T,
 }
 
+///
+/// The latest `ConnectToServer` not applied yet, kept until the client is disconnected so that
+/// requests sent early (e.g. during `Startup`) or while disconnecting aren't lost.
+///
+#[derive(Debug, Default, Resource)]
+pub struct PendingConnectToServer(pub(crate) Option<ConnectToServer>);
+
+impl PendingConnectToServer {
+    pub fn is_pending(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn client_initiates_connection_to_server(
-    mut connect_to_server_events: EventReader<ConnectToServer>,
+    mut connect_to_server_events: ResMut<Events<ConnectToServer>>,
+    mut pending_connect_to_server: ResMut<PendingConnectToServer>,
+    client: Option<Res<RenetClient>>,
     channel_configs: Res<NetworkConfigs>,
+    channel_registry: Res<ChannelRegistry>,
+    mut last_client_id: ResMut<LastClientId>,
+    mut active_connection: ResMut<ActiveConnection>,
+    mut network_errors: EventWriter<ClientNetworkError>,
     mut commands: Commands,
 ) {
-    for connect_to_server in connect_to_server_events.read() {
-        let (client, transport) =
-            connect_to_server.get_client_and_transport(channel_configs.clone());
+    if let Some(connect_to_server) = connect_to_server_events.drain().last() {
+        pending_connect_to_server.0 = Some(connect_to_server);
+    }
+    if client.is_some() {
+        return;
+    }
+    if let Some(mut connect_to_server) = pending_connect_to_server.0.take() {
+        let (client, transport, local_client_id, server_endpoint) =
+            match connect_to_server.server_addr().and_then(|server_addr| {
+                connect_to_server.get_client_and_transport(
+                    server_addr,
+                    channel_configs.clone(),
+                    channel_registry.fingerprint(),
+                    last_client_id.0,
+                )
+            }) {
+                Ok(client_and_transport) => client_and_transport,
+                Err(error) => {
+                    network_errors.send(ClientNetworkError(NetworkError::Config(error)));
+                    return;
+                },
+            };
+        last_client_id.0 = Some(local_client_id.0);
+        // Fallback servers are connected to with the same client id.
+        connect_to_server.client_id = Some(local_client_id.0);
+        active_connection.0 = Some(connect_to_server);
         commands.insert_resource(client);
         commands.insert_resource(transport);
+        commands.insert_resource(local_client_id);
+        commands.insert_resource(server_endpoint);
+    }
+}
+
+///
+/// Runs after the transport sent its packets, so `TokenSent` is only reported once it went out.
+///
+/// Netcode doesn't expose its handshake state, but the server's challenge is the only packet it
+/// sends to a connecting client (anything else ends the attempt), so the time since the last
+/// received packet going down while connecting means the challenge arrived.
+///
+pub fn client_reports_connection_progress(
+    transport: Res<NetcodeClientTransport>,
+    server_endpoint: Option<Res<ServerEndpoint>>,
+    mut connection_progress: ResMut<ClientConnectionProgress>,
+    mut connection_progress_events: EventWriter<ConnectionProgress>,
+) {
+    if transport.is_added() {
+        connection_progress.latest = None;
+    }
+    let last_received = transport.time_since_last_received_packet();
+    let progress = match connection_progress.latest {
+        Some(ConnectionProgress::Connected | ConnectionProgress::Failed { .. }) => None,
+        _ if transport.is_connected() => Some(ConnectionProgress::Connected),
+        _ if transport.is_disconnected() => {
+            transport
+                .disconnect_reason()
+                .map(|reason| ConnectionProgress::Failed {
+                    reason: reason.into(),
+                })
+        },
+        None => transport
+            .addr()
+            .ok()
+            .map(|local_addr| ConnectionProgress::SocketBound { local_addr }),
+        Some(ConnectionProgress::SocketBound { .. }) => {
+            server_endpoint.map(|server_endpoint| ConnectionProgress::TokenSent {
+                server_addr: server_endpoint.addr,
+            })
+        },
+        Some(ConnectionProgress::TokenSent { .. })
+            if last_received < connection_progress.last_received =>
+        {
+            Some(ConnectionProgress::ChallengeReceived)
+        },
+        Some(ConnectionProgress::TokenSent { .. } | ConnectionProgress::ChallengeReceived) => None,
+    };
+    if let Some(progress) = progress {
+        connection_progress.latest = Some(progress);
+        connection_progress_events.send(progress);
+    }
+    connection_progress.last_received = last_received;
+}
+
+pub fn client_reports_disconnection(
+    transport: Res<NetcodeClientTransport>,
+    connection_progress: Res<ClientConnectionProgress>,
+    mut server_disconnect_reason: ResMut<ServerDisconnectReason>,
+    mut reported: Local<bool>,
+    mut disconnected_from_server_events: EventWriter<DisconnectedFromServer>,
+) {
+    if transport.is_added() {
+        server_disconnect_reason.0 = None;
+        *reported = false;
+    }
+    // Attempts that never connected are reported as `ConnectionProgress::Failed`.
+    if *reported
+        || !transport.is_disconnected()
+        || connection_progress.latest() != Some(ConnectionProgress::Connected)
+    {
+        return;
+    }
+    let reason = server_disconnect_reason
+        .0
+        .or_else(|| transport.disconnect_reason().map(DisconnectReason::from));
+    if let Some(reason) = reason {
+        *reported = true;
+        disconnected_from_server_events.send(DisconnectedFromServer { reason });
+    }
+}
+
+///
+/// Replaces the client and its transport with ones for the next fallback server when the current
+/// server never answered the connection request.
+///
+pub fn client_fails_over_to_next_server(
+    transport: Res<NetcodeClientTransport>,
+    channel_configs: Res<NetworkConfigs>,
+    channel_registry: Res<ChannelRegistry>,
+    mut active_connection: ResMut<ActiveConnection>,
+    mut failed_over_to_events: EventWriter<FailedOverTo>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+    mut commands: Commands,
+) {
+    if !active_connection.fails_over(&transport) {
+        return;
+    }
+    let Some(connect_to_server) = &mut active_connection.0 else {
+        return;
+    };
+    while !connect_to_server.fallback_addrs.is_empty() {
+        let server_addr = connect_to_server.fallback_addrs.remove(0);
+        match connect_to_server.get_client_and_transport(
+            server_addr,
+            channel_configs.clone(),
+            channel_registry.fingerprint(),
+            None,
+        ) {
+            Ok((client, transport, local_client_id, server_endpoint)) => {
+                commands.insert_resource(client);
+                commands.insert_resource(transport);
+                commands.insert_resource(local_client_id);
+                commands.insert_resource(server_endpoint);
+                failed_over_to_events.send(FailedOverTo { addr: server_addr });
+                return;
+            },
+            Err(error) => {
+                network_errors.send(ClientNetworkError(NetworkError::Config(error)));
+            },
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn client_disconnects_from_server(
     mut disconnect_from_server_events: EventReader<DisconnectFromServer>,
     mut client: ResMut<RenetClient>,
     mut transport: ResMut<NetcodeClientTransport>,
+    mut delta_baselines: ResMut<ClientDeltaBaselines>,
+    mut sequences: ResMut<ClientSequences>,
+    mut outbox: ResMut<ClientOutbox>,
+    mut server_liveness: ResMut<ServerLiveness>,
+    mut teams: ResMut<Teams>,
+    mut sessions: ResMut<Sessions>,
+    mut active_connection: ResMut<ActiveConnection>,
+    mut client_stopped_events: EventWriter<ClientStopped>,
     mut commands: Commands,
 ) {
-    for _ in disconnect_from_server_events.read() {
+    if disconnect_from_server_events.read().count() > 0 {
+        active_connection.0 = None;
+        teams.clear();
+        sessions.clear();
+        delta_baselines.0.clear();
+        sequences.0.clear();
+        outbox.clear();
+        server_liveness.reset();
         client.disconnect();
         transport.disconnect();
         commands.remove_resource::<RenetClient>();
         // TODO: See if this is a bug waiting to happen like in src/server.rs
         commands.remove_resource::<NetcodeClientTransport>();
+        commands.remove_resource::<LocalClientId>();
+        commands.remove_resource::<ServerEndpoint>();
+        client_stopped_events.send(ClientStopped);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn client_receives_messages_from_server<
     const I: u8,
     T: Event + Serialize + DeserializeOwned,
 >(
     mut client: ResMut<RenetClient>,
+    channel_configs: Res<NetworkConfigs>,
+    deterministic_ordering: Res<DeterministicOrdering>,
+    mut delta_baselines: ResMut<ClientDeltaBaselines>,
+    mut sequences: ResMut<ClientSequences>,
+    mut streams: ResMut<ClientStreams>,
+    mut channel_health: ResMut<ChannelHealth>,
+    mut latest_from_server: ResMut<LatestFromServer<T>>,
+    mut queued_from_server: ResMut<QueuedFromServer<T>>,
     mut server_message_received_events: EventWriter<ReceiveFromServer<T>>,
+    mut decode_error_events: EventWriter<MessageDecodeError>,
+    mut network_errors: EventWriter<ClientNetworkError>,
 ) {
+    let channel_config = &channel_configs.0[I as usize];
+    if channel_config.latest_only {
+        latest_from_server.0 = None;
+    }
+    let mut received = Vec::new();
     while let Some(message) = client.receive_message(I) {
-        let (server_message, _) =
-            bincode::serde::decode_from_slice(&message, bincode::config::standard()).unwrap();
-        server_message_received_events.send(ReceiveFromServer {
-            content: server_message,
-        });
+        let mut arrival = None;
+        let decoded = if channel_config.uses_delta() {
+            delta_baselines
+                .0
+                .decode(I, &message)
+                .ok_or(DecodeErrorKind::MissingDeltaBaseline)
+                .and_then(|payload| codec::decode(channel_config, &payload))
+        } else if channel_config.uses_resumption() {
+            match streams.streams.receive((), I, &message) {
+                Ok(Some(payload)) => codec::decode(channel_config, &payload),
+                Err(kind) => Err(kind),
+                // Resent after a resumed session, but delivered before the drop.
+                Ok(None) => {
+                    channel_health.record_arrival(I, Arrival::Duplicate);
+                    continue;
+                },
+            }
+        } else if channel_config.uses_sequencing() {
+            // Compared in the order renet hands messages over, before any reordering.
+            match sequence::split(&message) {
+                Some((sequence, payload)) => {
+                    arrival = Some(sequences.0.arrival(I, sequence));
+                    codec::decode(channel_config, &payload)
+                },
+                None => Err(DecodeErrorKind::Invalid("missing sequence".to_string())),
+            }
+        } else {
+            codec::decode(channel_config, &message)
+        };
+        received.push((arrival, decoded));
+    }
+    if deterministic_ordering.0 {
+        // Unreliable messages may arrive out of order within a tick.
+        received
+            .sort_by_key(|(_, decoded)| decoded.as_ref().ok().and_then(|decoded| decoded.sent_at));
+    }
+    for (arrival, decoded) in received {
+        let decoded = match decoded {
+            Ok(decoded) => decoded,
+            Err(kind) => {
+                network_errors.send(ClientNetworkError(NetworkError::Decode {
+                    client_id: None,
+                    channel_id: I,
+                    kind: kind.clone(),
+                }));
+                decode_error_events.send(MessageDecodeError {
+                    client_id: None,
+                    channel_id: I,
+                    kind,
+                });
+                continue;
+            },
+        };
+        if let (Some(_), Some(message_id)) =
+            (channel_config.delivery_deadline(), decoded.message_id)
+        {
+            // Acknowledged even when dropped below, so the server stops resending it.
+            client.send_message(
+                CONTROL_CHANNEL_ID,
+                ControlMessage::Acknowledge {
+                    channel_id: I,
+                    message_id,
+                }
+                .encode(),
+            );
+        }
+        if let Some(window) = channel_config.dedup_window() {
+            let message_id = decoded.message_id.unwrap_or_default();
+            if sequences.0.repeated(I, message_id, window) {
+                channel_health.record_arrival(I, Arrival::Duplicate);
+                continue;
+            }
+        }
+        if let Some(arrival) = arrival {
+            channel_health.record_arrival(I, arrival);
+            if arrival != Arrival::InOrder {
+                continue;
+            }
+        }
+        let message = ReceiveFromServer {
+            content: decoded.content,
+            sent_at: decoded.sent_at,
+            received_at: codec::now(),
+        };
+        if channel_config.latest_only {
+            latest_from_server.0 = Some(message);
+        } else if channel_config.queued {
+            queued_from_server.0.push_back(message);
+        } else {
+            server_message_received_events.send(message);
+        }
     }
 }
 
 pub fn client_sends_messages_to_server<const I: u8, T: Event + Serialize + DeserializeOwned>(
-    mut client: ResMut<RenetClient>,
+    mut outbox: ResMut<ClientOutbox>,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
     mut send_message_to_server_events: EventReader<SendToServer<T>>,
 ) {
+    let channel_config = &channel_configs.0[I as usize];
     for message in send_message_to_server_events.read() {
-        let payload =
-            bincode::serde::encode_to_vec(&message.content, bincode::config::standard()).unwrap();
-        client.send_message(I, payload);
+        outbox.push(
+            channel_config,
+            QueuedMessage {
+                channel_id: I,
+                payload: codec::encode(channel_config, &message.content),
+                queued_at: time.elapsed(),
+            },
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn client_flushes_outbox(
+    mut client: ResMut<RenetClient>,
+    mut outbox: ResMut<ClientOutbox>,
+    mut delta_baselines: ResMut<ClientDeltaBaselines>,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    mut pending_deliveries: ResMut<ClientPendingDeliveries>,
+    mut streams: ResMut<ClientStreams>,
+    mut sequences: ResMut<ClientSequences>,
+    client_negotiation: Res<ClientNegotiation>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+) {
+    let ClientOutbox { queue, coalescer } = &mut *outbox;
+    let send_interval = |channel_id: u8| {
+        channel_configs.0[channel_id as usize]
+            .send_interval()
+            .unwrap_or_default()
+    };
+    for (_, message) in coalescer.release(time.elapsed(), send_interval) {
+        queue.push_back(message);
+    }
+    // The server drops messages from clients that are still negotiating.
+    if client_negotiation.is_negotiating() {
+        return;
+    }
+    // Resumable messages wait until the server told whether the session was resumed, and
+    // background ones until renet has room for them.
+    let mut held = VecDeque::new();
+    let mut full_channels: Vec<u8> = Vec::new();
+    for message in queue.drain(..) {
+        let Some(channel_config) = channel_configs.0.get(message.channel_id as usize) else {
+            continue;
+        };
+        if streams.awaiting_verdict && channel_config.uses_resumption() {
+            held.push_back(message);
+            continue;
+        }
+        if channel_config.background
+            && (full_channels.contains(&message.channel_id)
+                || !client.can_send_message(message.channel_id, message.payload.len()))
+        {
+            full_channels.push(message.channel_id);
+            held.push_back(message);
+            continue;
+        }
+        if !client.can_send_message(message.channel_id, message.payload.len()) {
+            network_errors.send(ClientNetworkError(NetworkError::Send {
+                client_id: None,
+                channel_id: message.channel_id,
+                reason: SendErrorReason::ChannelFull,
+            }));
+            continue;
+        }
+        if channel_config.uses_delta() {
+            let payload = delta_baselines
+                .0
+                .encode(message.channel_id, message.payload.to_vec());
+            client.send_message(message.channel_id, payload);
+        } else if channel_config.uses_resumption() {
+            let payload = streams
+                .streams
+                .stamp((), message.channel_id, &message.payload);
+            client.send_message(message.channel_id, payload);
+        } else {
+            let payload = if channel_config.uses_sequencing() {
+                sequences.0.stamp(message.channel_id, &message.payload)
+            } else {
+                message.payload
+            };
+            pending_deliveries.0.track(
+                (),
+                message.channel_id,
+                channel_config,
+                &payload,
+                time.elapsed(),
+            );
+            client.send_message(message.channel_id, payload);
+        }
+    }
+    queue.extend(held);
+}
+
+pub fn client_signals_ready(mut client: ResMut<RenetClient>, negotiation: Res<Negotiation>) {
+    // Negotiating clients signal it once the server accepted them.
+    if negotiation.enabled {
+        return;
+    }
+    client.send_message(CONTROL_CHANNEL_ID, ControlMessage::ClientReady.encode());
+}
+
+///
+/// The client's outgoing state the server's acknowledgements apply to, see `delivery` and
+/// `streams`.
+///
+#[derive(SystemParam)]
+pub struct ClientAcknowledgements<'w> {
+    pending_deliveries: ResMut<'w, ClientPendingDeliveries>,
+    streams: ResMut<'w, ClientStreams>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn client_receives_control_messages(
+    mut client: ResMut<RenetClient>,
+    mut handshake: Handshake,
+    mut server_liveness: ResMut<ServerLiveness>,
+    time: Res<Time>,
+    mut teams: ResMut<Teams>,
+    mut connection_denied_events: EventWriter<ConnectionDenied>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+    mut client_team_changed_events: EventWriter<ClientTeamChanged>,
+    mut sessions: ResMut<Sessions>,
+    mut session_events: SessionEvents,
+    mut ready_checks: ClientReadyChecks,
+    mut server_migrating_events: EventWriter<ServerMigrating>,
+    mut redirecting_events: EventWriter<Redirecting>,
+    mut match_found_events: EventWriter<MatchFound>,
+    mut server_disconnect_reason: ResMut<ServerDisconnectReason>,
+    mut acknowledgements: ClientAcknowledgements,
+) {
+    while let Some(message) = client.receive_message(CONTROL_CHANNEL_ID) {
+        match ControlMessage::decode(&message) {
+            Some(ControlMessage::Disconnecting(reason)) => {
+                server_disconnect_reason.0 = Some(reason);
+            },
+            Some(ControlMessage::Denied(reason)) => {
+                if reason == DenialReason::ChannelMismatch {
+                    error!(
+                        "The server registered different network types than this client: {:?}",
+                        handshake.schema_check.channel_registry.type_names()
+                    );
+                }
+                connection_denied_events.send(ConnectionDenied { reason });
+                disconnect_from_server_events.send(DisconnectFromServer);
+            },
+            Some(ControlMessage::Heartbeat) => server_liveness.heard(time.elapsed()),
+            Some(ControlMessage::TeamChanged { client_id, team }) => {
+                teams.apply(client_id, team);
+                client_team_changed_events.send(ClientTeamChanged { client_id, team });
+            },
+            Some(ControlMessage::Session(change)) => {
+                sessions.apply(change);
+                session_events.send(change);
+            },
+            Some(ControlMessage::ReadyCheck { id, timeout }) => ready_checks.start(id, timeout),
+            Some(ControlMessage::ReadyCheckComplete { ready, not_ready }) => {
+                ready_checks.complete(ready, not_ready);
+            },
+            Some(ControlMessage::Migrate { addr }) => {
+                server_migrating_events.send(ServerMigrating { addr });
+            },
+            Some(ControlMessage::Redirect { addr, token }) => {
+                redirecting_events.send(Redirecting { addr, token });
+            },
+            Some(ControlMessage::MatchFound { addr, token }) => {
+                match_found_events.send(MatchFound {
+                    server_addr: addr,
+                    token,
+                });
+            },
+            Some(ControlMessage::Acknowledge {
+                channel_id,
+                message_id,
+            }) => {
+                acknowledgements
+                    .pending_deliveries
+                    .0
+                    .acknowledge((), channel_id, message_id);
+            },
+            Some(ControlMessage::StreamReceived {
+                channel_id,
+                sequence,
+            }) => {
+                acknowledgements
+                    .streams
+                    .streams
+                    .acknowledge((), channel_id, sequence);
+            },
+            Some(ControlMessage::ResumeStreams) => {
+                let streams = &mut acknowledgements.streams;
+                streams.awaiting_verdict = false;
+                for (channel_id, frame) in streams.streams.resume(()) {
+                    client.send_message(channel_id, frame);
+                }
+            },
+            Some(ControlMessage::ResetStreams) => {
+                let streams = &mut acknowledgements.streams;
+                streams.awaiting_verdict = false;
+                streams.streams.clear();
+            },
+            Some(ControlMessage::SchemaTypes(type_schemas)) => {
+                client.send_message(
+                    CONTROL_CHANNEL_ID,
+                    ControlMessage::SchemaTypes(handshake.schema_check.type_schemas()).encode(),
+                );
+                handshake.schema_check.compare(None, type_schemas);
+            },
+            Some(ControlMessage::NegotiatedCapabilities(negotiated)) => {
+                handshake.client_negotiated(negotiated);
+            },
+            Some(
+                ControlMessage::ReadyCheckAnswer { .. }
+                | ControlMessage::ClientReady
+                | ControlMessage::Enqueue { .. }
+                | ControlMessage::Dequeue
+                | ControlMessage::SchemaHash(_)
+                | ControlMessage::Capabilities(_),
+            )
+            | None => {},
+        }
+    }
+}
+
+pub fn client_receives_unknown_channel_messages(
+    mut client: ResMut<RenetClient>,
+    channel_configs: Res<NetworkConfigs>,
+    channel_registry: Res<ChannelRegistry>,
+    mut unknown_channel_message_events: EventWriter<UnknownChannelMessage>,
+) {
+    for channel_id in channel_registry.unregistered(&channel_configs) {
+        while let Some(bytes) = client.receive_message(channel_id) {
+            unknown_channel_message_events.send(UnknownChannelMessage {
+                client_id: None,
+                channel_id,
+                bytes,
+            });
+        }
+    }
+}
+
+pub fn client_detects_unreachable_server(
+    client: Res<RenetClient>,
+    mut server_liveness: ResMut<ServerLiveness>,
+    time: Res<Time>,
+    mut server_unreachable_events: EventWriter<ServerUnreachable>,
+    mut server_reachable_events: EventWriter<ServerReachable>,
+) {
+    if !client.is_connected() {
+        return;
+    }
+    // Start counting from the moment the connection is established.
+    let last_heard = *server_liveness.last_heard.get_or_insert(time.elapsed());
+    let unreachable = time.elapsed() > last_heard + server_liveness.timeout;
+    if unreachable && !server_liveness.unreachable {
+        server_unreachable_events.send(ServerUnreachable { last_heard });
+    } else if !unreachable && server_liveness.unreachable {
+        server_reachable_events.send(ServerReachable);
     }
+    server_liveness.unreachable = unreachable;
 }
@@ -0,0 +1,395 @@
+//!
+//! Links from this app to other servers running this crate (cluster links), so that a game server
+//! can talk to a coordinator (matchmaking, persistence, cross-zone chat) with typed events while
+//! serving its own clients.
+//!
+//! Register the backend types with `add_cluster_event` (they use their own channel set, separate
+//! from the one of `client_server_events_plugin!`), then connect with `ConnectClusterLink` under
+//! a label. Messages are exchanged with `SendToCluster` and `ReceiveFromCluster`, both naming the
+//! link by its label. The coordinator is a regular server of this crate registering the same types
+//! on the same channels with the same `NetworkConfig`s (e.g. with `register_network_event`), the
+//! links are regular clients to it.
+//!
+//! Links are plain connections: delta encoding, sequencing, coalescing, send rates and
+//! latest-only/queued delivery of the channels' `NetworkConfig`s are left to the game channels.
+//!
+use bevy::app::AppExit;
+use bevy::prelude::{
+    App, Event, EventReader, EventWriter, Events, IntoSystemConfigs, Last, PostUpdate, PreUpdate,
+    Res, ResMut, Resource, Time,
+};
+use bevy_renet::renet::RenetClient;
+use renet::transport::NetcodeClientTransport;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::collections::HashMap;
+
+use crate::{
+    client::ConnectToServer,
+    codec,
+    control::ControlMessage,
+    error::{ClientNetworkError, NetworkError},
+    shutdown::GracefulShutdown,
+    transport::guarded,
+    ChannelRegistry, ClientId, DisconnectReason, MessageDecodeError, NetworkConfig, NetworkConfigs,
+    CONTROL_CHANNEL_ID,
+};
+
+///
+/// Registers type `T` on channel `I` of the cluster links, with its own `NetworkConfig`.
+///
+/// Panics if the channel is already used by a cluster type or reserved, or if `T` is already
+/// registered as a cluster type.
+///
+pub fn add_cluster_event<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    app: &mut App,
+    config: NetworkConfig,
+) {
+    app.world_mut()
+        .get_resource_or_insert_with(ClusterChannels::default)
+        .0
+        .register_with::<I, T>(Some(config));
+    app.add_event::<SendToCluster<T>>()
+        .add_event::<ReceiveFromCluster<T>>()
+        .add_systems(
+            PreUpdate,
+            cluster_receives_messages::<I, T>.after(cluster_updates_links),
+        )
+        .add_systems(
+            PostUpdate,
+            cluster_sends_messages::<I, T>
+                .after(cluster_connects_links)
+                .before(cluster_sends_packets),
+        );
+}
+
+///
+/// The types registered through `add_cluster_event`, with their channel and `NetworkConfig`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClusterChannels(ChannelRegistry);
+
+impl ClusterChannels {
+    pub fn registry(&self) -> &ChannelRegistry {
+        &self.0
+    }
+
+    fn network_configs(&self) -> NetworkConfigs {
+        let mut network_configs = NetworkConfigs::default();
+        for registered in self.0.iter() {
+            let index = registered.channel_id as usize;
+            if network_configs.0.len() <= index {
+                network_configs
+                    .0
+                    .resize_with(index + 1, NetworkConfig::default);
+            }
+            network_configs.0[index] = registered.config.clone();
+        }
+        network_configs
+    }
+}
+
+///
+/// Send this event to connect to another server under `label`, replacing the link already using
+/// that label. The connection settings are the ones of `ConnectToServer`, except for
+/// `fallback_addrs` and `resume` which are ignored.
+///
+#[derive(Debug, Event)]
+pub struct ConnectClusterLink {
+    pub label: String,
+    pub connect: ConnectToServer,
+}
+
+///
+/// Send this event to close the link using `label`.
+///
+#[derive(Debug, Event)]
+pub struct DisconnectClusterLink {
+    pub label: String,
+}
+
+///
+/// Received once the link using `label` is connected.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClusterLinkConnected {
+    pub label: String,
+}
+
+///
+/// Received when the link using `label` ends without a `DisconnectClusterLink` (or never
+/// connected), the link is removed.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClusterLinkDisconnected {
+    pub label: String,
+    pub reason: Option<DisconnectReason>,
+}
+
+///
+/// Send this event to send `content` over the link using `label`. Messages for unknown links are
+/// dropped, messages sent before the link is connected go out once it is.
+///
+#[derive(Debug, Event)]
+pub struct SendToCluster<T: Event> {
+    pub label: String,
+    pub content: T,
+}
+
+///
+/// Received when `content` arrives over the link using `label`.
+///
+#[derive(Debug, Event)]
+pub struct ReceiveFromCluster<T: Event> {
+    pub label: String,
+    pub content: T,
+}
+
+struct ClusterLink {
+    client: RenetClient,
+    transport: NetcodeClientTransport,
+    client_id: ClientId,
+    network_configs: NetworkConfigs,
+    /// Reserved channels the coordinator may still use (e.g. ticks), emptied every tick.
+    drained_channels: Vec<u8>,
+    connected: bool,
+    disconnect_reason: Option<DisconnectReason>,
+}
+
+///
+/// The cluster links of this app, by label.
+///
+#[derive(Default, Resource)]
+pub struct ClusterLinks(HashMap<String, ClusterLink>);
+
+impl ClusterLinks {
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    pub fn is_connected(&self, label: &str) -> bool {
+        self.0.get(label).is_some_and(|link| link.connected)
+    }
+
+    /// The client id the link uses on the other server.
+    pub fn client_id(&self, label: &str) -> Option<ClientId> {
+        self.0.get(label).map(|link| link.client_id)
+    }
+}
+
+pub fn cluster_updates_links(
+    time: Res<Time>,
+    mut cluster_links: ResMut<ClusterLinks>,
+    mut link_connected_events: EventWriter<ClusterLinkConnected>,
+    mut link_disconnected_events: EventWriter<ClusterLinkDisconnected>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+) {
+    let mut disconnected = Vec::new();
+    for (label, link) in cluster_links.0.iter_mut() {
+        link.client.update(time.delta());
+        match guarded(|| link.transport.update(time.delta(), &mut link.client)) {
+            Ok(Ok(())) => {},
+            Ok(Err(error)) => {
+                network_errors.send(ClientNetworkError(NetworkError::Transport(
+                    error.to_string(),
+                )));
+            },
+            Err(message) => {
+                network_errors.send(ClientNetworkError(NetworkError::TransportPanic(message)));
+                disconnected.push(ClusterLinkDisconnected {
+                    label: label.clone(),
+                    reason: Some(DisconnectReason::Transport),
+                });
+                continue;
+            },
+        }
+        if !link.connected && link.transport.is_connected() {
+            link.connected = true;
+            link.client
+                .send_message(CONTROL_CHANNEL_ID, ControlMessage::ClientReady.encode());
+            link_connected_events.send(ClusterLinkConnected {
+                label: label.clone(),
+            });
+        }
+        while let Some(message) = link.client.receive_message(CONTROL_CHANNEL_ID) {
+            match ControlMessage::decode(&message) {
+                Some(ControlMessage::Disconnecting(reason)) => {
+                    link.disconnect_reason = Some(reason);
+                },
+                Some(ControlMessage::Denied(reason)) => {
+                    link.disconnect_reason = Some(reason.into());
+                    link.transport.disconnect();
+                },
+                // Sessions, teams, ready checks and redirects concern the game clients.
+                _ => {},
+            }
+        }
+        for channel_id in &link.drained_channels {
+            while link.client.receive_message(*channel_id).is_some() {}
+        }
+        if link.transport.is_disconnected() {
+            let reason = link.disconnect_reason.or_else(|| {
+                link.transport
+                    .disconnect_reason()
+                    .map(DisconnectReason::from)
+            });
+            disconnected.push(ClusterLinkDisconnected {
+                label: label.clone(),
+                reason,
+            });
+        }
+    }
+    for link_disconnected in disconnected {
+        cluster_links.0.remove(&link_disconnected.label);
+        link_disconnected_events.send(link_disconnected);
+    }
+}
+
+pub fn cluster_receives_messages<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    mut cluster_links: ResMut<ClusterLinks>,
+    mut receive_from_cluster_events: EventWriter<ReceiveFromCluster<T>>,
+    mut decode_error_events: EventWriter<MessageDecodeError>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+) {
+    for (label, link) in cluster_links.0.iter_mut() {
+        let channel_config = &link.network_configs.0[I as usize];
+        while let Some(message) = link.client.receive_message(I) {
+            match codec::decode(channel_config, &message) {
+                Ok(decoded) => {
+                    receive_from_cluster_events.send(ReceiveFromCluster {
+                        label: label.clone(),
+                        content: decoded.content,
+                    });
+                },
+                Err(kind) => {
+                    network_errors.send(ClientNetworkError(NetworkError::Decode {
+                        client_id: None,
+                        channel_id: I,
+                        kind: kind.clone(),
+                    }));
+                    decode_error_events.send(MessageDecodeError {
+                        client_id: None,
+                        channel_id: I,
+                        kind,
+                    });
+                },
+            }
+        }
+    }
+}
+
+pub fn cluster_connects_links(
+    mut connect_link_events: ResMut<Events<ConnectClusterLink>>,
+    mut disconnect_link_events: EventReader<DisconnectClusterLink>,
+    cluster_channels: Res<ClusterChannels>,
+    mut cluster_links: ResMut<ClusterLinks>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+) {
+    for DisconnectClusterLink { label } in disconnect_link_events.read() {
+        if let Some(mut link) = cluster_links.0.remove(label) {
+            link.transport.disconnect();
+        }
+    }
+    for ConnectClusterLink { label, connect } in connect_link_events.drain() {
+        let network_configs = cluster_channels.network_configs();
+        let (client, transport, client_id, _) = match connect.server_addr().and_then(|addr| {
+            connect.get_client_and_transport(
+                addr,
+                network_configs.clone(),
+                cluster_channels.0.fingerprint(),
+                None,
+            )
+        }) {
+            Ok(client_and_transport) => client_and_transport,
+            Err(error) => {
+                network_errors.send(ClientNetworkError(NetworkError::Config(error)));
+                continue;
+            },
+        };
+        let drained_channels = Vec::<renet::ChannelConfig>::from(network_configs.clone())
+            .into_iter()
+            .map(|channel_config| channel_config.channel_id)
+            .filter(|channel_id| {
+                *channel_id != CONTROL_CHANNEL_ID && !cluster_channels.0.contains(*channel_id)
+            })
+            .collect();
+        let link = ClusterLink {
+            client,
+            transport,
+            client_id: client_id.0,
+            network_configs,
+            drained_channels,
+            connected: false,
+            disconnect_reason: None,
+        };
+        if let Some(mut replaced) = cluster_links.0.insert(label, link) {
+            replaced.transport.disconnect();
+        }
+    }
+}
+
+pub fn cluster_sends_messages<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    mut cluster_links: ResMut<ClusterLinks>,
+    mut send_to_cluster_events: EventReader<SendToCluster<T>>,
+) {
+    for SendToCluster { label, content } in send_to_cluster_events.read() {
+        let Some(link) = cluster_links.0.get_mut(label) else {
+            continue;
+        };
+        let payload = codec::encode(&link.network_configs.0[I as usize], content);
+        link.client.send_message(I, payload);
+    }
+}
+
+pub fn cluster_sends_packets(
+    mut cluster_links: ResMut<ClusterLinks>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+) {
+    for link in cluster_links.0.values_mut() {
+        match guarded(|| link.transport.send_packets(&mut link.client)) {
+            Ok(Ok(())) => {},
+            Ok(Err(error)) => {
+                network_errors.send(ClientNetworkError(NetworkError::Transport(
+                    error.to_string(),
+                )));
+            },
+            Err(message) => {
+                network_errors.send(ClientNetworkError(NetworkError::TransportPanic(message)));
+                // Reported as disconnected by the next update.
+                let _ = guarded(|| link.transport.disconnect());
+            },
+        }
+    }
+}
+
+pub fn cluster_disconnects_links_on_exit(
+    graceful_shutdown: Res<GracefulShutdown>,
+    mut app_exit_events: EventReader<AppExit>,
+    mut cluster_links: ResMut<ClusterLinks>,
+) {
+    if app_exit_events.read().count() == 0 || !graceful_shutdown.0 {
+        return;
+    }
+    for link in cluster_links.0.values_mut() {
+        // Errors can't be reported anymore, the disconnection is sent regardless.
+        let _ = link.transport.send_packets(&mut link.client);
+        link.client.disconnect();
+        link.transport.disconnect();
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ClusterChannels>()
+        .init_resource::<ClusterLinks>()
+        .add_event::<ConnectClusterLink>()
+        .add_event::<DisconnectClusterLink>()
+        .add_event::<ClusterLinkConnected>()
+        .add_event::<ClusterLinkDisconnected>()
+        .add_systems(PreUpdate, cluster_updates_links)
+        .add_systems(
+            PostUpdate,
+            (cluster_connects_links, cluster_sends_packets).chain(),
+        )
+        .add_systems(Last, cluster_disconnects_links_on_exit);
+}
@@ -0,0 +1,421 @@
+//!
+//! Wire format of the typed messages, according to each type's `NetworkConfig`.
+//!
+//! Only public for the benchmarks, not part of the crate's API.
+//!
+use bincode::error::DecodeError;
+use bytes::{BufMut, BytesMut};
+use renet::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    pool::encode_pooled, ByteOrder, DecodeErrorKind, IntEncoding, NetworkConfig, WireFormat,
+};
+
+/// Wall-clock time since the UNIX epoch, used to timestamp messages.
+pub(crate) fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+///
+/// Id of the next message of a deduplicated type (see `NetworkConfig::dedup_window`), shared by
+/// every type. Starts at a random value, so that a restarted peer doesn't reuse recent ids.
+///
+fn next_message_id() -> u32 {
+    static NEXT_MESSAGE_ID: OnceLock<AtomicU32> = OnceLock::new();
+    NEXT_MESSAGE_ID
+        .get_or_init(|| {
+            let mut seed = [0; 4];
+            // Ids only need to differ from the recent ones, a fixed start still does that.
+            let _ = getrandom::getrandom(&mut seed);
+            AtomicU32::new(u32::from_le_bytes(seed))
+        })
+        .fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct Decoded<T> {
+    pub content: T,
+    pub sent_at: Option<Duration>,
+    pub message_id: Option<u32>,
+}
+
+///
+/// Runs `$body` with `$config` bound to the bincode configuration of `$wire_format`. Bincode
+/// configurations are types, so each combination is its own branch.
+///
+macro_rules! with_wire_format {
+    ($wire_format:expr, $config:ident => $body:expr) => {{
+        let standard = bincode::config::standard();
+        match ($wire_format.int_encoding, $wire_format.byte_order) {
+            (IntEncoding::Variable, ByteOrder::Little) => {
+                let $config = standard;
+                $body
+            },
+            (IntEncoding::Fixed, ByteOrder::Little) => {
+                let $config = standard.with_fixed_int_encoding();
+                $body
+            },
+            (IntEncoding::Variable, ByteOrder::Big) => {
+                let $config = standard.with_big_endian();
+                $body
+            },
+            (IntEncoding::Fixed, ByteOrder::Big) => {
+                let $config = standard.with_fixed_int_encoding().with_big_endian();
+                $body
+            },
+        }
+    }};
+}
+
+pub(crate) use with_wire_format;
+
+///
+/// Runs `$body` with `$config` bound to the bincode configuration of `$wire_format`, limited to
+/// `$limit` rounded up to the next power of 4 (from 4 KiB up to 1 GiB) since bincode only supports
+/// compile-time limits. Evaluates to `None` if the limit is over 1 GiB.
+///
+macro_rules! with_decode_limit {
+    ($wire_format:expr, $limit:expr, $config:ident => $body:expr) => {
+        $crate::codec::with_wire_format!($wire_format, bincode_config => {
+            $crate::codec::with_decode_limit!(
+                @limits bincode_config, $limit, $config => $body,
+                1 << 12, 1 << 14, 1 << 16, 1 << 18, 1 << 20, 1 << 22, 1 << 24, 1 << 26, 1 << 28,
+                1 << 30
+            )
+        })
+    };
+    (@limits $bincode_config:ident, $limit:expr, $config:ident => $body:expr, $($max:expr),*) => {
+        $(
+            if $limit <= $max {
+                let $config = $bincode_config.with_limit::<{ $max }>();
+                Some($body)
+            } else
+        )* {
+            None
+        }
+    };
+}
+
+pub(crate) use with_decode_limit;
+
+fn encode_into<T: Serialize>(buffer: &mut BytesMut, content: &T, wire_format: WireFormat) {
+    #[cfg(feature = "bincode-native")]
+    let _scope = crate::native::FormatScope::new(wire_format, None);
+    with_wire_format!(wire_format, bincode_config => {
+        bincode::serde::encode_into_std_write(content, &mut buffer.writer(), bincode_config)
+            .unwrap();
+    })
+}
+
+///
+/// Sent for unit-like types (e.g. `struct Ping;`), whose content is never serialized since it
+/// carries no data. Bincode decodes it like the content itself: nothing is read for unit structs,
+/// and it is the variant index of single variant enums (with variable int encoding).
+///
+const UNIT_PAYLOAD: &[u8] = &[0];
+
+fn unit_payload<T>(config: &NetworkConfig) -> Option<Bytes> {
+    (std::mem::size_of::<T>() == 0
+        && !config.uses_timestamps()
+        && config.dedup_window().is_none()
+        && !config.uses_json()
+        && config.wire_format.int_encoding == IntEncoding::Variable)
+        .then(|| Bytes::from_static(UNIT_PAYLOAD))
+}
+
+///
+/// Writes the message id and send time preceding the content, for the types using them. Bincode
+/// encodes the `(message_id, sent_at, content)` tuple as its fields back to back.
+///
+fn encode_header(buffer: &mut BytesMut, config: &NetworkConfig) {
+    if config.dedup_window().is_some() {
+        encode_into(buffer, &next_message_id(), config.wire_format);
+    }
+    if config.uses_timestamps() {
+        let sent_at = now().as_micros() as u64;
+        encode_into(buffer, &sent_at, config.wire_format);
+    }
+}
+
+pub fn encode<T: Serialize>(config: &NetworkConfig, content: &T) -> Bytes {
+    if let Some(payload) = unit_payload::<T>(config) {
+        return payload;
+    }
+    #[cfg(feature = "json")]
+    if config.json {
+        return encode_pooled(|buffer| json::encode_into(buffer, config, content));
+    }
+    encode_pooled(|buffer| {
+        encode_header(buffer, config);
+        encode_into(buffer, content, config.wire_format);
+    })
+}
+
+///
+/// Payloads encoded during one run of a send system, keyed by their encoded content, so that
+/// identical contents sent to several clients share a single buffer. Types stamping a message id
+/// (see `NetworkConfig::dedup_window`) get a new id on every call, only their content is shared.
+///
+#[derive(Default)]
+pub(crate) struct PayloadCache(HashMap<Bytes, Bytes>);
+
+impl PayloadCache {
+    pub(crate) fn encode<T: Serialize>(&mut self, config: &NetworkConfig, content: &T) -> Bytes {
+        if let Some(payload) = unit_payload::<T>(config) {
+            return payload;
+        }
+        // The type tag, id and timestamp are part of the JSON object, the content can't be shared.
+        if config.uses_json() {
+            return encode(config, content);
+        }
+        let content = encode_pooled(|buffer| encode_into(buffer, content, config.wire_format));
+        if config.dedup_window().is_some() {
+            return encode_pooled(|buffer| {
+                encode_header(buffer, config);
+                buffer.extend_from_slice(&content);
+            });
+        }
+        if let Some(payload) = self.0.get(&content) {
+            return payload.clone();
+        }
+        let payload = if config.uses_timestamps() {
+            encode_pooled(|buffer| {
+                encode_header(buffer, config);
+                buffer.extend_from_slice(&content);
+            })
+        } else {
+            content.clone()
+        };
+        self.0.insert(content, payload.clone());
+        payload
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// The encoded content alone, without the header that differs between messages of the same
+/// content, to compare them (see `SendDeduplication`).
+pub(crate) fn content_key<T: Serialize>(config: &NetworkConfig, content: &T) -> Bytes {
+    encode_pooled(|buffer| encode_into(buffer, content, config.wire_format))
+}
+
+/// The id of an encoded message, for the types stamping one (see `NetworkConfig::dedup_window`).
+pub(crate) fn message_id(config: &NetworkConfig, bytes: &[u8]) -> Option<u32> {
+    config.dedup_window()?;
+    #[cfg(feature = "json")]
+    if config.json {
+        return json::message_id(bytes);
+    }
+    // The id comes first, the rest of the message is left undecoded.
+    with_wire_format!(config.wire_format, bincode_config => {
+        bincode::serde::decode_from_slice(bytes, bincode_config)
+            .ok()
+            .map(|(message_id, _): (u32, usize)| message_id)
+    })
+}
+
+pub fn decode<T: DeserializeOwned>(
+    config: &NetworkConfig,
+    bytes: &[u8],
+) -> Result<Decoded<T>, DecodeErrorKind> {
+    let limit = config.decode_limit();
+    #[cfg(feature = "bincode-native")]
+    let _scope = crate::native::FormatScope::new(config.wire_format, Some(limit));
+    #[cfg(feature = "json")]
+    if config.json {
+        return json::decode(config, bytes, limit);
+    }
+    let wire_format = config.wire_format;
+    let (message_id, sent_at, content) =
+        match (config.dedup_window().is_some(), config.uses_timestamps()) {
+            (true, true) => {
+                let (message_id, sent_at, content): (u32, u64, T) =
+                    decode_limited(bytes, limit, wire_format)?;
+                (Some(message_id), Some(sent_at), content)
+            },
+            (true, false) => {
+                let (message_id, content): (u32, T) = decode_limited(bytes, limit, wire_format)?;
+                (Some(message_id), None, content)
+            },
+            (false, true) => {
+                let (sent_at, content): (u64, T) = decode_limited(bytes, limit, wire_format)?;
+                (None, Some(sent_at), content)
+            },
+            (false, false) => (None, None, decode_limited(bytes, limit, wire_format)?),
+        };
+    Ok(Decoded {
+        content,
+        sent_at: sent_at.map(Duration::from_micros),
+        message_id,
+    })
+}
+
+///
+/// The configured limit is rounded up (see `with_decode_limit`), which still stops crafted length
+/// prefixes from making us allocate far more memory than the type would ever need.
+///
+fn decode_limited<T: DeserializeOwned>(
+    bytes: &[u8],
+    limit: usize,
+    wire_format: WireFormat,
+) -> Result<T, DecodeErrorKind> {
+    with_decode_limit!(wire_format, limit, bincode_config => {
+        bincode::serde::decode_from_slice(bytes, bincode_config)
+            .map(|(content, _)| content)
+            .map_err(DecodeErrorKind::from)
+    })
+    .unwrap_or(Err(DecodeErrorKind::LimitExceeded))
+}
+
+impl From<DecodeError> for DecodeErrorKind {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::LimitExceeded => DecodeErrorKind::LimitExceeded,
+            error => DecodeErrorKind::Invalid(error.to_string()),
+        }
+    }
+}
+
+///
+/// Messages of types with `NetworkConfig::json` set, sent as
+/// `{"type": "game::PlayerInput", "sent_at": 1700000000000000, "content": {...}}`.
+/// `sent_at` (microseconds since the UNIX epoch) is only present with timestamps, `message_id`
+/// only with a `dedup_window`, and received
+/// messages may tag their type with its name alone (`"PlayerInput"`).
+///
+#[cfg(feature = "json")]
+mod json {
+    use bytes::{BufMut, BytesMut};
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+    use std::borrow::Cow;
+    use std::time::Duration;
+
+    use super::{next_message_id, now, Decoded};
+    use crate::{DecodeErrorKind, NetworkConfig};
+
+    #[derive(Serialize, Deserialize)]
+    struct JsonMessage<'a, T> {
+        #[serde(rename = "type", borrow)]
+        type_name: Cow<'a, str>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<u64>,
+        content: T,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonMessageId {
+        message_id: Option<u32>,
+    }
+
+    pub(super) fn message_id(bytes: &[u8]) -> Option<u32> {
+        serde_json::from_slice::<JsonMessageId>(bytes)
+            .ok()?
+            .message_id
+    }
+
+    pub(super) fn encode_into<T: Serialize>(
+        buffer: &mut BytesMut,
+        config: &NetworkConfig,
+        content: &T,
+    ) {
+        let message = JsonMessage {
+            type_name: Cow::Borrowed(std::any::type_name::<T>()),
+            message_id: config.dedup_window().map(|_| next_message_id()),
+            sent_at: config.uses_timestamps().then(|| now().as_micros() as u64),
+            content,
+        };
+        serde_json::to_writer(buffer.writer(), &message).unwrap();
+    }
+
+    pub(super) fn decode<T: DeserializeOwned>(
+        config: &NetworkConfig,
+        bytes: &[u8],
+        limit: usize,
+    ) -> Result<Decoded<T>, DecodeErrorKind> {
+        // JSON never takes more memory than a few times its text, unlike length prefixes.
+        if bytes.len() > limit {
+            return Err(DecodeErrorKind::LimitExceeded);
+        }
+        let message: JsonMessage<T> = serde_json::from_slice(bytes)
+            .map_err(|error| DecodeErrorKind::Invalid(error.to_string()))?;
+        let type_name = std::any::type_name::<T>();
+        if message.type_name != type_name
+            && !type_name.ends_with(&format!("::{}", message.type_name))
+        {
+            return Err(DecodeErrorKind::Invalid(format!(
+                "expected a {} message, got {}",
+                type_name, message.type_name
+            )));
+        }
+        let sent_at = match (config.uses_timestamps(), message.sent_at) {
+            (true, Some(sent_at)) => Some(Duration::from_micros(sent_at)),
+            (true, None) => {
+                return Err(DecodeErrorKind::Invalid("missing sent_at".to_string()));
+            },
+            (false, _) => None,
+        };
+        let message_id = match (config.dedup_window(), message.message_id) {
+            (Some(_), Some(message_id)) => Some(message_id),
+            (Some(_), None) => {
+                return Err(DecodeErrorKind::Invalid("missing message_id".to_string()));
+            },
+            (None, _) => None,
+        };
+        Ok(Decoded {
+            content: message.content,
+            sent_at,
+            message_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deduplicated() -> NetworkConfig {
+        NetworkConfig {
+            dedup_window: Some(8),
+            ..NetworkConfig::unreliable()
+        }
+    }
+
+    #[test]
+    fn cached_payloads_get_their_own_message_id() {
+        let config = deduplicated();
+        let mut cache = PayloadCache::default();
+        let first = cache.encode(&config, &"hello");
+        let second = cache.encode(&config, &"hello");
+        let first_id = message_id(&config, &first).unwrap();
+        let second_id = message_id(&config, &second).unwrap();
+        assert_ne!(first_id, second_id);
+        let decoded: Decoded<String> = decode(&config, &second).unwrap();
+        assert_eq!(decoded.content, "hello");
+        assert_eq!(decoded.message_id, Some(second_id));
+    }
+
+    #[test]
+    fn cached_payloads_are_shared_without_message_id() {
+        let config = NetworkConfig {
+            timestamps: true,
+            ..NetworkConfig::unreliable()
+        };
+        let mut cache = PayloadCache::default();
+        let first = cache.encode(&config, &"hello");
+        let second = cache.encode(&config, &"hello");
+        assert_eq!(first, second);
+        assert_ne!(first, cache.encode(&config, &"world"));
+    }
+}
@@ -0,0 +1,298 @@
+//!
+//! Network settings loaded from a RON or TOML file, enabled with the `config` feature.
+//!
+//! Lets dedicated server operators tune a deployment (addresses, limits, key, per-type channel
+//! settings) without recompiling:
+//!
+//! ```toml
+//! [server]
+//! ip = "0.0.0.0"
+//! port = 5000
+//! public_addr = "203.0.113.7:5000"
+//! max_clients = 32
+//! key_file = "server.key"
+//!
+//! [channels.PlayerMovement]
+//! send_type = "Unreliable"
+//! max_send_rate = 30.0
+//! ```
+//!
+use bevy::prelude::{Mut, World};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{
+    keys::{load_key_file, KeyError},
+    server::StartServer,
+    ChannelRegistry, NetworkConfig, NetworkConfigs, SendType, WireFormat,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSettings {
+    pub server: ServerSettings,
+    /// Overrides for the registered types' `NetworkConfig`, keyed by type name (either the full
+    /// path or just the name).
+    pub channels: BTreeMap<String, ChannelSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub ip: String,
+    pub port: u16,
+    pub public_addr: Option<SocketAddr>,
+    pub max_clients: usize,
+    pub protocol_id: u64,
+    pub available_bytes_per_tick: u64,
+    pub password: Option<String>,
+    /// File holding the private key, see `keys::load_key_file`.
+    /// The server runs unencrypted without it.
+    pub key_file: Option<PathBuf>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        let start_server = StartServer::default();
+        Self {
+            ip: start_server.ip,
+            port: start_server.port,
+            public_addr: None,
+            max_clients: start_server.max_clients,
+            protocol_id: start_server.protocol_id,
+            available_bytes_per_tick: start_server.available_bytes_per_tick,
+            password: None,
+            key_file: None,
+        }
+    }
+}
+
+///
+/// Per-type overrides, unset fields keep the `NetworkConfig` given in code.
+///
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelSettings {
+    pub send_type: Option<SendTypeSettings>,
+    pub max_memory_usage_bytes: Option<usize>,
+    pub accept_from_spectators: Option<bool>,
+    pub ttl_ms: Option<u64>,
+    pub timestamps: Option<bool>,
+    pub delta: Option<bool>,
+    pub max_send_rate: Option<f32>,
+    pub max_decode_bytes: Option<usize>,
+    pub sequenced: Option<bool>,
+    pub dedup_window: Option<usize>,
+    pub deadline_ms: Option<u64>,
+    pub resumable: Option<bool>,
+    pub priority: Option<u8>,
+    pub background: Option<bool>,
+    pub latest_only: Option<bool>,
+    pub queued: Option<bool>,
+    pub receive_budget: Option<usize>,
+    pub receive_per_client: Option<usize>,
+    pub wire_format: Option<WireFormat>,
+    #[cfg(feature = "json")]
+    pub json: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SendTypeSettings {
+    Unreliable,
+    ReliableOrdered { resend_time_ms: u64 },
+    ReliableUnordered { resend_time_ms: u64 },
+}
+
+impl From<&SendTypeSettings> for SendType {
+    fn from(send_type: &SendTypeSettings) -> Self {
+        match send_type {
+            SendTypeSettings::Unreliable => SendType::Unreliable,
+            SendTypeSettings::ReliableOrdered { resend_time_ms } => SendType::ReliableOrdered {
+                resend_time: Duration::from_millis(*resend_time_ms),
+            },
+            SendTypeSettings::ReliableUnordered { resend_time_ms } => SendType::ReliableUnordered {
+                resend_time: Duration::from_millis(*resend_time_ms),
+            },
+        }
+    }
+}
+
+impl ChannelSettings {
+    fn apply(&self, config: &mut NetworkConfig) {
+        if let Some(send_type) = &self.send_type {
+            config.send_type = send_type.into();
+        }
+        if let Some(max_memory_usage_bytes) = self.max_memory_usage_bytes {
+            config.max_memory_usage_bytes = max_memory_usage_bytes;
+        }
+        if let Some(accept_from_spectators) = self.accept_from_spectators {
+            config.accept_from_spectators = accept_from_spectators;
+        }
+        if let Some(ttl_ms) = self.ttl_ms {
+            config.ttl = Some(Duration::from_millis(ttl_ms));
+        }
+        if let Some(timestamps) = self.timestamps {
+            config.timestamps = timestamps;
+        }
+        if let Some(delta) = self.delta {
+            config.delta = delta;
+        }
+        if let Some(max_send_rate) = self.max_send_rate {
+            config.max_send_rate = Some(max_send_rate);
+        }
+        if let Some(max_decode_bytes) = self.max_decode_bytes {
+            config.max_decode_bytes = Some(max_decode_bytes);
+        }
+        if let Some(sequenced) = self.sequenced {
+            config.sequenced = sequenced;
+        }
+        if let Some(dedup_window) = self.dedup_window {
+            config.dedup_window = Some(dedup_window);
+        }
+        if let Some(deadline_ms) = self.deadline_ms {
+            config.deadline = Some(Duration::from_millis(deadline_ms));
+        }
+        if let Some(resumable) = self.resumable {
+            config.resumable = resumable;
+        }
+        if let Some(priority) = self.priority {
+            config.priority = priority;
+        }
+        if let Some(background) = self.background {
+            config.background = background;
+        }
+        if let Some(latest_only) = self.latest_only {
+            config.latest_only = latest_only;
+        }
+        if let Some(queued) = self.queued {
+            config.queued = queued;
+        }
+        if let Some(receive_budget) = self.receive_budget {
+            config.receive_budget = Some(receive_budget);
+        }
+        if let Some(receive_per_client) = self.receive_per_client {
+            config.receive_per_client = Some(receive_per_client);
+        }
+        if let Some(wire_format) = self.wire_format {
+            config.wire_format = wire_format;
+        }
+        #[cfg(feature = "json")]
+        if let Some(json) = self.json {
+            config.json = json;
+        }
+    }
+}
+
+impl NetworkSettings {
+    /// Loads settings from a `.ron` or `.toml` file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SettingsError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("ron") => Self::from_ron_str(&contents),
+            Some("toml") => Self::from_toml_str(&contents),
+            _ => Err(SettingsError::UnknownFormat(path.to_path_buf())),
+        }
+    }
+
+    pub fn from_ron_str(contents: &str) -> Result<Self, SettingsError> {
+        ron::from_str(contents).map_err(|error| SettingsError::Parse(error.to_string()))
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self, SettingsError> {
+        toml::from_str(contents).map_err(|error| SettingsError::Parse(error.to_string()))
+    }
+
+    ///
+    /// Applies the channel overrides to the `NetworkConfigs` (and `ChannelRegistry`) of an app.
+    /// Must run after the plugin is added, and before starting or connecting.
+    ///
+    pub fn apply_channels(&self, world: &mut World) -> Result<(), SettingsError> {
+        world.resource_scope(|world, mut channel_registry: Mut<ChannelRegistry>| {
+            let mut network_configs = world.resource_mut::<NetworkConfigs>();
+            for (name, channel_settings) in &self.channels {
+                let channel_id = channel_registry
+                    .iter()
+                    .find(|registered| {
+                        registered.type_name == name
+                            || registered.type_name.ends_with(&format!("::{name}"))
+                    })
+                    .map(|registered| registered.channel_id)
+                    .ok_or_else(|| SettingsError::UnknownChannel(name.clone()))?;
+                // Types registered by plugins added after `ClientServerEventsPlugin` only get
+                // their channel in `NetworkConfigs` once every plugin is built.
+                let mut config = match network_configs.0.get(channel_id as usize) {
+                    Some(config) => config.clone(),
+                    None => channel_registry.get(channel_id).unwrap().config.clone(),
+                };
+                channel_settings.apply(&mut config);
+                if let Some(network_config) = network_configs.0.get_mut(channel_id as usize) {
+                    *network_config = config.clone();
+                }
+                channel_registry.set_config(channel_id, config);
+            }
+            Ok(())
+        })
+    }
+
+    /// The `StartServer` event described by the server settings, reading the key file if any.
+    pub fn start_server(&self) -> Result<StartServer, SettingsError> {
+        let private_key = self
+            .server
+            .key_file
+            .as_ref()
+            .map(load_key_file)
+            .transpose()
+            .map_err(SettingsError::Key)?;
+        Ok(StartServer {
+            ip: self.server.ip.clone(),
+            port: self.server.port,
+            public_addr: self.server.public_addr,
+            max_clients: self.server.max_clients,
+            protocol_id: self.server.protocol_id,
+            available_bytes_per_tick: self.server.available_bytes_per_tick,
+            private_key,
+            password: self.server.password.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(std::io::Error),
+    Parse(String),
+    /// The file extension is neither `.ron` nor `.toml`.
+    UnknownFormat(PathBuf),
+    /// No registered type matches this channel name.
+    UnknownChannel(String),
+    /// The key file could not be read.
+    Key(KeyError),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "could not read network settings: {}", error),
+            Self::Parse(error) => write!(f, "invalid network settings: {}", error),
+            Self::UnknownFormat(path) => {
+                write!(f, "unknown network settings format: {}", path.display())
+            },
+            Self::UnknownChannel(name) => write!(f, "no network type named {}", name),
+            Self::Key(error) => write!(f, "invalid private key file: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl From<std::io::Error> for SettingsError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
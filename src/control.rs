@@ -0,0 +1,129 @@
+//!
+//! Internal messages exchanged by the client and server plugins on a reserved channel.
+//!
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::{
+    handshake::TypeSchema,
+    ClientId,
+    {sessions::SessionChange, teams::TeamId, DenialReason, DisconnectReason},
+};
+
+/// Upper bound on the memory decoding a control message may claim, so that a crafted length prefix
+/// (e.g. of `Enqueue::queue`) can't make a peer allocate huge buffers.
+const MAX_CONTROL_MESSAGE_BYTES: usize = 1 << 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ControlMessage {
+    Denied(DenialReason),
+    /// Sent right before the server disconnects the client, see `DisconnectClient`.
+    Disconnecting(DisconnectReason),
+    /// Sent periodically by the server so clients notice when it stops responding.
+    Heartbeat,
+    TeamChanged {
+        client_id: ClientId,
+        team: Option<TeamId>,
+    },
+    Session(SessionChange),
+    /// Sent by clients once their connection is established, see `ClientWarmUp`.
+    ClientReady,
+    ReadyCheck {
+        id: u32,
+        timeout: Duration,
+    },
+    /// Sent by clients.
+    ReadyCheckAnswer {
+        id: u32,
+        ready: bool,
+    },
+    ReadyCheckComplete {
+        ready: Vec<ClientId>,
+        not_ready: Vec<ClientId>,
+    },
+    /// The server is moving to another address, see `MigrateServer`.
+    Migrate {
+        addr: SocketAddr,
+    },
+    /// The client is moving to another server, see `RedirectClient`.
+    Redirect {
+        addr: SocketAddr,
+        token: Option<Vec<u8>>,
+    },
+    /// Sent by clients, see `EnqueueForMatch`.
+    Enqueue {
+        queue: String,
+    },
+    /// Sent by clients, see `LeaveMatchQueue`.
+    Dequeue,
+    /// The client's match is ready, see `SendMatchFound`.
+    MatchFound {
+        addr: SocketAddr,
+        token: Option<Vec<u8>>,
+    },
+    /// Sent by both ends for the types with a deadline, see `delivery`.
+    Acknowledge {
+        channel_id: u8,
+        message_id: u32,
+    },
+    /// Sent by both ends for the resumable types, see `streams`.
+    StreamReceived {
+        channel_id: u8,
+        sequence: u64,
+    },
+    /// The client's session was resumed, see `streams`.
+    ResumeStreams,
+    /// The client's session is a new one, see `streams`.
+    ResetStreams,
+    /// Sent by clients once connected, see `handshake`.
+    SchemaHash(u32),
+    /// Sent by both ends when the schema hashes differ, see `handshake`.
+    SchemaTypes(Vec<TypeSchema>),
+    /// Sent by clients once connected, see `handshake`.
+    Capabilities(BTreeSet<String>),
+    /// The server's answer to `Capabilities`, the flags both ends support.
+    NegotiatedCapabilities(BTreeSet<String>),
+}
+
+impl ControlMessage {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard().with_limit::<MAX_CONTROL_MESSAGE_BYTES>();
+        bincode::serde::decode_from_slice(bytes, config)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crafted_length_prefixes_are_rejected() {
+        // `Enqueue`, `SchemaTypes` and `Capabilities` claiming 2^62 bytes or elements.
+        for variant in [11, 19, 20] {
+            let mut bytes = vec![variant, 253];
+            bytes.extend_from_slice(&(1u64 << 62).to_le_bytes());
+            assert!(ControlMessage::decode(&bytes).is_none(), "{variant}");
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let bytes = ControlMessage::Enqueue {
+            queue: "ranked".to_string(),
+        }
+        .encode();
+        assert!(matches!(
+            ControlMessage::decode(&bytes),
+            Some(ControlMessage::Enqueue { queue }) if queue == "ranked"
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+//!
+//! Reliable delivery up to a deadline for unreliable types with a `NetworkConfig::deadline`, for
+//! messages that matter but are worthless once late (e.g. emotes, transient pickups).
+//!
+//! The receiver acknowledges every message of these types on the control channel, and the sender
+//! resends it every `RESEND_INTERVAL` until it is acknowledged. Once the deadline passed the
+//! sender gives up and gets a `DeliveryAbandoned<T>` event with the message. Resent messages are
+//! only applied once (see `NetworkConfig::dedup_window`). Messages still pending when the client
+//! disconnects or the server stops are abandoned at their deadline as well.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventWriter, IntoSystemConfigs, PostUpdate, Res, ResMut, Resource,
+    Time,
+};
+use bevy_renet::renet::{RenetClient, RenetServer};
+use renet::Bytes;
+use serde::de::DeserializeOwned;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::{codec, sequence, ClientId, NetworkConfig, NetworkConfigs, NetworkSet};
+
+/// How often messages are resent until they are acknowledged.
+pub const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The `NetworkConfig::dedup_window` of types with a deadline and no window of their own.
+pub const DEADLINE_DEDUP_WINDOW: usize = 1024;
+
+///
+/// Received on the sender when a message of a type with a `NetworkConfig::deadline` wasn't
+/// acknowledged in time. `client_id` is the client the server sent it to, `None` on clients.
+///
+#[derive(Debug, Event)]
+pub struct DeliveryAbandoned<T> {
+    pub client_id: Option<ClientId>,
+    pub content: T,
+}
+
+#[derive(Debug)]
+struct PendingDelivery {
+    payload: Bytes,
+    resend_at: Duration,
+    expires_at: Duration,
+}
+
+///
+/// Sent messages awaiting their acknowledgement, by receiver, channel and message id.
+///
+#[derive(Debug)]
+pub(crate) struct PendingDeliveries<K>(BTreeMap<(K, u8, u32), PendingDelivery>);
+
+impl<K> Default for PendingDeliveries<K> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<K: Copy + Ord> PendingDeliveries<K> {
+    /// Tracks `payload` until it is acknowledged, if its type has a deadline.
+    pub(crate) fn track(
+        &mut self,
+        key: K,
+        channel_id: u8,
+        config: &NetworkConfig,
+        payload: &Bytes,
+        now: Duration,
+    ) {
+        let Some(deadline) = config.delivery_deadline() else {
+            return;
+        };
+        let Some(message_id) = codec::message_id(config, sequence::unstamped(config, payload))
+        else {
+            return;
+        };
+        self.0.insert(
+            (key, channel_id, message_id),
+            PendingDelivery {
+                payload: payload.clone(),
+                resend_at: now + RESEND_INTERVAL,
+                expires_at: now + deadline,
+            },
+        );
+    }
+
+    pub(crate) fn acknowledge(&mut self, key: K, channel_id: u8, message_id: u32) {
+        self.0.remove(&(key, channel_id, message_id));
+    }
+
+    fn receivers(&self) -> impl Iterator<Item = K> + '_ {
+        self.0.keys().map(|(key, _, _)| *key)
+    }
+
+    /// The payloads due for a resend, with their receiver and channel.
+    fn resends(&mut self, now: Duration) -> Vec<(K, u8, Bytes)> {
+        self.0
+            .iter_mut()
+            .filter(|(_, pending)| pending.resend_at <= now && pending.expires_at > now)
+            .map(|((key, channel_id, _), pending)| {
+                pending.resend_at = now + RESEND_INTERVAL;
+                (*key, *channel_id, pending.payload.clone())
+            })
+            .collect()
+    }
+
+    /// Stops tracking the payloads of `channel_id` past their deadline, and returns them.
+    fn take_expired(&mut self, channel_id: u8, now: Duration) -> Vec<(K, Bytes)> {
+        let mut expired = Vec::new();
+        self.0.retain(|(key, pending_channel_id, _), pending| {
+            let abandoned = *pending_channel_id == channel_id && pending.expires_at <= now;
+            if abandoned {
+                expired.push((*key, pending.payload.clone()));
+            }
+            !abandoned
+        });
+        expired
+    }
+}
+
+///
+/// The messages the server sent and awaits acknowledgements for, see the module documentation.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerPendingDeliveries(pub(crate) PendingDeliveries<ClientId>);
+
+impl ServerPendingDeliveries {
+    /// The number of messages sent to the client that weren't acknowledged yet.
+    pub fn pending(&self, client_id: ClientId) -> usize {
+        self.0
+            .receivers()
+            .filter(|pending_client_id| *pending_client_id == client_id)
+            .count()
+    }
+}
+
+///
+/// The messages the client sent and awaits acknowledgements for, see the module documentation.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientPendingDeliveries(pub(crate) PendingDeliveries<()>);
+
+impl ClientPendingDeliveries {
+    /// The number of messages sent to the server that weren't acknowledged yet.
+    pub fn pending(&self) -> usize {
+        self.0.receivers().count()
+    }
+}
+
+pub fn server_resends_pending_deliveries(
+    mut server: ResMut<RenetServer>,
+    time: Res<Time>,
+    mut pending_deliveries: ResMut<ServerPendingDeliveries>,
+) {
+    for (client_id, channel_id, payload) in pending_deliveries.0.resends(time.elapsed()) {
+        let renet_client_id = client_id.into();
+        if server.is_connected(renet_client_id)
+            && server.can_send_message(renet_client_id, channel_id, payload.len())
+        {
+            server.send_message(renet_client_id, channel_id, payload);
+        }
+    }
+}
+
+pub fn client_resends_pending_deliveries(
+    mut client: ResMut<RenetClient>,
+    time: Res<Time>,
+    mut pending_deliveries: ResMut<ClientPendingDeliveries>,
+) {
+    let resends = pending_deliveries.0.resends(time.elapsed());
+    if !client.is_connected() {
+        return;
+    }
+    for ((), channel_id, payload) in resends {
+        if client.can_send_message(channel_id, payload.len()) {
+            client.send_message(channel_id, payload);
+        }
+    }
+}
+
+pub fn abandons_expired_deliveries<const I: u8, T: Event + DeserializeOwned>(
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    mut server_pending_deliveries: ResMut<ServerPendingDeliveries>,
+    mut client_pending_deliveries: ResMut<ClientPendingDeliveries>,
+    mut delivery_abandoned_events: EventWriter<DeliveryAbandoned<T>>,
+) {
+    let channel_config = &channel_configs.0[I as usize];
+    if channel_config.delivery_deadline().is_none() {
+        return;
+    }
+    let now = time.elapsed();
+    let abandoned = server_pending_deliveries
+        .0
+        .take_expired(I, now)
+        .into_iter()
+        .map(|(client_id, payload)| (Some(client_id), payload))
+        .chain(
+            client_pending_deliveries
+                .0
+                .take_expired(I, now)
+                .into_iter()
+                .map(|((), payload)| (None, payload)),
+        );
+    for (client_id, payload) in abandoned {
+        // The payload was encoded by this app, it always decodes.
+        let payload = sequence::unstamped(channel_config, &payload);
+        if let Ok(decoded) = codec::decode::<T>(channel_config, payload) {
+            delivery_abandoned_events.send(DeliveryAbandoned {
+                client_id,
+                content: decoded.content,
+            });
+        }
+    }
+}
+
+pub(crate) fn add_delivery_event<const I: u8, T: Event + DeserializeOwned>(app: &mut App) {
+    app.add_event::<DeliveryAbandoned<T>>()
+        .add_systems(PostUpdate, abandons_expired_deliveries::<I, T>);
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ServerPendingDeliveries>()
+        .init_resource::<ClientPendingDeliveries>()
+        .add_systems(
+            PostUpdate,
+            server_resends_pending_deliveries
+                .in_set(NetworkSet::Flush)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            client_resends_pending_deliveries
+                .in_set(NetworkSet::Flush)
+                .run_if(resource_exists::<RenetClient>),
+        );
+}
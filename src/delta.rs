@@ -0,0 +1,259 @@
+//!
+//! Delta encoding of repeated state messages. Each payload is sent as a patch against the
+//! previous payload on the same channel, which the reliable-ordered delivery guarantees the
+//! receiver still holds. A full payload is sent whenever there is no usable baseline.
+//!
+//! Unreliable types can't be delta encoded, a lost message would leave the receiver without the
+//! baseline of the next patches (see `NetworkConfig::delta`).
+//!
+use std::collections::HashMap;
+use std::hash::Hash;
+
+const FULL: u8 = 0;
+const PATCH: u8 = 1;
+
+///
+/// Last payload sent and received per key (channel, or client and channel).
+///
+#[derive(Debug)]
+pub(crate) struct Baselines<K> {
+    sent: HashMap<K, Vec<u8>>,
+    received: HashMap<K, Vec<u8>>,
+}
+
+impl<K> Default for Baselines<K> {
+    fn default() -> Self {
+        Self {
+            sent: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Copy> Baselines<K> {
+    pub(crate) fn encode(&mut self, key: K, payload: Vec<u8>) -> Vec<u8> {
+        let mut frame;
+        match self
+            .sent
+            .get(&key)
+            .and_then(|baseline| diff(baseline, &payload))
+        {
+            Some(patch) => {
+                frame = Vec::with_capacity(patch.len() + 1);
+                frame.push(PATCH);
+                frame.extend_from_slice(&patch);
+            },
+            None => {
+                frame = Vec::with_capacity(payload.len() + 1);
+                frame.push(FULL);
+                frame.extend_from_slice(&payload);
+            },
+        }
+        self.sent.insert(key, payload);
+        frame
+    }
+
+    /// Returns `None` when the frame is malformed or references a baseline we don't have.
+    pub(crate) fn decode(&mut self, key: K, frame: &[u8]) -> Option<Vec<u8>> {
+        let (tag, body) = frame.split_first()?;
+        let payload = match *tag {
+            FULL => body.to_vec(),
+            PATCH => apply(self.received.get(&key)?, body)?,
+            _ => return None,
+        };
+        self.received.insert(key, payload.clone());
+        Some(payload)
+    }
+
+    /// Every key with a baseline, sent or received (possibly twice).
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        self.sent.keys().chain(self.received.keys())
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        self.sent.retain(|key, _| keep(key));
+        self.received.retain(|key, _| keep(key));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.sent.clear();
+        self.received.clear();
+    }
+}
+
+///
+/// Patch made of `(skip, length, bytes)` runs over a baseline of the same length.
+/// Returns `None` if the payload can't be diffed or the patch wouldn't be smaller.
+///
+fn diff(baseline: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    if baseline.len() != payload.len() {
+        return None;
+    }
+    let mut patch = Vec::new();
+    let mut position = 0;
+    let mut index = 0;
+    while index < payload.len() {
+        if baseline[index] == payload[index] {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < payload.len() && baseline[index] != payload[index] {
+            index += 1;
+        }
+        write_varint(&mut patch, start - position);
+        write_varint(&mut patch, index - start);
+        patch.extend_from_slice(&payload[start..index]);
+        position = index;
+        if patch.len() >= payload.len() {
+            return None;
+        }
+    }
+    Some(patch)
+}
+
+fn apply(baseline: &[u8], mut patch: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = baseline.to_vec();
+    let mut position = 0usize;
+    while !patch.is_empty() {
+        let skip = read_varint(&mut patch)?;
+        let length = read_varint(&mut patch)?;
+        let start = position.checked_add(skip)?;
+        let end = start.checked_add(length)?;
+        if end > payload.len() || length > patch.len() {
+            return None;
+        }
+        payload[start..end].copy_from_slice(&patch[..length]);
+        patch = &patch[length..];
+        position = end;
+    }
+    Some(payload)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (byte, rest) = buffer.split_first()?;
+        *buffer = rest;
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed(baseline: &[u8], changes: &[(usize, u8)]) -> Vec<u8> {
+        let mut payload = baseline.to_vec();
+        for (index, byte) in changes {
+            payload[*index] = *byte;
+        }
+        payload
+    }
+
+    #[test]
+    fn diff_and_apply_round_trip() {
+        let baseline: Vec<u8> = (0..64).collect();
+        let payload = changed(&baseline, &[(0, 200), (10, 201), (11, 202), (63, 203)]);
+        let patch = diff(&baseline, &payload).unwrap();
+        assert!(patch.len() < payload.len());
+        assert_eq!(apply(&baseline, &patch).unwrap(), payload);
+    }
+
+    #[test]
+    fn diff_of_identical_payloads_is_empty() {
+        let baseline = vec![7; 16];
+        let patch = diff(&baseline, &baseline).unwrap();
+        assert!(patch.is_empty());
+        assert_eq!(apply(&baseline, &patch).unwrap(), baseline);
+    }
+
+    #[test]
+    fn diff_rejects_other_lengths_and_larger_patches() {
+        assert_eq!(diff(&[1, 2, 3], &[1, 2]), None);
+        assert_eq!(diff(&[1, 2, 3], &[4, 5, 6]), None);
+    }
+
+    #[test]
+    fn apply_rejects_malformed_patches() {
+        let baseline = vec![0; 8];
+        // Truncated varint.
+        assert_eq!(apply(&baseline, &[0x80]), None);
+        // Length missing.
+        assert_eq!(apply(&baseline, &[0]), None);
+        // Fewer bytes than the run's length.
+        assert_eq!(apply(&baseline, &[0, 3, 1, 2]), None);
+        // Run past the end of the baseline.
+        assert_eq!(apply(&baseline, &[6, 3, 1, 2, 3]), None);
+        // Skip overflowing the position.
+        let mut patch = Vec::new();
+        write_varint(&mut patch, usize::MAX);
+        write_varint(&mut patch, 1);
+        patch.push(1);
+        assert_eq!(apply(&baseline, &patch), None);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as usize,
+            usize::MAX,
+        ] {
+            let mut buffer = Vec::new();
+            write_varint(&mut buffer, value);
+            let mut bytes = buffer.as_slice();
+            assert_eq!(read_varint(&mut bytes), Some(value));
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn varint_rejects_truncated_and_overlong_input() {
+        assert_eq!(read_varint(&mut [].as_slice()), None);
+        assert_eq!(read_varint(&mut [0x80, 0x80].as_slice()), None);
+        assert_eq!(read_varint(&mut [0xff; 11].as_slice()), None);
+    }
+
+    #[test]
+    fn baselines_round_trip() {
+        let mut sender = Baselines::default();
+        let mut receiver = Baselines::default();
+        let first = vec![1; 32];
+        let second = changed(&first, &[(5, 9)]);
+        let full = sender.encode(0u8, first.clone());
+        assert_eq!(full[0], FULL);
+        assert_eq!(receiver.decode(0u8, &full).unwrap(), first);
+        let patch = sender.encode(0u8, second.clone());
+        assert_eq!(patch[0], PATCH);
+        assert!(patch.len() < second.len());
+        assert_eq!(receiver.decode(0u8, &patch).unwrap(), second);
+    }
+
+    #[test]
+    fn decode_rejects_malformed_frames() {
+        let mut receiver = Baselines::default();
+        assert_eq!(receiver.decode(0u8, &[]), None);
+        assert_eq!(receiver.decode(0u8, &[2, 1]), None);
+        // A patch without a baseline.
+        assert_eq!(receiver.decode(0u8, &[PATCH, 0, 1, 1]), None);
+    }
+}
@@ -0,0 +1,224 @@
+//!
+//! A single error event covering everything that can go wrong on either end, so one system can
+//! handle all of it, and per role events for apps that are both a server and a client.
+//!
+use bevy::ecs::event::ManualEventReader;
+use bevy::prelude::{
+    App, Event, EventReader, EventWriter, Events, IntoSystemConfigs, Last, PostUpdate, PreUpdate,
+    Res, ResMut, Resource,
+};
+use bevy_renet::renet::transport::NetcodeTransportError;
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{
+    transport::{client_sends_packets, client_updates_transport, server_updates_transport},
+    ClientId,
+    {keys::KeyError, DecodeErrorKind},
+};
+
+///
+/// Emitted on either end for transport errors, decode errors, messages that couldn't be sent and
+/// servers or connections that couldn't be set up.
+///
+/// `NetcodeTransportError` and `MessageDecodeError` events are still sent, this event mirrors
+/// them next to the errors that have no event of their own. Every error is also sent as either a
+/// `ServerNetworkError` or a `ClientNetworkError`, depending on the end it happened on.
+///
+#[derive(Debug, Clone, Event)]
+pub enum NetworkError {
+    /// Reported by the netcode transport, see `NetcodeTransportError`.
+    Transport(String),
+    /// The netcode transport panicked, with the panic message. The server was stopped or the
+    /// client disconnected, see the `transport` module.
+    TransportPanic(String),
+    /// A received message was dropped, see `MessageDecodeError`.
+    Decode {
+        /// The sending client, `None` when the message came from the server.
+        client_id: Option<ClientId>,
+        channel_id: u8,
+        kind: DecodeErrorKind,
+    },
+    /// A message was dropped instead of being handed to renet.
+    Send {
+        /// The receiving client, `None` when the message was for the server.
+        client_id: Option<ClientId>,
+        channel_id: u8,
+        reason: SendErrorReason,
+    },
+    /// A `StartServer` or `ConnectToServer` couldn't be applied.
+    Config(ConfigError),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(error) => write!(f, "transport error: {}", error),
+            Self::TransportPanic(message) => write!(f, "transport panicked: {}", message),
+            Self::Decode {
+                client_id,
+                channel_id,
+                kind,
+            } => write!(
+                f,
+                "could not decode message from {:?} on channel {}: {:?}",
+                client_id, channel_id, kind
+            ),
+            Self::Send {
+                client_id,
+                channel_id,
+                reason,
+            } => write!(
+                f,
+                "could not send message to {:?} on channel {}: {:?}",
+                client_id, channel_id, reason
+            ),
+            Self::Config(error) => write!(f, "invalid configuration: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendErrorReason {
+    /// The channel's `NetworkConfig::max_memory_usage_bytes` is reached, sending more would make
+    /// renet drop the connection.
+    ChannelFull,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigError {
+    /// The ip and port don't form a valid socket address.
+    InvalidAddress(String),
+    /// Binding, cloning or configuring the socket failed.
+    Io(Arc<std::io::Error>),
+    /// The netcode transport or connect token couldn't be created.
+    Transport(String),
+    /// The private key file couldn't be loaded, see `StartServer::private_key_path`.
+    Key(Arc<KeyError>),
+    /// A redirect token of this many bytes doesn't fit in the user data, see
+    /// `redirect::MAX_REDIRECT_TOKEN_BYTES`.
+    RedirectTokenTooLong(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddress(addr) => write!(f, "invalid address {}", addr),
+            Self::Io(error) => write!(f, "socket error: {}", error),
+            Self::Transport(error) => write!(f, "transport error: {}", error),
+            Self::Key(error) => write!(f, "private key error: {}", error),
+            Self::RedirectTokenTooLong(len) => {
+                write!(f, "redirect token too long ({} bytes)", len)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<KeyError> for ConfigError {
+    fn from(error: KeyError) -> Self {
+        Self::Key(Arc::new(error))
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(Arc::new(error))
+    }
+}
+
+///
+/// A `NetworkError` that happened on the server (including the server of a listen server).
+///
+#[derive(Debug, Clone, Event)]
+pub struct ServerNetworkError(pub NetworkError);
+
+///
+/// A `NetworkError` that happened on the client (including the client of a listen server).
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClientNetworkError(pub NetworkError);
+
+///
+/// Position in the `NetcodeTransportError` events shared by the systems attributing them to the
+/// server or the client.
+///
+#[derive(Default, Resource)]
+pub struct TransportErrorsCursor(ManualEventReader<NetcodeTransportError>);
+
+fn unread_transport_errors<'a>(
+    transport_errors: &'a Events<NetcodeTransportError>,
+    cursor: &'a mut TransportErrorsCursor,
+) -> impl Iterator<Item = NetworkError> + 'a {
+    cursor
+        .0
+        .read(transport_errors)
+        .map(|transport_error| NetworkError::Transport(transport_error.to_string()))
+}
+
+///
+/// Runs right after the server's transport update, which is the only server system sending
+/// transport errors. Anything sent since the client's last transport system is the server's.
+///
+pub fn server_attributes_transport_errors(
+    transport_errors: Res<Events<NetcodeTransportError>>,
+    mut cursor: ResMut<TransportErrorsCursor>,
+    mut server_network_errors: EventWriter<ServerNetworkError>,
+) {
+    for network_error in unread_transport_errors(&transport_errors, &mut cursor) {
+        server_network_errors.send(ServerNetworkError(network_error));
+    }
+}
+
+///
+/// Runs right after the client's transport update and packet sending.
+///
+pub fn client_attributes_transport_errors(
+    transport_errors: Res<Events<NetcodeTransportError>>,
+    mut cursor: ResMut<TransportErrorsCursor>,
+    mut client_network_errors: EventWriter<ClientNetworkError>,
+) {
+    for network_error in unread_transport_errors(&transport_errors, &mut cursor) {
+        client_network_errors.send(ClientNetworkError(network_error));
+    }
+}
+
+pub fn forwards_network_errors(
+    mut server_network_errors: EventReader<ServerNetworkError>,
+    mut client_network_errors: EventReader<ClientNetworkError>,
+    mut network_errors: EventWriter<NetworkError>,
+) {
+    for ServerNetworkError(network_error) in server_network_errors.read() {
+        network_errors.send(network_error.clone());
+    }
+    for ClientNetworkError(network_error) in client_network_errors.read() {
+        network_errors.send(network_error.clone());
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    // Both transports send the same `NetcodeTransportError` events, they are told apart by
+    // reading them in between the two transports' systems.
+    app.init_resource::<TransportErrorsCursor>()
+        .add_event::<NetworkError>()
+        .add_event::<ServerNetworkError>()
+        .add_event::<ClientNetworkError>()
+        .add_systems(
+            PreUpdate,
+            (
+                server_attributes_transport_errors
+                    .after(server_updates_transport)
+                    .before(client_updates_transport),
+                client_attributes_transport_errors.after(client_updates_transport),
+            ),
+        )
+        .add_systems(
+            PostUpdate,
+            client_attributes_transport_errors.after(client_sends_packets),
+        )
+        .add_systems(Last, forwards_network_errors);
+}
@@ -0,0 +1,231 @@
+//!
+//! IP based filtering of incoming connections.
+//!
+use bevy::prelude::{Event, Resource};
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+///
+/// A range of IP addresses in CIDR notation (`10.0.0.0/8`, `::1/128`).
+/// A bare address (`192.168.1.20`) is a range containing only that address.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Prefix lengths larger than the address size are clamped. IPv4-mapped IPv6 ranges are stored
+    /// as the IPv4 range they map, like the addresses they are compared with.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Self {
+        let (addr, prefix_len) = match addr {
+            IpAddr::V6(v6) if prefix_len >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), prefix_len - 96),
+                None => (addr, prefix_len),
+            },
+            _ => (addr, prefix_len),
+        };
+        let max_prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self {
+            addr,
+            prefix_len: prefix_len.min(max_prefix_len),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        Self::new(addr, u8::MAX)
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpRange(pub String);
+
+impl fmt::Display for InvalidIpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IP range: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpRange {}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpRange(s.to_string());
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+                let prefix_len: u8 = prefix_len.trim().parse().map_err(|_| invalid())?;
+                Ok(Self::new(addr, prefix_len))
+            },
+            None => s
+                .trim()
+                .parse::<IpAddr>()
+                .map(Self::from)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+///
+/// Decides which IP addresses may connect to the server.
+/// Denied ranges always win. When at least one range is allowed, only allowed addresses get in.
+/// Insert it before starting the server, or mutate it at runtime.
+///
+#[derive(Debug, Default, Clone, Resource)]
+pub struct ConnectionFilter {
+    allowed: Vec<IpRange>,
+    denied: Vec<IpRange>,
+}
+
+impl ConnectionFilter {
+    pub fn allow(&mut self, range: impl Into<IpRange>) -> &mut Self {
+        self.allowed.push(range.into());
+        self
+    }
+
+    pub fn deny(&mut self, range: impl Into<IpRange>) -> &mut Self {
+        self.denied.push(range.into());
+        self
+    }
+
+    pub fn remove(&mut self, range: impl Into<IpRange>) -> &mut Self {
+        let range = range.into();
+        self.allowed.retain(|allowed| *allowed != range);
+        self.denied.retain(|denied| *denied != range);
+        self
+    }
+
+    pub fn allowed(&self) -> &[IpRange] {
+        &self.allowed
+    }
+
+    pub fn denied(&self) -> &[IpRange] {
+        &self.denied
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.denied.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(ip))
+    }
+}
+
+///
+/// Emitted on the server when a connection was refused by the `ConnectionFilter`.
+///
+#[derive(Debug, Event)]
+pub struct ConnectionFiltered {
+    pub addr: SocketAddr,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_contains_every_address_of_its_family() {
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(range("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+        assert!(!range("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_prefix_contains_only_its_address() {
+        assert!(range("192.168.1.20/32").contains(ip("192.168.1.20")));
+        assert!(!range("192.168.1.20/32").contains(ip("192.168.1.21")));
+        assert!(range("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!range("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert_eq!(range("192.168.1.20"), range("192.168.1.20/32"));
+        assert_eq!(range("::1"), range("::1/128"));
+    }
+
+    #[test]
+    fn partial_prefixes() {
+        assert!(range("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!range("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(range("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!range("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!range("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        assert!(range("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(range("::ffff:10.0.0.0/104").contains(ip("::ffff:10.1.2.3")));
+        assert_eq!(range("::ffff:10.0.0.0/104"), range("10.0.0.0/8"));
+    }
+
+    #[test]
+    fn oversized_prefixes_are_clamped() {
+        assert_eq!(range("10.0.0.1/33"), range("10.0.0.1/32"));
+        assert_eq!(range("::1/200"), range("::1/128"));
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for s in [
+            "",
+            "/",
+            "10.0.0.0/",
+            "/8",
+            "10.0.0/8",
+            "10.0.0.0/-1",
+            "10.0.0.0/256",
+            "10.0.0.0/8/8",
+            "256.0.0.0/8",
+            "example.com",
+            "2001:db8::/x",
+        ] {
+            assert_eq!(
+                s.parse::<IpRange>(),
+                Err(InvalidIpRange(s.to_string())),
+                "{s}"
+            );
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//!
+//! Zone handoffs between servers of a sharded world, enabled with the `handoff` feature.
+//!
+//! Send `HandOffClient` on the server the client is leaving, with the player's state serialized
+//! by the game. The state is sent to the destination server over a TCP link authenticated with
+//! the secret shared by both servers (`ZoneHandoff::secret`), and once the destination has it the
+//! client is redirected there (see `RedirectClient`) with a one-time ticket. The destination
+//! server receives `ClientHandedOff` with the state when the client connects with that ticket,
+//! right after its `ClientConnected`. `HandoffFailed` is received on the first server when the
+//! state couldn't be delivered, the client stays connected to it.
+//!
+//! Destination servers listen for handoffs on `ZoneHandoff::listen_addr`, which only other
+//! servers should be able to reach.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate,
+    PreUpdate, Res, ResMut, Resource, Time,
+};
+use bevy::utils::HashMap;
+use bevy_renet::renet::RenetServer;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{
+    redirect::{server_redirects_clients, RedirectClient},
+    server::{
+        server_tracks_connected_and_disconnected_clients, ClientConnected, ServerClients,
+        ServerStopped,
+    },
+    ClientId,
+};
+
+/// Largest player state a handoff can carry.
+pub const MAX_HANDOFF_STATE_BYTES: usize = 64 * 1024;
+
+const MAC_BYTES: usize = 32;
+
+type Ticket = [u8; 16];
+
+///
+/// Handoff settings, shared by the servers handing clients off and the ones receiving them.
+///
+#[derive(Debug, Resource)]
+pub struct ZoneHandoff {
+    /// Authenticates the servers to each other, handoffs are disabled while `None`.
+    pub secret: Option<[u8; 32]>,
+    /// Where this server accepts handoffs from other servers, `None` to not accept any.
+    pub listen_addr: Option<SocketAddr>,
+    /// How long delivering the state to the destination server may take.
+    pub timeout: Duration,
+    /// How long the destination server keeps a state for its client to connect.
+    pub ticket_lifetime: Duration,
+    listener: Option<(SocketAddr, TcpListener)>,
+}
+
+impl Default for ZoneHandoff {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            listen_addr: None,
+            timeout: Duration::from_secs(5),
+            ticket_lifetime: Duration::from_secs(30),
+            listener: None,
+        }
+    }
+}
+
+///
+/// Send this event from the server to move a connected client to the server whose clients
+/// connect to `addr` and which accepts handoffs on `handoff_addr`, along with its `state`.
+///
+#[derive(Debug, Event)]
+pub struct HandOffClient {
+    pub client_id: ClientId,
+    pub addr: SocketAddr,
+    pub handoff_addr: SocketAddr,
+    pub state: Vec<u8>,
+}
+
+///
+/// Received on the server a client was handed off to, with the state sent by the previous server.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClientHandedOff {
+    pub client_id: ClientId,
+    pub state: Vec<u8>,
+}
+
+///
+/// Received on the server handing a client off when its state couldn't be delivered, the client
+/// wasn't redirected.
+///
+#[derive(Debug, Clone, Event)]
+pub struct HandoffFailed {
+    pub client_id: ClientId,
+    pub error: HandoffError,
+}
+
+#[derive(Debug, Clone)]
+pub enum HandoffError {
+    /// `ZoneHandoff::secret` isn't set.
+    NotConfigured,
+    /// The state is larger than `MAX_HANDOFF_STATE_BYTES`.
+    StateTooLarge(usize),
+    /// The destination server couldn't be reached or didn't answer in time.
+    Io(Arc<std::io::Error>),
+    /// The destination server refused the state, usually because of a different secret.
+    Rejected,
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotConfigured => write!(f, "no handoff secret configured"),
+            Self::StateTooLarge(len) => write!(f, "state too large ({} bytes)", len),
+            Self::Io(error) => write!(f, "could not reach the destination server: {}", error),
+            Self::Rejected => write!(f, "the destination server refused the handoff"),
+        }
+    }
+}
+
+impl std::error::Error for HandoffError {}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HandoffFrame {
+    ticket: Ticket,
+    client_id: ClientId,
+    state: Vec<u8>,
+}
+
+struct Delivery {
+    client_id: ClientId,
+    addr: SocketAddr,
+    ticket: Ticket,
+    result: Result<(), HandoffError>,
+}
+
+///
+/// Handoffs being delivered by background threads, drained by `server_completes_handoffs`.
+///
+#[derive(Default, Resource)]
+pub struct HandoffDeliveries(Arc<Mutex<Vec<Delivery>>>);
+
+struct IncomingHandoff {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    expires_at: Duration,
+}
+
+struct ReceivedHandoff {
+    client_id: ClientId,
+    state: Vec<u8>,
+    expires_at: Duration,
+}
+
+///
+/// Handoff connections being read and states waiting for their client, on the destination.
+///
+#[derive(Default, Resource)]
+pub struct ReceivedHandoffs {
+    incoming: Vec<IncomingHandoff>,
+    received: HashMap<Ticket, ReceivedHandoff>,
+}
+
+impl ReceivedHandoffs {
+    /// Number of states waiting for their client to connect.
+    pub fn pending(&self) -> usize {
+        self.received.len()
+    }
+}
+
+fn mac(secret: &[u8; 32], bytes: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(bytes);
+    mac
+}
+
+/// Sends the frame (length, frame, MAC) and waits for the destination's acknowledgement.
+fn deliver(
+    handoff_addr: SocketAddr,
+    message: &[u8],
+    timeout: Duration,
+) -> Result<(), HandoffError> {
+    let io = |error| HandoffError::Io(Arc::new(error));
+    let mut stream = TcpStream::connect_timeout(&handoff_addr, timeout).map_err(io)?;
+    stream.set_write_timeout(Some(timeout)).map_err(io)?;
+    stream.set_read_timeout(Some(timeout)).map_err(io)?;
+    stream
+        .write_all(&(message.len() as u32).to_le_bytes())
+        .and_then(|_| stream.write_all(message))
+        .map_err(io)?;
+    let mut ack = [0];
+    match stream.read(&mut ack) {
+        Ok(1) if ack[0] == 1 => Ok(()),
+        Ok(_) => Err(HandoffError::Rejected),
+        Err(error) => Err(io(error)),
+    }
+}
+
+pub fn server_hands_off_clients(
+    zone_handoff: Res<ZoneHandoff>,
+    deliveries: Res<HandoffDeliveries>,
+    mut hand_off_client_events: EventReader<HandOffClient>,
+    mut handoff_failed_events: EventWriter<HandoffFailed>,
+) {
+    for hand_off_client in hand_off_client_events.read() {
+        let client_id = hand_off_client.client_id;
+        let Some(secret) = zone_handoff.secret else {
+            handoff_failed_events.send(HandoffFailed {
+                client_id,
+                error: HandoffError::NotConfigured,
+            });
+            continue;
+        };
+        if hand_off_client.state.len() > MAX_HANDOFF_STATE_BYTES {
+            handoff_failed_events.send(HandoffFailed {
+                client_id,
+                error: HandoffError::StateTooLarge(hand_off_client.state.len()),
+            });
+            continue;
+        }
+        let mut ticket = [0; 16];
+        getrandom::getrandom(&mut ticket).expect("OS random number generator unavailable");
+        let mut message = bincode::serde::encode_to_vec(
+            HandoffFrame {
+                ticket,
+                client_id,
+                state: hand_off_client.state.clone(),
+            },
+            bincode::config::standard(),
+        )
+        .unwrap();
+        message.extend_from_slice(&mac(&secret, &message).finalize().into_bytes());
+        let handoff_addr = hand_off_client.handoff_addr;
+        let addr = hand_off_client.addr;
+        let timeout = zone_handoff.timeout;
+        let deliveries = deliveries.0.clone();
+        // Connecting and waiting for the acknowledgement would stall the server's tick.
+        std::thread::spawn(move || {
+            let result = deliver(handoff_addr, &message, timeout);
+            deliveries.lock().unwrap().push(Delivery {
+                client_id,
+                addr,
+                ticket,
+                result,
+            });
+        });
+    }
+}
+
+pub fn server_completes_handoffs(
+    deliveries: Res<HandoffDeliveries>,
+    server_clients: ServerClients,
+    mut redirect_client_events: EventWriter<RedirectClient>,
+    mut handoff_failed_events: EventWriter<HandoffFailed>,
+) {
+    let delivered = std::mem::take(&mut *deliveries.0.lock().unwrap());
+    for delivery in delivered {
+        match delivery.result {
+            // The client may have left while its state was being delivered.
+            Ok(()) if server_clients.is_connected(delivery.client_id) => {
+                redirect_client_events.send(RedirectClient {
+                    client_id: delivery.client_id,
+                    addr: delivery.addr,
+                    token: Some(delivery.ticket.to_vec()),
+                });
+            },
+            Ok(()) => {},
+            Err(error) => {
+                handoff_failed_events.send(HandoffFailed {
+                    client_id: delivery.client_id,
+                    error,
+                });
+            },
+        }
+    }
+}
+
+/// Reads what arrived on an incoming connection, returns the message once complete.
+fn read_incoming(incoming: &mut IncomingHandoff) -> Result<Option<Vec<u8>>, ()> {
+    let mut chunk = [0; 4096];
+    loop {
+        match incoming.stream.read(&mut chunk) {
+            Ok(0) => return Err(()),
+            Ok(len) => incoming.buffer.extend_from_slice(&chunk[..len]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+            Err(_) => return Err(()),
+        }
+    }
+    let Some(len) = incoming.buffer.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    // Room for the frame's own fields on top of the state.
+    if len > MAX_HANDOFF_STATE_BYTES + MAC_BYTES + 64 {
+        return Err(());
+    }
+    if incoming.buffer.len() < 4 + len {
+        return Ok(None);
+    }
+    Ok(Some(incoming.buffer[4..4 + len].to_vec()))
+}
+
+pub fn server_receives_handoffs(
+    mut zone_handoff: ResMut<ZoneHandoff>,
+    mut received_handoffs: ResMut<ReceivedHandoffs>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed();
+    let (Some(secret), Some(listen_addr)) = (zone_handoff.secret, zone_handoff.listen_addr) else {
+        zone_handoff.listener = None;
+        return;
+    };
+    if zone_handoff
+        .listener
+        .as_ref()
+        .is_none_or(|(addr, _)| *addr != listen_addr)
+    {
+        // Retried every tick until the address can be bound.
+        zone_handoff.listener = TcpListener::bind(listen_addr)
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .ok()
+            .map(|listener| (listen_addr, listener));
+    }
+    if let Some((_, listener)) = &zone_handoff.listener {
+        while let Ok((stream, _)) = listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                received_handoffs.incoming.push(IncomingHandoff {
+                    stream,
+                    buffer: Vec::new(),
+                    expires_at: now + zone_handoff.timeout,
+                });
+            }
+        }
+    }
+
+    let ReceivedHandoffs { incoming, received } = &mut *received_handoffs;
+    incoming.retain_mut(|incoming| {
+        let message = match read_incoming(incoming) {
+            Ok(Some(message)) => message,
+            Ok(None) => return now < incoming.expires_at,
+            Err(()) => return false,
+        };
+        let Some((frame, tag)) = message.split_at_checked(message.len().saturating_sub(MAC_BYTES))
+        else {
+            return false;
+        };
+        // Connections with a wrong MAC are closed without an acknowledgement.
+        if mac(&secret, frame).verify_slice(tag).is_err() {
+            return false;
+        }
+        if let Ok((frame, _)) =
+            bincode::serde::decode_from_slice::<HandoffFrame, _>(frame, bincode::config::standard())
+        {
+            received.insert(
+                frame.ticket,
+                ReceivedHandoff {
+                    client_id: frame.client_id,
+                    state: frame.state,
+                    expires_at: now + zone_handoff.ticket_lifetime,
+                },
+            );
+            let _ = incoming.stream.write_all(&[1]);
+        }
+        false
+    });
+    received.retain(|_, received| now < received.expires_at);
+}
+
+pub fn server_claims_handoffs(
+    server_clients: ServerClients,
+    mut received_handoffs: ResMut<ReceivedHandoffs>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_handed_off_events: EventWriter<ClientHandedOff>,
+) {
+    for ClientConnected { client_id, .. } in client_connected_events.read() {
+        let Some(ticket) = server_clients
+            .redirect_token(*client_id)
+            .and_then(|token| Ticket::try_from(token.as_slice()).ok())
+        else {
+            continue;
+        };
+        // A ticket is only good for the client it was issued for, and only once.
+        if received_handoffs
+            .received
+            .get(&ticket)
+            .is_some_and(|received| received.client_id == *client_id)
+        {
+            if let Some(received) = received_handoffs.received.remove(&ticket) {
+                client_handed_off_events.send(ClientHandedOff {
+                    client_id: *client_id,
+                    state: received.state,
+                });
+            }
+        }
+    }
+}
+
+pub fn server_resets_handoffs(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut zone_handoff: ResMut<ZoneHandoff>,
+    mut received_handoffs: ResMut<ReceivedHandoffs>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        zone_handoff.listener = None;
+        received_handoffs.incoming.clear();
+        received_handoffs.received.clear();
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ZoneHandoff>()
+        .init_resource::<HandoffDeliveries>()
+        .init_resource::<ReceivedHandoffs>()
+        .add_event::<HandOffClient>()
+        .add_event::<ClientHandedOff>()
+        .add_event::<HandoffFailed>()
+        .add_systems(
+            PreUpdate,
+            (server_receives_handoffs, server_claims_handoffs)
+                .chain()
+                .after(server_tracks_connected_and_disconnected_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (server_hands_off_clients, server_completes_handoffs)
+                .before(server_redirects_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_resets_handoffs);
+}
@@ -0,0 +1,340 @@
+//!
+//! Schema handshake: catches peers built from other versions of the network types (e.g. a client
+//! built from yesterday's commit) as soon as they connect, instead of on the first message that
+//! fails to decode.
+//!
+//! Every registered type is hashed from its name, the `NetworkConfig` settings changing its wire
+//! format, and with the `schema` feature its serde layout. Clients send the hash of all of them
+//! once connected. When the server's hash differs, both ends exchange their per-type hashes and
+//! get a `SchemaMismatch` event with the names of the types registered differently.
+//!
+//! Unlike a different number of registered types (see `DenialReason::ChannelMismatch`), a
+//! mismatch doesn't refuse the connection, disconnect the client from `SchemaMismatch` to do so.
+//!
+//! Clients also advertise their `Capabilities` (feature flags such as `"compression"` or
+//! `"replication-v2"`) once connected. The server keeps the flags both ends support per client in
+//! `ClientCapabilities` and answers with them, clients keep them in `ServerCapabilities`, and both
+//! ends get a `CapabilitiesNegotiated` event. With `Negotiation` enabled, this happens before
+//! `ClientConnected`.
+//!
+use bevy::ecs::system::SystemParam;
+use bevy::log::warn;
+use bevy::prelude::{
+    App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res, ResMut, Resource,
+};
+use bevy::utils::HashMap;
+use bevy_renet::{client_just_connected, renet::RenetClient};
+use renet::SendType;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    client::ClientStopped,
+    control::ControlMessage,
+    fnv1a,
+    negotiation::Negotiation,
+    server::{ConnectedClients, SuspendedClients},
+    ChannelRegistry, ClientId, NetworkSet, RegisteredChannel, CONTROL_CHANNEL_ID, FNV_OFFSET_BASIS,
+};
+
+///
+/// The peer registered some types differently, see the module documentation.
+///
+#[derive(Debug, Clone, Event)]
+pub struct SchemaMismatch {
+    /// The mismatched client on the server, `None` on clients.
+    pub client_id: Option<ClientId>,
+    /// The types registered differently, by either end.
+    pub type_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TypeSchema {
+    channel_id: u8,
+    type_name: String,
+    hash: u32,
+}
+
+fn type_hash(registered: &RegisteredChannel) -> u32 {
+    let config = &registered.config;
+    let send_type = match config.send_type {
+        SendType::Unreliable => 0,
+        SendType::ReliableUnordered { .. } => 1,
+        SendType::ReliableOrdered { .. } => 2,
+    };
+    let wire_settings = [
+        send_type,
+        config.uses_timestamps() as u8,
+        config.uses_sequencing() as u8,
+        config.dedup_window().is_some() as u8,
+        config.uses_delta() as u8,
+        config.uses_resumption() as u8,
+        config.uses_json() as u8,
+        config.wire_format.int_encoding as u8,
+        config.wire_format.byte_order as u8,
+    ];
+    let mut hash = fnv1a(FNV_OFFSET_BASIS, registered.type_name.as_bytes());
+    hash = fnv1a(hash, &wire_settings);
+    #[cfg(feature = "schema")]
+    if let Some(layout) = crate::schema::layout(registered.trace) {
+        hash = fnv1a(hash, layout.as_bytes());
+    }
+    hash
+}
+
+///
+/// The registered types and what the schema handshake compares them with, see the module
+/// documentation.
+///
+#[derive(SystemParam)]
+pub struct SchemaCheck<'w> {
+    pub(crate) channel_registry: Res<'w, ChannelRegistry>,
+    schema_mismatch_events: EventWriter<'w, SchemaMismatch>,
+}
+
+impl SchemaCheck<'_> {
+    pub(crate) fn type_schemas(&self) -> Vec<TypeSchema> {
+        self.channel_registry
+            .iter()
+            .map(|registered| TypeSchema {
+                channel_id: registered.channel_id,
+                type_name: registered.type_name.to_string(),
+                hash: type_hash(registered),
+            })
+            .collect()
+    }
+
+    pub(crate) fn schema_hash(&self) -> u32 {
+        self.type_schemas()
+            .iter()
+            .fold(FNV_OFFSET_BASIS, |hash, type_schema| {
+                let hash = fnv1a(hash, &[type_schema.channel_id]);
+                fnv1a(hash, &type_schema.hash.to_le_bytes())
+            })
+    }
+
+    /// Sends `SchemaMismatch` when the peer's types differ from ours.
+    pub(crate) fn compare(&mut self, client_id: Option<ClientId>, theirs: Vec<TypeSchema>) {
+        let ours: BTreeMap<u8, TypeSchema> = self
+            .type_schemas()
+            .into_iter()
+            .map(|type_schema| (type_schema.channel_id, type_schema))
+            .collect();
+        let mut theirs: BTreeMap<u8, TypeSchema> = theirs
+            .into_iter()
+            .map(|type_schema| (type_schema.channel_id, type_schema))
+            .collect();
+        let mut type_names = Vec::new();
+        for (channel_id, our_type) in ours {
+            match theirs.remove(&channel_id) {
+                Some(their_type) if their_type == our_type => {},
+                Some(their_type) => {
+                    let renamed = their_type.type_name != our_type.type_name;
+                    type_names.push(our_type.type_name);
+                    if renamed {
+                        type_names.push(their_type.type_name);
+                    }
+                },
+                None => type_names.push(our_type.type_name),
+            }
+        }
+        type_names.extend(theirs.into_values().map(|their_type| their_type.type_name));
+        if type_names.is_empty() {
+            return;
+        }
+        match client_id {
+            Some(client_id) => warn!(
+                "Client {} registered these network types differently: {:?}",
+                client_id, type_names
+            ),
+            None => warn!(
+                "The server registered these network types differently: {:?}",
+                type_names
+            ),
+        }
+        self.schema_mismatch_events.send(SchemaMismatch {
+            client_id,
+            type_names,
+        });
+    }
+}
+
+///
+/// The feature flags this end supports, the same resource on servers and clients.
+///
+#[derive(Debug, Default, Clone, Resource)]
+pub struct Capabilities(pub BTreeSet<String>);
+
+impl Capabilities {
+    pub fn with(mut self, flag: impl Into<String>) -> Self {
+        self.0.insert(flag.into());
+        self
+    }
+}
+
+///
+/// The flags negotiated with each client, on the server.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientCapabilities(HashMap<ClientId, BTreeSet<String>>);
+
+impl ClientCapabilities {
+    /// `None` until the client advertised its capabilities.
+    pub fn get(&self, client_id: ClientId) -> Option<&BTreeSet<String>> {
+        self.0.get(&client_id)
+    }
+
+    pub fn supports(&self, client_id: ClientId, flag: &str) -> bool {
+        self.get(client_id)
+            .is_some_and(|capabilities| capabilities.contains(flag))
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().copied()
+    }
+}
+
+///
+/// The flags negotiated with the server, on clients.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerCapabilities(Option<BTreeSet<String>>);
+
+impl ServerCapabilities {
+    /// `None` until the server answered.
+    pub fn get(&self) -> Option<&BTreeSet<String>> {
+        self.0.as_ref()
+    }
+
+    pub fn supports(&self, flag: &str) -> bool {
+        self.get()
+            .is_some_and(|capabilities| capabilities.contains(flag))
+    }
+}
+
+///
+/// The flags both ends support, received once the server answered a client's `Capabilities`.
+/// `client_id` is the client on the server, `None` on clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct CapabilitiesNegotiated {
+    pub client_id: Option<ClientId>,
+    pub capabilities: BTreeSet<String>,
+}
+
+///
+/// What the control systems need for the handshake, see the module documentation.
+///
+#[derive(SystemParam)]
+pub struct Handshake<'w> {
+    pub(crate) schema_check: SchemaCheck<'w>,
+    capabilities: Res<'w, Capabilities>,
+    connected_clients: Res<'w, ConnectedClients>,
+    negotiation: Res<'w, Negotiation>,
+    client_capabilities: ResMut<'w, ClientCapabilities>,
+    server_capabilities: ResMut<'w, ServerCapabilities>,
+    capabilities_negotiated_events: EventWriter<'w, CapabilitiesNegotiated>,
+}
+
+impl Handshake<'_> {
+    /// Keeps the flags both the server and the client support, `None` for clients that aren't
+    /// connected or negotiating (e.g. denied).
+    pub(crate) fn server_negotiates(
+        &mut self,
+        client_id: ClientId,
+        advertised: BTreeSet<String>,
+    ) -> Option<BTreeSet<String>> {
+        if self.connected_clients.get(client_id).is_none()
+            && !self.negotiation.is_negotiating(client_id)
+        {
+            return None;
+        }
+        let negotiated: BTreeSet<String> = self
+            .capabilities
+            .0
+            .intersection(&advertised)
+            .cloned()
+            .collect();
+        self.client_capabilities
+            .0
+            .insert(client_id, negotiated.clone());
+        self.capabilities_negotiated_events
+            .send(CapabilitiesNegotiated {
+                client_id: Some(client_id),
+                capabilities: negotiated.clone(),
+            });
+        Some(negotiated)
+    }
+
+    pub(crate) fn client_negotiated(&mut self, negotiated: BTreeSet<String>) {
+        self.server_capabilities.0 = Some(negotiated.clone());
+        self.capabilities_negotiated_events
+            .send(CapabilitiesNegotiated {
+                client_id: None,
+                capabilities: negotiated,
+            });
+    }
+}
+
+pub fn client_sends_schema_hash(mut client: ResMut<RenetClient>, schema_check: SchemaCheck) {
+    client.send_message(
+        CONTROL_CHANNEL_ID,
+        ControlMessage::SchemaHash(schema_check.schema_hash()).encode(),
+    );
+}
+
+pub fn client_advertises_capabilities(
+    mut client: ResMut<RenetClient>,
+    capabilities: Res<Capabilities>,
+) {
+    client.send_message(
+        CONTROL_CHANNEL_ID,
+        ControlMessage::Capabilities(capabilities.0.clone()).encode(),
+    );
+}
+
+/// Forgets the capabilities of clients the server doesn't know anymore, including suspended
+/// clients that expired and clients that left while negotiating.
+pub fn server_forgets_client_capabilities(
+    connected_clients: Res<ConnectedClients>,
+    suspended_clients: Res<SuspendedClients>,
+    negotiation: Res<Negotiation>,
+    mut client_capabilities: ResMut<ClientCapabilities>,
+) {
+    client_capabilities.0.retain(|client_id, _| {
+        connected_clients.get(*client_id).is_some()
+            || suspended_clients.contains(*client_id)
+            || negotiation.is_negotiating(*client_id)
+    });
+}
+
+pub fn client_forgets_server_capabilities(
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut server_capabilities: ResMut<ServerCapabilities>,
+) {
+    if client_stopped_events.read().count() > 0 {
+        server_capabilities.0 = None;
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<Capabilities>()
+        .init_resource::<ClientCapabilities>()
+        .init_resource::<ServerCapabilities>()
+        .add_event::<SchemaMismatch>()
+        .add_event::<CapabilitiesNegotiated>()
+        .add_systems(
+            PostUpdate,
+            (client_sends_schema_hash, client_advertises_capabilities)
+                .in_set(NetworkSet::Send)
+                .run_if(client_just_connected),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                server_forgets_client_capabilities,
+                client_forgets_server_capabilities,
+            ),
+        );
+}
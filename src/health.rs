@@ -0,0 +1,93 @@
+//!
+//! Reliability counters per channel, to attribute jitter and bandwidth problems to the channels
+//! (and so the registered types, see `ChannelRegistry`) they happen on.
+//!
+//! Messages of sequenced channels (see `NetworkConfig::sequenced`) are counted as out of order
+//! when sent before the latest message received from the same peer on that channel, and as
+//! duplicates when they carry the same sequence number, both are dropped. Messages dropped by a
+//! `NetworkConfig::dedup_window` are counted as duplicates too. Renet doesn't expose its
+//! retransmissions, `ServerClients::packet_loss` is the closest measure of resends per
+//! connection.
+//!
+use bevy::prelude::{App, EventReader, Last, ResMut, Resource};
+#[cfg(feature = "inspector")]
+use bevy::prelude::{Reflect, ReflectResource};
+
+use std::collections::BTreeMap;
+
+use crate::{error::NetworkError, sequence::Arrival};
+
+///
+/// Counters of one channel, see `ChannelHealth`.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+pub struct ChannelCounters {
+    pub out_of_order: u64,
+    pub duplicates: u64,
+    /// See `MessageDecodeError`.
+    pub decode_errors: u64,
+    /// Messages dropped because the channel was full, see `NetworkError::Send`.
+    pub send_failures: u64,
+}
+
+///
+/// Reliability counters per channel since the app started, on either end.
+/// Messages dropped from the server's outbox as stale are counted by `DroppedStaleMessages`.
+///
+#[derive(Debug, Default, Resource)]
+#[cfg_attr(feature = "inspector", derive(Reflect), reflect(Resource))]
+pub struct ChannelHealth(BTreeMap<u8, ChannelCounters>);
+
+impl ChannelHealth {
+    pub fn channel(&self, channel_id: u8) -> ChannelCounters {
+        self.0.get(&channel_id).copied().unwrap_or_default()
+    }
+
+    /// The channels with at least one counted event, ordered by channel.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &ChannelCounters)> {
+        self.0
+            .iter()
+            .map(|(channel_id, counters)| (*channel_id, counters))
+    }
+
+    pub(crate) fn record_arrival(&mut self, channel_id: u8, arrival: Arrival) {
+        match arrival {
+            Arrival::InOrder => {},
+            Arrival::Duplicate => self.0.entry(channel_id).or_default().duplicates += 1,
+            Arrival::OutOfOrder => self.0.entry(channel_id).or_default().out_of_order += 1,
+        }
+    }
+}
+
+pub fn counts_channel_errors(
+    mut channel_health: ResMut<ChannelHealth>,
+    mut network_errors: EventReader<NetworkError>,
+) {
+    for network_error in network_errors.read() {
+        match network_error {
+            NetworkError::Decode { channel_id, .. } => {
+                channel_health
+                    .0
+                    .entry(*channel_id)
+                    .or_default()
+                    .decode_errors += 1;
+            },
+            NetworkError::Send { channel_id, .. } => {
+                channel_health
+                    .0
+                    .entry(*channel_id)
+                    .or_default()
+                    .send_failures += 1;
+            },
+            NetworkError::Transport(_)
+            | NetworkError::TransportPanic(_)
+            | NetworkError::Config(_) => {},
+        }
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ChannelHealth>()
+        .add_systems(Last, counts_channel_errors);
+}
@@ -0,0 +1,131 @@
+//!
+//! Reflection of the crate's state for editor tooling such as bevy-inspector-egui, so that a
+//! world inspector shows the live network state during development.
+//!
+//! `ConnectedClients`, `ChannelRegistry`, `ChannelHealth` and `ConnectionTelemetry` are registered
+//! as reflected resources, along with `RecentNetworkEvents`: a ring buffer of the latest
+//! connection lifecycle events and errors, on either end, formatted with their `Debug` output.
+//!
+use bevy::prelude::{
+    App, Event, EventReader, PostUpdate, Reflect, ReflectResource, Res, ResMut, Resource, Time,
+};
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::{
+    client::{ConnectionDenied, DisconnectedFromServer},
+    error::{ClientNetworkError, ServerNetworkError},
+    health::ChannelHealth,
+    server::{
+        ClientConnected, ClientConnectionLost, ClientDenied, ClientDisconnected, ClientReconnected,
+        ConnectedClients, PlayerReconnected, ServerStopped,
+    },
+    telemetry::ConnectionTelemetry,
+    ChannelRegistry, MessageDecodeError,
+};
+
+///
+/// One event kept by `RecentNetworkEvents`.
+///
+#[derive(Debug, Clone, Reflect)]
+pub struct NetworkEventRecord {
+    /// The app's elapsed time when the event was read.
+    pub at: Duration,
+    pub event: String,
+}
+
+///
+/// The latest network events, oldest first, see the module documentation. Holds up to `capacity`
+/// events (64 by default).
+///
+#[derive(Debug, Resource, Reflect)]
+#[reflect(Resource)]
+pub struct RecentNetworkEvents {
+    pub capacity: usize,
+    events: VecDeque<NetworkEventRecord>,
+}
+
+impl Default for RecentNetworkEvents {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            events: VecDeque::new(),
+        }
+    }
+}
+
+impl RecentNetworkEvents {
+    pub fn iter(&self) -> impl Iterator<Item = &NetworkEventRecord> {
+        self.events.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    fn record<E: Event + Debug>(&mut self, at: Duration, events: &mut EventReader<E>) {
+        for event in events.read() {
+            self.events.push_back(NetworkEventRecord {
+                at,
+                event: format!("{:?}", event),
+            });
+        }
+        while self.events.len() > self.capacity {
+            self.events.pop_front();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_records_network_events(
+    time: Res<Time>,
+    mut recent_events: ResMut<RecentNetworkEvents>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    mut player_reconnected_events: EventReader<PlayerReconnected>,
+    mut client_connection_lost_events: EventReader<ClientConnectionLost>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut client_denied_events: EventReader<ClientDenied>,
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut server_network_errors: EventReader<ServerNetworkError>,
+) {
+    let now = time.elapsed();
+    recent_events.record(now, &mut client_connected_events);
+    recent_events.record(now, &mut client_reconnected_events);
+    recent_events.record(now, &mut player_reconnected_events);
+    recent_events.record(now, &mut client_connection_lost_events);
+    recent_events.record(now, &mut client_disconnected_events);
+    recent_events.record(now, &mut client_denied_events);
+    recent_events.record(now, &mut server_stopped_events);
+    recent_events.record(now, &mut server_network_errors);
+}
+
+pub fn client_records_network_events(
+    time: Res<Time>,
+    mut recent_events: ResMut<RecentNetworkEvents>,
+    mut connection_denied_events: EventReader<ConnectionDenied>,
+    mut disconnected_from_server_events: EventReader<DisconnectedFromServer>,
+    mut client_network_errors: EventReader<ClientNetworkError>,
+    mut decode_error_events: EventReader<MessageDecodeError>,
+) {
+    let now = time.elapsed();
+    recent_events.record(now, &mut connection_denied_events);
+    recent_events.record(now, &mut disconnected_from_server_events);
+    recent_events.record(now, &mut client_network_errors);
+    recent_events.record(now, &mut decode_error_events);
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.register_type::<ConnectedClients>()
+        .register_type::<ChannelRegistry>()
+        .register_type::<ChannelHealth>()
+        .register_type::<ConnectionTelemetry>()
+        .register_type::<RecentNetworkEvents>()
+        .init_resource::<RecentNetworkEvents>()
+        .add_systems(
+            PostUpdate,
+            (server_records_network_events, client_records_network_events),
+        );
+}
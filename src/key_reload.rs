@@ -0,0 +1,176 @@
+//!
+//! Private key rotation for dedicated servers whose key is managed outside of the game (e.g.
+//! written by secret management tooling), see `StartServer::key_reload`.
+//!
+//! The netcode transport can't change its key while running, so the server restarts with the new
+//! key once the grace period is over: connected clients are disconnected and reconnect with
+//! connect tokens built from the new key. The grace period leaves time to hand the new key out
+//! to clients (or to whatever builds their tokens), and to warn players on `ServerKeyChanged`.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource, Time,
+};
+use bevy_renet::renet::RenetServer;
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    error::{NetworkError, ServerNetworkError},
+    keys::load_key_file,
+    server::{server_starts, server_stops, PendingStartServer, StartServer, StopServer},
+};
+
+///
+/// How the private key file is watched, see `StartServer::key_reload`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyReload {
+    /// How often the file's modification time is checked.
+    pub poll_interval: Duration,
+    /// How long the server keeps running with the old key once the file changed.
+    pub grace_period: Duration,
+}
+
+impl Default for KeyReload {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(5),
+            grace_period: Duration::from_secs(30),
+        }
+    }
+}
+
+///
+/// Received on the server when its key file holds a new key, the server restarts with it in
+/// `restarts_in`.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ServerKeyChanged {
+    pub restarts_in: Duration,
+}
+
+#[derive(Debug)]
+struct WatchedKeyFile {
+    /// Sent again to restart the server, it loads the new key from the file.
+    start_server: StartServer,
+    path: PathBuf,
+    reload: KeyReload,
+    modified: Option<SystemTime>,
+    key: Option<[u8; 32]>,
+    next_poll: Option<Duration>,
+    restart_at: Option<Duration>,
+}
+
+///
+/// The key file watched for the running server, if it was started with `key_reload`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerKeyFile(Option<WatchedKeyFile>);
+
+impl ServerKeyFile {
+    pub fn is_watching(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Whether a new key was found and the server is about to restart.
+    pub fn is_rotating(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|watched| watched.restart_at.is_some())
+    }
+
+    pub(crate) fn watch(&mut self, start_server: StartServer) {
+        self.0 = match (&start_server.private_key_path, start_server.key_reload) {
+            (Some(path), Some(reload)) => Some(WatchedKeyFile {
+                path: path.clone(),
+                reload,
+                modified: modified(path),
+                key: load_key_file(path).ok(),
+                next_poll: None,
+                restart_at: None,
+                start_server,
+            }),
+            _ => None,
+        };
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub fn server_watches_key_file(
+    time: Res<Time>,
+    mut server_key_file: ResMut<ServerKeyFile>,
+    mut pending_start_server: ResMut<PendingStartServer>,
+    mut stop_server_events: EventWriter<StopServer>,
+    mut server_key_changed_events: EventWriter<ServerKeyChanged>,
+    mut network_errors: EventWriter<ServerNetworkError>,
+) {
+    let Some(watched) = &mut server_key_file.0 else {
+        return;
+    };
+    let now = time.elapsed();
+    if let Some(restart_at) = watched.restart_at {
+        if now >= restart_at {
+            if let Some(watched) = server_key_file.0.take() {
+                pending_start_server.0 = Some(watched.start_server);
+                stop_server_events.send(StopServer);
+            }
+        }
+        return;
+    }
+    if watched.next_poll.is_some_and(|next_poll| now < next_poll) {
+        return;
+    }
+    watched.next_poll = Some(now + watched.reload.poll_interval);
+    let modified = modified(&watched.path);
+    if modified == watched.modified {
+        return;
+    }
+    watched.modified = modified;
+    match load_key_file(&watched.path) {
+        Ok(key) if watched.key != Some(key) => {
+            watched.key = Some(key);
+            watched.restart_at = Some(now + watched.reload.grace_period);
+            server_key_changed_events.send(ServerKeyChanged {
+                restarts_in: watched.reload.grace_period,
+            });
+        },
+        Ok(_) => {},
+        // Possibly caught halfway through writing, the next write changes the modification time.
+        Err(error) => {
+            network_errors.send(ServerNetworkError(NetworkError::Config(error.into())));
+        },
+    }
+}
+
+pub fn server_forgets_key_file(
+    mut stop_server_events: EventReader<StopServer>,
+    mut server_key_file: ResMut<ServerKeyFile>,
+) {
+    if stop_server_events.read().count() > 0 {
+        server_key_file.0 = None;
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ServerKeyFile>()
+        .add_event::<ServerKeyChanged>()
+        .add_systems(
+            PostUpdate,
+            (
+                server_watches_key_file.run_if(resource_exists::<RenetServer>),
+                // Reads the restart's own `StopServer` right away, before the restarted server
+                // watches the file again.
+                server_forgets_key_file,
+            )
+                .chain()
+                .before(server_stops)
+                .before(server_starts),
+        );
+}
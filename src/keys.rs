@@ -0,0 +1,110 @@
+//!
+//! Private key helpers for Authenticated connections.
+//!
+//! Keys are 32 bytes, stored as 64 hexadecimal characters in files and environment variables so
+//! they can be copied around safely.
+//!
+use pbkdf2::pbkdf2_hmac_array;
+use sha2::Sha256;
+
+use std::fmt;
+use std::path::Path;
+
+/// PBKDF2 rounds used by `derive_key`, changing it changes every derived key.
+const DERIVE_KEY_ROUNDS: u32 = 100_000;
+
+///
+/// A new key from the OS random number generator.
+///
+pub fn generate_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).expect("OS random number generator unavailable");
+    key
+}
+
+///
+/// Derives a key from a passphrase with PBKDF2-HMAC-SHA256.
+/// The server and clients must use the same `salt`, an application specific constant is fine.
+/// Deliberately slow (around a hundred milliseconds), derive the key once and keep it.
+///
+pub fn derive_key(passphrase: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> [u8; 32] {
+    pbkdf2_hmac_array::<Sha256, 32>(passphrase.as_ref(), salt.as_ref(), DERIVE_KEY_ROUNDS)
+}
+
+pub fn key_to_hex(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn key_from_hex(hex: &str) -> Result<[u8; 32], KeyError> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(KeyError::InvalidKey);
+    }
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| KeyError::InvalidKey)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| KeyError::InvalidKey)?;
+    }
+    Ok(key)
+}
+
+///
+/// Reads a key file, either 64 hexadecimal characters (as written by `save_key_file`) or 32 raw
+/// bytes.
+///
+pub fn load_key_file(path: impl AsRef<Path>) -> Result<[u8; 32], KeyError> {
+    let contents = std::fs::read(path)?;
+    if let Ok(key) = <[u8; 32]>::try_from(contents.as_slice()) {
+        return Ok(key);
+    }
+    key_from_hex(&String::from_utf8_lossy(&contents))
+}
+
+///
+/// Writes the key as hexadecimal, restricting the file to its owner on unix.
+///
+pub fn save_key_file(path: impl AsRef<Path>, key: &[u8; 32]) -> Result<(), KeyError> {
+    let path = path.as_ref();
+    std::fs::write(path, key_to_hex(key))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+///
+/// Reads a hexadecimal key from an environment variable.
+///
+pub fn load_key_env(name: &str) -> Result<[u8; 32], KeyError> {
+    let hex = std::env::var(name).map_err(|_| KeyError::MissingEnv(name.to_string()))?;
+    key_from_hex(&hex)
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(std::io::Error),
+    /// The environment variable is unset or not valid unicode.
+    MissingEnv(String),
+    /// Neither 64 hexadecimal characters nor 32 raw bytes.
+    InvalidKey,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "could not read or write key: {}", error),
+            Self::MissingEnv(name) => write!(f, "environment variable {} is not set", name),
+            Self::InvalidKey => write!(f, "key must be 64 hexadecimal characters"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl From<std::io::Error> for KeyError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
@@ -1,41 +1,162 @@
 #![doc = include_str!("../README.md")]
+use std::any::TypeId;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::UdpSocket;
 use std::time::Duration;
 
-use renet::{RenetClient, RenetServer};
+use renet::{
+    transport::{NetcodeClientTransport, NetcodeDisconnectReason},
+    RenetClient, RenetServer,
+};
 
 use bevy::prelude::{
-    not, resource_exists, resource_removed, App, IntoSystemConfigs, Plugin, PostUpdate, PreUpdate,
-    Resource,
+    resource_exists, warn, App, Event, EventWriter, Events, IntoSystemConfigs,
+    IntoSystemSetConfigs, Plugin, PostUpdate, PreUpdate, Res, ResMut, Resource, SystemSet,
 };
 
+#[cfg(feature = "inspector")]
+use bevy::prelude::{Reflect, ReflectResource};
 use bevy_renet::{
-    transport::{NetcodeClientPlugin, NetcodeServerPlugin},
-    RenetClientPlugin, RenetServerPlugin,
+    client_just_connected, RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin,
 };
 
 use client::{
-    client_disconnects_from_server, client_initiates_connection_to_server, ConnectToServer,
-    DisconnectFromServer,
+    client_detects_unreachable_server, client_disconnects_from_server,
+    client_fails_over_to_next_server, client_flushes_outbox, client_initiates_connection_to_server,
+    client_receives_control_messages, client_receives_messages_from_server,
+    client_receives_unknown_channel_messages, client_reports_connection_progress,
+    client_reports_disconnection, client_sends_messages_to_server, client_signals_ready,
+    ActiveConnection, ClientConnectionProgress, ClientDeltaBaselines, ClientOutbox,
+    ClientSequences, ClientStopped, ConnectToServer, ConnectionDenied, ConnectionProgress,
+    DisconnectFromServer, DisconnectedFromServer, FailedOverTo, LastClientId, LatestFromServer,
+    PendingConnectToServer, QueuedFromServer, ReceiveFromServer, SendToServer,
+    ServerDisconnectReason, ServerLiveness, ServerReachable, ServerUnreachable,
 };
 
+use filter::{ConnectionFilter, ConnectionFiltered};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use server::{
-    cleanup_transport, server_starts, server_stops,
-    server_tracks_connected_and_disconnected_clients, ClientConnected, ClientDisconnected,
-    StartServer, StopServer,
+    cleanup_transport, server_broadcasts_messages_to_clients, server_disconnects_clients,
+    server_disconnects_denied_clients, server_expires_suspended_clients, server_flushes_outbox,
+    server_forgets_disconnected_clients, server_pauses_and_resumes_connections,
+    server_receives_control_messages, server_receives_messages_from_clients,
+    server_receives_unknown_channel_messages, server_replicates_sessions, server_replicates_teams,
+    server_sends_heartbeats, server_sends_messages_to_clients,
+    server_sends_messages_to_clients_where, server_sends_messages_to_team,
+    server_sets_client_bandwidth, server_sets_session_budgets, server_starts, server_stops,
+    server_tracks_connected_and_disconnected_clients, server_warms_up_clients,
+    AcceptingConnections, BannedPlayers, ClientBandwidth, ClientConnected, ClientConnectionLost,
+    ClientDenied, ClientDisconnected, ClientReconnected, ClientWarmUp, ConnectedClients,
+    DeniedClients, DisconnectClient, DisconnectingClients, DroppedStaleMessages, LatestFromClients,
+    PauseAcceptingConnections, PendingStartServer, PlayerReconnected, QueuedFromClients,
+    ReceiveBacklog, ReceiveFromClient, RedundantSends, ResumeAcceptingConnections,
+    SendDeduplication, SendToClient, SendToClients, SendToClientsWhere, SendToTeam,
+    ServerDeltaBaselines, ServerOutbox, ServerPassword, ServerSequences, ServerState,
+    ServerStopped, SessionBudgets, SessionResumption, SetClientBandwidth, SetSessionBudget,
+    StartServer, StopServer, SuspendedClients,
 };
+use sessions::{ClientSessionChanged, SessionStateChanged, Sessions};
+use socket2::SockRef;
+use teams::{ClientTeamChanged, Teams};
 
 pub use paste;
 pub use renet::{
     transport::NetcodeTransportError, RenetClient as Client, RenetServer as Server, SendType,
 };
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod audit;
+pub mod broadcast;
+#[cfg(feature = "chat")]
+pub mod chat;
 pub mod client;
+pub mod cluster;
+#[doc(hidden)]
+pub mod codec;
+#[cfg(feature = "config")]
+pub mod config;
+mod control;
+pub mod delivery;
+mod delta;
+pub mod error;
+pub mod filter;
+#[cfg(feature = "handoff")]
+pub mod handoff;
+pub mod handshake;
+pub mod health;
+#[cfg(feature = "inspector")]
+pub mod inspector;
+pub mod key_reload;
+pub mod keys;
+pub mod logging;
 pub mod macros;
+pub mod matchmaking;
+pub mod migration;
+pub mod mute;
+#[cfg(feature = "bincode-native")]
+pub mod native;
+pub mod negotiation;
+mod outbox;
+pub mod pool;
+pub mod quantize;
+pub mod ready_check;
+pub mod redirect;
+pub mod relay;
+pub mod resource;
+#[cfg(feature = "schema")]
+pub mod schema;
+mod sequence;
 pub mod server;
+pub mod sessions;
+pub mod shutdown;
+#[cfg(feature = "signals")]
+pub mod signals;
+pub mod streams;
+pub mod teams;
+pub mod telemetry;
+pub mod tick;
+pub mod transport;
+mod user_data;
+#[cfg(feature = "voice")]
+pub mod voice;
+pub mod watchdog;
+
+/// Channel reserved for the crate's own control messages, user types use the channels below it.
+pub(crate) const CONTROL_CHANNEL_ID: u8 = u8::MAX;
+
+/// Channel reserved for the admin console, kept even without the `admin` feature so enabling it
+/// doesn't change which channels user types get.
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub(crate) const ADMIN_CHANNEL_ID: u8 = u8::MAX - 1;
+
+/// Channel reserved for the `chat` feature, kept without it for the same reason.
+#[cfg_attr(not(feature = "chat"), allow(dead_code))]
+pub(crate) const CHAT_CHANNEL_ID: u8 = u8::MAX - 2;
+
+/// Channel reserved for the `voice` feature, kept without it for the same reason.
+#[cfg_attr(not(feature = "voice"), allow(dead_code))]
+pub(crate) const VOICE_CHANNEL_ID: u8 = u8::MAX - 3;
+
+/// Unreliable channel carrying the server tick.
+pub(crate) const TICK_CHANNEL_ID: u8 = u8::MAX - 4;
+
+/// Channel carrying client to client messages relayed by the server.
+pub(crate) const RELAY_CHANNEL_ID: u8 = u8::MAX - 5;
+
+/// Channel carrying the messages of clients negotiating their connection.
+pub(crate) const NEGOTIATION_CHANNEL_ID: u8 = u8::MAX - 6;
+
+/// User types get the channels below this one.
+pub(crate) const LOWEST_RESERVED_CHANNEL_ID: u8 = NEGOTIATION_CHANNEL_ID;
 
 ///
 /// Converts a string to a key that can be used for Authenticated connections.
 /// Key is 32 bytes long, truncating and padding occurs otherwise.
 ///
+/// Insecure, only meant for tests: the key is as guessable as the string and anything past 32
+/// bytes is ignored. Use `keys::generate_key` or `keys::derive_key` instead.
+///
+#[deprecated(note = "insecure, use `keys::generate_key` or `keys::derive_key` instead")]
 pub fn string_to_key<K: Into<String>>(string: K) -> [u8; 32] {
     let mut key: [u8; 32] = [0; 32];
     string
@@ -46,48 +167,412 @@ pub fn string_to_key<K: Into<String>>(string: K) -> [u8; 32] {
     key
 }
 
+///
+/// Registers the events and systems needed to send type `T` over channel `I`.
+/// Used by the `client_server_events_plugin` macro.
+///
+pub fn add_network_event<const I: u8, T: Event + Serialize + DeserializeOwned>(app: &mut App) {
+    app.world_mut()
+        .get_resource_or_insert_with(ChannelRegistry::default)
+        .register::<I, T>();
+    add_network_event_systems::<I, T>(app);
+}
+
+///
+/// Registers type `T` on channel `I` with its own `NetworkConfig`, so that plugins (e.g. from
+/// other crates) can register their types next to the ones of `client_server_events_plugin!`.
+/// The plugins may be added before or after `ClientServerEventsPlugin`, the channel layout is
+/// completed (and checked for conflicts) once every plugin is built. See also
+/// `register_network_events!`.
+///
+/// Panics if the channel is already used or reserved, or if `T` is already registered.
+///
+pub fn register_network_event<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    app: &mut App,
+    config: NetworkConfig,
+) {
+    app.world_mut()
+        .get_resource_or_insert_with(ChannelRegistry::default)
+        .register_with::<I, T>(Some(config));
+    add_network_event_systems::<I, T>(app);
+}
+
+fn add_network_event_systems<const I: u8, T: Event + Serialize + DeserializeOwned>(app: &mut App) {
+    app.add_event::<SendToClient<T>>()
+        .add_event::<SendToClients<T>>()
+        .add_event::<SendToClientsWhere<T>>()
+        .add_event::<SendToTeam<T>>()
+        .add_event::<ReceiveFromClient<T>>()
+        .init_resource::<LatestFromClients<T>>()
+        .init_resource::<QueuedFromClients<T>>()
+        .add_event::<SendToServer<T>>()
+        .add_event::<ReceiveFromServer<T>>()
+        .init_resource::<LatestFromServer<T>>()
+        .init_resource::<QueuedFromServer<T>>()
+        .add_systems(
+            PostUpdate,
+            (
+                server_sends_messages_to_clients::<I, T>,
+                server_broadcasts_messages_to_clients::<I, T>,
+                server_sends_messages_to_clients_where::<I, T>,
+                server_sends_messages_to_team::<I, T>,
+            )
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            server_receives_messages_from_clients::<I, T>.run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            client_sends_messages_to_server::<I, T>
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(
+            PostUpdate,
+            client_receives_messages_from_server::<I, T>.run_if(resource_exists::<RenetClient>),
+        );
+    relay::add_relay_event::<I, T>(app);
+    delivery::add_delivery_event::<I, T>(app);
+    broadcast::add_scheduled_broadcasts::<T>(app);
+}
+
+///
+/// Where outgoing messages get queued (`Send`) and handed over to renet (`Flush`) in `PostUpdate`.
+/// Systems sending network events in `PostUpdate` should run before `NetworkSet::Send`.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
+pub enum NetworkSet {
+    Send,
+    Flush,
+}
+
 pub struct ClientServerEventsPlugin {
     pub channels_config: NetworkConfigs,
 }
 
+impl ClientServerEventsPlugin {
+    ///
+    /// Completes the channel layout with the types registered by `register_network_event`, and
+    /// gives every registered type its config. Run when the plugin is built and again once every
+    /// plugin is built, for types registered by plugins added later.
+    ///
+    fn merge_registered_channels(&self, app: &mut App) {
+        let world = app.world_mut();
+        let Some(mut channel_registry) = world.remove_resource::<ChannelRegistry>() else {
+            return;
+        };
+        let mut network_configs = world.resource_mut::<NetworkConfigs>();
+        let ChannelRegistry {
+            channels,
+            configured,
+        } = &mut channel_registry;
+        for (channel_id, registered) in channels.iter_mut() {
+            let index = *channel_id as usize;
+            if configured.contains(channel_id) {
+                if index < self.channels_config.0.len() {
+                    panic!(
+                        "{} is registered on channel {channel_id} with its own NetworkConfig, \
+                         but ClientServerEventsPlugin::channels_config also configures it",
+                        registered.type_name
+                    );
+                }
+                if network_configs.0.len() <= index {
+                    network_configs
+                        .0
+                        .resize_with(index + 1, NetworkConfig::default);
+                }
+                network_configs.0[index] = registered.config.clone();
+            } else {
+                if index >= self.channels_config.0.len() {
+                    panic!(
+                        "{} is registered on channel {channel_id} but only {} NetworkConfigs exist",
+                        registered.type_name,
+                        self.channels_config.0.len()
+                    );
+                }
+                registered.config = network_configs.0[index].clone();
+            }
+            if registered.config.delta && !registered.config.uses_delta() {
+                warn!(
+                    "{} enables `delta` but isn't sent with SendType::ReliableOrdered, \
+                     its messages are sent in full",
+                    registered.type_name
+                );
+            }
+        }
+        world.insert_resource(channel_registry);
+    }
+}
+
 impl Plugin for ClientServerEventsPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(self.channels_config.clone())
+        app.insert_resource(self.channels_config.clone());
+        self.merge_registered_channels(app);
+        app.init_resource::<ConnectedClients>()
+            .init_resource::<AcceptingConnections>()
+            .init_resource::<DeterministicOrdering>()
+            .init_resource::<SendDeduplication>()
+            .init_resource::<RedundantSends>()
+            .init_resource::<DeniedClients>()
+            .init_resource::<BannedPlayers>()
+            .init_resource::<DisconnectingClients>()
+            .init_resource::<ServerState>()
+            .init_resource::<ServerPassword>()
+            .init_resource::<PendingStartServer>()
+            .init_resource::<ClientWarmUp>()
+            .init_resource::<SessionResumption>()
+            .init_resource::<SuspendedClients>()
+            .init_resource::<LastClientId>()
+            .init_resource::<PendingConnectToServer>()
+            .init_resource::<ActiveConnection>()
+            .init_resource::<ClientConnectionProgress>()
+            .init_resource::<ServerDisconnectReason>()
+            .init_resource::<ConnectionFilter>()
+            .init_resource::<ServerOutbox>()
+            .init_resource::<ClientBandwidth>()
+            .init_resource::<SessionBudgets>()
+            .init_resource::<DroppedStaleMessages>()
+            .init_resource::<ReceiveBacklog>()
+            .init_resource::<ServerDeltaBaselines>()
+            .init_resource::<ClientDeltaBaselines>()
+            .init_resource::<ServerSequences>()
+            .init_resource::<ClientSequences>()
+            .init_resource::<ClientOutbox>()
+            .init_resource::<ServerLiveness>()
+            .init_resource::<Teams>()
+            .init_resource::<Sessions>()
+            .init_resource::<ChannelRegistry>()
+            // The netcode transports are updated by the `transport` module's systems.
             .add_plugins(RenetServerPlugin)
-            .add_plugins(NetcodeServerPlugin)
             .add_plugins(RenetClientPlugin)
-            .add_plugins(NetcodeClientPlugin)
             .add_event::<StartServer>()
             .add_event::<StopServer>()
+            .add_event::<ServerStopped>()
             .add_event::<ClientConnected>()
             .add_event::<ClientDisconnected>()
+            .add_event::<DisconnectClient>()
+            .add_event::<ClientConnectionLost>()
+            .add_event::<ClientReconnected>()
+            .add_event::<PlayerReconnected>()
             .add_event::<ConnectToServer>()
             .add_event::<DisconnectFromServer>()
+            .add_event::<ClientStopped>()
+            .add_event::<SwitchRole>()
+            .add_event::<ClientTeamChanged>()
+            .add_event::<SessionStateChanged>()
+            .add_event::<ClientSessionChanged>()
+            .add_event::<PauseAcceptingConnections>()
+            .add_event::<ResumeAcceptingConnections>()
+            .add_event::<ConnectionDenied>()
+            .add_event::<ConnectionFiltered>()
+            .add_event::<ClientDenied>()
+            .add_event::<SetClientBandwidth>()
+            .add_event::<SetSessionBudget>()
+            .add_event::<MessageDecodeError>()
+            .add_event::<UnknownChannelMessage>()
+            .add_event::<ServerUnreachable>()
+            .add_event::<ServerReachable>()
+            .add_event::<FailedOverTo>()
+            .add_event::<ConnectionProgress>()
+            .add_event::<DisconnectedFromServer>()
+            .configure_sets(
+                PostUpdate,
+                (NetworkSet::Send, NetworkSet::Flush)
+                    .chain()
+                    .before(RenetSend),
+            )
             .add_systems(
                 PreUpdate,
-                cleanup_transport.run_if(resource_removed::<renet::RenetServer>()),
+                cleanup_transport.run_if(ServerState::is(ServerState::Stopping)),
+            )
+            .add_systems(
+                PreUpdate,
+                (
+                    server_tracks_connected_and_disconnected_clients,
+                    server_expires_suspended_clients,
+                    server_forgets_disconnected_clients,
+                    // Warm-ups start before the clients' ready signals are received.
+                    server_warms_up_clients,
+                    server_receives_control_messages,
+                )
+                    .chain()
+                    .after(RenetReceive)
+                    .run_if(resource_exists::<RenetServer>),
             )
             .add_systems(
                 PostUpdate,
-                server_starts.run_if(not(resource_exists::<RenetServer>)),
+                switches_role
+                    .before(server_starts)
+                    .before(server_stops)
+                    .before(client_initiates_connection_to_server)
+                    .before(client_disconnects_from_server),
             )
+            .add_systems(PostUpdate, server_starts)
             .add_systems(
                 PostUpdate,
                 server_stops.run_if(resource_exists::<RenetServer>),
             )
+            .add_systems(PostUpdate, server_pauses_and_resumes_connections)
+            .add_systems(PostUpdate, server_sets_client_bandwidth)
+            .add_systems(PostUpdate, server_sets_session_budgets)
             .add_systems(
                 PostUpdate,
-                server_tracks_connected_and_disconnected_clients
+                server_flushes_outbox
+                    .in_set(NetworkSet::Flush)
                     .run_if(resource_exists::<RenetServer>),
             )
             .add_systems(
                 PostUpdate,
-                client_initiates_connection_to_server.run_if(not(resource_exists::<RenetClient>)),
+                (
+                    server_disconnects_denied_clients,
+                    server_disconnects_clients,
+                )
+                    .run_if(resource_exists::<RenetServer>),
+            )
+            .add_systems(
+                PostUpdate,
+                server_sends_heartbeats.run_if(resource_exists::<RenetServer>),
+            )
+            .add_systems(
+                PostUpdate,
+                (server_replicates_teams, server_replicates_sessions)
+                    .in_set(NetworkSet::Send)
+                    .run_if(resource_exists::<RenetServer>),
+            )
+            .add_systems(PostUpdate, client_initiates_connection_to_server)
+            .add_systems(
+                PostUpdate,
+                (
+                    client_reports_connection_progress,
+                    client_reports_disconnection,
+                )
+                    .chain()
+                    .after(transport::client_sends_packets)
+                    .run_if(resource_exists::<NetcodeClientTransport>),
+            )
+            .add_systems(
+                PostUpdate,
+                client_fails_over_to_next_server
+                    .before(client_initiates_connection_to_server)
+                    .run_if(resource_exists::<NetcodeClientTransport>),
             )
             .add_systems(
                 PostUpdate,
                 client_disconnects_from_server.run_if(resource_exists::<RenetClient>),
+            )
+            .add_systems(
+                PostUpdate,
+                server_receives_unknown_channel_messages.run_if(resource_exists::<RenetServer>),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    client_receives_control_messages,
+                    client_detects_unreachable_server,
+                )
+                    .chain()
+                    .run_if(resource_exists::<RenetClient>),
+            )
+            .add_systems(
+                PostUpdate,
+                client_signals_ready
+                    .in_set(NetworkSet::Send)
+                    .run_if(client_just_connected),
+            )
+            .add_systems(
+                PostUpdate,
+                client_receives_unknown_channel_messages.run_if(resource_exists::<RenetClient>),
+            )
+            .add_systems(
+                PostUpdate,
+                client_flushes_outbox
+                    .in_set(NetworkSet::Flush)
+                    .run_if(resource_exists::<RenetClient>),
             );
+        audit::build(app);
+        cluster::build(app);
+        delivery::build(app);
+        error::build(app);
+        handshake::build(app);
+        health::build(app);
+        key_reload::build(app);
+        logging::build(app);
+        matchmaking::build(app);
+        migration::build(app);
+        mute::build(app);
+        negotiation::build(app);
+        pool::build(app);
+        ready_check::build(app);
+        redirect::build(app);
+        relay::build(app);
+        shutdown::build(app);
+        streams::build(app);
+        telemetry::build(app);
+        tick::build(app);
+        transport::build(app);
+        watchdog::build(app);
+        #[cfg(feature = "admin")]
+        admin::build(app);
+        #[cfg(feature = "chat")]
+        chat::build(app);
+        #[cfg(feature = "handoff")]
+        handoff::build(app);
+        #[cfg(feature = "inspector")]
+        inspector::build(app);
+        #[cfg(feature = "signals")]
+        signals::build(app);
+        #[cfg(feature = "voice")]
+        voice::build(app);
+    }
+
+    fn finish(&self, app: &mut App) {
+        self.merge_registered_channels(app);
+    }
+}
+
+///
+/// Switches this app between the client and server roles (e.g. from a "host or join" menu),
+/// tearing down the current role before starting the new one.
+///
+#[derive(Debug, Event)]
+pub enum SwitchRole {
+    /// Disconnects from the server if connected, then starts (or restarts) the server.
+    ToServer(Box<StartServer>),
+    /// Stops the server if running, then connects (or reconnects) to the server.
+    ToClient(Box<ConnectToServer>),
+}
+
+pub fn switches_role(
+    mut switch_role_events: ResMut<Events<SwitchRole>>,
+    client: Option<Res<RenetClient>>,
+    server_state: Res<ServerState>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+    mut stop_server_events: EventWriter<StopServer>,
+    mut start_server_events: EventWriter<StartServer>,
+    mut connect_to_server_events: EventWriter<ConnectToServer>,
+) {
+    // Only the last request matters, the teardowns below make switching idempotent.
+    let Some(switch_role) = switch_role_events.drain().last() else {
+        return;
+    };
+    if client.is_some() {
+        disconnect_from_server_events.send(DisconnectFromServer);
+    }
+    if *server_state == ServerState::Running {
+        stop_server_events.send(StopServer);
+    }
+    // The start events wait for the teardowns to complete, see `PendingStartServer` and
+    // `PendingConnectToServer`.
+    match switch_role {
+        SwitchRole::ToServer(start_server) => {
+            start_server_events.send(*start_server);
+        },
+        SwitchRole::ToClient(connect_to_server) => {
+            connect_to_server_events.send(*connect_to_server);
+        },
     }
 }
 
@@ -100,24 +585,303 @@ impl Default for NetworkConfigs {
     }
 }
 
+///
+/// Sorts the messages received during a tick before they are emitted, so that deterministic
+/// simulations and replays see them in the same order regardless of network jitter.
+/// Messages are ordered by sender (client id on the server and for relayed messages), then by
+/// send time for types with timestamps, keeping their arrival order otherwise.
+/// Disabled by default, messages are emitted in the order renet hands them over.
+///
+#[derive(Debug, Default, Resource)]
+pub struct DeterministicOrdering(pub bool);
+
+impl NetworkConfigs {
+    /// Priority of a channel, see `NetworkConfig::priority`.
+    pub(crate) fn priority(&self, channel_id: u8) -> u8 {
+        self.0
+            .get(channel_id as usize)
+            .map_or(0, |config| config.priority)
+    }
+
+    /// Whether a channel only gets the spare budget, see `NetworkConfig::background`.
+    pub(crate) fn is_background(&self, channel_id: u8) -> bool {
+        self.0
+            .get(channel_id as usize)
+            .is_some_and(|config| config.background)
+    }
+}
+
+///
+/// Renet fills packets from the channels in the order they are configured, so the control and
+/// tick channels go first, followed by the user channels from highest to lowest priority.
+/// Background channels go last, after the reserved ones, so they only get the bytes left over.
+///
 impl From<NetworkConfigs> for Vec<renet::ChannelConfig> {
     fn from(val: NetworkConfigs) -> Self {
-        let mut renet_configs = Vec::new();
-        for i in 0..val.0.len().min(u8::MAX as usize) {
-            renet_configs.push(renet::ChannelConfig {
-                channel_id: i as u8,
-                max_memory_usage_bytes: val.0[i].max_memory_usage_bytes,
-                send_type: val.0[i].send_type.clone(),
-            });
-        }
+        let reliable = SendType::ReliableOrdered {
+            resend_time: Duration::from_millis(300),
+        };
+        let mut renet_configs = vec![
+            reserved_channel_config(CONTROL_CHANNEL_ID, reliable.clone()),
+            reserved_channel_config(TICK_CHANNEL_ID, SendType::Unreliable),
+            reserved_channel_config(RELAY_CHANNEL_ID, reliable.clone()),
+            reserved_channel_config(NEGOTIATION_CHANNEL_ID, reliable.clone()),
+        ];
+        let mut channel_ids: Vec<u8> =
+            (0..val.0.len().min(LOWEST_RESERVED_CHANNEL_ID as usize) as u8).collect();
+        channel_ids.sort_by_key(|channel_id| std::cmp::Reverse(val.priority(*channel_id)));
+        let (background_ids, channel_ids): (Vec<u8>, Vec<u8>) = channel_ids
+            .into_iter()
+            .partition(|channel_id| val.is_background(*channel_id));
+        let user_channel_config = |channel_id: u8| {
+            let config = &val.0[channel_id as usize];
+            renet::ChannelConfig {
+                channel_id,
+                max_memory_usage_bytes: config.max_memory_usage_bytes,
+                send_type: config.send_type.clone(),
+            }
+        };
+        renet_configs.extend(channel_ids.into_iter().map(user_channel_config));
+        #[cfg(feature = "admin")]
+        renet_configs.push(reserved_channel_config(ADMIN_CHANNEL_ID, reliable.clone()));
+        #[cfg(feature = "chat")]
+        renet_configs.push(reserved_channel_config(CHAT_CHANNEL_ID, reliable));
+        // Sequenced by the voice module, renet has no sequenced channels.
+        #[cfg(feature = "voice")]
+        renet_configs.push(reserved_channel_config(
+            VOICE_CHANNEL_ID,
+            SendType::Unreliable,
+        ));
+        renet_configs.extend(background_ids.into_iter().map(user_channel_config));
         renet_configs
     }
 }
 
-#[derive(Clone)]
+fn reserved_channel_config(channel_id: u8, send_type: SendType) -> renet::ChannelConfig {
+    renet::ChannelConfig {
+        channel_id,
+        max_memory_usage_bytes: 1024 * 1024,
+        send_type,
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct NetworkConfig {
     pub send_type: SendType,
     pub max_memory_usage_bytes: usize,
+    /// Whether the server accepts this type from spectators. Disabled by default so that
+    /// spectators can't send gameplay input, enable it for things like chat.
+    pub accept_from_spectators: bool,
+    /// How long an unreliable message may wait in the server's outbox (see `SetClientBandwidth`)
+    /// before being dropped as stale. Ignored for reliable send types.
+    pub ttl: Option<Duration>,
+    /// Stamps every message with the sender's wall-clock time, surfaced as `sent_at` on the
+    /// receive events. Both ends must agree on this setting.
+    pub timestamps: bool,
+    /// Sends each message as a binary diff against the previous one on this channel, which
+    /// drastically cuts bandwidth for snapshot-style state that is re-sent periodically.
+    /// Only applies to `SendType::ReliableOrdered`, the only delivery guaranteeing that the
+    /// receiver holds the previous message, other send types ignore it with a warning. Both ends
+    /// must agree on this setting.
+    pub delta: bool,
+    /// Maximum number of messages per second sent for this type (per client on the server).
+    /// Extra messages sent in between are coalesced, only the latest one goes out.
+    pub max_send_rate: Option<f32>,
+    /// Upper bound on the memory decoding a single message may claim, protecting against
+    /// crafted length prefixes. Defaults to `max_memory_usage_bytes` when unset.
+    pub max_decode_bytes: Option<usize>,
+    /// Drops messages older than the latest one received on this type, so that out of order
+    /// state updates never overwrite newer ones. Renet has no sequenced channels, this stamps
+    /// messages with a sequence number per peer and filters them on reception.
+    /// Only applies to `SendType::Unreliable`, both ends must agree on this setting.
+    pub sequenced: bool,
+    /// Stamps every message with an id and drops the ones whose id is among the last
+    /// `dedup_window` received from the same sender on this type, so that messages delivered
+    /// twice are only applied once. Only applies to `SendType::Unreliable`, both ends must agree
+    /// on this setting.
+    pub dedup_window: Option<usize>,
+    /// Resends every message until the receiver acknowledges it, giving up once `deadline` passed
+    /// with a `DeliveryAbandoned<T>` event on the sender (see `delivery`). For messages that
+    /// matter but are worthless once late, like emotes. Only applies to `SendType::Unreliable`,
+    /// implies a `dedup_window` (`delivery::DEADLINE_DEDUP_WINDOW` unless set), both ends must
+    /// agree on this setting.
+    pub deadline: Option<Duration>,
+    /// Numbers the messages so that a resumed session (see `SessionResumption`) carries on where
+    /// the connection dropped: messages the receiver didn't acknowledge are resent, and the ones
+    /// it already delivered are skipped (see `streams`). Only applies to
+    /// `SendType::ReliableOrdered` without `delta`, both ends must agree on this setting.
+    pub resumable: bool,
+    /// Types with a higher priority are sent first when the per-tick byte budget (or the
+    /// client's `SetClientBandwidth` limit) can't fit everything, so critical types (inputs,
+    /// acks) aren't starved by bulk transfers. Types of equal priority keep their send order.
+    pub priority: u8,
+    /// Only sends this type with the bytes left over once every other type was sent, for big
+    /// non-urgent transfers (texture packs, replays) that must never add latency to gameplay.
+    /// Its messages wait in the outbox while other types are held back by a bandwidth limit, and
+    /// while renet's buffer for the channel is full, instead of being dropped.
+    pub background: bool,
+    /// Keeps only the newest message from each client per tick in the `LatestFromClients`
+    /// resource (and the newest message from the server in `LatestFromServer` on clients)
+    /// instead of sending an event for every message, which cuts the event volume of
+    /// high-frequency streams. The resources are reused every tick, unlike the event buffers.
+    pub latest_only: bool,
+    /// Keeps the received messages in the `QueuedFromClients` / `QueuedFromServer` resource until
+    /// they are drained instead of sending events, which bevy drops after two frames even if no
+    /// system read them (e.g. while the reader is gated by a state). Ignored with `latest_only`.
+    pub queued: bool,
+    /// Maximum number of messages of this type the server processes per tick, the rest are
+    /// carried over to the next ticks (see `ReceiveBacklog`), so that a burst (e.g. after a
+    /// hitch) is spread over several frames instead of causing a spike. Unlimited when unset.
+    pub receive_budget: Option<usize>,
+    /// Maximum number of messages of this type the server drains from each client per tick, the
+    /// rest wait in renet's buffers (counting towards `max_memory_usage_bytes`) until the next
+    /// tick, so that a spamming client doesn't delay the others. Clients are drained in turns,
+    /// one message each, whether it is set or not. Unlimited when unset.
+    pub receive_per_client: Option<usize>,
+    /// How the messages are laid out by bincode, for peers that don't use this crate's defaults
+    /// (e.g. written in another language, or with bincode 1). Both ends must agree on it.
+    pub wire_format: WireFormat,
+    /// Sends the messages as JSON objects tagged with the type name instead of bincode, so that
+    /// external tools (test bots, dashboards) can read and write them without knowing bincode's
+    /// layout. Much bigger on the wire, meant for the few types such tools use. Both ends must
+    /// agree on this setting, `delta` still applies on top of it.
+    #[cfg(feature = "json")]
+    pub json: bool,
+}
+
+///
+/// The bincode settings of a type's messages.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireFormat {
+    pub int_encoding: IntEncoding,
+    pub byte_order: ByteOrder,
+}
+
+impl WireFormat {
+    /// Fixed size little endian integers, like bincode 1.
+    pub fn legacy() -> Self {
+        Self {
+            int_encoding: IntEncoding::Fixed,
+            byte_order: ByteOrder::Little,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IntEncoding {
+    /// Small values take fewer bytes, see bincode's variable int encoding.
+    #[default]
+    Variable,
+    /// Every integer takes the size of its type.
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+impl NetworkConfig {
+    /// Reliable and ordered delivery, the default.
+    pub fn reliable_ordered() -> Self {
+        Self::default()
+    }
+
+    /// Reliable delivery in any order, for independent messages that must arrive.
+    pub fn reliable_unordered() -> Self {
+        Self {
+            send_type: SendType::ReliableUnordered {
+                resend_time: Duration::from_millis(300),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Fire and forget delivery, for messages that are quickly superseded.
+    pub fn unreliable() -> Self {
+        Self {
+            send_type: SendType::Unreliable,
+            ..Default::default()
+        }
+    }
+
+    /// Unreliable delivery where late messages are dropped, for continuously updated state
+    /// (positions, inputs), see `sequenced`.
+    pub fn unreliable_sequenced() -> Self {
+        Self {
+            sequenced: true,
+            ..Self::unreliable()
+        }
+    }
+
+    pub(crate) fn decode_limit(&self) -> usize {
+        self.max_decode_bytes.unwrap_or(self.max_memory_usage_bytes)
+    }
+
+    /// Minimum time between two messages of this type, if its send rate is limited.
+    pub(crate) fn send_interval(&self) -> Option<Duration> {
+        self.max_send_rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f32(1.0 / rate))
+    }
+
+    /// Whether delta encoding applies to this config's send type.
+    pub(crate) fn uses_delta(&self) -> bool {
+        self.delta && matches!(self.send_type, SendType::ReliableOrdered { .. })
+    }
+
+    /// Whether late messages are dropped, see `sequenced`.
+    pub(crate) fn uses_sequencing(&self) -> bool {
+        self.sequenced && matches!(self.send_type, SendType::Unreliable)
+    }
+
+    /// The number of message ids remembered, if duplicates are dropped, see `dedup_window`.
+    pub(crate) fn dedup_window(&self) -> Option<usize> {
+        match self.send_type {
+            SendType::Unreliable => self
+                .dedup_window
+                .filter(|window| *window > 0)
+                .or(self.deadline.map(|_| delivery::DEADLINE_DEDUP_WINDOW)),
+            _ => None,
+        }
+    }
+
+    /// Whether messages are numbered to resume their stream, see `resumable`.
+    pub(crate) fn uses_resumption(&self) -> bool {
+        self.resumable && !self.delta && matches!(self.send_type, SendType::ReliableOrdered { .. })
+    }
+
+    /// How long messages are resent until given up, see `deadline`.
+    pub(crate) fn delivery_deadline(&self) -> Option<Duration> {
+        match self.send_type {
+            SendType::Unreliable => self.deadline,
+            _ => None,
+        }
+    }
+
+    /// Whether messages are sent as JSON, see `json`.
+    pub(crate) fn uses_json(&self) -> bool {
+        #[cfg(feature = "json")]
+        return self.json;
+        #[cfg(not(feature = "json"))]
+        return false;
+    }
+
+    /// Whether messages carry their send time, see `timestamps`.
+    pub(crate) fn uses_timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    /// The ttl, if it applies to this config's send type.
+    pub(crate) fn unreliable_ttl(&self) -> Option<Duration> {
+        match self.send_type {
+            SendType::Unreliable => self.ttl,
+            _ => None,
+        }
+    }
 }
 
 impl Default for NetworkConfig {
@@ -127,6 +891,488 @@ impl Default for NetworkConfig {
             send_type: SendType::ReliableOrdered {
                 resend_time: Duration::from_millis(300),
             },
+            accept_from_spectators: false,
+            ttl: None,
+            timestamps: false,
+            delta: false,
+            max_send_rate: None,
+            max_decode_bytes: None,
+            sequenced: false,
+            dedup_window: None,
+            deadline: None,
+            resumable: false,
+            priority: 0,
+            background: false,
+            latest_only: false,
+            queued: false,
+            receive_budget: None,
+            receive_per_client: None,
+            wire_format: WireFormat::default(),
+            #[cfg(feature = "json")]
+            json: false,
+        }
+    }
+}
+
+///
+/// OS-level options applied to the UDP socket before the transport takes over.
+/// Unset options keep the operating system defaults. The transport always switches the socket to
+/// non-blocking mode.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// `SO_SNDBUF`, in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF`, in bytes. Raising it helps busy servers absorb bursts without dropping packets.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub(crate) fn apply(&self, socket: &UdpSocket) -> std::io::Result<()> {
+        let socket = SockRef::from(socket);
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
         }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Identifies a client of the server, the same on the server and on the client itself (see
+/// `LocalClientId`).
+///
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+pub struct ClientId(pub u64);
+
+impl ClientId {
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<u64> for ClientId {
+    fn from(client_id: u64) -> Self {
+        Self(client_id)
+    }
+}
+
+impl From<ClientId> for u64 {
+    fn from(client_id: ClientId) -> Self {
+        client_id.0
+    }
+}
+
+impl From<renet::ClientId> for ClientId {
+    fn from(client_id: renet::ClientId) -> Self {
+        Self(client_id.raw())
     }
 }
+
+impl From<ClientId> for renet::ClientId {
+    fn from(client_id: ClientId) -> Self {
+        Self::from_raw(client_id.0)
+    }
+}
+
+///
+/// Persistent identity of a player (e.g. an account UUID), handed to the server in the client's
+/// user data (see `ConnectToServer::player_id`) unlike the `ClientId` that changes between
+/// connections. Read it on the server with `ConnectedClients::player_id`.
+/// Converts from and to `u128`, as returned by `uuid::Uuid::as_u128` and taken by
+/// `uuid::Uuid::from_u128`.
+///
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+pub struct PlayerId(pub u128);
+
+impl PlayerId {
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(u128::from_be_bytes(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+}
+
+/// Formatted as a hyphenated UUID.
+impl std::fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            self.0 >> 96,
+            (self.0 >> 80) & 0xffff,
+            (self.0 >> 64) & 0xffff,
+            (self.0 >> 48) & 0xffff,
+            self.0 & 0xffff_ffff_ffff
+        )
+    }
+}
+
+impl From<u128> for PlayerId {
+    fn from(player_id: u128) -> Self {
+        Self(player_id)
+    }
+}
+
+impl From<PlayerId> for u128 {
+    fn from(player_id: PlayerId) -> Self {
+        player_id.0
+    }
+}
+
+///
+/// How a client takes part in the game, chosen when connecting to the server.
+/// Spectators still receive everything the server sends, but the server drops the types they
+/// send unless `NetworkConfig::accept_from_spectators` is set.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+pub enum ConnectionMode {
+    #[default]
+    Player,
+    Spectator,
+}
+
+///
+/// Why the server refused a client that had already reached it.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DenialReason {
+    /// The server is not accepting new connections at the moment.
+    ServerPaused,
+    /// The client's address was refused by the server's `ConnectionFilter`.
+    Filtered,
+    /// The client registered a different number of network types than the server.
+    ChannelMismatch,
+    /// The client's `ConnectToServer::password` doesn't match the server's.
+    WrongPassword,
+    /// The client's player is in the server's `BannedPlayers`.
+    Banned,
+    /// Another client with the same `ConnectToServer::player_id` is connected.
+    PlayerAlreadyConnected,
+    /// The server refused the client's negotiation, or it took too long, see `Negotiation`.
+    NegotiationFailed,
+}
+
+///
+/// Why a connection ended, on either end. Its `Display` is meant to be shown to players as is.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    DisconnectedByClient,
+    /// The server ended the connection without giving a more specific reason.
+    DisconnectedByServer,
+    /// The server moved the client to another server, see `RedirectClient`.
+    Redirected,
+    /// The server is shutting down, see `GracefulShutdown`.
+    ServerShutdown,
+    /// See `DisconnectClient`.
+    Kicked,
+    /// See `DisconnectClient`, also received by players in the server's `BannedPlayers` when they
+    /// connect. Clients without a player id are kept out with the `ConnectionFilter`.
+    Banned,
+    /// The server had no free slot when the client tried to connect.
+    ServerFull,
+    /// The client and server registered different network types.
+    ProtocolMismatch,
+    /// The server refused the client, see `DenialReason`.
+    Denied(DenialReason),
+    /// The server never answered the connection attempt.
+    ServerUnreachable,
+    /// The connect token expired before the connection was established.
+    ConnectTokenExpired,
+    /// Nothing was received from the other end for too long.
+    TimedOut,
+    /// The transport closed the connection.
+    Transport,
+    /// A message couldn't be sent or received on the channel (e.g. its memory limit was reached).
+    ChannelError {
+        channel_id: u8,
+    },
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DisconnectedByClient => write!(f, "Disconnected by the client"),
+            Self::DisconnectedByServer => write!(f, "Disconnected by the server"),
+            Self::ServerShutdown => write!(f, "The server shut down"),
+            Self::Redirected => write!(f, "Moved to another server"),
+            Self::Kicked => write!(f, "Kicked from the server"),
+            Self::Banned => write!(f, "Banned from the server"),
+            Self::ServerFull => write!(f, "The server is full"),
+            Self::ProtocolMismatch => write!(f, "The client and server versions don't match"),
+            Self::Denied(DenialReason::ServerPaused) => {
+                write!(f, "The server is not accepting new players")
+            },
+            Self::Denied(DenialReason::Filtered) => {
+                write!(f, "Connections from your address are refused")
+            },
+            Self::Denied(DenialReason::ChannelMismatch) => {
+                write!(f, "The client and server versions don't match")
+            },
+            Self::Denied(DenialReason::WrongPassword) => write!(f, "Wrong password"),
+            Self::Denied(DenialReason::Banned) => write!(f, "Banned from the server"),
+            Self::Denied(DenialReason::PlayerAlreadyConnected) => {
+                write!(f, "Already connected from another client")
+            },
+            Self::Denied(DenialReason::NegotiationFailed) => {
+                write!(f, "The server refused the connection")
+            },
+            Self::ServerUnreachable => write!(f, "The server could not be reached"),
+            Self::ConnectTokenExpired => write!(f, "The connection attempt expired"),
+            Self::TimedOut => write!(f, "Connection timed out"),
+            Self::Transport => write!(f, "Connection closed"),
+            Self::ChannelError { channel_id } => {
+                write!(f, "Network error on channel {}", channel_id)
+            },
+        }
+    }
+}
+
+impl From<DenialReason> for DisconnectReason {
+    fn from(reason: DenialReason) -> Self {
+        match reason {
+            DenialReason::ChannelMismatch => Self::ProtocolMismatch,
+            DenialReason::Banned => Self::Banned,
+            reason => Self::Denied(reason),
+        }
+    }
+}
+
+impl From<renet::DisconnectReason> for DisconnectReason {
+    fn from(reason: renet::DisconnectReason) -> Self {
+        match reason {
+            renet::DisconnectReason::Transport => Self::Transport,
+            renet::DisconnectReason::DisconnectedByClient => Self::DisconnectedByClient,
+            renet::DisconnectReason::DisconnectedByServer => Self::DisconnectedByServer,
+            renet::DisconnectReason::SendChannelError { channel_id, .. }
+            | renet::DisconnectReason::ReceiveChannelError { channel_id, .. }
+            | renet::DisconnectReason::ReceivedInvalidChannelId(channel_id) => {
+                Self::ChannelError { channel_id }
+            },
+        }
+    }
+}
+
+impl From<NetcodeDisconnectReason> for DisconnectReason {
+    fn from(reason: NetcodeDisconnectReason) -> Self {
+        match reason {
+            NetcodeDisconnectReason::ConnectTokenExpired => Self::ConnectTokenExpired,
+            NetcodeDisconnectReason::ConnectionTimedOut => Self::TimedOut,
+            NetcodeDisconnectReason::ConnectionRequestTimedOut
+            | NetcodeDisconnectReason::ConnectionResponseTimedOut => Self::ServerUnreachable,
+            // Netcode only denies connections when the server has no free slot.
+            NetcodeDisconnectReason::ConnectionDenied => Self::ServerFull,
+            NetcodeDisconnectReason::DisconnectedByClient => Self::DisconnectedByClient,
+            NetcodeDisconnectReason::DisconnectedByServer => Self::DisconnectedByServer,
+        }
+    }
+}
+
+///
+/// Emitted on either end when a received message could not be decoded and was dropped.
+///
+#[derive(Debug, Event)]
+pub struct MessageDecodeError {
+    /// The sending client, `None` when the message came from the server.
+    pub client_id: Option<ClientId>,
+    pub channel_id: u8,
+    pub kind: DecodeErrorKind,
+}
+
+///
+/// Emitted on either end for messages received on a configured channel that has no registered
+/// type, which usually means the peers were built with different types.
+///
+#[derive(Debug, Event)]
+pub struct UnknownChannelMessage {
+    /// The sending client, `None` when the message came from the server.
+    pub client_id: Option<ClientId>,
+    pub channel_id: u8,
+    pub bytes: renet::Bytes,
+}
+
+///
+/// The types registered through `add_network_event`, with their channel and `NetworkConfig`.
+/// Useful to label traffic by type in tooling (debug overlays, metrics, logging).
+///
+#[derive(Debug, Default, Resource)]
+#[cfg_attr(feature = "inspector", derive(Reflect), reflect(Resource))]
+pub struct ChannelRegistry {
+    channels: BTreeMap<u8, RegisteredChannel>,
+    /// Channels registered with their own `NetworkConfig`, see `register_network_event`.
+    configured: BTreeSet<u8>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+pub struct RegisteredChannel {
+    pub channel_id: u8,
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    /// Filled in when `ClientServerEventsPlugin` is added.
+    #[cfg_attr(feature = "inspector", reflect(ignore))]
+    pub config: NetworkConfig,
+    /// Traces the type's serde layout, see the `schema` module.
+    #[cfg(feature = "schema")]
+    #[cfg_attr(feature = "inspector", reflect(ignore, default = "schema::untraced"))]
+    pub(crate) trace: schema::TraceFn,
+}
+
+impl ChannelRegistry {
+    fn register<const I: u8, T: DeserializeOwned + 'static>(&mut self) {
+        self.register_with::<I, T>(None);
+    }
+
+    fn register_with<const I: u8, T: DeserializeOwned + 'static>(
+        &mut self,
+        config: Option<NetworkConfig>,
+    ) {
+        let type_name = std::any::type_name::<T>();
+        if I >= LOWEST_RESERVED_CHANNEL_ID {
+            panic!(
+                "{type_name} can't use channel {I}, channels {} and above are reserved",
+                LOWEST_RESERVED_CHANNEL_ID
+            );
+        }
+        if let Some(registered) = self
+            .channels
+            .values()
+            .find(|registered| registered.type_id == TypeId::of::<T>())
+        {
+            panic!(
+                "{} is registered as a network event more than once",
+                registered.type_name
+            );
+        }
+        if let Some(registered) = self.channels.get(&I) {
+            panic!(
+                "{type_name} can't use channel {I}, it is already used by {}",
+                registered.type_name
+            );
+        }
+        if config.is_some() {
+            self.configured.insert(I);
+        }
+        self.channels.insert(
+            I,
+            RegisteredChannel {
+                channel_id: I,
+                type_id: TypeId::of::<T>(),
+                type_name,
+                config: config.unwrap_or_default(),
+                #[cfg(feature = "schema")]
+                trace: schema::trace::<T>,
+            },
+        );
+    }
+
+    pub fn contains(&self, channel_id: u8) -> bool {
+        self.channels.contains_key(&channel_id)
+    }
+
+    pub fn get(&self, channel_id: u8) -> Option<&RegisteredChannel> {
+        self.channels.get(&channel_id)
+    }
+
+    /// The channel `T` was registered on.
+    pub fn channel_of<T: 'static>(&self) -> Option<u8> {
+        self.channel_of_type_id(TypeId::of::<T>())
+    }
+
+    pub fn channel_of_type_id(&self, type_id: TypeId) -> Option<u8> {
+        self.channels
+            .values()
+            .find(|registered| registered.type_id == type_id)
+            .map(|registered| registered.channel_id)
+    }
+
+    /// The registered types, ordered by channel.
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredChannel> {
+        self.channels.values()
+    }
+
+    /// Name of the type registered on a channel.
+    pub fn type_name(&self, channel_id: u8) -> Option<&'static str> {
+        self.channels
+            .get(&channel_id)
+            .map(|registered| registered.type_name)
+    }
+
+    /// Configured channels without a registered type.
+    pub(crate) fn unregistered(&self, configs: &NetworkConfigs) -> impl Iterator<Item = u8> + '_ {
+        (0..configs.0.len().min(LOWEST_RESERVED_CHANNEL_ID as usize) as u8)
+            .filter(|channel_id| !self.contains(*channel_id))
+    }
+
+    /// Summary of the registered types, exchanged on connection to detect mismatched peers.
+    pub(crate) fn fingerprint(&self) -> ChannelsFingerprint {
+        let mut types_hash = FNV_OFFSET_BASIS;
+        for (channel_id, registered) in &self.channels {
+            types_hash = fnv1a(types_hash, &[*channel_id]);
+            types_hash = fnv1a(types_hash, registered.type_name.as_bytes());
+        }
+        ChannelsFingerprint {
+            count: self.channels.len() as u8,
+            types_hash,
+        }
+    }
+
+    #[cfg_attr(not(feature = "config"), allow(dead_code))]
+    pub(crate) fn set_config(&mut self, channel_id: u8, config: NetworkConfig) {
+        if let Some(registered) = self.channels.get_mut(&channel_id) {
+            registered.config = config;
+        }
+    }
+
+    pub(crate) fn type_names(&self) -> Vec<&'static str> {
+        self.channels
+            .values()
+            .map(|registered| registered.type_name)
+            .collect()
+    }
+}
+
+pub(crate) const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+
+/// FNV-1a, stable across builds unlike the std hasher.
+pub(crate) fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChannelsFingerprint {
+    pub(crate) count: u8,
+    pub(crate) types_hash: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// Decoding would claim more memory than the type's `NetworkConfig::max_decode_bytes`.
+    LimitExceeded,
+    /// A delta encoded message arrived without the baseline it was diffed against.
+    MissingDeltaBaseline,
+    /// The bytes don't match the expected type.
+    Invalid(String),
+}
@@ -0,0 +1,336 @@
+//!
+//! Logs the connection lifecycle (connections, disconnections, refused connections and transport
+//! errors) through bevy's logging, at the levels set in the `NetworkLogConfig` resource.
+//!
+use bevy::log::{debug, error, info, trace, warn, Level};
+use bevy::prelude::{App, EventReader, IntoSystemConfigs, PostUpdate, Res, Resource};
+use bevy_renet::renet::transport::NetcodeTransportError;
+
+use std::fmt::Arguments;
+
+use crate::{
+    audit::LeakedClientState,
+    client::{
+        client_initiates_connection_to_server, ClientStopped, ConnectToServer, ConnectionDenied,
+        DisconnectedFromServer, FailedOverTo, ServerReachable, ServerUnreachable,
+    },
+    error::NetworkError,
+    filter::ConnectionFiltered,
+    key_reload::ServerKeyChanged,
+    migration::{MigrationFailed, ServerMigrating},
+    redirect::{RedirectFailed, Redirecting},
+    server::{
+        server_starts, server_tracks_connected_and_disconnected_clients, ClientConnected,
+        ClientConnectionLost, ClientDenied, ClientDisconnected, ClientReconnected, ServerClients,
+        ServerStopped, StartServer,
+    },
+    watchdog::NetworkStalled,
+};
+
+///
+/// The level each kind of network event is logged at, `None` to not log it.
+///
+#[derive(Debug, Clone, Resource)]
+pub struct NetworkLogConfig {
+    /// Servers starting and stopping, clients connecting to a server.
+    pub lifecycle: Option<Level>,
+    /// Clients connecting or reconnecting to the server.
+    pub connections: Option<Level>,
+    /// Clients disconnecting or losing their connection, the server becoming unreachable.
+    pub disconnections: Option<Level>,
+    /// Connections refused by the server (filtered, paused, wrong password, ...).
+    pub rejections: Option<Level>,
+    pub transport_errors: Option<Level>,
+    /// Servers or connections that couldn't be set up, messages that couldn't be sent.
+    pub errors: Option<Level>,
+    /// Per-client state outliving its client, see `ClientStateAudit`.
+    pub leaks: Option<Level>,
+    /// Transports that stopped being updated, see `TransportWatchdog`.
+    pub stalls: Option<Level>,
+}
+
+impl Default for NetworkLogConfig {
+    fn default() -> Self {
+        Self {
+            lifecycle: Some(Level::INFO),
+            connections: Some(Level::INFO),
+            disconnections: Some(Level::INFO),
+            rejections: Some(Level::WARN),
+            transport_errors: Some(Level::ERROR),
+            errors: Some(Level::ERROR),
+            leaks: Some(Level::WARN),
+            stalls: Some(Level::WARN),
+        }
+    }
+}
+
+impl NetworkLogConfig {
+    /// Logs nothing, for games that handle the events themselves.
+    pub fn silent() -> Self {
+        Self {
+            lifecycle: None,
+            connections: None,
+            disconnections: None,
+            rejections: None,
+            transport_errors: None,
+            errors: None,
+            leaks: None,
+            stalls: None,
+        }
+    }
+}
+
+fn log(level: Option<Level>, message: Arguments) {
+    match level {
+        Some(Level::ERROR) => error!("{}", message),
+        Some(Level::WARN) => warn!("{}", message),
+        Some(Level::INFO) => info!("{}", message),
+        Some(Level::DEBUG) => debug!("{}", message),
+        Some(Level::TRACE) => trace!("{}", message),
+        None => {},
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_logs_connections(
+    log_config: Res<NetworkLogConfig>,
+    server_clients: ServerClients,
+    mut start_server_events: EventReader<StartServer>,
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    mut client_connection_lost_events: EventReader<ClientConnectionLost>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut client_denied_events: EventReader<ClientDenied>,
+    mut connection_filtered_events: EventReader<ConnectionFiltered>,
+    mut server_key_changed_events: EventReader<ServerKeyChanged>,
+    mut leaked_client_state_events: EventReader<LeakedClientState>,
+) {
+    for start_server in start_server_events.read() {
+        log(
+            log_config.lifecycle,
+            format_args!(
+                "Starting server on {}:{}",
+                start_server.ip, start_server.port
+            ),
+        );
+    }
+    for _ in server_stopped_events.read() {
+        log(log_config.lifecycle, format_args!("Server stopped"));
+    }
+    for ServerKeyChanged { restarts_in } in server_key_changed_events.read() {
+        log(
+            log_config.lifecycle,
+            format_args!(
+                "Private key file changed, restarting the server in {:?}",
+                restarts_in
+            ),
+        );
+    }
+    for ClientConnected { client_id, mode } in client_connected_events.read() {
+        log(
+            log_config.connections,
+            format_args!(
+                "Client {} connected from {:?} as {:?}",
+                client_id,
+                server_clients.addr(*client_id),
+                mode
+            ),
+        );
+    }
+    for ClientReconnected { client_id, mode } in client_reconnected_events.read() {
+        log(
+            log_config.connections,
+            format_args!(
+                "Client {} reconnected from {:?} as {:?}",
+                client_id,
+                server_clients.addr(*client_id),
+                mode
+            ),
+        );
+    }
+    for ClientConnectionLost { client_id, reason } in client_connection_lost_events.read() {
+        log(
+            log_config.disconnections,
+            format_args!("Client {} lost its connection: {}", client_id, reason),
+        );
+    }
+    for ClientDisconnected { client_id, reason } in client_disconnected_events.read() {
+        log(
+            log_config.disconnections,
+            format_args!("Client {} disconnected: {}", client_id, reason),
+        );
+    }
+    for ClientDenied { client_id, reason } in client_denied_events.read() {
+        log(
+            log_config.rejections,
+            format_args!(
+                "Client {} from {:?} denied: {:?}",
+                client_id,
+                server_clients.addr(*client_id),
+                reason
+            ),
+        );
+    }
+    for ConnectionFiltered { addr } in connection_filtered_events.read() {
+        log(
+            log_config.rejections,
+            format_args!("Connection from {} refused by the connection filter", addr),
+        );
+    }
+    for LeakedClientState { client_id, state } in leaked_client_state_events.read() {
+        log(
+            log_config.leaks,
+            format_args!(
+                "Client {} left the server but is still in its {}",
+                client_id, state
+            ),
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn client_logs_connections(
+    log_config: Res<NetworkLogConfig>,
+    mut connect_to_server_events: EventReader<ConnectToServer>,
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut connection_denied_events: EventReader<ConnectionDenied>,
+    mut server_unreachable_events: EventReader<ServerUnreachable>,
+    mut server_reachable_events: EventReader<ServerReachable>,
+    mut failed_over_to_events: EventReader<FailedOverTo>,
+    mut server_migrating_events: EventReader<ServerMigrating>,
+    mut migration_failed_events: EventReader<MigrationFailed>,
+    mut disconnected_from_server_events: EventReader<DisconnectedFromServer>,
+    mut redirecting_events: EventReader<Redirecting>,
+    mut redirect_failed_events: EventReader<RedirectFailed>,
+) {
+    for connect_to_server in connect_to_server_events.read() {
+        log(
+            log_config.lifecycle,
+            format_args!(
+                "Connecting to server {}:{}",
+                connect_to_server.server_ip, connect_to_server.server_port
+            ),
+        );
+    }
+    for _ in client_stopped_events.read() {
+        log(
+            log_config.disconnections,
+            format_args!("Disconnected from server"),
+        );
+    }
+    for ConnectionDenied { reason } in connection_denied_events.read() {
+        log(
+            log_config.rejections,
+            format_args!("Connection denied by the server: {:?}", reason),
+        );
+    }
+    for DisconnectedFromServer { reason } in disconnected_from_server_events.read() {
+        log(
+            log_config.disconnections,
+            format_args!("Disconnected from server: {}", reason),
+        );
+    }
+    for ServerUnreachable { last_heard } in server_unreachable_events.read() {
+        log(
+            log_config.disconnections,
+            format_args!("Server unreachable, last heard at {:?}", last_heard),
+        );
+    }
+    for _ in server_reachable_events.read() {
+        log(
+            log_config.connections,
+            format_args!("Server reachable again"),
+        );
+    }
+    for FailedOverTo { addr } in failed_over_to_events.read() {
+        log(
+            log_config.lifecycle,
+            format_args!("Server unreachable, connecting to {} instead", addr),
+        );
+    }
+    for ServerMigrating { addr } in server_migrating_events.read() {
+        log(
+            log_config.lifecycle,
+            format_args!("Server migrating to {}", addr),
+        );
+    }
+    for MigrationFailed { addr } in migration_failed_events.read() {
+        log(
+            log_config.errors,
+            format_args!("Could not connect to the server's new address {}", addr),
+        );
+    }
+    for Redirecting { addr, .. } in redirecting_events.read() {
+        log(
+            log_config.lifecycle,
+            format_args!("Redirected by the server to {}", addr),
+        );
+    }
+    for RedirectFailed { addr } in redirect_failed_events.read() {
+        log(
+            log_config.errors,
+            format_args!("Could not connect to the redirect target {}", addr),
+        );
+    }
+}
+
+pub fn logs_transport_errors(
+    log_config: Res<NetworkLogConfig>,
+    mut transport_errors: EventReader<NetcodeTransportError>,
+) {
+    for transport_error in transport_errors.read() {
+        log(
+            log_config.transport_errors,
+            format_args!("Transport error: {}", transport_error),
+        );
+    }
+}
+
+pub fn logs_network_errors(
+    log_config: Res<NetworkLogConfig>,
+    mut network_errors: EventReader<NetworkError>,
+) {
+    for network_error in network_errors.read() {
+        // Transport errors are logged from `NetcodeTransportError`, decode errors are expected
+        // from misbehaving peers and left to `MessageDecodeError` readers.
+        if let NetworkError::Config(_)
+        | NetworkError::Send { .. }
+        | NetworkError::TransportPanic(_) = network_error
+        {
+            log(log_config.errors, format_args!("{}", network_error));
+        }
+    }
+}
+
+pub fn logs_network_stalls(
+    log_config: Res<NetworkLogConfig>,
+    mut network_stalled_events: EventReader<NetworkStalled>,
+) {
+    for NetworkStalled { transport, frames } in network_stalled_events.read() {
+        log(
+            log_config.stalls,
+            format_args!(
+                "The {:?} transport wasn't updated for {} frames. {}",
+                transport,
+                frames,
+                transport.hint()
+            ),
+        );
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<NetworkLogConfig>().add_systems(
+        PostUpdate,
+        (
+            server_logs_connections
+                .after(server_tracks_connected_and_disconnected_clients)
+                .before(server_starts),
+            client_logs_connections.before(client_initiates_connection_to_server),
+            logs_transport_errors,
+            logs_network_errors,
+            logs_network_stalls,
+        ),
+    );
+}
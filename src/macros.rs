@@ -6,30 +6,7 @@ macro_rules! client_server_events_plugin {
 
         $vec_channel_configs.push($head_channel_config);
 
-        $app.add_event::<bevy_client_server_events::server::SendToClient<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::server::server_sends_messages_to_clients::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Server>),
-        );
-
-        $app.add_event::<bevy_client_server_events::server::SendToClients<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::server::server_broadcasts_messages_to_clients::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Server>),
-        );
-
-        $app.add_event::<bevy_client_server_events::server::ReceiveFromClient<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::server::server_receives_messages_from_clients::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Server>),
-        );
-
-        $app.add_event::<bevy_client_server_events::client::SendToServer<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::client::client_sends_messages_to_server::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Client>),
-        );
-
-        $app.add_event::<bevy_client_server_events::client::ReceiveFromServer<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::client::client_receives_messages_from_server::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Client>),
-        );
+        bevy_client_server_events::add_network_event::<$idx, $head_type>(&mut $app);
     };
 
     // For multiple type + channel configs.
@@ -37,30 +14,7 @@ macro_rules! client_server_events_plugin {
 
         $vec_channel_configs.push($head_channel_config);
 
-        $app.add_event::<bevy_client_server_events::server::SendToClient<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::server::server_sends_messages_to_clients::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Server>),
-        );
-
-        $app.add_event::<bevy_client_server_events::server::SendToClients<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::server::server_broadcasts_messages_to_clients::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Server>),
-        );
-
-        $app.add_event::<bevy_client_server_events::server::ReceiveFromClient<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::server::server_receives_messages_from_clients::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Server>),
-        );
-
-        $app.add_event::<bevy_client_server_events::client::SendToServer<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::client::client_sends_messages_to_server::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Client>),
-        );
-
-        $app.add_event::<bevy_client_server_events::client::ReceiveFromServer<$head_type>>().add_systems(
-            bevy::prelude::PostUpdate,
-            bevy::prelude::IntoSystemConfigs::run_if(bevy_client_server_events::client::client_receives_messages_from_server::<$idx, $head_type>, bevy::prelude::resource_exists::<bevy_client_server_events::Client>),
-        );
+        bevy_client_server_events::add_network_event::<$idx, $head_type>(&mut $app);
 
         bevy_client_server_events::paste::paste! {
             const [<$head_type:upper _IDX>]: u8 = $idx + 1; // Increment our index every type we iterate
@@ -80,3 +34,37 @@ macro_rules! client_server_events_plugin {
         );
     };
 }
+
+///
+/// Registers types on consecutive channels starting at `$start`, each with its own
+/// `NetworkConfig` (see `register_network_event`). Meant for plugins that register their own
+/// types, with a start channel acting as their namespace:
+///
+/// ```rust,ignore
+/// const COMBAT_CHANNELS: u8 = 32;
+///
+/// impl Plugin for CombatPlugin {
+///     fn build(&self, app: &mut App) {
+///         register_network_events!(app, COMBAT_CHANNELS,
+///             Attack => NetworkConfig::default(),
+///             Hit => NetworkConfig::unreliable(),
+///         );
+///     }
+/// }
+/// ```
+///
+#[macro_export]
+macro_rules! register_network_events {
+    ( @step $idx:expr, $app:expr, ) => {};
+
+    ( @step $idx:expr, $app:expr, $head_type:ty => $head_channel_config:expr $(, $tail_type:ty => $tail_channel_config:expr )* ) => {
+        $crate::register_network_event::<{ $idx }, $head_type>($app, $head_channel_config);
+        $crate::register_network_events!(@step $idx + 1, $app, $( $tail_type => $tail_channel_config ),*);
+    };
+
+    // Entry point for the macro, `$app` being a `&mut App`.
+    ( $app:expr, $start:expr, $( $type:ty => $channel_config:expr ),* $(,)? ) => {{
+        const START: u8 = $start;
+        $crate::register_network_events!(@step START, $app, $( $type => $channel_config ),*);
+    }};
+}
@@ -0,0 +1,302 @@
+//!
+//! Matchmaking queues: clients connected to a matchmaking server (a regular server of this crate)
+//! queue up, and are sent to the game server picked for them.
+//!
+//! Send `EnqueueForMatch` on a client, the matchmaking server receives `MatchmakingRequested`.
+//! Once it found a match it sends `SendMatchFound`, and the client receives `MatchFound`. Unless
+//! `AutoConnectToMatch` is disabled, the client then disconnects from the matchmaking server and
+//! connects to the game server with the same settings, handing `token` to it in its user data
+//! (read it there with `ServerClients::redirect_token`). `LeaveMatchQueue` leaves the queue, the
+//! matchmaking server receives `MatchmakingCancelled` (also received when a queued client
+//! disconnects).
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource,
+};
+use bevy_renet::renet::{RenetClient, RenetServer};
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::{
+    client::{
+        client_disconnects_from_server, client_initiates_connection_to_server,
+        client_receives_control_messages, ActiveConnection, ClientStopped, ConnectToServer,
+        DisconnectFromServer, LocalClientId,
+    },
+    control::ControlMessage,
+    server::{ClientDisconnected, ServerStopped},
+    ClientId, NetworkSet, CONTROL_CHANNEL_ID,
+};
+
+///
+/// Send this event from a client to join a matchmaking queue of the server it is connected to,
+/// replacing the queue it was in.
+///
+#[derive(Debug, Event)]
+pub struct EnqueueForMatch {
+    pub queue: String,
+}
+
+///
+/// Send this event from a client to leave its matchmaking queue.
+///
+#[derive(Debug, Event)]
+pub struct LeaveMatchQueue;
+
+///
+/// Received on a client when the matchmaking server found a match, hosted at `server_addr`.
+///
+#[derive(Debug, Clone, Event)]
+pub struct MatchFound {
+    pub server_addr: SocketAddr,
+    pub token: Option<Vec<u8>>,
+}
+
+///
+/// Whether clients connect to their match on their own once it is found, enabled by default.
+///
+#[derive(Debug, Resource)]
+pub struct AutoConnectToMatch(pub bool);
+
+impl Default for AutoConnectToMatch {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+///
+/// The matchmaking queue the client is in, if any.
+///
+#[derive(Debug, Default, Resource)]
+pub struct MatchmakingStatus(Option<String>);
+
+impl MatchmakingStatus {
+    pub fn is_queued(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn queue(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+///
+/// Received on the server when a client joins a matchmaking queue.
+///
+#[derive(Debug, Clone, Event)]
+pub struct MatchmakingRequested {
+    pub client_id: ClientId,
+    pub queue: String,
+}
+
+///
+/// Received on the server when a client leaves its matchmaking queue or disconnects while in it.
+///
+#[derive(Debug, Clone, Event)]
+pub struct MatchmakingCancelled {
+    pub client_id: ClientId,
+    pub queue: String,
+}
+
+///
+/// Send this event from the server to send a queued client to its match, with an optional token
+/// for the game server (at most `redirect::MAX_REDIRECT_TOKEN_BYTES`).
+///
+#[derive(Debug, Event)]
+pub struct SendMatchFound {
+    pub client_id: ClientId,
+    pub server_addr: SocketAddr,
+    pub token: Option<Vec<u8>>,
+}
+
+///
+/// The matchmaking queue of every queued client on the server.
+///
+#[derive(Debug, Default, Resource)]
+pub struct MatchmakingQueues(BTreeMap<ClientId, String>);
+
+impl MatchmakingQueues {
+    pub fn queue_of(&self, client_id: ClientId) -> Option<&str> {
+        self.0.get(&client_id).map(String::as_str)
+    }
+
+    /// The clients in `queue`, by client id.
+    pub fn members<'a>(&'a self, queue: &'a str) -> impl Iterator<Item = ClientId> + 'a {
+        self.0
+            .iter()
+            .filter(move |(_, member_queue)| *member_queue == queue)
+            .map(|(client_id, _)| *client_id)
+    }
+
+    pub(crate) fn enqueue(&mut self, client_id: ClientId, queue: String) -> Option<String> {
+        self.0.insert(client_id, queue)
+    }
+
+    pub(crate) fn dequeue(&mut self, client_id: ClientId) -> Option<String> {
+        self.0.remove(&client_id)
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().copied()
+    }
+}
+
+pub fn server_sends_matches_found(
+    mut server: ResMut<RenetServer>,
+    mut queues: ResMut<MatchmakingQueues>,
+    mut send_match_found_events: EventReader<SendMatchFound>,
+) {
+    for SendMatchFound {
+        client_id,
+        server_addr,
+        token,
+    } in send_match_found_events.read()
+    {
+        if queues.dequeue(*client_id).is_none() {
+            continue;
+        }
+        server.send_message(
+            (*client_id).into(),
+            CONTROL_CHANNEL_ID,
+            ControlMessage::MatchFound {
+                addr: *server_addr,
+                token: token.clone(),
+            }
+            .encode(),
+        );
+    }
+}
+
+pub fn server_dequeues_disconnected_clients(
+    mut queues: ResMut<MatchmakingQueues>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut matchmaking_cancelled_events: EventWriter<MatchmakingCancelled>,
+) {
+    for client_disconnected in client_disconnected_events.read() {
+        if let Some(queue) = queues.dequeue(client_disconnected.client_id) {
+            matchmaking_cancelled_events.send(MatchmakingCancelled {
+                client_id: client_disconnected.client_id,
+                queue,
+            });
+        }
+    }
+}
+
+pub fn server_resets_matchmaking_queues(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut queues: ResMut<MatchmakingQueues>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        queues.0.clear();
+    }
+}
+
+pub fn client_joins_match_queues(
+    mut client: ResMut<RenetClient>,
+    mut status: ResMut<MatchmakingStatus>,
+    mut enqueue_events: EventReader<EnqueueForMatch>,
+    mut leave_queue_events: EventReader<LeaveMatchQueue>,
+) {
+    for EnqueueForMatch { queue } in enqueue_events.read() {
+        client.send_message(
+            CONTROL_CHANNEL_ID,
+            ControlMessage::Enqueue {
+                queue: queue.clone(),
+            }
+            .encode(),
+        );
+        status.0 = Some(queue.clone());
+    }
+    if leave_queue_events.read().count() > 0 && status.0.take().is_some() {
+        client.send_message(CONTROL_CHANNEL_ID, ControlMessage::Dequeue.encode());
+    }
+}
+
+pub fn client_connects_to_matches(
+    mut match_found_events: EventReader<MatchFound>,
+    auto_connect: Res<AutoConnectToMatch>,
+    active_connection: Res<ActiveConnection>,
+    local_client_id: Option<Res<LocalClientId>>,
+    mut status: ResMut<MatchmakingStatus>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+    mut connect_to_server_events: EventWriter<ConnectToServer>,
+) {
+    let Some(MatchFound { server_addr, token }) = match_found_events.read().last() else {
+        return;
+    };
+    status.0 = None;
+    let Some(matchmaker) = active_connection.0.as_ref().filter(|_| auto_connect.0) else {
+        return;
+    };
+    disconnect_from_server_events.send(DisconnectFromServer);
+    // The matchmaker's fallbacks and bound socket don't apply to the game server.
+    connect_to_server_events.send(ConnectToServer {
+        server_ip: server_addr.ip().to_string(),
+        server_port: server_addr.port(),
+        protocol_id: matchmaker.protocol_id,
+        available_bytes_per_tick: matchmaker.available_bytes_per_tick,
+        client_id: local_client_id.map(|local_client_id| local_client_id.0),
+        user_data: matchmaker.user_data,
+        expire_seconds: matchmaker.expire_seconds,
+        timeout_seconds: matchmaker.timeout_seconds,
+        private_key: matchmaker.private_key,
+        password: matchmaker.password.clone(),
+        mode: matchmaker.mode,
+        socket_options: matchmaker.socket_options,
+        redirect_token: token.clone(),
+        player_id: matchmaker.player_id,
+        ..Default::default()
+    });
+}
+
+pub fn client_resets_matchmaking_status(
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut status: ResMut<MatchmakingStatus>,
+) {
+    if client_stopped_events.read().count() > 0 {
+        status.0 = None;
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<AutoConnectToMatch>()
+        .init_resource::<MatchmakingStatus>()
+        .init_resource::<MatchmakingQueues>()
+        .add_event::<EnqueueForMatch>()
+        .add_event::<LeaveMatchQueue>()
+        .add_event::<MatchFound>()
+        .add_event::<MatchmakingRequested>()
+        .add_event::<MatchmakingCancelled>()
+        .add_event::<SendMatchFound>()
+        .add_systems(
+            PostUpdate,
+            server_sends_matches_found
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                server_dequeues_disconnected_clients,
+                server_resets_matchmaking_queues,
+            ),
+        )
+        .add_systems(
+            PostUpdate,
+            client_joins_match_queues
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                client_connects_to_matches
+                    .after(client_receives_control_messages)
+                    .before(client_disconnects_from_server)
+                    .before(client_initiates_connection_to_server),
+                client_resets_matchmaking_status,
+            ),
+        );
+}
@@ -0,0 +1,166 @@
+//!
+//! Planned server address changes (e.g. before a host restart or an infrastructure move): the
+//! server tells its clients where it is moving, and they reconnect there on their own.
+//!
+//! Send `MigrateServer` on the server. Clients receive `ServerMigrating`, disconnect and connect to
+//! the new address with the same settings, the same client id and `resume` set, so a server that
+//! knows them (see `SessionResumption`) resumes their session. `ServerMigrated` or
+//! `MigrationFailed` is received once the new connection is established or given up on.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource,
+};
+use bevy_renet::renet::RenetServer;
+use renet::transport::NetcodeClientTransport;
+
+use std::net::SocketAddr;
+
+use crate::{
+    client::{
+        client_disconnects_from_server, client_initiates_connection_to_server,
+        client_receives_control_messages, ActiveConnection, DisconnectFromServer, LocalClientId,
+        PendingConnectToServer,
+    },
+    control::ControlMessage,
+    NetworkSet, CONTROL_CHANNEL_ID,
+};
+
+///
+/// Send this event from the server to move every connected client to `addr`.
+///
+#[derive(Debug, Event)]
+pub struct MigrateServer {
+    pub addr: SocketAddr,
+}
+
+///
+/// Received on a client when the server announced it is moving to `addr`, right before the client
+/// disconnects to connect there.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ServerMigrating {
+    pub addr: SocketAddr,
+}
+
+///
+/// Received on a client once connected to the server's new address (or to one of the fallback
+/// servers).
+///
+#[derive(Debug, Clone, Event)]
+pub struct ServerMigrated {
+    pub addr: SocketAddr,
+}
+
+///
+/// Received on a client when the server's new address couldn't be connected to, or the connection
+/// was abandoned before it was established.
+///
+#[derive(Debug, Clone, Event)]
+pub struct MigrationFailed {
+    pub addr: SocketAddr,
+}
+
+///
+/// The address a client is migrating to, if any.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientMigration(Option<SocketAddr>);
+
+impl ClientMigration {
+    pub fn is_migrating(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn target(&self) -> Option<SocketAddr> {
+        self.0
+    }
+}
+
+pub fn server_announces_migration(
+    mut server: ResMut<RenetServer>,
+    mut migrate_server_events: EventReader<MigrateServer>,
+) {
+    for MigrateServer { addr } in migrate_server_events.read() {
+        server.broadcast_message(
+            CONTROL_CHANNEL_ID,
+            ControlMessage::Migrate { addr: *addr }.encode(),
+        );
+    }
+}
+
+pub fn client_migrates_to_new_server(
+    mut server_migrating_events: EventReader<ServerMigrating>,
+    local_client_id: Option<Res<LocalClientId>>,
+    mut active_connection: ResMut<ActiveConnection>,
+    mut pending_connect_to_server: ResMut<PendingConnectToServer>,
+    mut client_migration: ResMut<ClientMigration>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+) {
+    let Some(ServerMigrating { addr }) = server_migrating_events.read().last() else {
+        return;
+    };
+    let Some(mut connect_to_server) = active_connection.0.take() else {
+        return;
+    };
+    connect_to_server.server_ip = addr.ip().to_string();
+    connect_to_server.server_port = addr.port();
+    connect_to_server.client_id = local_client_id.map(|local_client_id| local_client_id.0);
+    connect_to_server.resume = true;
+    // A connection requested by the app in the meantime wins over the migration.
+    if !pending_connect_to_server.is_pending() {
+        pending_connect_to_server.0 = Some(connect_to_server);
+        client_migration.0 = Some(*addr);
+    }
+    disconnect_from_server_events.send(DisconnectFromServer);
+}
+
+pub fn client_tracks_migration(
+    transport: Option<Res<NetcodeClientTransport>>,
+    active_connection: Res<ActiveConnection>,
+    pending_connect_to_server: Res<PendingConnectToServer>,
+    mut client_migration: ResMut<ClientMigration>,
+    mut server_migrated_events: EventWriter<ServerMigrated>,
+    mut migration_failed_events: EventWriter<MigrationFailed>,
+) {
+    let Some(addr) = client_migration.0 else {
+        return;
+    };
+    match transport {
+        Some(transport) if transport.is_connected() => {
+            server_migrated_events.send(ServerMigrated { addr });
+        },
+        Some(transport)
+            if transport.is_disconnected() && !active_connection.fails_over(&transport) =>
+        {
+            migration_failed_events.send(MigrationFailed { addr });
+        },
+        None if !pending_connect_to_server.is_pending() => {
+            migration_failed_events.send(MigrationFailed { addr });
+        },
+        _ => return,
+    }
+    client_migration.0 = None;
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ClientMigration>()
+        .add_event::<MigrateServer>()
+        .add_event::<ServerMigrating>()
+        .add_event::<ServerMigrated>()
+        .add_event::<MigrationFailed>()
+        .add_systems(
+            PostUpdate,
+            server_announces_migration
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (client_tracks_migration, client_migrates_to_new_server)
+                .chain()
+                .after(client_receives_control_messages)
+                .before(client_disconnects_from_server)
+                .before(client_initiates_connection_to_server),
+        );
+}
@@ -0,0 +1,92 @@
+//!
+//! Per client suppression of network types on the server, for moderation (e.g. muting a player's
+//! voice or emotes) without touching the systems sending and receiving them.
+//!
+//! Types muted to a client are dropped from every `SendToClient`, `SendToClients`,
+//! `SendToClientsWhere` and `SendToTeam` for that client, and never relayed to it. Types muted
+//! from a client are dropped when received, before any `ReceiveFromClient` or relay. Mutes are
+//! forgotten once the client is disconnected (not while its session is suspended).
+//!
+use bevy::prelude::{App, EventReader, PostUpdate, ResMut, Resource};
+use bevy::utils::HashSet;
+
+use std::any::TypeId;
+
+use crate::{server::ClientDisconnected, ClientId};
+
+///
+/// The network types muted to and from each client, see the module documentation.
+///
+#[derive(Debug, Default, Resource)]
+pub struct MutedClientEvents {
+    to_client: HashSet<(ClientId, TypeId)>,
+    from_client: HashSet<(ClientId, TypeId)>,
+}
+
+impl MutedClientEvents {
+    /// Stops sending `T` to the client and receiving it from the client.
+    pub fn mute<T: 'static>(&mut self, client_id: ClientId) {
+        self.mute_to::<T>(client_id);
+        self.mute_from::<T>(client_id);
+    }
+
+    /// Stops sending `T` to the client.
+    pub fn mute_to<T: 'static>(&mut self, client_id: ClientId) {
+        self.to_client.insert((client_id, TypeId::of::<T>()));
+    }
+
+    /// Drops `T` when received from the client.
+    pub fn mute_from<T: 'static>(&mut self, client_id: ClientId) {
+        self.from_client.insert((client_id, TypeId::of::<T>()));
+    }
+
+    /// Lifts both directions of the mute.
+    pub fn unmute<T: 'static>(&mut self, client_id: ClientId) {
+        self.to_client.remove(&(client_id, TypeId::of::<T>()));
+        self.from_client.remove(&(client_id, TypeId::of::<T>()));
+    }
+
+    pub fn is_muted_to<T: 'static>(&self, client_id: ClientId) -> bool {
+        self.is_type_muted_to(client_id, TypeId::of::<T>())
+    }
+
+    pub fn is_muted_from<T: 'static>(&self, client_id: ClientId) -> bool {
+        self.is_type_muted_from(client_id, TypeId::of::<T>())
+    }
+
+    pub(crate) fn is_type_muted_to(&self, client_id: ClientId, type_id: TypeId) -> bool {
+        !self.to_client.is_empty() && self.to_client.contains(&(client_id, type_id))
+    }
+
+    pub(crate) fn is_type_muted_from(&self, client_id: ClientId, type_id: TypeId) -> bool {
+        !self.from_client.is_empty() && self.from_client.contains(&(client_id, type_id))
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.to_client
+            .iter()
+            .chain(self.from_client.iter())
+            .map(|(client_id, _)| *client_id)
+    }
+
+    fn forget(&mut self, client_id: ClientId) {
+        self.to_client
+            .retain(|(muted_client_id, _)| *muted_client_id != client_id);
+        self.from_client
+            .retain(|(muted_client_id, _)| *muted_client_id != client_id);
+    }
+}
+
+pub fn server_forgets_muted_client_events(
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut muted_client_events: ResMut<MutedClientEvents>,
+) {
+    for client_disconnected in client_disconnected_events.read() {
+        muted_client_events.forget(client_disconnected.client_id);
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<MutedClientEvents>()
+        .add_systems(PostUpdate, server_forgets_muted_client_events);
+}
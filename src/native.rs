@@ -0,0 +1,141 @@
+//!
+//! Sending types that derive bincode's `Encode`/`Decode` instead of serde's
+//! `Serialize`/`Deserialize`, one type at a time.
+//!
+//! Wrap the type in `Native` when registering it and when sending or receiving it:
+//!
+//! ```rust,ignore
+//! #[derive(bincode::Encode, bincode::Decode)]
+//! struct Snapshot { /* ... */ }
+//!
+//! client_server_events_plugin!(app, Native<Snapshot> => NetworkConfig::default());
+//!
+//! fn send_snapshot(mut events: EventWriter<SendToClients<Native<Snapshot>>>) {
+//!     events.send(SendToClients { content: Native(snapshot()) });
+//! }
+//! ```
+//!
+//! The wrapped type is encoded with the `wire_format` of the registered type's `NetworkConfig`,
+//! and decoding it never claims more memory than its `max_decode_bytes`. It travels as a byte
+//! string inside the serde based wire format, so each message carries one more length prefix than
+//! with serde's traits.
+//!
+use bevy::prelude::Event;
+use bincode::{Decode, Encode};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use std::cell::Cell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use crate::{
+    codec::{with_decode_limit, with_wire_format},
+    ByteOrder, IntEncoding, WireFormat,
+};
+
+/// Decoding limit outside of the crate's codec, e.g. when a `Native` is nested in a chat message.
+const DEFAULT_DECODE_LIMIT: usize = 16 * 1024 * 1024;
+
+thread_local! {
+    /// The wire format and decoding limit of the message being encoded or decoded by the codec,
+    /// serde gives no way to pass them to `Native`.
+    static FORMAT: Cell<(WireFormat, usize)> = const {
+        let standard = WireFormat {
+            int_encoding: IntEncoding::Variable,
+            byte_order: ByteOrder::Little,
+        };
+        Cell::new((standard, DEFAULT_DECODE_LIMIT))
+    };
+}
+
+///
+/// Sets the format `Native` values use until dropped, set by the codec around each message.
+///
+pub(crate) struct FormatScope {
+    previous: (WireFormat, usize),
+}
+
+impl FormatScope {
+    /// `limit` keeps the current decoding limit if `None`, for encoding.
+    pub(crate) fn new(wire_format: WireFormat, limit: Option<usize>) -> Self {
+        let previous = FORMAT.get();
+        FORMAT.set((wire_format, limit.unwrap_or(previous.1)));
+        Self { previous }
+    }
+}
+
+impl Drop for FormatScope {
+    fn drop(&mut self) {
+        FORMAT.set(self.previous);
+    }
+}
+
+///
+/// A type encoded with its own bincode `Encode`/`Decode` implementations, which is carried as a
+/// byte string (adding a length prefix) by the serde based wire format.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Event)]
+pub struct Native<T: Send + Sync + 'static>(pub T);
+
+impl<T: Send + Sync + 'static> Deref for Native<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Send + Sync + 'static> DerefMut for Native<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Encode + Send + Sync + 'static> Serialize for Native<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (wire_format, _) = FORMAT.get();
+        let bytes =
+            with_wire_format!(wire_format, config => bincode::encode_to_vec(&self.0, config))
+                .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+impl<'de, T: Decode + Send + Sync + 'static> Deserialize<'de> for Native<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+        let (wire_format, limit) = FORMAT.get();
+        with_decode_limit!(wire_format, limit, config => {
+            bincode::decode_from_slice(&bytes, config)
+                .map(|(content, _)| Native(content))
+                .map_err(de::Error::custom)
+        })
+        .unwrap_or_else(|| Err(de::Error::custom("decoding limit over 1 GiB")))
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("bincode encoded bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
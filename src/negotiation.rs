@@ -0,0 +1,435 @@
+//!
+//! A negotiation phase between the transport connecting and `ClientConnected`, for apps that need
+//! to agree on something before playing (capabilities, compression, locale...).
+//!
+//! When `Negotiation::enabled` is set on both ends, clients passing the server's checks are held
+//! back: both ends get a `NegotiationStarted` event and exchange up to `max_messages` messages
+//! each with `SendNegotiationMessage` and `NegotiationMessage`, on a reserved reliable channel.
+//! The server then sends `AcceptNegotiation` to finalize the connection (`ClientConnected` or
+//! `ClientReconnected` on the server, `NegotiationFinished` on the client), or `RefuseNegotiation`
+//! to deny the client with `DenialReason::NegotiationFailed`. Clients sending more messages than
+//! allowed, or not accepted within `timeout`, are denied the same way.
+//!
+//! Messages from negotiating clients on other channels are dropped, clients hold theirs until
+//! the negotiation is finished.
+//!
+use bevy::log::warn;
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate,
+    PreUpdate, Res, ResMut, Resource, Time,
+};
+use bevy::utils::HashMap;
+use bevy_renet::{
+    client_just_connected,
+    renet::{RenetClient, RenetServer},
+};
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+use crate::{
+    client::ClientStopped,
+    control::ControlMessage,
+    server::{
+        server_tracks_connected_and_disconnected_clients, ClientDenied, ConnectedClient,
+        ConnectedClients, ConnectionEvents, DeniedClients, ServerStopped, SuspendedClients,
+    },
+    ClientId, DenialReason, NetworkSet, PlayerId, CONTROL_CHANNEL_ID, NEGOTIATION_CHANNEL_ID,
+};
+
+/// Upper bound on the memory decoding a negotiation message may claim, the memory renet gives the
+/// negotiation channel, so that a crafted length prefix can't make a peer allocate huge buffers.
+const MAX_NEGOTIATION_MESSAGE_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum NegotiationPayload {
+    /// A user message, either way.
+    Message(Vec<u8>),
+    /// From the server once it accepted the client.
+    Finished,
+}
+
+impl NegotiationPayload {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard().with_limit::<MAX_NEGOTIATION_MESSAGE_BYTES>();
+        bincode::serde::decode_from_slice(bytes, config)
+            .ok()
+            .map(|(payload, _)| payload)
+    }
+}
+
+///
+/// A connection was held back for negotiating, see the module documentation. `client_id` is the
+/// negotiating client on the server, `None` on clients.
+///
+#[derive(Debug, Event)]
+pub struct NegotiationStarted {
+    pub client_id: Option<ClientId>,
+}
+
+///
+/// Send this event to the negotiating client (`client_id`) from the server, or to the server
+/// (`client_id` is ignored) from a client.
+///
+#[derive(Debug, Event)]
+pub struct SendNegotiationMessage {
+    pub client_id: Option<ClientId>,
+    pub content: Vec<u8>,
+}
+
+///
+/// A negotiation message from the client (`client_id`) on the server, or from the server
+/// (`client_id` is `None`) on clients.
+///
+#[derive(Debug, Event)]
+pub struct NegotiationMessage {
+    pub client_id: Option<ClientId>,
+    pub content: Vec<u8>,
+}
+
+///
+/// Send this event from the server to finish the negotiation and connect the client.
+///
+#[derive(Debug, Event)]
+pub struct AcceptNegotiation {
+    pub client_id: ClientId,
+}
+
+///
+/// Send this event from the server to deny a negotiating client.
+///
+#[derive(Debug, Event)]
+pub struct RefuseNegotiation {
+    pub client_id: ClientId,
+}
+
+///
+/// Received on the client once the server accepted it, the connection is then established.
+///
+#[derive(Debug, Event)]
+pub struct NegotiationFinished;
+
+#[derive(Debug)]
+struct PendingNegotiation {
+    client: ConnectedClient,
+    expires_at: Duration,
+    sent: usize,
+    received: usize,
+}
+
+///
+/// Negotiation settings, the same on both ends, and the clients the server holds back while they
+/// negotiate.
+///
+#[derive(Debug, Resource)]
+pub struct Negotiation {
+    /// Disabled by default, clients are connected as soon as they pass the server's checks.
+    pub enabled: bool,
+    /// The number of messages each end may send per negotiation.
+    pub max_messages: usize,
+    pub timeout: Duration,
+    pending: HashMap<ClientId, PendingNegotiation>,
+}
+
+impl Default for Negotiation {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_messages: 8,
+            timeout: Duration::from_secs(5),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl Negotiation {
+    pub fn is_negotiating(&self, client_id: ClientId) -> bool {
+        self.pending.contains_key(&client_id)
+    }
+
+    pub(crate) fn is_negotiating_player(&self, player_id: PlayerId) -> bool {
+        self.pending
+            .values()
+            .any(|pending| pending.client.player_id == Some(player_id))
+    }
+
+    pub(crate) fn start(&mut self, client_id: ClientId, client: ConnectedClient, now: Duration) {
+        self.pending.insert(
+            client_id,
+            PendingNegotiation {
+                client,
+                expires_at: now + self.timeout,
+                sent: 0,
+                received: 0,
+            },
+        );
+    }
+
+    /// Forgets a client that disconnected while negotiating, `false` if it wasn't.
+    pub(crate) fn abandon(&mut self, client_id: ClientId) -> bool {
+        self.pending.remove(&client_id).is_some()
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.pending.keys().copied()
+    }
+}
+
+///
+/// Whether the client is negotiating, and how many messages it sent so far.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientNegotiation {
+    negotiating: bool,
+    sent: usize,
+}
+
+impl ClientNegotiation {
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+}
+
+pub fn server_receives_negotiation_messages(
+    mut server: ResMut<RenetServer>,
+    mut negotiation: ResMut<Negotiation>,
+    mut denied_clients: ResMut<DeniedClients>,
+    time: Res<Time>,
+    mut negotiation_message_events: EventWriter<NegotiationMessage>,
+    mut client_denied_events: EventWriter<ClientDenied>,
+) {
+    let now = time.elapsed();
+    let mut failed = Vec::new();
+    for renet_client_id in server.clients_id() {
+        let client_id = ClientId::from(renet_client_id);
+        while let Some(message) = server.receive_message(renet_client_id, NEGOTIATION_CHANNEL_ID) {
+            let Some(pending) = negotiation.pending.get_mut(&client_id) else {
+                continue;
+            };
+            let Some(NegotiationPayload::Message(content)) = NegotiationPayload::decode(&message)
+            else {
+                continue;
+            };
+            pending.received += 1;
+            if pending.received > negotiation.max_messages {
+                warn!(
+                    "Client {} sent more than {} negotiation messages",
+                    client_id, negotiation.max_messages
+                );
+                failed.push(client_id);
+                break;
+            }
+            negotiation_message_events.send(NegotiationMessage {
+                client_id: Some(client_id),
+                content,
+            });
+        }
+    }
+    failed.extend(
+        negotiation
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.expires_at <= now)
+            .map(|(client_id, _)| *client_id),
+    );
+    for client_id in failed {
+        if negotiation.abandon(client_id) {
+            denied_clients.deny(
+                &mut server,
+                client_id,
+                DenialReason::NegotiationFailed,
+                now,
+                &mut client_denied_events,
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_finishes_negotiations(
+    mut server: ResMut<RenetServer>,
+    mut negotiation: ResMut<Negotiation>,
+    mut denied_clients: ResMut<DeniedClients>,
+    mut connected_clients: ResMut<ConnectedClients>,
+    mut suspended_clients: ResMut<SuspendedClients>,
+    time: Res<Time>,
+    mut send_negotiation_message_events: EventReader<SendNegotiationMessage>,
+    mut accept_negotiation_events: EventReader<AcceptNegotiation>,
+    mut refuse_negotiation_events: EventReader<RefuseNegotiation>,
+    mut events: ConnectionEvents,
+) {
+    let max_messages = negotiation.max_messages;
+    for SendNegotiationMessage { client_id, content } in send_negotiation_message_events.read() {
+        let Some(client_id) = *client_id else {
+            continue;
+        };
+        let Some(pending) = negotiation.pending.get_mut(&client_id) else {
+            warn!(
+                "Negotiation message dropped, client {} is not negotiating",
+                client_id
+            );
+            continue;
+        };
+        if pending.sent >= max_messages {
+            warn!(
+                "Negotiation message dropped, {} were already sent to client {}",
+                max_messages, client_id
+            );
+            continue;
+        }
+        pending.sent += 1;
+        server.send_message(
+            client_id.into(),
+            NEGOTIATION_CHANNEL_ID,
+            NegotiationPayload::Message(content.clone()).encode(),
+        );
+    }
+    for AcceptNegotiation { client_id } in accept_negotiation_events.read() {
+        let Some(pending) = negotiation.pending.remove(client_id) else {
+            continue;
+        };
+        server.send_message(
+            (*client_id).into(),
+            NEGOTIATION_CHANNEL_ID,
+            NegotiationPayload::Finished.encode(),
+        );
+        events.admit(
+            *client_id,
+            pending.client,
+            &mut connected_clients,
+            &mut suspended_clients,
+        );
+    }
+    for RefuseNegotiation { client_id } in refuse_negotiation_events.read() {
+        if negotiation.abandon(*client_id) {
+            denied_clients.deny(
+                &mut server,
+                *client_id,
+                DenialReason::NegotiationFailed,
+                time.elapsed(),
+                &mut events.denied,
+            );
+        }
+    }
+}
+
+pub fn server_forgets_negotiations(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut negotiation: ResMut<Negotiation>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        negotiation.pending.clear();
+    }
+}
+
+pub fn client_starts_negotiating(
+    negotiation: Res<Negotiation>,
+    mut client_negotiation: ResMut<ClientNegotiation>,
+    mut negotiation_started_events: EventWriter<NegotiationStarted>,
+) {
+    if negotiation.enabled {
+        *client_negotiation = ClientNegotiation {
+            negotiating: true,
+            sent: 0,
+        };
+        negotiation_started_events.send(NegotiationStarted { client_id: None });
+    }
+}
+
+pub fn client_receives_negotiation_messages(
+    mut client: ResMut<RenetClient>,
+    mut client_negotiation: ResMut<ClientNegotiation>,
+    mut negotiation_message_events: EventWriter<NegotiationMessage>,
+    mut negotiation_finished_events: EventWriter<NegotiationFinished>,
+) {
+    while let Some(message) = client.receive_message(NEGOTIATION_CHANNEL_ID) {
+        match NegotiationPayload::decode(&message) {
+            Some(NegotiationPayload::Message(content)) => {
+                negotiation_message_events.send(NegotiationMessage {
+                    client_id: None,
+                    content,
+                });
+            },
+            Some(NegotiationPayload::Finished) => {
+                client_negotiation.negotiating = false;
+                client.send_message(CONTROL_CHANNEL_ID, ControlMessage::ClientReady.encode());
+                negotiation_finished_events.send(NegotiationFinished);
+            },
+            None => {},
+        }
+    }
+}
+
+pub fn client_sends_negotiation_messages(
+    mut client: ResMut<RenetClient>,
+    negotiation: Res<Negotiation>,
+    mut client_negotiation: ResMut<ClientNegotiation>,
+    mut send_negotiation_message_events: EventReader<SendNegotiationMessage>,
+) {
+    for SendNegotiationMessage { content, .. } in send_negotiation_message_events.read() {
+        if !client_negotiation.negotiating {
+            warn!("Negotiation message dropped, the client is not negotiating");
+            continue;
+        }
+        if client_negotiation.sent >= negotiation.max_messages {
+            warn!(
+                "Negotiation message dropped, {} were already sent to the server",
+                negotiation.max_messages
+            );
+            continue;
+        }
+        client_negotiation.sent += 1;
+        client.send_message(
+            NEGOTIATION_CHANNEL_ID,
+            NegotiationPayload::Message(content.clone()).encode(),
+        );
+    }
+}
+
+pub fn client_stops_negotiating(
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut client_negotiation: ResMut<ClientNegotiation>,
+) {
+    if client_stopped_events.read().count() > 0 {
+        client_negotiation.negotiating = false;
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<Negotiation>()
+        .init_resource::<ClientNegotiation>()
+        .add_event::<NegotiationStarted>()
+        .add_event::<SendNegotiationMessage>()
+        .add_event::<NegotiationMessage>()
+        .add_event::<AcceptNegotiation>()
+        .add_event::<RefuseNegotiation>()
+        .add_event::<NegotiationFinished>()
+        .add_systems(
+            PreUpdate,
+            server_receives_negotiation_messages
+                .after(server_tracks_connected_and_disconnected_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            server_finishes_negotiations
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_forgets_negotiations)
+        .add_systems(
+            PostUpdate,
+            (
+                client_starts_negotiating.run_if(client_just_connected),
+                client_receives_negotiation_messages,
+                client_sends_negotiation_messages.in_set(NetworkSet::Send),
+            )
+                .chain()
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(PostUpdate, client_stops_negotiating);
+}
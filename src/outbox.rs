@@ -0,0 +1,85 @@
+//!
+//! Building blocks shared by the client and server outboxes, which hold encoded messages until
+//! they are handed over to renet at the end of the tick.
+//!
+use renet::Bytes;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub(crate) struct QueuedMessage {
+    pub channel_id: u8,
+    pub payload: Bytes,
+    pub queued_at: Duration,
+}
+
+///
+/// Holds only the latest message per key until its channel's send interval has elapsed,
+/// see `NetworkConfig::max_send_rate`.
+///
+#[derive(Debug)]
+pub(crate) struct Coalescer<K> {
+    pending: HashMap<K, QueuedMessage>,
+    last_sent: HashMap<K, Duration>,
+}
+
+impl<K> Default for Coalescer<K> {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+            last_sent: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Copy> Coalescer<K> {
+    /// Replaces any message still pending for this key.
+    pub(crate) fn offer(&mut self, key: K, message: QueuedMessage) {
+        self.pending.insert(key, message);
+    }
+
+    /// Takes the pending messages whose send interval has elapsed.
+    pub(crate) fn release(
+        &mut self,
+        now: Duration,
+        interval: impl Fn(u8) -> Duration,
+    ) -> Vec<(K, QueuedMessage)> {
+        let due: Vec<K> = self
+            .pending
+            .iter()
+            .filter(|(key, message)| {
+                self.last_sent.get(*key).is_none_or(|last_sent| {
+                    now.saturating_sub(*last_sent) >= interval(message.channel_id)
+                })
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        due.into_iter()
+            .filter_map(|key| {
+                self.last_sent.insert(key, now);
+                self.pending.remove(&key).map(|message| (key, message))
+            })
+            .collect()
+    }
+
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Every key with a pending message or a send time (possibly twice).
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        self.pending.keys().chain(self.last_sent.keys())
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        self.pending.retain(|key, _| keep(key));
+        self.last_sent.retain(|key, _| keep(key));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pending.clear();
+        self.last_sent.clear();
+    }
+}
@@ -0,0 +1,77 @@
+//!
+//! Reusable buffers for encoding outgoing messages. Payloads are split off a per-thread buffer
+//! instead of each getting their own allocation, and the buffer's memory is reused once every
+//! payload split off it has been sent and dropped.
+//!
+use bevy::prelude::{App, Last, ResMut, Resource};
+use bytes::BytesMut;
+use renet::Bytes;
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Memory reserved at once for the payloads of a thread.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Below this much free space a new chunk is reserved before encoding.
+const MIN_FREE_SPACE: usize = 2 * 1024;
+
+thread_local! {
+    static ENCODE_BUFFER: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
+static ENCODED: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Encodes a payload with `write` into the current thread's buffer.
+///
+pub(crate) fn encode_pooled(write: impl FnOnce(&mut BytesMut)) -> Bytes {
+    ENCODE_BUFFER.with_borrow_mut(|buffer| {
+        if buffer.capacity() < MIN_FREE_SPACE && !buffer.try_reclaim(CHUNK_SIZE) {
+            buffer.reserve(CHUNK_SIZE);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        let capacity = buffer.capacity();
+        write(buffer);
+        if buffer.len() > capacity {
+            // The payload didn't fit in the chunk, the buffer grew.
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ENCODED.fetch_add(1, Ordering::Relaxed);
+        buffer.split().freeze()
+    })
+}
+
+///
+/// Counters of the encode buffers since the app started, updated every frame.
+/// Ideally `allocations` stays far below `encoded`: a payload sitting in an outbox for a long time
+/// keeps its whole chunk from being reused.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Resource)]
+pub struct EncodeBufferStats {
+    /// Payloads encoded, on either end.
+    pub encoded: u64,
+    /// Encodes that needed new memory.
+    pub allocations: u64,
+}
+
+impl EncodeBufferStats {
+    /// Share of the encodes that reused memory, from 0 to 1.
+    pub fn reuse_ratio(&self) -> f64 {
+        if self.encoded == 0 {
+            return 0.0;
+        }
+        1.0 - self.allocations as f64 / self.encoded as f64
+    }
+}
+
+pub fn updates_encode_buffer_stats(mut stats: ResMut<EncodeBufferStats>) {
+    stats.encoded = ENCODED.load(Ordering::Relaxed);
+    stats.allocations = ALLOCATIONS.load(Ordering::Relaxed);
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<EncodeBufferStats>()
+        .add_systems(Last, updates_encode_buffer_stats);
+}
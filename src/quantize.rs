@@ -0,0 +1,286 @@
+//!
+//! Lossy encodings for float-heavy payloads, so position and rotation streams don't ship full
+//! float precision they don't need.
+//!
+//! The wrappers serialize to smaller representations and can be used as field types directly:
+//!
+//! ```rust,ignore
+//! #[derive(Event, Serialize, Deserialize)]
+//! struct PlayerState {
+//!     position: FixedVec3<100>, // Centimeter precision.
+//!     rotation: CompressedQuat, // 7 bytes instead of 16.
+//!     #[serde(with = "quantize::half")]
+//!     speed: f32, // 2 bytes instead of 4.
+//! }
+//! ```
+//!
+//! Bincode encodes integers with a variable length, so fixed-point values close to zero take
+//! even less room. Payloads that are re-sent with few changes can also enable
+//! `NetworkConfig::delta`.
+//!
+use bevy::math::{Quat, Vec3};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+///
+/// A float stored as a fixed-point integer with a precision of `1 / SCALE`
+/// (e.g. `Fixed<100>` keeps two decimals). Values out of the `i32` range saturate.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Fixed<const SCALE: u32>(pub f32);
+
+impl<const SCALE: u32> Serialize for Fixed<SCALE> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        to_fixed(self.0, SCALE).serialize(serializer)
+    }
+}
+
+impl<'de, const SCALE: u32> Deserialize<'de> for Fixed<SCALE> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        i32::deserialize(deserializer).map(|value| Self(from_fixed(value, SCALE)))
+    }
+}
+
+///
+/// A `Vec3` stored as three fixed-point integers, see `Fixed`.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FixedVec3<const SCALE: u32>(pub Vec3);
+
+impl<const SCALE: u32> Serialize for FixedVec3<SCALE> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        [
+            to_fixed(self.0.x, SCALE),
+            to_fixed(self.0.y, SCALE),
+            to_fixed(self.0.z, SCALE),
+        ]
+        .serialize(serializer)
+    }
+}
+
+impl<'de, const SCALE: u32> Deserialize<'de> for FixedVec3<SCALE> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let [x, y, z] = <[i32; 3]>::deserialize(deserializer)?;
+        Ok(Self(Vec3::new(
+            from_fixed(x, SCALE),
+            from_fixed(y, SCALE),
+            from_fixed(z, SCALE),
+        )))
+    }
+}
+
+fn to_fixed(value: f32, scale: u32) -> i32 {
+    (value as f64 * scale as f64).round() as i32
+}
+
+fn from_fixed(value: i32, scale: u32) -> f32 {
+    (value as f64 / scale.max(1) as f64) as f32
+}
+
+///
+/// A float stored as an IEEE 754 half-precision float (2 bytes, about 3 significant digits,
+/// up to 65504). Use `#[serde(with = "quantize::half")]` on plain `f32` fields instead.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Half(pub f32);
+
+impl Serialize for Half {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        half::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Half {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        half::deserialize(deserializer).map(Self)
+    }
+}
+
+///
+/// Serde `with` module storing an `f32` field as a half-precision float, see `Half`.
+///
+pub mod half {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        // As bytes, bincode would use up to 3 bytes for a u16.
+        f32_to_f16(*value).to_le_bytes().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        <[u8; 2]>::deserialize(deserializer).map(|bytes| f16_to_f32(u16::from_le_bytes(bytes)))
+    }
+
+    /// Rounds to the nearest half float, ties to even.
+    pub fn f32_to_f16(value: f32) -> u16 {
+        let bits = value.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exponent = ((bits >> 23) & 0xff) as i32;
+        let mantissa = bits & 0x7f_ffff;
+        if exponent == 0xff {
+            let nan = if mantissa != 0 { 0x200 } else { 0 };
+            return sign | 0x7c00 | nan;
+        }
+        let half_exponent = exponent - 127 + 15;
+        if half_exponent >= 0x1f {
+            return sign | 0x7c00; // Too large, infinity.
+        }
+        let (mantissa, shift, exponent_bits) = if half_exponent <= 0 {
+            if half_exponent < -10 {
+                return sign; // Too small, zero.
+            }
+            // Subnormal, the implicit leading bit becomes explicit.
+            (mantissa | 0x80_0000, (14 - half_exponent) as u32, 0)
+        } else {
+            (mantissa, 13, (half_exponent as u32) << 10)
+        };
+        let half = exponent_bits | (mantissa >> shift);
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        // A carry into the exponent still yields the right value (or infinity).
+        let rounded = if remainder > halfway || (remainder == halfway && half & 1 == 1) {
+            half + 1
+        } else {
+            half
+        };
+        sign | rounded as u16
+    }
+
+    pub fn f16_to_f32(half: u16) -> f32 {
+        let sign = ((half & 0x8000) as u32) << 16;
+        let exponent = ((half >> 10) & 0x1f) as u32;
+        let mantissa = (half & 0x3ff) as u32;
+        match (exponent, mantissa) {
+            (0, 0) => f32::from_bits(sign),
+            (0, _) => {
+                let magnitude = mantissa as f32 * f32::powi(2.0, -24);
+                if sign != 0 {
+                    -magnitude
+                } else {
+                    magnitude
+                }
+            },
+            (0x1f, _) => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+            _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+        }
+    }
+}
+
+///
+/// A rotation stored with the "smallest three" encoding: the index of the largest component
+/// and the other three as 16-bit integers, 7 bytes instead of 16 with an error around 0.0001.
+/// The rotation is normalized when encoding.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompressedQuat(pub Quat);
+
+/// Components other than the largest are within ±1/√2.
+const QUAT_SCALE: f32 = i16::MAX as f32 * std::f32::consts::SQRT_2;
+
+impl Serialize for CompressedQuat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let components = self.0.normalize().to_array();
+        let largest = (0..4)
+            .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+            .unwrap_or(3);
+        // q and -q are the same rotation, flip it so the dropped component is positive.
+        let sign = if components[largest] < 0.0 { -1.0 } else { 1.0 };
+        let mut bytes = [0u8; 7];
+        bytes[0] = largest as u8;
+        for (slot, index) in bytes[1..]
+            .chunks_mut(2)
+            .zip((0..4).filter(|index| *index != largest))
+        {
+            let component = (components[index] * sign * QUAT_SCALE).round() as i16;
+            slot.copy_from_slice(&component.to_le_bytes());
+        }
+        bytes.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompressedQuat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = <[u8; 7]>::deserialize(deserializer)?;
+        let largest = (bytes[0] as usize).min(3);
+        let mut components = [0.0; 4];
+        let mut sum_of_squares = 0.0;
+        for (component, index) in bytes[1..]
+            .chunks(2)
+            .zip((0..4).filter(|index| *index != largest))
+        {
+            let component = i16::from_le_bytes([component[0], component[1]]);
+            components[index] = component as f32 / QUAT_SCALE;
+            sum_of_squares += components[index] * components[index];
+        }
+        components[largest] = (1.0 - sum_of_squares).max(0.0).sqrt();
+        Ok(Self(Quat::from_array(components).normalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::half::{f16_to_f32, f32_to_f16};
+
+    #[test]
+    fn half_round_trips_every_value() {
+        for half in 0..=u16::MAX {
+            let value = f16_to_f32(half);
+            if value.is_nan() {
+                assert!(f32_to_f16(value) & 0x7fff > 0x7c00);
+            } else {
+                assert_eq!(f32_to_f16(value), half, "{half:#06x}");
+            }
+        }
+    }
+
+    #[test]
+    fn half_keeps_signed_zeros() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert!(f16_to_f32(0x8000).is_sign_negative());
+        assert_eq!(f16_to_f32(0x8000), 0.0);
+    }
+
+    #[test]
+    fn half_subnormal_boundary() {
+        let smallest_subnormal = f32::powi(2.0, -24);
+        assert_eq!(f32_to_f16(smallest_subnormal), 0x0001);
+        assert_eq!(f32_to_f16(1023.0 * smallest_subnormal), 0x03ff);
+        assert_eq!(f32_to_f16(f32::powi(2.0, -14)), 0x0400);
+        // Half the smallest subnormal ties to zero, anything above rounds up.
+        assert_eq!(f32_to_f16(0.5 * smallest_subnormal), 0x0000);
+        assert_eq!(f32_to_f16(0.75 * smallest_subnormal), 0x0001);
+        assert_eq!(f32_to_f16(0.25 * smallest_subnormal), 0x0000);
+        assert_eq!(f32_to_f16(-0.25 * smallest_subnormal), 0x8000);
+        // Rounding the largest subnormal up carries into the smallest normal.
+        assert_eq!(f32_to_f16(1023.5 * smallest_subnormal), 0x0400);
+    }
+
+    #[test]
+    fn half_overflows_to_infinity() {
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65519.0), 0x7bff);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1.0e9), 0x7c00);
+        assert_eq!(f32_to_f16(-1.0e9), 0xfc00);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+    }
+
+    #[test]
+    fn half_keeps_nan() {
+        let half = f32_to_f16(f32::NAN);
+        assert_eq!(half & 0x7c00, 0x7c00);
+        assert_ne!(half & 0x03ff, 0);
+        assert!(f16_to_f32(half).is_nan());
+    }
+
+    #[test]
+    fn half_rounds_ties_to_even() {
+        let ulp = f32::powi(2.0, -10);
+        assert_eq!(f32_to_f16(1.0 + 0.5 * ulp), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 1.5 * ulp), 0x3c02);
+        assert_eq!(f32_to_f16(1.0 + 0.75 * ulp), 0x3c01);
+        assert_eq!(f32_to_f16(1.0 + 0.25 * ulp), 0x3c00);
+    }
+}
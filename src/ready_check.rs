@@ -0,0 +1,310 @@
+//!
+//! Lobby ready checks: the server asks a set of clients whether they are ready and collects the
+//! answers until everyone answered or the timeout expires.
+//!
+//! Send a `ReadyCheck` event on the server. Selected clients receive `ReadyCheckStarted` and reply
+//! with `AnswerReadyCheck`, and once the check is over `ReadyCheckComplete` is received on the
+//! server and on the participating clients. Clients that didn't answer in time, or disconnected
+//! during the check, are counted as not ready.
+//!
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource, Time,
+};
+use bevy_renet::renet::{Bytes, RenetClient, RenetServer};
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::{
+    client::ClientStopped,
+    control::ControlMessage,
+    server::{ClientFilter, ConnectedClients, ServerStopped},
+    teams::Teams,
+    ClientId, NetworkSet, CONTROL_CHANNEL_ID,
+};
+
+///
+/// Send this event from the server to start a ready check among the clients matching
+/// `participants`. A ready check already running completes right away when a new one starts.
+///
+#[derive(Debug, Event)]
+pub struct ReadyCheck {
+    pub timeout: Duration,
+    pub participants: ClientFilter,
+}
+
+///
+/// Received on a client when the server asks it to answer a ready check, with the time left to
+/// answer (e.g. to display a countdown).
+///
+#[derive(Debug, Clone, Event)]
+pub struct ReadyCheckStarted {
+    pub timeout: Duration,
+}
+
+///
+/// Send this event from a client to answer the pending ready check, ignored when there is none.
+///
+#[derive(Debug, Event)]
+pub struct AnswerReadyCheck {
+    pub ready: bool,
+}
+
+///
+/// The outcome of a ready check, received on the server and on the participating clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ReadyCheckComplete {
+    pub ready: Vec<ClientId>,
+    pub not_ready: Vec<ClientId>,
+}
+
+#[derive(Debug)]
+struct RunningReadyCheck {
+    id: u32,
+    deadline: Duration,
+    participants: Vec<ClientId>,
+    pending: BTreeSet<ClientId>,
+    ready: Vec<ClientId>,
+    not_ready: Vec<ClientId>,
+}
+
+///
+/// The ready check running on the server, if any.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ReadyChecks {
+    running: Option<RunningReadyCheck>,
+    next_id: u32,
+}
+
+impl ReadyChecks {
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Clients that haven't answered the running ready check yet.
+    pub fn pending(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.running
+            .iter()
+            .flat_map(|running| running.pending.iter().copied())
+    }
+
+    pub(crate) fn answer(&mut self, client_id: ClientId, id: u32, ready: bool) {
+        let Some(running) = &mut self.running else {
+            return;
+        };
+        if running.id != id || !running.pending.remove(&client_id) {
+            return;
+        }
+        if ready {
+            running.ready.push(client_id);
+        } else {
+            running.not_ready.push(client_id);
+        }
+    }
+}
+
+///
+/// The ready check a client was asked to answer, if any.
+///
+#[derive(Debug, Default, Resource)]
+pub struct PendingReadyCheck(Option<u32>);
+
+impl PendingReadyCheck {
+    pub fn is_pending(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+///
+/// Client side state and events updated from ready check control messages.
+///
+#[derive(SystemParam)]
+pub struct ClientReadyChecks<'w> {
+    pending: ResMut<'w, PendingReadyCheck>,
+    started: EventWriter<'w, ReadyCheckStarted>,
+    complete: EventWriter<'w, ReadyCheckComplete>,
+}
+
+impl ClientReadyChecks<'_> {
+    pub(crate) fn start(&mut self, id: u32, timeout: Duration) {
+        self.pending.0 = Some(id);
+        self.started.send(ReadyCheckStarted { timeout });
+    }
+
+    pub(crate) fn complete(&mut self, ready: Vec<ClientId>, not_ready: Vec<ClientId>) {
+        self.pending.0 = None;
+        self.complete.send(ReadyCheckComplete { ready, not_ready });
+    }
+}
+
+fn finish(
+    server: &mut RenetServer,
+    connected_clients: &ConnectedClients,
+    mut running: RunningReadyCheck,
+    ready_check_complete_events: &mut EventWriter<ReadyCheckComplete>,
+) {
+    running.not_ready.extend(running.pending);
+    let complete = Bytes::from(
+        ControlMessage::ReadyCheckComplete {
+            ready: running.ready.clone(),
+            not_ready: running.not_ready.clone(),
+        }
+        .encode(),
+    );
+    for client_id in running.participants {
+        if connected_clients.get(client_id).is_some() {
+            server.send_message(client_id.into(), CONTROL_CHANNEL_ID, complete.clone());
+        }
+    }
+    ready_check_complete_events.send(ReadyCheckComplete {
+        ready: running.ready,
+        not_ready: running.not_ready,
+    });
+}
+
+pub fn server_starts_ready_checks(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    teams: Res<Teams>,
+    time: Res<Time>,
+    mut ready_checks: ResMut<ReadyChecks>,
+    mut ready_check_events: EventReader<ReadyCheck>,
+    mut ready_check_complete_events: EventWriter<ReadyCheckComplete>,
+) {
+    for ready_check in ready_check_events.read() {
+        if let Some(running) = ready_checks.running.take() {
+            finish(
+                &mut server,
+                &connected_clients,
+                running,
+                &mut ready_check_complete_events,
+            );
+        }
+        let participants: Vec<ClientId> = connected_clients
+            .iter()
+            .filter(|(client_id, client)| {
+                ready_check
+                    .participants
+                    .matches(**client_id, client, &teams)
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        let id = ready_checks.next_id;
+        ready_checks.next_id = id.wrapping_add(1);
+        let started = Bytes::from(
+            ControlMessage::ReadyCheck {
+                id,
+                timeout: ready_check.timeout,
+            }
+            .encode(),
+        );
+        for client_id in participants.iter() {
+            server.send_message((*client_id).into(), CONTROL_CHANNEL_ID, started.clone());
+        }
+        ready_checks.running = Some(RunningReadyCheck {
+            id,
+            deadline: time.elapsed() + ready_check.timeout,
+            pending: participants.iter().copied().collect(),
+            participants,
+            ready: Vec::new(),
+            not_ready: Vec::new(),
+        });
+    }
+}
+
+pub fn server_completes_ready_checks(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    time: Res<Time>,
+    mut ready_checks: ResMut<ReadyChecks>,
+    mut ready_check_complete_events: EventWriter<ReadyCheckComplete>,
+) {
+    let Some(running) = &mut ready_checks.running else {
+        return;
+    };
+    let disconnected: Vec<ClientId> = running
+        .pending
+        .iter()
+        .copied()
+        .filter(|client_id| connected_clients.get(*client_id).is_none())
+        .collect();
+    for client_id in disconnected {
+        running.pending.remove(&client_id);
+        running.not_ready.push(client_id);
+    }
+    if running.pending.is_empty() || time.elapsed() >= running.deadline {
+        if let Some(running) = ready_checks.running.take() {
+            finish(
+                &mut server,
+                &connected_clients,
+                running,
+                &mut ready_check_complete_events,
+            );
+        }
+    }
+}
+
+pub fn server_resets_ready_checks(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut ready_checks: ResMut<ReadyChecks>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        ready_checks.running = None;
+    }
+}
+
+pub fn client_answers_ready_checks(
+    mut client: ResMut<RenetClient>,
+    mut pending_ready_check: ResMut<PendingReadyCheck>,
+    mut answer_ready_check_events: EventReader<AnswerReadyCheck>,
+) {
+    for answer in answer_ready_check_events.read() {
+        if let Some(id) = pending_ready_check.0.take() {
+            client.send_message(
+                CONTROL_CHANNEL_ID,
+                ControlMessage::ReadyCheckAnswer {
+                    id,
+                    ready: answer.ready,
+                }
+                .encode(),
+            );
+        }
+    }
+}
+
+pub fn client_resets_pending_ready_check(
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut pending_ready_check: ResMut<PendingReadyCheck>,
+) {
+    if client_stopped_events.read().count() > 0 {
+        pending_ready_check.0 = None;
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ReadyChecks>()
+        .init_resource::<PendingReadyCheck>()
+        .add_event::<ReadyCheck>()
+        .add_event::<ReadyCheckStarted>()
+        .add_event::<AnswerReadyCheck>()
+        .add_event::<ReadyCheckComplete>()
+        .add_systems(
+            PostUpdate,
+            (server_starts_ready_checks, server_completes_ready_checks)
+                .chain()
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_resets_ready_checks)
+        .add_systems(
+            PostUpdate,
+            client_answers_ready_checks
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(PostUpdate, client_resets_pending_ready_check);
+}
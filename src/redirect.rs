@@ -0,0 +1,205 @@
+//!
+//! Hand-offs of single clients to another server (zone servers, match servers): the server tells
+//! a client where to go, and the client disconnects and connects there on its own.
+//!
+//! Send `RedirectClient` on the server. The client receives `Redirecting`, disconnects and
+//! connects to the new address with the same settings and client id, handing `token` to the new
+//! server in its user data (read it there with `ServerClients::redirect_token`, e.g. to check a
+//! ticket signed by the first server). `Redirected` or `RedirectFailed` is received once the new
+//! connection is established or given up on. The first server sees the client leave with
+//! `DisconnectReason::Redirected`.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource,
+};
+use bevy_renet::renet::RenetServer;
+use renet::transport::NetcodeClientTransport;
+
+use std::net::SocketAddr;
+
+use crate::{
+    client::{
+        client_disconnects_from_server, client_initiates_connection_to_server,
+        client_receives_control_messages, ActiveConnection, DisconnectFromServer, LocalClientId,
+        PendingConnectToServer,
+    },
+    control::ControlMessage,
+    error::{ConfigError, NetworkError, ServerNetworkError},
+    server::{server_disconnects_clients, ConnectedClients, DisconnectClient},
+    user_data, ClientId, DisconnectReason, CONTROL_CHANNEL_ID,
+};
+
+/// Longest `RedirectClient::token`, it shares the user data with the crate's connection metadata.
+pub const MAX_REDIRECT_TOKEN_BYTES: usize = user_data::MAX_REDIRECT_TOKEN_BYTES;
+
+///
+/// Send this event from the server to move a connected client to the server at `addr`, with an
+/// optional token for that server (at most `MAX_REDIRECT_TOKEN_BYTES`).
+///
+#[derive(Debug, Event)]
+pub struct RedirectClient {
+    pub client_id: ClientId,
+    pub addr: SocketAddr,
+    pub token: Option<Vec<u8>>,
+}
+
+///
+/// Received on a client when the server moves it to `addr`, right before the client disconnects
+/// to connect there.
+///
+#[derive(Debug, Clone, Event)]
+pub struct Redirecting {
+    pub addr: SocketAddr,
+    pub token: Option<Vec<u8>>,
+}
+
+///
+/// Received on a client once connected to the server it was redirected to.
+///
+#[derive(Debug, Clone, Event)]
+pub struct Redirected {
+    pub addr: SocketAddr,
+}
+
+///
+/// Received on a client when the server it was redirected to couldn't be connected to, or the
+/// connection was abandoned before it was established.
+///
+#[derive(Debug, Clone, Event)]
+pub struct RedirectFailed {
+    pub addr: SocketAddr,
+}
+
+///
+/// The address a client is being redirected to, if any.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientRedirect(Option<SocketAddr>);
+
+impl ClientRedirect {
+    pub fn is_redirecting(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn target(&self) -> Option<SocketAddr> {
+        self.0
+    }
+}
+
+pub fn server_redirects_clients(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    mut redirect_client_events: EventReader<RedirectClient>,
+    mut disconnect_client_events: EventWriter<DisconnectClient>,
+    mut network_errors: EventWriter<ServerNetworkError>,
+) {
+    for RedirectClient {
+        client_id,
+        addr,
+        token,
+    } in redirect_client_events.read()
+    {
+        if connected_clients.get(*client_id).is_none() {
+            continue;
+        }
+        if let Some(token) = token
+            .as_ref()
+            .filter(|token| token.len() > MAX_REDIRECT_TOKEN_BYTES)
+        {
+            network_errors.send(ServerNetworkError(NetworkError::Config(
+                ConfigError::RedirectTokenTooLong(token.len()),
+            )));
+            continue;
+        }
+        server.send_message(
+            (*client_id).into(),
+            CONTROL_CHANNEL_ID,
+            ControlMessage::Redirect {
+                addr: *addr,
+                token: token.clone(),
+            }
+            .encode(),
+        );
+        // Disconnects the client in case it doesn't leave on its own.
+        disconnect_client_events.send(DisconnectClient {
+            client_id: *client_id,
+            reason: DisconnectReason::Redirected,
+        });
+    }
+}
+
+pub fn client_follows_redirects(
+    mut redirecting_events: EventReader<Redirecting>,
+    local_client_id: Option<Res<LocalClientId>>,
+    mut active_connection: ResMut<ActiveConnection>,
+    mut pending_connect_to_server: ResMut<PendingConnectToServer>,
+    mut client_redirect: ResMut<ClientRedirect>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+) {
+    let Some(Redirecting { addr, token }) = redirecting_events.read().last() else {
+        return;
+    };
+    let Some(mut connect_to_server) = active_connection.0.take() else {
+        return;
+    };
+    connect_to_server.server_ip = addr.ip().to_string();
+    connect_to_server.server_port = addr.port();
+    connect_to_server.client_id = local_client_id.map(|local_client_id| local_client_id.0);
+    connect_to_server.redirect_token = token.clone();
+    // The fallbacks were meant for the previous server.
+    connect_to_server.fallback_addrs.clear();
+    // A connection requested by the app in the meantime wins over the redirect.
+    if !pending_connect_to_server.is_pending() {
+        pending_connect_to_server.0 = Some(connect_to_server);
+        client_redirect.0 = Some(*addr);
+    }
+    disconnect_from_server_events.send(DisconnectFromServer);
+}
+
+pub fn client_tracks_redirect(
+    transport: Option<Res<NetcodeClientTransport>>,
+    pending_connect_to_server: Res<PendingConnectToServer>,
+    mut client_redirect: ResMut<ClientRedirect>,
+    mut redirected_events: EventWriter<Redirected>,
+    mut redirect_failed_events: EventWriter<RedirectFailed>,
+) {
+    let Some(addr) = client_redirect.0 else {
+        return;
+    };
+    match transport {
+        Some(transport) if transport.is_connected() => {
+            redirected_events.send(Redirected { addr });
+        },
+        Some(transport) if transport.is_disconnected() => {
+            redirect_failed_events.send(RedirectFailed { addr });
+        },
+        None if !pending_connect_to_server.is_pending() => {
+            redirect_failed_events.send(RedirectFailed { addr });
+        },
+        _ => return,
+    }
+    client_redirect.0 = None;
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ClientRedirect>()
+        .add_event::<RedirectClient>()
+        .add_event::<Redirecting>()
+        .add_event::<Redirected>()
+        .add_event::<RedirectFailed>()
+        .add_systems(
+            PostUpdate,
+            server_redirects_clients
+                .before(server_disconnects_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (client_tracks_redirect, client_follows_redirects)
+                .chain()
+                .after(client_receives_control_messages)
+                .before(client_disconnects_from_server)
+                .before(client_initiates_connection_to_server),
+        );
+}
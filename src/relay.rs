@@ -0,0 +1,424 @@
+//!
+//! Messages sent by a client to another client, relayed by the server.
+//!
+//! Clients send `SendToClientViaServer<T>` events, the server checks its `RelayPolicy` and
+//! forwards the message to the target client, which receives a `ReceiveFromClientViaServer<T>`
+//! event. Relayed messages travel on a reserved reliable channel, the server never decodes them.
+//!
+//! Messages sent with a `receipt` id are acknowledged: the sender gets a `DeliveredToTarget` event
+//! once the target received the message, or a `TargetOffline` event if the server couldn't relay
+//! it (target not connected, or message refused by the `RelayPolicy` or `MutedClientEvents`).
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, Res,
+    ResMut, Resource,
+};
+use bevy::utils::{HashMap, HashSet};
+use bevy_renet::renet::{RenetClient, RenetServer};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::any::TypeId;
+use std::time::Duration;
+
+use crate::{
+    codec,
+    error::{ClientNetworkError, NetworkError},
+    mute::MutedClientEvents,
+    server::ConnectedClients,
+    ChannelRegistry, ClientId, ConnectionMode, DeterministicOrdering, MessageDecodeError,
+    NetworkConfigs, NetworkSet, RELAY_CHANNEL_ID,
+};
+
+/// Upper bound on the memory decoding a relayed message may claim, the memory renet gives the
+/// relay channel, so that a crafted length prefix can't make a peer allocate huge buffers.
+const MAX_RELAY_MESSAGE_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RelayPayload {
+    /// From the sender to the server.
+    Send {
+        channel_id: u8,
+        target: ClientId,
+        receipt: Option<u64>,
+        payload: Vec<u8>,
+    },
+    /// From the server to the target.
+    Deliver {
+        channel_id: u8,
+        sender: ClientId,
+        receipt: Option<u64>,
+        payload: Vec<u8>,
+    },
+    /// From the target to the server, then from the server to the sender.
+    Delivered { client_id: ClientId, receipt: u64 },
+    /// From the server to the sender.
+    Undeliverable { target: ClientId, receipt: u64 },
+}
+
+impl RelayPayload {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard().with_limit::<MAX_RELAY_MESSAGE_BYTES>();
+        bincode::serde::decode_from_slice(bytes, config)
+            .ok()
+            .map(|(payload, _)| payload)
+    }
+}
+
+///
+/// Send this event from a client to have the server forward `content` to another client.
+///
+#[derive(Debug, Event)]
+pub struct SendToClientViaServer<T: Event + Serialize + DeserializeOwned> {
+    pub target_client_id: ClientId,
+    pub content: T,
+    /// Id echoed back in the `DeliveredToTarget` or `TargetOffline` event, no receipt is sent
+    /// when `None`.
+    pub receipt: Option<u64>,
+}
+
+///
+/// A message another client sent to this one through the server.
+///
+#[derive(Debug, Event)]
+pub struct ReceiveFromClientViaServer<T: Event + Serialize + DeserializeOwned> {
+    /// The client that sent the message.
+    pub client_id: ClientId,
+    pub content: T,
+    /// When the sender sent the message (since the UNIX epoch, by the sender's clock).
+    /// Only available when `NetworkConfig::timestamps` is enabled for this type.
+    pub sent_at: Option<Duration>,
+    pub received_at: Duration,
+}
+
+///
+/// The target of a `SendToClientViaServer` with a `receipt` received the message.
+///
+#[derive(Debug, Clone, Event)]
+pub struct DeliveredToTarget {
+    pub target_client_id: ClientId,
+    pub receipt: u64,
+}
+
+///
+/// The server couldn't relay a `SendToClientViaServer` with a `receipt`: the target isn't
+/// connected, or the server's `RelayPolicy` refused the message (deliberately indistinguishable,
+/// so senders can't tell they are blocked).
+///
+#[derive(Debug, Clone, Event)]
+pub struct TargetOffline {
+    pub target_client_id: ClientId,
+    pub receipt: u64,
+}
+
+type RelayPolicyCallback = Box<dyn Fn(ClientId, ClientId, &'static str) -> bool + Send + Sync>;
+
+///
+/// Which client to client messages the server relays. Nothing is relayed by default, allow
+/// types with `allow::<T>()`.
+///
+/// Spectators can only relay types whose `NetworkConfig` has `accept_from_spectators` enabled.
+///
+#[derive(Default, Resource)]
+pub struct RelayPolicy {
+    allowed: HashSet<TypeId>,
+    blocked: HashMap<ClientId, HashSet<ClientId>>,
+    callback: Option<RelayPolicyCallback>,
+}
+
+impl RelayPolicy {
+    pub fn allow<T: 'static>(&mut self) {
+        self.allowed.insert(TypeId::of::<T>());
+    }
+
+    pub fn disallow<T: 'static>(&mut self) {
+        self.allowed.remove(&TypeId::of::<T>());
+    }
+
+    pub fn is_allowed<T: 'static>(&self) -> bool {
+        self.allowed.contains(&TypeId::of::<T>())
+    }
+
+    /// Stops relaying messages from `blocked_client_id` to `client_id`, persists across
+    /// reconnections.
+    pub fn block(&mut self, client_id: ClientId, blocked_client_id: ClientId) {
+        self.blocked
+            .entry(client_id)
+            .or_default()
+            .insert(blocked_client_id);
+    }
+
+    pub fn unblock(&mut self, client_id: ClientId, blocked_client_id: ClientId) {
+        if let Some(blocked) = self.blocked.get_mut(&client_id) {
+            blocked.remove(&blocked_client_id);
+        }
+    }
+
+    pub fn is_blocked(&self, client_id: ClientId, blocked_client_id: ClientId) -> bool {
+        self.blocked
+            .get(&client_id)
+            .is_some_and(|blocked| blocked.contains(&blocked_client_id))
+    }
+
+    ///
+    /// Sets a callback run on every message of an allowed type, with the sender, the target and
+    /// the type name. Returning `false` drops the message, which is where block lists go.
+    ///
+    pub fn set_callback(
+        &mut self,
+        callback: impl Fn(ClientId, ClientId, &'static str) -> bool + Send + Sync + 'static,
+    ) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    pub fn clear_callback(&mut self) {
+        self.callback = None;
+    }
+
+    fn permits(
+        &self,
+        sender: ClientId,
+        target: ClientId,
+        type_id: TypeId,
+        type_name: &'static str,
+    ) -> bool {
+        self.allowed.contains(&type_id)
+            && !self.is_blocked(target, sender)
+            && self
+                .callback
+                .as_ref()
+                .is_none_or(|callback| callback(sender, target, type_name))
+    }
+}
+
+impl std::fmt::Debug for RelayPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RelayPolicy")
+            .field("allowed", &self.allowed)
+            .field("blocked", &self.blocked)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
+
+///
+/// Relayed messages received this tick, per channel, until the typed systems decode them.
+///
+#[derive(Debug, Default, Resource)]
+pub struct RelayInbox(HashMap<u8, Vec<(ClientId, Vec<u8>)>>);
+
+pub fn server_relays_client_messages(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    channel_registry: Res<ChannelRegistry>,
+    relay_policy: Res<RelayPolicy>,
+    muted_client_events: Res<MutedClientEvents>,
+) {
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, RELAY_CHANNEL_ID) {
+            let sender = ClientId::from(client_id);
+            let Some(client) = connected_clients.get(sender) else {
+                continue;
+            };
+            match RelayPayload::decode(&message) {
+                Some(RelayPayload::Send {
+                    channel_id,
+                    target,
+                    receipt,
+                    payload,
+                }) => {
+                    let Some(registered) = channel_registry.get(channel_id) else {
+                        continue;
+                    };
+                    if client.mode == ConnectionMode::Spectator
+                        && !registered.config.accept_from_spectators
+                    {
+                        continue;
+                    }
+                    let relayed = target != sender
+                        && connected_clients.get(target).is_some()
+                        && !muted_client_events.is_type_muted_from(sender, registered.type_id)
+                        && !muted_client_events.is_type_muted_to(target, registered.type_id)
+                        && relay_policy.permits(
+                            sender,
+                            target,
+                            registered.type_id,
+                            registered.type_name,
+                        );
+                    if relayed {
+                        let deliver = RelayPayload::Deliver {
+                            channel_id,
+                            sender,
+                            receipt,
+                            payload,
+                        };
+                        server.send_message(target.into(), RELAY_CHANNEL_ID, deliver.encode());
+                    } else if let Some(receipt) = receipt {
+                        let undeliverable = RelayPayload::Undeliverable { target, receipt };
+                        server.send_message(client_id, RELAY_CHANNEL_ID, undeliverable.encode());
+                    }
+                },
+                // The target acknowledges, pass it on to the original sender.
+                Some(RelayPayload::Delivered {
+                    client_id: original_sender,
+                    receipt,
+                }) if connected_clients.get(original_sender).is_some() => {
+                    let delivered = RelayPayload::Delivered {
+                        client_id: sender,
+                        receipt,
+                    };
+                    server.send_message(
+                        original_sender.into(),
+                        RELAY_CHANNEL_ID,
+                        delivered.encode(),
+                    );
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+pub fn client_sends_messages_via_server<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    mut client: ResMut<RenetClient>,
+    channel_configs: Res<NetworkConfigs>,
+    mut send_to_client_via_server_events: EventReader<SendToClientViaServer<T>>,
+) {
+    let channel_config = &channel_configs.0[I as usize];
+    for message in send_to_client_via_server_events.read() {
+        let send = RelayPayload::Send {
+            channel_id: I,
+            target: message.target_client_id,
+            receipt: message.receipt,
+            payload: codec::encode(channel_config, &message.content).to_vec(),
+        };
+        client.send_message(RELAY_CHANNEL_ID, send.encode());
+    }
+}
+
+pub fn client_receives_relayed_messages(
+    mut client: ResMut<RenetClient>,
+    mut relay_inbox: ResMut<RelayInbox>,
+    mut delivered_events: EventWriter<DeliveredToTarget>,
+    mut target_offline_events: EventWriter<TargetOffline>,
+) {
+    // Messages left over for types this client didn't register are dropped.
+    relay_inbox.0.clear();
+    while let Some(message) = client.receive_message(RELAY_CHANNEL_ID) {
+        match RelayPayload::decode(&message) {
+            Some(RelayPayload::Deliver {
+                channel_id,
+                sender,
+                receipt,
+                payload,
+            }) => {
+                if let Some(receipt) = receipt {
+                    let delivered = RelayPayload::Delivered {
+                        client_id: sender,
+                        receipt,
+                    };
+                    client.send_message(RELAY_CHANNEL_ID, delivered.encode());
+                }
+                relay_inbox
+                    .0
+                    .entry(channel_id)
+                    .or_default()
+                    .push((sender, payload));
+            },
+            Some(RelayPayload::Delivered { client_id, receipt }) => {
+                delivered_events.send(DeliveredToTarget {
+                    target_client_id: client_id,
+                    receipt,
+                });
+            },
+            Some(RelayPayload::Undeliverable { target, receipt }) => {
+                target_offline_events.send(TargetOffline {
+                    target_client_id: target,
+                    receipt,
+                });
+            },
+            _ => {},
+        }
+    }
+}
+
+pub fn client_receives_messages_via_server<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    mut relay_inbox: ResMut<RelayInbox>,
+    channel_configs: Res<NetworkConfigs>,
+    deterministic_ordering: Res<DeterministicOrdering>,
+    mut receive_events: EventWriter<ReceiveFromClientViaServer<T>>,
+    mut decode_error_events: EventWriter<MessageDecodeError>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+) {
+    let channel_config = &channel_configs.0[I as usize];
+    let mut received: Vec<_> = relay_inbox
+        .0
+        .remove(&I)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(client_id, payload)| (client_id, codec::decode(channel_config, &payload)))
+        .collect();
+    if deterministic_ordering.0 {
+        received.sort_by_key(|(client_id, decoded)| {
+            (
+                *client_id,
+                decoded.as_ref().ok().and_then(|decoded| decoded.sent_at),
+            )
+        });
+    }
+    for (client_id, decoded) in received {
+        match decoded {
+            Ok(decoded) => {
+                receive_events.send(ReceiveFromClientViaServer {
+                    client_id,
+                    content: decoded.content,
+                    sent_at: decoded.sent_at,
+                    received_at: codec::now(),
+                });
+            },
+            Err(kind) => {
+                network_errors.send(ClientNetworkError(NetworkError::Decode {
+                    client_id: Some(client_id),
+                    channel_id: I,
+                    kind: kind.clone(),
+                }));
+                decode_error_events.send(MessageDecodeError {
+                    client_id: Some(client_id),
+                    channel_id: I,
+                    kind,
+                });
+            },
+        }
+    }
+}
+
+pub(crate) fn add_relay_event<const I: u8, T: Event + Serialize + DeserializeOwned>(app: &mut App) {
+    app.add_event::<SendToClientViaServer<T>>()
+        .add_event::<ReceiveFromClientViaServer<T>>()
+        .add_systems(
+            PostUpdate,
+            (
+                client_sends_messages_via_server::<I, T>.in_set(NetworkSet::Send),
+                client_receives_messages_via_server::<I, T>.after(client_receives_relayed_messages),
+            )
+                .run_if(resource_exists::<RenetClient>),
+        );
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<RelayPolicy>()
+        .init_resource::<RelayInbox>()
+        .add_event::<DeliveredToTarget>()
+        .add_event::<TargetOffline>()
+        .add_systems(
+            PostUpdate,
+            server_relays_client_messages.run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            client_receives_relayed_messages.run_if(resource_exists::<RenetClient>),
+        );
+}
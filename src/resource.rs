@@ -0,0 +1,130 @@
+//!
+//! Server resources mirrored on the clients, sent whenever they change but no more often than a
+//! minimum interval.
+//!
+use bevy::prelude::{
+    not, resource_exists, App, Commands, DetectChanges, Event, EventReader, EventWriter,
+    IntoSystemConfigs, PostUpdate, Res, ResMut, Resource, Time,
+};
+use bevy_renet::renet::{RenetClient, RenetServer};
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::{
+    client::ReceiveFromServer,
+    server::{ClientConnected, ClientReconnected, SendToClient, SendToClients},
+    NetworkSet,
+};
+
+///
+/// Replication state of the resource R, see `add_replicated_resource`.
+///
+#[derive(Debug, Resource)]
+pub struct ReplicatedResource<R> {
+    /// Changes within this interval of the last broadcast are coalesced, only the latest value
+    /// is sent once the interval is over.
+    pub min_interval: Duration,
+    last_sent: Option<Duration>,
+    dirty: bool,
+    _resource: PhantomData<R>,
+}
+
+impl<R> ReplicatedResource<R> {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_sent: None,
+            dirty: false,
+            _resource: PhantomData,
+        }
+    }
+
+    /// Whether a change is waiting for the interval to elapse.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+}
+
+pub fn server_broadcasts_resource_changes<
+    R: Resource + Event + Clone + Serialize + DeserializeOwned,
+>(
+    resource: Option<Res<R>>,
+    time: Res<Time>,
+    mut replicated_resource: ResMut<ReplicatedResource<R>>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    mut send_to_client_events: EventWriter<SendToClient<R>>,
+    mut send_to_clients_events: EventWriter<SendToClients<R>>,
+) {
+    let Some(resource) = resource else {
+        return;
+    };
+    let new_client_ids = client_connected_events
+        .read()
+        .map(|client_connected| client_connected.client_id)
+        .chain(
+            client_reconnected_events
+                .read()
+                .map(|client_reconnected| client_reconnected.client_id),
+        );
+    for client_id in new_client_ids {
+        send_to_client_events.send(SendToClient {
+            client_id,
+            content: resource.clone(),
+        });
+    }
+    if resource.is_changed() {
+        replicated_resource.dirty = true;
+    }
+    let now = time.elapsed();
+    let interval_elapsed = replicated_resource
+        .last_sent
+        .is_none_or(|last_sent| now.saturating_sub(last_sent) >= replicated_resource.min_interval);
+    if replicated_resource.dirty && interval_elapsed {
+        send_to_clients_events.send(SendToClients {
+            content: resource.clone(),
+        });
+        replicated_resource.last_sent = Some(now);
+        replicated_resource.dirty = false;
+    }
+}
+
+pub fn client_applies_resource_changes<
+    R: Resource + Event + Clone + Serialize + DeserializeOwned,
+>(
+    mut receive_from_server_events: EventReader<ReceiveFromServer<R>>,
+    mut commands: Commands,
+) {
+    if let Some(received) = receive_from_server_events.read().last() {
+        commands.insert_resource(received.content.clone());
+    }
+}
+
+///
+/// Mirrors the server's resource R on the clients: the whole resource is sent to clients as they
+/// connect and to every client when it changes, at most once per `min_interval`.
+///
+/// R must also be registered as a network type (e.g. in `client_server_events_plugin!`), its
+/// `NetworkConfig` decides how it is sent. Clients that also run the server (listen servers)
+/// keep the server's resource.
+///
+pub fn add_replicated_resource<R: Resource + Event + Clone + Serialize + DeserializeOwned>(
+    app: &mut App,
+    min_interval: Duration,
+) {
+    app.insert_resource(ReplicatedResource::<R>::new(min_interval))
+        .add_systems(
+            PostUpdate,
+            server_broadcasts_resource_changes::<R>
+                .before(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            client_applies_resource_changes::<R>
+                .run_if(resource_exists::<RenetClient>)
+                .run_if(not(resource_exists::<RenetServer>)),
+        );
+}
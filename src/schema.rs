@@ -0,0 +1,269 @@
+//!
+//! Exports the registered types as a proto3 schema, for services written in other languages that
+//! need to read or write the same messages.
+//!
+//! The schema is traced from the types' serde implementations, it describes their fields as seen
+//! by serde (renames included). Services can parse the messages of types sent with
+//! `NetworkConfig::json` through proto3's JSON mapping (e.g. Go's `protojson`), with the content
+//! under the `content` key. Enums become a message with one field per variant in a `oneof`,
+//! which matches serde's JSON for variants with data, unit variants are sent as bare strings.
+//!
+//! ```rust,ignore
+//! fn export_schema(channel_registry: Res<ChannelRegistry>) {
+//!     let proto = schema::proto_schema(&channel_registry, "my_game").unwrap();
+//!     std::fs::write("my_game.proto", proto).unwrap();
+//! }
+//! ```
+//!
+use serde::de::DeserializeOwned;
+use serde_reflection::{ContainerFormat, Format, Named, Tracer, TracerConfig, VariantFormat};
+
+use std::fmt::{self, Write};
+
+use crate::ChannelRegistry;
+
+pub(crate) type TraceFn = fn(&mut Tracer) -> serde_reflection::Result<Format>;
+
+/// Stands in for the trace of channels rebuilt through reflection, see the `inspector` module.
+#[cfg(feature = "inspector")]
+pub(crate) fn untraced() -> TraceFn {
+    trace::<()>
+}
+
+pub(crate) fn trace<T: DeserializeOwned>(tracer: &mut Tracer) -> serde_reflection::Result<Format> {
+    tracer.trace_simple_type::<T>().map(|(format, _)| format)
+}
+
+/// The serde layout traced by `trace` with the containers it uses, see the `handshake` module.
+pub(crate) fn layout(trace: TraceFn) -> Option<String> {
+    let mut tracer = Tracer::new(TracerConfig::default());
+    let format = trace(&mut tracer).ok()?;
+    let containers = tracer.registry().ok()?;
+    Some(format!("{:?} {:?}", format, containers))
+}
+
+///
+/// A registered type whose layout couldn't be traced, usually because its `Deserialize`
+/// implementation rejects the placeholder values used for tracing.
+///
+#[derive(Debug, Clone)]
+pub struct SchemaError {
+    pub type_name: &'static str,
+    pub error: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not trace {}: {}", self.type_name, self.error)
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+///
+/// Describes every type registered in `channel_registry` (and the types they contain) as proto3
+/// messages in `package`, each registered type annotated with its channel.
+///
+pub fn proto_schema(
+    channel_registry: &ChannelRegistry,
+    package: &str,
+) -> Result<String, SchemaError> {
+    let mut tracer = Tracer::new(TracerConfig::default());
+    let mut roots = Vec::new();
+    for registered in channel_registry.iter() {
+        let format = (registered.trace)(&mut tracer).map_err(|error| SchemaError {
+            type_name: registered.type_name,
+            error: error.to_string(),
+        })?;
+        roots.push((registered.channel_id, registered.type_name, format));
+    }
+    let containers = tracer.registry().map_err(|error| SchemaError {
+        type_name: "registered types",
+        error: error.to_string(),
+    })?;
+
+    let mut out = String::new();
+    writeln!(out, "syntax = \"proto3\";\n\npackage {};\n", package).unwrap();
+    for (channel_id, type_name, format) in roots {
+        match format {
+            Format::TypeName(name) => {
+                writeln!(
+                    out,
+                    "// Channel {}: {} is sent as {}.",
+                    channel_id, type_name, name
+                )
+            },
+            _ => writeln!(
+                out,
+                "// Channel {}: {} is not a struct or an enum, it has no message.",
+                channel_id, type_name
+            ),
+        }
+        .unwrap();
+    }
+    let mut messages = ProtoMessages::default();
+    for (name, container) in containers.iter() {
+        messages.container(name, container);
+    }
+    for message in messages.messages {
+        write!(out, "\n{}", message).unwrap();
+    }
+    Ok(out)
+}
+
+///
+/// The generated messages, including the wrappers proto3 needs for nested options, sequences,
+/// maps with message keys and tuples.
+///
+#[derive(Default)]
+struct ProtoMessages {
+    messages: Vec<String>,
+    has_unit: bool,
+}
+
+impl ProtoMessages {
+    fn container(&mut self, name: &str, container: &ContainerFormat) {
+        let fields: Vec<(String, &Format)> = match container {
+            ContainerFormat::UnitStruct => Vec::new(),
+            ContainerFormat::NewTypeStruct(format) => vec![("value".to_string(), format)],
+            ContainerFormat::TupleStruct(formats) => tuple_fields(formats),
+            ContainerFormat::Struct(named) => named_fields(named),
+            ContainerFormat::Enum(variants) => {
+                let mut body = String::new();
+                writeln!(body, "  oneof variant {{").unwrap();
+                for (index, variant) in variants {
+                    let name_variant = format!("{name}{}", variant.name);
+                    let field_type = match &variant.value {
+                        VariantFormat::Unit => self.unit(),
+                        VariantFormat::NewType(format) => self.plain_type(&name_variant, format),
+                        VariantFormat::Tuple(formats) => {
+                            self.message(&name_variant, &tuple_fields(formats));
+                            name_variant
+                        },
+                        VariantFormat::Struct(named) => {
+                            self.message(&name_variant, &named_fields(named));
+                            name_variant
+                        },
+                        VariantFormat::Variable(_) => unreachable!("formats are normalized"),
+                    };
+                    writeln!(body, "    {} {} = {};", field_type, variant.name, index + 1).unwrap();
+                }
+                writeln!(body, "  }}").unwrap();
+                self.messages
+                    .push(format!("message {} {{\n{}}}\n", name, body));
+                return;
+            },
+        };
+        self.message(name, &fields);
+    }
+
+    fn message(&mut self, name: &str, fields: &[(String, &Format)]) {
+        let mut body = String::new();
+        for (number, (field, format)) in fields.iter().enumerate() {
+            let field_type = self.field_type(&format!("{name}{}", capitalized(field)), format);
+            writeln!(body, "  {} {} = {};", field_type, field, number + 1).unwrap();
+        }
+        self.messages
+            .push(format!("message {} {{\n{}}}\n", name, body));
+    }
+
+    /// The type of a field, with its `optional` / `repeated` label.
+    fn field_type(&mut self, wrapper: &str, format: &Format) -> String {
+        match format {
+            Format::Option(inner) => format!("optional {}", self.plain_type(wrapper, inner)),
+            Format::Seq(inner)
+            | Format::TupleArray {
+                content: inner,
+                size: _,
+            } => format!("repeated {}", self.plain_type(wrapper, inner)),
+            Format::Map { key, value } if map_key(key).is_some() => format!(
+                "map<{}, {}>",
+                map_key(key).unwrap(),
+                self.plain_type(wrapper, value)
+            ),
+            Format::Map { key, value } => {
+                let entry = format!("{wrapper}Entry");
+                self.message(
+                    &entry,
+                    &[("key".to_string(), key), ("value".to_string(), value)],
+                );
+                format!("repeated {}", entry)
+            },
+            format => self.plain_type(wrapper, format),
+        }
+    }
+
+    /// A type usable without a label, wrapping the formats that need one in a message.
+    fn plain_type(&mut self, wrapper: &str, format: &Format) -> String {
+        if let Some(scalar) = scalar(format) {
+            return scalar.to_string();
+        }
+        match format {
+            Format::TypeName(name) => name.clone(),
+            Format::Unit => self.unit(),
+            Format::Tuple(formats) => {
+                self.message(wrapper, &tuple_fields(formats));
+                wrapper.to_string()
+            },
+            format => {
+                self.message(wrapper, &[("value".to_string(), format)]);
+                wrapper.to_string()
+            },
+        }
+    }
+
+    fn unit(&mut self) -> String {
+        if !self.has_unit {
+            self.has_unit = true;
+            self.messages.push("message Unit {\n}\n".to_string());
+        }
+        "Unit".to_string()
+    }
+}
+
+fn scalar(format: &Format) -> Option<&'static str> {
+    Some(match format {
+        Format::Bool => "bool",
+        Format::I8 | Format::I16 | Format::I32 => "sint32",
+        Format::I64 => "sint64",
+        Format::U8 | Format::U16 | Format::U32 => "uint32",
+        Format::U64 => "uint64",
+        // Proto has no 128 bit integers.
+        Format::I128 | Format::U128 | Format::Char | Format::Str => "string",
+        Format::F32 => "float",
+        Format::F64 => "double",
+        Format::Bytes => "bytes",
+        _ => return None,
+    })
+}
+
+/// Proto map keys can only be integers, booleans and strings.
+fn map_key(format: &Format) -> Option<&'static str> {
+    match format {
+        Format::F32 | Format::F64 | Format::Bytes => None,
+        format => scalar(format),
+    }
+}
+
+fn tuple_fields(formats: &[Format]) -> Vec<(String, &Format)> {
+    formats
+        .iter()
+        .enumerate()
+        .map(|(index, format)| (format!("_{index}"), format))
+        .collect()
+}
+
+fn named_fields(named: &[Named<Format>]) -> Vec<(String, &Format)> {
+    named
+        .iter()
+        .map(|Named { name, value }| (name.clone(), value))
+        .collect()
+}
+
+fn capitalized(field: &str) -> String {
+    let mut chars = field.trim_start_matches('_').chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
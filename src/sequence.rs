@@ -0,0 +1,185 @@
+//!
+//! Sequencing of unreliable messages: senders stamp a wrapping counter per peer and channel ahead
+//! of the payload, and receivers drop messages older than the latest one received on the same
+//! channel. The arrivals of sequenced channels are tracked for `ChannelHealth`.
+//!
+//! Channels with a `NetworkConfig::dedup_window` also remember the ids of the latest messages
+//! received, to drop the ones delivered twice.
+//!
+use renet::Bytes;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+use crate::NetworkConfig;
+
+/// Size of the sequence number stamped ahead of sequenced payloads.
+const HEADER_BYTES: usize = 4;
+
+///
+/// How a sequenced message arrived compared to the latest one from the same sender.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Arrival {
+    InOrder,
+    Duplicate,
+    OutOfOrder,
+}
+
+///
+/// Ids of the latest messages received, oldest first.
+///
+#[derive(Debug, Default)]
+struct ReceivedIds {
+    order: VecDeque<u32>,
+    ids: HashSet<u32>,
+}
+
+///
+/// Sequence number of the latest message received and of the next one sent per key (channel, or
+/// client and channel), and the ids of the latest messages for deduplicated channels.
+///
+#[derive(Debug)]
+pub(crate) struct Sequences<K> {
+    latest: HashMap<K, u32>,
+    next: HashMap<K, u32>,
+    received_ids: HashMap<K, ReceivedIds>,
+}
+
+impl<K> Default for Sequences<K> {
+    fn default() -> Self {
+        Self {
+            latest: HashMap::new(),
+            next: HashMap::new(),
+            received_ids: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Copy> Sequences<K> {
+    /// Prefixes `payload` with the next sequence number of `key`.
+    pub(crate) fn stamp(&mut self, key: K, payload: &[u8]) -> Bytes {
+        let next = self.next.entry(key).or_default();
+        let mut stamped = Vec::with_capacity(HEADER_BYTES + payload.len());
+        stamped.extend_from_slice(&next.to_le_bytes());
+        stamped.extend_from_slice(payload);
+        *next = next.wrapping_add(1);
+        Bytes::from(stamped)
+    }
+
+    /// Whether a message stamped with `sequence` is newer than everything received so far, the
+    /// latest sequence only moves forward. Sequences wrap around, so newer means less than half
+    /// the counter range ahead.
+    pub(crate) fn arrival(&mut self, key: K, sequence: u32) -> Arrival {
+        match self.latest.get(&key) {
+            Some(latest) if sequence == *latest => Arrival::Duplicate,
+            Some(latest) if (sequence.wrapping_sub(*latest) as i32) < 0 => Arrival::OutOfOrder,
+            _ => {
+                self.latest.insert(key, sequence);
+                Arrival::InOrder
+            },
+        }
+    }
+
+    /// Whether the message was among the last `window` messages received, remembering it if not.
+    pub(crate) fn repeated(&mut self, key: K, message_id: u32, window: usize) -> bool {
+        let received = self.received_ids.entry(key).or_default();
+        if received.ids.contains(&message_id) {
+            return true;
+        }
+        if received.order.len() >= window {
+            if let Some(oldest) = received.order.pop_front() {
+                received.ids.remove(&oldest);
+            }
+        }
+        received.order.push_back(message_id);
+        received.ids.insert(message_id);
+        false
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        let keys: HashSet<_> = self
+            .latest
+            .keys()
+            .chain(self.next.keys())
+            .chain(self.received_ids.keys())
+            .collect();
+        keys.into_iter()
+    }
+
+    /// Forgets what was received, the sequences sent keep counting so that a peer which still
+    /// remembers them doesn't drop the next messages.
+    pub(crate) fn retain_received(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        self.latest.retain(|key, _| keep(key));
+        self.received_ids.retain(|key, _| keep(key));
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        self.next.retain(|key, _| keep(key));
+        self.retain_received(keep);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.latest.clear();
+        self.next.clear();
+        self.received_ids.clear();
+    }
+}
+
+///
+/// Splits the sequence number stamped by `Sequences::stamp` from the payload.
+///
+pub(crate) fn split(payload: &Bytes) -> Option<(u32, Bytes)> {
+    let header = payload.get(..HEADER_BYTES)?;
+    let sequence = u32::from_le_bytes(header.try_into().unwrap());
+    Some((sequence, payload.slice(HEADER_BYTES..)))
+}
+
+///
+/// The payload without its sequence number, for the types stamping one.
+///
+pub(crate) fn unstamped<'a>(config: &NetworkConfig, payload: &'a [u8]) -> &'a [u8] {
+    if config.uses_sequencing() {
+        payload.get(HEADER_BYTES..).unwrap_or_default()
+    } else {
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_ids_are_detected_within_the_window() {
+        let mut sequences = Sequences::default();
+        assert!(!sequences.repeated(0, 10, 2));
+        assert!(sequences.repeated(0, 10, 2));
+        assert!(!sequences.repeated(1, 10, 2));
+        assert!(!sequences.repeated(0, 11, 2));
+        assert!(sequences.repeated(0, 10, 2));
+        // 10 leaves the window.
+        assert!(!sequences.repeated(0, 12, 2));
+        assert!(!sequences.repeated(0, 10, 2));
+        assert!(sequences.repeated(0, 12, 2));
+    }
+
+    #[test]
+    fn forgotten_keys_start_over() {
+        let mut sequences = Sequences::default();
+        assert!(!sequences.repeated(0, 10, 2));
+        assert_eq!(sequences.arrival(0, 5), Arrival::InOrder);
+        sequences.retain_received(|key| *key != 0);
+        assert!(!sequences.repeated(0, 10, 2));
+        assert_eq!(sequences.arrival(0, 0), Arrival::InOrder);
+    }
+
+    #[test]
+    fn arrivals_wrap_around() {
+        let mut sequences = Sequences::default();
+        assert_eq!(sequences.arrival(0, u32::MAX), Arrival::InOrder);
+        assert_eq!(sequences.arrival(0, u32::MAX), Arrival::Duplicate);
+        assert_eq!(sequences.arrival(0, 0), Arrival::InOrder);
+        assert_eq!(sequences.arrival(0, u32::MAX), Arrival::OutOfOrder);
+    }
+}
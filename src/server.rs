@@ -1,15 +1,60 @@
-use bevy::prelude::{Commands, Event, EventReader, EventWriter, Res, ResMut};
+use bevy::ecs::system::SystemParam;
+use bevy::log::{error, warn};
+use bevy::prelude::{
+    Commands, Event, EventReader, EventWriter, Events, Local, Res, ResMut, Resource, Time,
+};
+#[cfg(feature = "inspector")]
+use bevy::prelude::{Reflect, ReflectResource};
+use bevy::tasks::{ComputeTaskPool, ParallelSlice, TaskPool};
+use bevy::utils::{HashMap, HashSet};
 use bevy_renet::renet::{
     transport::{ServerAuthentication, ServerConfig},
-    ClientId, ConnectionConfig, RenetServer,
+    ConnectionConfig, RenetServer,
+};
+use renet::{transport::NetcodeServerTransport, Bytes, ServerEvent};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::any::{type_name, TypeId};
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    codec::{self, PayloadCache},
+    control::ControlMessage,
+    delivery::ServerPendingDeliveries,
+    delta::Baselines,
+    error::{ConfigError, NetworkError, SendErrorReason, ServerNetworkError},
+    filter::{ConnectionFilter, ConnectionFiltered},
+    handshake::Handshake,
+    health::ChannelHealth,
+    key_reload::{KeyReload, ServerKeyFile},
+    keys::load_key_file,
+    matchmaking::{MatchmakingCancelled, MatchmakingQueues, MatchmakingRequested},
+    mute::MutedClientEvents,
+    negotiation::{Negotiation, NegotiationStarted},
+    outbox::{Coalescer, QueuedMessage},
+    ready_check::ReadyChecks,
+    sequence::{self, Arrival, Sequences},
+    sessions::{SessionEvents, SessionId, Sessions},
+    streams::ServerStreams,
+    teams::{ClientTeamChanged, TeamId, Teams},
+    telemetry::ConnectionTelemetry,
+    user_data::{
+        password_hash, read_channels_fingerprint, read_connection_mode, read_password_hash,
+        read_player_id, read_redirect_token, PasswordHash,
+    },
+    ChannelRegistry, ClientId, ConnectionMode, DecodeErrorKind, DenialReason,
+    DeterministicOrdering, DisconnectReason, MessageDecodeError, NetworkConfig, NetworkConfigs,
+    PlayerId, SocketOptions, UnknownChannelMessage, CONTROL_CHANNEL_ID,
 };
-use renet::{transport::NetcodeServerTransport, DisconnectReason, ServerEvent};
-use serde::{de::DeserializeOwned, Serialize};
 
-use std::net::UdpSocket;
-use std::time::SystemTime;
+/// How long a denied client gets to receive its denial reason before being disconnected.
+const DENIAL_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
-use crate::NetworkConfigs;
+/// How often connected clients are sent a heartbeat, see `ServerLiveness`.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Debug, Event)]
 pub struct StartServer {
@@ -19,6 +64,22 @@ pub struct StartServer {
     pub protocol_id: u64,
     pub available_bytes_per_tick: u64,
     pub private_key: Option<[u8; 32]>,
+    /// Loads the private key from this file (see `keys::load_key_file`) when the server starts,
+    /// instead of using `private_key`.
+    pub private_key_path: Option<PathBuf>,
+    /// Watches `private_key_path` and restarts the server with the new key when the file
+    /// changes, see `KeyReload`.
+    pub key_reload: Option<KeyReload>,
+    /// Password clients must provide in `ConnectToServer::password`, others are denied with
+    /// `DenialReason::WrongPassword`. Only a hash is exchanged, but without a `private_key` it
+    /// travels in clear and can be replayed by someone watching the traffic.
+    pub password: Option<String>,
+    /// Address clients connect to, when it differs from the bound `ip:port` (e.g. behind NAT).
+    pub public_addr: Option<SocketAddr>,
+    /// An already bound (and configured) socket to use instead of binding `ip:port`.
+    /// The `ip` and `port` (or `public_addr`) are still advertised as the server's address.
+    pub socket: Option<UdpSocket>,
+    pub socket_options: SocketOptions,
 }
 
 impl Default for StartServer {
@@ -30,6 +91,12 @@ impl Default for StartServer {
             protocol_id: 1,
             available_bytes_per_tick: 60_000,
             private_key: None,
+            private_key_path: None,
+            key_reload: None,
+            password: None,
+            public_addr: None,
+            socket: None,
+            socket_options: SocketOptions::default(),
         }
     }
 }
@@ -38,18 +105,29 @@ impl StartServer {
     fn get_server_and_transport(
         &self,
         channel_configs: NetworkConfigs,
-    ) -> (RenetServer, NetcodeServerTransport) {
+    ) -> Result<(RenetServer, NetcodeServerTransport), ConfigError> {
         let server = RenetServer::new(ConnectionConfig {
             available_bytes_per_tick: self.available_bytes_per_tick,
             server_channels_config: channel_configs.clone().into(),
             client_channels_config: channel_configs.into(),
         });
-        let public_addr = format!("{}:{}", self.ip, self.port).parse().unwrap();
-        let socket = UdpSocket::bind(public_addr).unwrap();
+        let bind_addr = format!("{}:{}", self.ip, self.port);
+        let bind_addr: SocketAddr = bind_addr
+            .parse()
+            .map_err(|_| ConfigError::InvalidAddress(bind_addr))?;
+        let socket = match &self.socket {
+            Some(socket) => socket.try_clone()?,
+            None => UdpSocket::bind(bind_addr)?,
+        };
+        self.socket_options.apply(&socket)?;
         let current_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap();
-        let authentication = if let Some(private_key) = self.private_key {
+        let private_key = match &self.private_key_path {
+            Some(path) => Some(load_key_file(path)?),
+            None => self.private_key,
+        };
+        let authentication = if let Some(private_key) = private_key {
             ServerAuthentication::Secure { private_key }
         } else {
             ServerAuthentication::Unsecure
@@ -58,38 +136,699 @@ impl StartServer {
             current_time,
             max_clients: self.max_clients,
             protocol_id: self.protocol_id,
-            public_addresses: vec![public_addr],
+            public_addresses: vec![self.public_addr.unwrap_or(bind_addr)],
             authentication,
         };
 
-        let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
-        (server, transport)
+        let transport = NetcodeServerTransport::new(server_config, socket)?;
+        Ok((server, transport))
     }
 }
 
 #[derive(Debug, Event)]
 pub struct StopServer;
 
+///
+/// Hash of the running server's password, set from `StartServer::password`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerPassword(Option<PasswordHash>);
+
+///
+/// Sent once the server and its transport are gone after a `StopServer`, the port is free again.
+///
+#[derive(Debug, Event)]
+pub struct ServerStopped;
+
+///
+/// Lifecycle of the server. Stopping takes a tick since the transport can only be removed the
+/// tick after the server, a `StartServer` sent meanwhile is handled once `Stopped`.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Resource)]
+pub enum ServerState {
+    #[default]
+    Stopped,
+    Running,
+    Stopping,
+}
+
+impl ServerState {
+    /// Run condition for systems that should only run in this state.
+    pub fn is(state: ServerState) -> impl Fn(Res<ServerState>) -> bool + Clone {
+        move |current_state: Res<ServerState>| *current_state == state
+    }
+}
+
+///
+/// Stops admitting new clients without affecting the ones already connected.
+/// Clients connecting while paused receive `DenialReason::ServerPaused`.
+///
+#[derive(Debug, Event)]
+pub struct PauseAcceptingConnections;
+
+#[derive(Debug, Event)]
+pub struct ResumeAcceptingConnections;
+
+///
+/// Whether the server currently admits new clients, persists across server restarts.
+///
+#[derive(Debug, Resource)]
+pub struct AcceptingConnections(pub bool);
+
+impl Default for AcceptingConnections {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+/// Clients that were denied, with the elapsed time at which they get disconnected.
+#[derive(Debug, Default, Resource)]
+pub struct DeniedClients(HashMap<ClientId, Duration>);
+
+impl DeniedClients {
+    pub(crate) fn deny(
+        &mut self,
+        server: &mut RenetServer,
+        client_id: ClientId,
+        reason: DenialReason,
+        now: Duration,
+        client_denied_events: &mut EventWriter<ClientDenied>,
+    ) {
+        server.send_message(
+            client_id.into(),
+            CONTROL_CHANNEL_ID,
+            ControlMessage::Denied(reason).encode(),
+        );
+        self.0.insert(client_id, now + DENIAL_GRACE_PERIOD);
+        client_denied_events.send(ClientDenied { client_id, reason });
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().copied()
+    }
+}
+
+///
+/// Players denied with `DenialReason::Banned` when they connect, by player id so that bans outlive
+/// client ids. Banning doesn't disconnect the player, send `DisconnectClient` for that.
+///
+#[derive(Debug, Default, Resource)]
+pub struct BannedPlayers(HashSet<PlayerId>);
+
+impl BannedPlayers {
+    pub fn ban(&mut self, player_id: PlayerId) {
+        self.0.insert(player_id);
+    }
+
+    pub fn unban(&mut self, player_id: PlayerId) {
+        self.0.remove(&player_id);
+    }
+
+    pub fn is_banned(&self, player_id: PlayerId) -> bool {
+        self.0.contains(&player_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PlayerId> {
+        self.0.iter()
+    }
+}
+
+///
+/// Send this event from the server to disconnect a client, telling it why first (see
+/// `DisconnectedFromServer` on the client). `ClientDisconnected` follows with the same reason,
+/// without going through `SessionResumption`.
+///
+#[derive(Debug, Event)]
+pub struct DisconnectClient {
+    pub client_id: ClientId,
+    pub reason: DisconnectReason,
+}
+
+/// Clients disconnected with `DisconnectClient`, with the elapsed time at which they get
+/// disconnected.
+#[derive(Debug, Default, Resource)]
+pub struct DisconnectingClients(HashMap<ClientId, (Duration, DisconnectReason)>);
+
+impl DisconnectingClients {
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().copied()
+    }
+}
+
 #[derive(Debug, Event)]
 pub struct ClientConnected {
-    pub client_id: u64,
+    pub client_id: ClientId,
+    pub mode: ConnectionMode,
+}
+
+///
+/// A connecting client was refused and is being disconnected, it never got a `ClientConnected`.
+///
+#[derive(Debug, Event)]
+pub struct ClientDenied {
+    pub client_id: ClientId,
+    pub reason: DenialReason,
 }
 
 #[derive(Debug, Event)]
 pub struct ClientDisconnected {
-    pub client_id: u64,
+    pub client_id: ClientId,
     pub reason: DisconnectReason,
 }
 
+///
+/// A client lost its connection but may come back within `SessionResumption::grace_period`.
+/// Followed by either `ClientReconnected` or, once the grace period is over, `ClientDisconnected`.
+///
+#[derive(Debug, Event)]
+pub struct ClientConnectionLost {
+    pub client_id: ClientId,
+    pub reason: DisconnectReason,
+}
+
+///
+/// A client came back within the grace period, sent instead of `ClientConnected`.
+///
+#[derive(Debug, Event)]
+pub struct ClientReconnected {
+    pub client_id: ClientId,
+    pub mode: ConnectionMode,
+}
+
+///
+/// A client whose `ConnectToServer::player_id` belongs to a suspended client reconnected under a
+/// new client id. The previous client gets its `ClientDisconnected` and the new one its
+/// `ClientConnected`, this event tells which player state to carry over.
+///
+#[derive(Debug, Event)]
+pub struct PlayerReconnected {
+    pub player_id: PlayerId,
+    pub client_id: ClientId,
+    pub previous_client_id: ClientId,
+}
+
+///
+/// Lets clients that lose their connection resume their session when they reconnect with the
+/// same client id (see `ConnectToServer::resume`) within the grace period.
+/// Clients reconnecting with the same player id but a new client id get `PlayerReconnected`.
+/// Disabled by default, clients are disconnected right away.
+///
+#[derive(Debug, Default, Resource)]
+pub struct SessionResumption {
+    pub grace_period: Option<Duration>,
+}
+
+/// Clients that lost their connection, with the elapsed time at which their session expires.
+#[derive(Debug, Default, Resource)]
+pub struct SuspendedClients(HashMap<ClientId, (Duration, DisconnectReason, Option<PlayerId>)>);
+
+impl SuspendedClients {
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.0.contains_key(&client_id)
+    }
+
+    pub fn player_id(&self, client_id: ClientId) -> Option<PlayerId> {
+        self.0
+            .get(&client_id)
+            .and_then(|(_, _, player_id)| *player_id)
+    }
+
+    fn take_player(&mut self, player_id: PlayerId) -> Option<(ClientId, DisconnectReason)> {
+        let client_id = self
+            .0
+            .iter()
+            .find(|(_, (_, _, suspended_player_id))| *suspended_player_id == Some(player_id))
+            .map(|(client_id, _)| *client_id)?;
+        self.0
+            .remove(&client_id)
+            .map(|(_, reason, _)| (client_id, reason))
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &ClientId> {
+        self.0.keys()
+    }
+}
+
+/// Events sent by `server_tracks_connected_and_disconnected_clients`.
+#[derive(SystemParam)]
+pub struct ConnectionEvents<'w> {
+    connected: EventWriter<'w, ClientConnected>,
+    reconnected: EventWriter<'w, ClientReconnected>,
+    connection_lost: EventWriter<'w, ClientConnectionLost>,
+    disconnected: EventWriter<'w, ClientDisconnected>,
+    filtered: EventWriter<'w, ConnectionFiltered>,
+    pub(crate) denied: EventWriter<'w, ClientDenied>,
+    player_reconnected: EventWriter<'w, PlayerReconnected>,
+    negotiation_started: EventWriter<'w, NegotiationStarted>,
+}
+
+impl ConnectionEvents<'_> {
+    /// Adds a client that passed every check to the connected clients, resuming its session or
+    /// its player's if it had one.
+    pub(crate) fn admit(
+        &mut self,
+        client_id: ClientId,
+        client: ConnectedClient,
+        connected_clients: &mut ConnectedClients,
+        suspended_clients: &mut SuspendedClients,
+    ) {
+        let ConnectedClient { mode, player_id } = client;
+        connected_clients.0.insert(client_id, client);
+        if suspended_clients.0.remove(&client_id).is_some() {
+            self.reconnected.send(ClientReconnected { client_id, mode });
+            return;
+        }
+        let previous = player_id.and_then(|player_id| {
+            suspended_clients
+                .take_player(player_id)
+                .map(|previous| (player_id, previous))
+        });
+        if let Some((player_id, (previous_client_id, reason))) = previous {
+            self.disconnected.send(ClientDisconnected {
+                client_id: previous_client_id,
+                reason,
+            });
+            self.connected.send(ClientConnected { client_id, mode });
+            self.player_reconnected.send(PlayerReconnected {
+                player_id,
+                client_id,
+                previous_client_id,
+            });
+        } else {
+            self.connected.send(ClientConnected { client_id, mode });
+        }
+    }
+}
+
+///
+/// Limits how many bytes of messages are handed to renet for a client every tick, on top of
+/// renet's own per-connection budget. Messages over the limit wait for the next tick.
+/// Setting `bytes_per_tick` to `None` removes the limit.
+///
+#[derive(Debug, Event)]
+pub struct SetClientBandwidth {
+    pub client_id: ClientId,
+    pub bytes_per_tick: Option<u64>,
+}
+
+///
+/// Per-client bandwidth limits set through `SetClientBandwidth`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ClientBandwidth(HashMap<ClientId, u64>);
+
+impl ClientBandwidth {
+    pub fn get(&self, client_id: ClientId) -> Option<u64> {
+        self.0.get(&client_id).copied()
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().copied()
+    }
+}
+
+///
+/// How many bytes of messages are handed to renet every tick for all the members of a session
+/// together, so a busy match can't use up the bandwidth of the others. Applied on top of the
+/// members' own `SetClientBandwidth` limits, messages over a limit wait for the next tick.
+///
+#[derive(Debug, Clone, Default)]
+pub struct SessionBudget {
+    pub bytes_per_tick: Option<u64>,
+    /// Limits for single channels within the session (e.g. to keep chat from crowding out
+    /// inputs), messages on other channels still go through when a channel is over its limit.
+    pub channels: HashMap<u8, u64>,
+}
+
+///
+/// Sets (or with `None`, removes) the budget of an existing session, see `SessionBudget`.
+/// The budget is dropped along with the session.
+///
+#[derive(Debug, Event)]
+pub struct SetSessionBudget {
+    pub session: SessionId,
+    pub budget: Option<SessionBudget>,
+}
+
+///
+/// Per-session budgets set through `SetSessionBudget`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct SessionBudgets(HashMap<SessionId, SessionBudget>);
+
+impl SessionBudgets {
+    pub fn get(&self, session: SessionId) -> Option<&SessionBudget> {
+        self.0.get(&session)
+    }
+}
+
+///
+/// Holds the messages for newly connected clients until they report their connection as
+/// established (or `timeout` expires), so messages sent right after `ClientConnected` aren't
+/// dropped before the client is ready to receive them. Messages are then sent in order.
+///
+#[derive(Debug, Resource)]
+pub struct ClientWarmUp {
+    pub timeout: Duration,
+    warming_up: HashMap<ClientId, Duration>,
+}
+
+impl Default for ClientWarmUp {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            warming_up: HashMap::new(),
+        }
+    }
+}
+
+impl ClientWarmUp {
+    pub fn is_warming_up(&self, client_id: ClientId) -> bool {
+        self.warming_up.contains_key(&client_id)
+    }
+
+    fn start(&mut self, client_id: ClientId, now: Duration) {
+        self.warming_up.insert(client_id, now + self.timeout);
+    }
+
+    fn ready(&mut self, client_id: ClientId) {
+        self.warming_up.remove(&client_id);
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.warming_up.keys().copied()
+    }
+}
+
+///
+/// Encoded messages waiting to be handed to renet, per client.
+/// Filled by the typed send systems and drained by `server_flushes_outbox`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerOutbox {
+    queues: HashMap<ClientId, VecDeque<QueuedMessage>>,
+    coalescer: Coalescer<(ClientId, u8)>,
+    /// The kind of send that first queued each content this tick, see `SendDeduplication`.
+    queued_kinds: HashMap<(ClientId, u8, Bytes), SendKind>,
+}
+
+impl ServerOutbox {
+    fn push(&mut self, client_id: ClientId, config: &NetworkConfig, message: QueuedMessage) {
+        if config.send_interval().is_some() {
+            self.coalescer
+                .offer((client_id, message.channel_id), message);
+        } else {
+            self.queues.entry(client_id).or_default().push_back(message);
+        }
+    }
+
+    /// Number of messages still waiting to be sent to a client.
+    pub fn queued(&self, client_id: ClientId) -> usize {
+        self.queues.get(&client_id).map_or(0, VecDeque::len)
+    }
+
+    /// Clients with queued or coalesced messages, possibly more than once.
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.queues
+            .keys()
+            .copied()
+            .chain(self.coalescer.keys().map(|(client_id, _)| *client_id))
+    }
+
+    fn forget(&mut self, client_id: ClientId) {
+        self.queues.remove(&client_id);
+        self.coalescer
+            .retain(|(queued_client_id, _)| *queued_client_id != client_id);
+        self.queued_kinds
+            .retain(|(queued_client_id, _, _), _| *queued_client_id != client_id);
+    }
+
+    fn clear(&mut self) {
+        self.queues.clear();
+        self.coalescer.clear();
+        self.queued_kinds.clear();
+    }
+}
+
+///
+/// Drops a message queued for a client when the same content was already queued for it on the
+/// same channel this tick by another kind of send (e.g. `SendToClient` and `SendToClients` from
+/// systems reacting to the same thing), so that the client doesn't receive it twice. Sends of the
+/// same kind are never dropped. Disabled by default.
+///
+/// Redundant sends are counted in `RedundantSends`, and logged once per channel in debug builds.
+/// Comparing sends serializes every message's content a second time, so nothing is compared while
+/// disabled.
+///
+#[derive(Debug, Default, Resource)]
+pub struct SendDeduplication(pub bool);
+
+/// The event a message was queued from, see `SendDeduplication`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendKind {
+    Client,
+    Clients,
+    ClientsWhere,
+    Team,
+}
+
+///
+/// Number of messages queued for a client with the same content as one queued by another kind of
+/// send on the same channel and tick, per channel, see `SendDeduplication`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct RedundantSends {
+    counts: HashMap<u8, u64>,
+    logged: HashSet<u8>,
+}
+
+impl RedundantSends {
+    pub fn channel(&self, channel_id: u8) -> u64 {
+        self.counts.get(&channel_id).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+///
+/// The outbox as seen by the typed send systems, comparing their messages when
+/// `SendDeduplication` is enabled.
+///
+#[derive(SystemParam)]
+pub struct DeduplicatedOutbox<'w> {
+    outbox: ResMut<'w, ServerOutbox>,
+    send_deduplication: Res<'w, SendDeduplication>,
+    redundant_sends: ResMut<'w, RedundantSends>,
+}
+
+impl DeduplicatedOutbox<'_> {
+    /// The key comparing `content` with the other sends, `None` when sends aren't compared.
+    fn content_key<T: Serialize>(&self, config: &NetworkConfig, content: &T) -> Option<Bytes> {
+        self.send_deduplication
+            .0
+            .then(|| codec::content_key(config, content))
+    }
+
+    fn push<T>(
+        &mut self,
+        client_id: ClientId,
+        config: &NetworkConfig,
+        message: QueuedMessage,
+        content_key: Option<&Bytes>,
+        kind: SendKind,
+    ) {
+        if let Some(content_key) = content_key {
+            let queued_kind = *self
+                .outbox
+                .queued_kinds
+                .entry((client_id, message.channel_id, content_key.clone()))
+                .or_insert(kind);
+            if queued_kind != kind {
+                let channel_id = message.channel_id;
+                if cfg!(debug_assertions) && self.redundant_sends.logged.insert(channel_id) {
+                    warn!(
+                        "{} sent twice to client {} ({:?} and {:?} sends), see `SendDeduplication`",
+                        type_name::<T>(),
+                        client_id,
+                        queued_kind,
+                        kind
+                    );
+                }
+                *self.redundant_sends.counts.entry(channel_id).or_default() += 1;
+                return;
+            }
+        }
+        self.outbox.push(client_id, config, message);
+    }
+}
+
+///
+/// Number of unreliable messages dropped from the outbox because they outlived their
+/// `NetworkConfig::ttl`, per channel.
+///
+#[derive(Debug, Default, Resource)]
+pub struct DroppedStaleMessages(HashMap<u8, u64>);
+
+impl DroppedStaleMessages {
+    pub fn channel(&self, channel_id: u8) -> u64 {
+        self.0.get(&channel_id).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+///
+/// Received messages carried over to the next ticks because their type's
+/// `NetworkConfig::receive_budget` was used up, per channel.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ReceiveBacklog(HashMap<u8, VecDeque<ReceivedPayload>>);
+
+/// A message's sender, how it arrived if its type is sequenced, and payload once delta decoded.
+type ReceivedPayload = (ClientId, Option<Arrival>, Result<Bytes, DecodeErrorKind>);
+
+impl ReceiveBacklog {
+    /// Number of messages waiting on a channel.
+    pub fn channel(&self, channel_id: u8) -> usize {
+        self.0.get(&channel_id).map_or(0, VecDeque::len)
+    }
+
+    pub fn total(&self) -> usize {
+        self.0.values().map(VecDeque::len).sum()
+    }
+
+    /// Senders of the messages waiting, possibly more than once.
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0
+            .values()
+            .flat_map(|backlog| backlog.iter().map(|(client_id, _, _)| *client_id))
+    }
+}
+
+///
+/// Delta encoding baselines per client and channel, see `NetworkConfig::delta`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerDeltaBaselines(Baselines<(ClientId, u8)>);
+
+impl ServerDeltaBaselines {
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().map(|(client_id, _)| *client_id)
+    }
+}
+
+///
+/// Payloads encoded by one run of `server_sends_messages_to_clients`, shared by the clients
+/// receiving identical contents.
+///
+#[derive(Default)]
+pub struct ServerPayloadCache(PayloadCache);
+
+///
+/// Sequence numbers received from and sent to every client per channel, see
+/// `NetworkConfig::sequenced`.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerSequences(Sequences<(ClientId, u8)>);
+
+impl ServerSequences {
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().map(|(client_id, _)| *client_id)
+    }
+}
+
 #[derive(Debug, Event)]
 pub struct ReceiveFromClient<T: Event + Serialize + DeserializeOwned> {
-    pub client_id: u64,
+    pub client_id: ClientId,
     pub content: T,
+    /// When the client sent the message (since the UNIX epoch, by the client's clock).
+    /// Only available when `NetworkConfig::timestamps` is enabled for this type.
+    pub sent_at: Option<Duration>,
+    /// When the message was received (since the UNIX epoch, by the server's clock).
+    pub received_at: Duration,
+}
+
+///
+/// The newest message of type `T` received from each client this tick, filled instead of
+/// `ReceiveFromClient<T>` events for types with `NetworkConfig::latest_only` enabled.
+///
+#[derive(Debug, Resource)]
+pub struct LatestFromClients<T: Event + Serialize + DeserializeOwned> {
+    latest: HashMap<ClientId, ReceiveFromClient<T>>,
+}
+
+impl<T: Event + Serialize + DeserializeOwned> Default for LatestFromClients<T> {
+    fn default() -> Self {
+        Self {
+            latest: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Event + Serialize + DeserializeOwned> LatestFromClients<T> {
+    pub fn get(&self, client_id: ClientId) -> Option<&ReceiveFromClient<T>> {
+        self.latest.get(&client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ReceiveFromClient<T>> {
+        self.latest.values()
+    }
+
+    /// Takes the messages, so that they are only handled once.
+    pub fn drain(&mut self) -> impl Iterator<Item = ReceiveFromClient<T>> + '_ {
+        self.latest.drain().map(|(_, message)| message)
+    }
+
+    pub fn len(&self) -> usize {
+        self.latest.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_empty()
+    }
+}
+
+///
+/// Messages of type `T` received from the clients, kept until drained instead of
+/// `ReceiveFromClient<T>` events for types with `NetworkConfig::queued` enabled.
+///
+#[derive(Debug, Resource)]
+pub struct QueuedFromClients<T: Event + Serialize + DeserializeOwned>(
+    VecDeque<ReceiveFromClient<T>>,
+);
+
+impl<T: Event + Serialize + DeserializeOwned> Default for QueuedFromClients<T> {
+    fn default() -> Self {
+        Self(VecDeque::new())
+    }
+}
+
+impl<T: Event + Serialize + DeserializeOwned> QueuedFromClients<T> {
+    pub fn iter(&self) -> impl Iterator<Item = &ReceiveFromClient<T>> {
+        self.0.iter()
+    }
+
+    /// Takes the messages in the order they were received.
+    pub fn drain(&mut self) -> impl Iterator<Item = ReceiveFromClient<T>> + '_ {
+        self.0.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Debug, Event)]
 pub struct SendToClient<T: Event + Serialize + DeserializeOwned> {
-    pub client_id: u64,
+    pub client_id: ClientId,
     pub content: T,
 }
 
@@ -98,100 +837,1298 @@ pub struct SendToClients<T: Event + Serialize + DeserializeOwned> {
     pub content: T,
 }
 
+///
+/// Sends type T to the connected clients matching `filter`.
+///
+#[derive(Debug, Event)]
+pub struct SendToClientsWhere<T: Event + Serialize + DeserializeOwned> {
+    pub content: T,
+    pub filter: ClientFilter,
+}
+
+///
+/// Sends type T to the connected members of `team`.
+///
+#[derive(Debug, Event)]
+pub struct SendToTeam<T: Event + Serialize + DeserializeOwned> {
+    pub team: TeamId,
+    pub content: T,
+}
+
+///
+/// Selects connected clients by their id, the metadata in `ConnectedClients` and their team.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientFilter {
+    All,
+    Mode(ConnectionMode),
+    Team(TeamId),
+    Clients(Vec<ClientId>),
+    Not(Box<ClientFilter>),
+    And(Vec<ClientFilter>),
+    Or(Vec<ClientFilter>),
+}
+
+impl ClientFilter {
+    /// Every client except the given one, typically the sender of the message being relayed.
+    pub fn except(client_id: ClientId) -> Self {
+        Self::Not(Box::new(Self::Clients(vec![client_id])))
+    }
+
+    pub fn matches(&self, client_id: ClientId, client: &ConnectedClient, teams: &Teams) -> bool {
+        match self {
+            Self::All => true,
+            Self::Mode(mode) => client.mode == *mode,
+            Self::Team(team) => teams.team_of(client_id) == Some(*team),
+            Self::Clients(client_ids) => client_ids.contains(&client_id),
+            Self::Not(filter) => !filter.matches(client_id, client, teams),
+            Self::And(filters) => filters
+                .iter()
+                .all(|filter| filter.matches(client_id, client, teams)),
+            Self::Or(filters) => filters
+                .iter()
+                .any(|filter| filter.matches(client_id, client, teams)),
+        }
+    }
+}
+
+///
+/// Details the server keeps about each connected client.
+///
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+pub struct ConnectedClient {
+    pub mode: ConnectionMode,
+    /// See `ConnectToServer::player_id`.
+    pub player_id: Option<PlayerId>,
+}
+
+///
+/// All clients currently connected to the server, keyed by client id.
+///
+#[derive(Debug, Default, Resource)]
+#[cfg_attr(feature = "inspector", derive(Reflect), reflect(Resource))]
+pub struct ConnectedClients(HashMap<ClientId, ConnectedClient>);
+
+impl ConnectedClients {
+    pub fn get(&self, client_id: ClientId) -> Option<&ConnectedClient> {
+        self.0.get(&client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &ConnectedClient)> {
+        self.0.iter()
+    }
+
+    pub fn player_id(&self, client_id: ClientId) -> Option<PlayerId> {
+        self.0.get(&client_id).and_then(|client| client.player_id)
+    }
+
+    #[cfg(all(test, any(feature = "admin", feature = "chat", feature = "voice")))]
+    pub(crate) fn insert(&mut self, client_id: ClientId, client: ConnectedClient) {
+        self.0.insert(client_id, client);
+    }
+
+    /// The connected client of the player, a player is connected from one client at most.
+    pub fn client_id(&self, player_id: PlayerId) -> Option<ClientId> {
+        self.0
+            .iter()
+            .find(|(_, client)| client.player_id == Some(player_id))
+            .map(|(client_id, _)| *client_id)
+    }
+}
+
+///
+/// System param giving access to the server's connected clients without depending on renet.
+/// All queries gracefully report no clients when the server isn't running.
+///
+#[derive(SystemParam)]
+pub struct ServerClients<'w> {
+    server: Option<Res<'w, RenetServer>>,
+    transport: Option<Res<'w, NetcodeServerTransport>>,
+    clients: Res<'w, ConnectedClients>,
+}
+
+impl<'w> ServerClients<'w> {
+    /// The clients in `ConnectedClients`, leaving out the ones still negotiating or denied.
+    pub fn ids(&self) -> Vec<ClientId> {
+        self.connected_clients()
+            .map(|clients| clients.0.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.connected_clients()
+            .map_or(0, |clients| clients.0.len())
+    }
+
+    pub fn is_connected(&self, client_id: ClientId) -> bool {
+        self.connected_clients()
+            .is_some_and(|clients| clients.get(client_id).is_some())
+    }
+
+    pub fn addr(&self, client_id: ClientId) -> Option<SocketAddr> {
+        self.transport
+            .as_ref()
+            .and_then(|transport| transport.client_addr(client_id.into()))
+    }
+
+    /// The token the client was given by the server that redirected it, see `RedirectClient`.
+    pub fn redirect_token(&self, client_id: ClientId) -> Option<Vec<u8>> {
+        self.transport
+            .as_ref()
+            .and_then(|transport| transport.user_data(client_id.into()))
+            .and_then(|user_data| read_redirect_token(&user_data))
+    }
+
+    /// Round-trip time to the client, as tracked by renet.
+    pub fn rtt(&self, client_id: ClientId) -> Option<f64> {
+        self.connected_server(client_id)
+            .map(|server| server.rtt(client_id.into()))
+    }
+
+    /// Packet loss ratio (0.0 to 1.0) to the client, as tracked by renet.
+    pub fn packet_loss(&self, client_id: ClientId) -> Option<f64> {
+        self.connected_server(client_id)
+            .map(|server| server.packet_loss(client_id.into()))
+    }
+
+    pub fn mode(&self, client_id: ClientId) -> Option<ConnectionMode> {
+        self.clients.get(client_id).map(|client| client.mode)
+    }
+
+    pub fn player_id(&self, client_id: ClientId) -> Option<PlayerId> {
+        self.clients.player_id(client_id)
+    }
+
+    fn connected_clients(&self) -> Option<&ConnectedClients> {
+        self.server.as_ref().map(|_| &*self.clients)
+    }
+
+    fn connected_server(&self, client_id: ClientId) -> Option<&RenetServer> {
+        self.server
+            .as_deref()
+            .filter(|_| self.is_connected(client_id))
+    }
+}
+
+///
+/// The latest `StartServer` not applied yet, kept until the server is `Stopped` so that requests
+/// sent early (e.g. during `Startup`) or while the previous server stops aren't lost.
+///
+#[derive(Debug, Default, Resource)]
+pub struct PendingStartServer(pub(crate) Option<StartServer>);
+
+impl PendingStartServer {
+    pub fn is_pending(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn server_starts(
-    mut start_server_events: EventReader<StartServer>,
+    mut start_server_events: ResMut<Events<StartServer>>,
+    mut pending_start_server: ResMut<PendingStartServer>,
     channel_configs: Res<NetworkConfigs>,
+    mut server_state: ResMut<ServerState>,
+    mut server_password: ResMut<ServerPassword>,
+    mut server_key_file: ResMut<ServerKeyFile>,
+    mut network_errors: EventWriter<ServerNetworkError>,
     mut commands: Commands,
 ) {
-    for start_server in start_server_events.read() {
-        let (server, transport) = start_server.get_server_and_transport(channel_configs.clone());
+    if let Some(start_server) = start_server_events.drain().last() {
+        pending_start_server.0 = Some(start_server);
+    }
+    if *server_state != ServerState::Stopped {
+        return;
+    }
+    if let Some(start_server) = pending_start_server.0.take() {
+        let (server, transport) =
+            match start_server.get_server_and_transport(channel_configs.clone()) {
+                Ok(server_and_transport) => server_and_transport,
+                Err(error) => {
+                    network_errors.send(ServerNetworkError(NetworkError::Config(error)));
+                    return;
+                },
+            };
         commands.insert_resource(server);
         commands.insert_resource(transport);
+        server_password.0 = start_server
+            .password
+            .as_ref()
+            .map(|password| password_hash(password, start_server.protocol_id));
+        *server_state = ServerState::Running;
+        server_key_file.watch(start_server);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn server_stops(
     mut stop_server_events: EventReader<StopServer>,
     mut server: ResMut<RenetServer>,
     mut transport: ResMut<NetcodeServerTransport>,
+    mut connected_clients: ResMut<ConnectedClients>,
+    mut denied_clients: ResMut<DeniedClients>,
+    mut disconnecting_clients: ResMut<DisconnectingClients>,
+    mut suspended_clients: ResMut<SuspendedClients>,
+    mut server_state: ResMut<ServerState>,
     mut commands: Commands,
 ) {
-    for _ in stop_server_events.read() {
+    if stop_server_events.read().count() > 0 {
+        *server_state = ServerState::Stopping;
+        connected_clients.0.clear();
+        denied_clients.0.clear();
+        disconnecting_clients.0.clear();
+        suspended_clients.0.clear();
         server.disconnect_all();
         transport.disconnect_all(&mut server);
         commands.remove_resource::<RenetServer>();
         // bevy_renet crashes due to missing resource if we remove the transport on this tick.
         // Removing it on the next tick instead (see cleanup_transport).
-        //commands.remove_resource::<NetcodeServerTransport>();
     }
 }
 
+pub fn server_pauses_and_resumes_connections(
+    mut pause_events: EventReader<PauseAcceptingConnections>,
+    mut resume_events: EventReader<ResumeAcceptingConnections>,
+    mut accepting_connections: ResMut<AcceptingConnections>,
+) {
+    if pause_events.read().count() > 0 {
+        accepting_connections.0 = false;
+    }
+    if resume_events.read().count() > 0 {
+        accepting_connections.0 = true;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn server_tracks_connected_and_disconnected_clients(
     mut server_events: EventReader<ServerEvent>,
-    mut client_connected_events: EventWriter<ClientConnected>,
-    mut client_disconnected_events: EventWriter<ClientDisconnected>,
+    mut server: ResMut<RenetServer>,
+    transport: Res<NetcodeServerTransport>,
+    accepting_connections: Res<AcceptingConnections>,
+    connection_filter: Res<ConnectionFilter>,
+    channel_registry: Res<ChannelRegistry>,
+    server_password: Res<ServerPassword>,
+    session_resumption: Res<SessionResumption>,
+    banned_players: Res<BannedPlayers>,
+    time: Res<Time>,
+    mut connected_clients: ResMut<ConnectedClients>,
+    mut denied_clients: ResMut<DeniedClients>,
+    mut disconnecting_clients: ResMut<DisconnectingClients>,
+    mut suspended_clients: ResMut<SuspendedClients>,
+    mut negotiation: ResMut<Negotiation>,
+    mut events: ConnectionEvents,
 ) {
     for server_event in server_events.read() {
         match server_event {
             ServerEvent::ClientConnected { client_id } => {
-                client_connected_events.send(ClientConnected {
-                    client_id: client_id.raw(),
+                let client_id = ClientId::from(*client_id);
+                if let Some(addr) = transport.client_addr(client_id.into()) {
+                    if !connection_filter.is_allowed(addr.ip()) {
+                        events.filtered.send(ConnectionFiltered { addr });
+                        denied_clients.deny(
+                            &mut server,
+                            client_id,
+                            DenialReason::Filtered,
+                            time.elapsed(),
+                            &mut events.denied,
+                        );
+                        continue;
+                    }
+                }
+                if !accepting_connections.0 {
+                    denied_clients.deny(
+                        &mut server,
+                        client_id,
+                        DenialReason::ServerPaused,
+                        time.elapsed(),
+                        &mut events.denied,
+                    );
+                    continue;
+                }
+                let user_data = transport.user_data(client_id.into());
+                let player_id = user_data.and_then(|user_data| read_player_id(&user_data));
+                let player_denial = player_id.and_then(|player_id| {
+                    if banned_players.is_banned(player_id) {
+                        Some(DenialReason::Banned)
+                    } else if connected_clients.client_id(player_id).is_some()
+                        || negotiation.is_negotiating_player(player_id)
+                    {
+                        Some(DenialReason::PlayerAlreadyConnected)
+                    } else {
+                        None
+                    }
                 });
+                if let Some(reason) = player_denial {
+                    denied_clients.deny(
+                        &mut server,
+                        client_id,
+                        reason,
+                        time.elapsed(),
+                        &mut events.denied,
+                    );
+                    continue;
+                }
+                if let Some(hash) = server_password.0 {
+                    if user_data.map(|user_data| read_password_hash(&user_data)) != Some(hash) {
+                        denied_clients.deny(
+                            &mut server,
+                            client_id,
+                            DenialReason::WrongPassword,
+                            time.elapsed(),
+                            &mut events.denied,
+                        );
+                        continue;
+                    }
+                }
+                let fingerprint = user_data
+                    .map(|user_data| read_channels_fingerprint(&user_data))
+                    .unwrap_or(channel_registry.fingerprint());
+                if fingerprint.count != channel_registry.fingerprint().count {
+                    error!(
+                        "Client {} registered {} network types, the server registered {}: {:?}",
+                        client_id,
+                        fingerprint.count,
+                        channel_registry.fingerprint().count,
+                        channel_registry.type_names()
+                    );
+                    denied_clients.deny(
+                        &mut server,
+                        client_id,
+                        DenialReason::ChannelMismatch,
+                        time.elapsed(),
+                        &mut events.denied,
+                    );
+                    continue;
+                }
+                if fingerprint != channel_registry.fingerprint() {
+                    warn!(
+                        "Client {} registered differently named network types, decoding may fail",
+                        client_id
+                    );
+                }
+                let mode = user_data
+                    .map(|user_data| read_connection_mode(&user_data))
+                    .unwrap_or_default();
+                let client = ConnectedClient { mode, player_id };
+                if negotiation.enabled {
+                    negotiation.start(client_id, client, time.elapsed());
+                    events.negotiation_started.send(NegotiationStarted {
+                        client_id: Some(client_id),
+                    });
+                    continue;
+                }
+                events.admit(
+                    client_id,
+                    client,
+                    &mut connected_clients,
+                    &mut suspended_clients,
+                );
             },
             ServerEvent::ClientDisconnected { client_id, reason } => {
-                client_disconnected_events.send(ClientDisconnected {
-                    client_id: client_id.raw(),
-                    reason: *reason,
-                });
+                let client_id = ClientId::from(*client_id);
+                if denied_clients.0.remove(&client_id).is_some() || negotiation.abandon(client_id) {
+                    continue;
+                }
+                let player_id = connected_clients
+                    .0
+                    .remove(&client_id)
+                    .and_then(|client| client.player_id);
+                if let Some((_, reason)) = disconnecting_clients.0.remove(&client_id) {
+                    events
+                        .disconnected
+                        .send(ClientDisconnected { client_id, reason });
+                } else if let Some(grace_period) = session_resumption.grace_period {
+                    suspended_clients.0.insert(
+                        client_id,
+                        (time.elapsed() + grace_period, (*reason).into(), player_id),
+                    );
+                    events.connection_lost.send(ClientConnectionLost {
+                        client_id,
+                        reason: (*reason).into(),
+                    });
+                } else {
+                    events.disconnected.send(ClientDisconnected {
+                        client_id,
+                        reason: (*reason).into(),
+                    });
+                }
             },
         }
     }
 }
 
+pub fn server_expires_suspended_clients(
+    mut suspended_clients: ResMut<SuspendedClients>,
+    time: Res<Time>,
+    mut client_disconnected_events: EventWriter<ClientDisconnected>,
+) {
+    suspended_clients
+        .0
+        .retain(|client_id, (expires_at, reason, _)| {
+            if time.elapsed() < *expires_at {
+                return true;
+            }
+            client_disconnected_events.send(ClientDisconnected {
+                client_id: *client_id,
+                reason: *reason,
+            });
+            false
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_forgets_disconnected_clients(
+    mut client_connection_lost_events: EventReader<ClientConnectionLost>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut outbox: ResMut<ServerOutbox>,
+    mut bandwidth: ResMut<ClientBandwidth>,
+    mut delta_baselines: ResMut<ServerDeltaBaselines>,
+    mut sequences: ResMut<ServerSequences>,
+    mut teams: ResMut<Teams>,
+    mut sessions: ResMut<Sessions>,
+) {
+    // Suspended clients keep their settings, but start over with fresh connection state.
+    for ClientConnectionLost { client_id, .. } in client_connection_lost_events.read() {
+        outbox.forget(*client_id);
+        delta_baselines
+            .0
+            .retain(|(baseline_client_id, _)| baseline_client_id != client_id);
+        sequences
+            .0
+            .retain_received(|(sequence_client_id, _)| sequence_client_id != client_id);
+    }
+    for ClientDisconnected { client_id, .. } in client_disconnected_events.read() {
+        teams.remove(*client_id);
+        sessions.leave(*client_id);
+        outbox.forget(*client_id);
+        bandwidth.0.remove(client_id);
+        delta_baselines
+            .0
+            .retain(|(baseline_client_id, _)| baseline_client_id != client_id);
+        sequences
+            .0
+            .retain(|(sequence_client_id, _)| sequence_client_id != client_id);
+    }
+}
+
+pub fn server_disconnects_denied_clients(
+    mut server: ResMut<RenetServer>,
+    denied_clients: Res<DeniedClients>,
+    time: Res<Time>,
+) {
+    for (client_id, disconnect_at) in denied_clients.0.iter() {
+        if time.elapsed() >= *disconnect_at {
+            server.disconnect((*client_id).into());
+        }
+    }
+}
+
+pub fn server_disconnects_clients(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    time: Res<Time>,
+    mut disconnecting_clients: ResMut<DisconnectingClients>,
+    mut disconnect_client_events: EventReader<DisconnectClient>,
+) {
+    for DisconnectClient { client_id, reason } in disconnect_client_events.read() {
+        if connected_clients.get(*client_id).is_none() {
+            continue;
+        }
+        server.send_message(
+            (*client_id).into(),
+            CONTROL_CHANNEL_ID,
+            ControlMessage::Disconnecting(*reason).encode(),
+        );
+        // Like denials, the client gets a moment to receive the reason.
+        disconnecting_clients
+            .0
+            .insert(*client_id, (time.elapsed() + DENIAL_GRACE_PERIOD, *reason));
+    }
+    for (client_id, (disconnect_at, _)) in disconnecting_clients.0.iter() {
+        if time.elapsed() >= *disconnect_at {
+            server.disconnect((*client_id).into());
+        }
+    }
+}
+
+pub fn server_sends_heartbeats(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    time: Res<Time>,
+    mut last_heartbeat: Local<Duration>,
+) {
+    if time.elapsed() < *last_heartbeat + HEARTBEAT_INTERVAL {
+        return;
+    }
+    *last_heartbeat = time.elapsed();
+    let heartbeat = Bytes::from(ControlMessage::Heartbeat.encode());
+    for (client_id, _) in connected_clients.iter() {
+        server.send_message((*client_id).into(), CONTROL_CHANNEL_ID, heartbeat.clone());
+    }
+}
+
+/// Below this many messages per tick, decoding them on the current thread is cheaper than
+/// spreading them across the compute task pool.
+const PARALLEL_DECODE_THRESHOLD: usize = 64;
+
+#[allow(clippy::too_many_arguments)]
 pub fn server_receives_messages_from_clients<
     const I: u8,
     T: Event + Serialize + DeserializeOwned,
 >(
     mut server: ResMut<RenetServer>,
+    channel_configs: Res<NetworkConfigs>,
+    deterministic_ordering: Res<DeterministicOrdering>,
+    connected_clients: Res<ConnectedClients>,
+    mut delta_baselines: ResMut<ServerDeltaBaselines>,
+    mut sequences: ResMut<ServerSequences>,
+    mut receive_backlog: ResMut<ReceiveBacklog>,
+    mut telemetry: ResMut<ConnectionTelemetry>,
+    mut channel_health: ResMut<ChannelHealth>,
+    muted_client_events: Res<MutedClientEvents>,
+    mut streams: ResMut<ServerStreams>,
+    mut latest_from_clients: ResMut<LatestFromClients<T>>,
+    mut queued_from_clients: ResMut<QueuedFromClients<T>>,
     mut client_message_events: EventWriter<ReceiveFromClient<T>>,
+    mut decode_error_events: EventWriter<MessageDecodeError>,
+    mut network_errors: EventWriter<ServerNetworkError>,
 ) {
-    for client_id in server.clients_id().into_iter() {
-        while let Some(message) = server.receive_message(client_id, I) {
-            let (content, _): (T, usize) =
-                bincode::serde::decode_from_slice(&message, bincode::config::standard()).unwrap();
-            client_message_events.send(ReceiveFromClient {
-                client_id: client_id.raw(),
-                content,
+    let channel_config = &channel_configs.0[I as usize];
+    if channel_config.latest_only {
+        latest_from_clients.latest.clear();
+    }
+    // Draining renet and delta decoding (which depends on the previous message) happen in order,
+    // only the decoding of the payloads is spread across threads.
+    let mut draining = Vec::new();
+    for renet_client_id in server.clients_id().into_iter() {
+        let accepted = connected_clients
+            .get(renet_client_id.into())
+            .is_some_and(|client| {
+                channel_config.accept_from_spectators || client.mode != ConnectionMode::Spectator
             });
+        if accepted {
+            draining.push(renet_client_id);
+        } else {
+            // Denied clients (and spectators, unless accepted) don't get to send anything.
+            while server.receive_message(renet_client_id, I).is_some() {}
+        }
+    }
+    // Clients take turns, one message each, so that a spamming client doesn't delay the others.
+    let mut payloads = Vec::new();
+    let mut turns = 0;
+    while !draining.is_empty()
+        && channel_config
+            .receive_per_client
+            .is_none_or(|receive_per_client| turns < receive_per_client)
+    {
+        draining.retain(|renet_client_id| {
+            let Some(message) = server.receive_message(*renet_client_id, I) else {
+                return false;
+            };
+            let client_id = ClientId::from(*renet_client_id);
+            telemetry.received(client_id, I, message.len());
+            let mut arrival = None;
+            let payload = if channel_config.uses_delta() {
+                delta_baselines
+                    .0
+                    .decode((client_id, I), &message)
+                    .map(Bytes::from)
+                    .ok_or(DecodeErrorKind::MissingDeltaBaseline)
+            } else if channel_config.uses_resumption() {
+                match streams.0.receive(client_id, I, &message) {
+                    Ok(Some(payload)) => Ok(payload),
+                    Err(kind) => Err(kind),
+                    // Resent after a resumed session, but delivered before the drop.
+                    Ok(None) => {
+                        channel_health.record_arrival(I, Arrival::Duplicate);
+                        return true;
+                    },
+                }
+            } else if channel_config.uses_sequencing() {
+                // Compared in the order renet hands messages over, before any reordering.
+                sequence::split(&message)
+                    .map(|(sequence, payload)| {
+                        arrival = Some(sequences.0.arrival((client_id, I), sequence));
+                        payload
+                    })
+                    .ok_or_else(|| DecodeErrorKind::Invalid("missing sequence".to_string()))
+            } else {
+                Ok(message)
+            };
+            payloads.push((client_id, arrival, payload));
+            true
+        });
+        turns += 1;
+    }
+    if let Some(budget) = channel_config.receive_budget {
+        let backlog = receive_backlog.0.entry(I).or_default();
+        backlog.extend(payloads);
+        payloads = backlog.drain(..budget.min(backlog.len())).collect();
+        // Clients may have left since their messages were carried over.
+        payloads.retain(|(client_id, _, _)| connected_clients.get(*client_id).is_some());
+    }
+    let decode = |(_, _, payload): &ReceivedPayload| {
+        payload
+            .as_ref()
+            .map_err(Clone::clone)
+            .and_then(|payload| codec::decode::<T>(channel_config, payload))
+    };
+    let decoded: Vec<_> = if payloads.len() < PARALLEL_DECODE_THRESHOLD {
+        payloads.iter().map(decode).collect()
+    } else {
+        let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        payloads
+            .par_splat_map(task_pool, None, |_, chunk| {
+                chunk.iter().map(decode).collect::<Vec<_>>()
+            })
+            .into_iter()
+            .flatten()
+            .collect()
+    };
+    let mut received: Vec<_> = payloads
+        .into_iter()
+        .zip(decoded)
+        .map(|((client_id, arrival, _), decoded)| (client_id, arrival, decoded))
+        .collect();
+    if deterministic_ordering.0 {
+        // Renet hands clients over in hash map order, each client's messages stay in order.
+        received.sort_by_key(|(client_id, _, decoded)| {
+            (
+                *client_id,
+                decoded.as_ref().ok().and_then(|decoded| decoded.sent_at),
+            )
+        });
+    }
+    for (client_id, arrival, decoded) in received {
+        let decoded = match decoded {
+            Ok(decoded) => decoded,
+            Err(kind) => {
+                network_errors.send(ServerNetworkError(NetworkError::Decode {
+                    client_id: Some(client_id),
+                    channel_id: I,
+                    kind: kind.clone(),
+                }));
+                decode_error_events.send(MessageDecodeError {
+                    client_id: Some(client_id),
+                    channel_id: I,
+                    kind,
+                });
+                continue;
+            },
+        };
+        if let (Some(_), Some(message_id)) =
+            (channel_config.delivery_deadline(), decoded.message_id)
+        {
+            // Acknowledged even when dropped below, so the client stops resending it.
+            server.send_message(
+                client_id.into(),
+                CONTROL_CHANNEL_ID,
+                ControlMessage::Acknowledge {
+                    channel_id: I,
+                    message_id,
+                }
+                .encode(),
+            );
+        }
+        if let Some(window) = channel_config.dedup_window() {
+            let message_id = decoded.message_id.unwrap_or_default();
+            if sequences.0.repeated((client_id, I), message_id, window) {
+                channel_health.record_arrival(I, Arrival::Duplicate);
+                continue;
+            }
+        }
+        if let Some(arrival) = arrival {
+            channel_health.record_arrival(I, arrival);
+            if arrival != Arrival::InOrder {
+                continue;
+            }
+        }
+        // Dropped once decoded, so that delta baselines and sequences stay in step.
+        if muted_client_events.is_type_muted_from(client_id, TypeId::of::<T>()) {
+            continue;
+        }
+        let message = ReceiveFromClient {
+            client_id,
+            content: decoded.content,
+            sent_at: decoded.sent_at,
+            received_at: codec::now(),
+        };
+        if channel_config.latest_only {
+            latest_from_clients.latest.insert(client_id, message);
+        } else if channel_config.queued {
+            queued_from_clients.0.push_back(message);
+        } else {
+            client_message_events.send(message);
         }
     }
 }
 
 pub fn server_sends_messages_to_clients<const I: u8, T: Event + Serialize + DeserializeOwned>(
-    mut server: ResMut<RenetServer>,
+    mut outbox: DeduplicatedOutbox,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    muted_client_events: Res<MutedClientEvents>,
+    mut payload_cache: Local<ServerPayloadCache>,
     mut send_message_to_client_events: EventReader<SendToClient<T>>,
 ) {
+    let channel_config = &channel_configs.0[I as usize];
     for message in send_message_to_client_events.read() {
-        let payload =
-            bincode::serde::encode_to_vec(&message.content, bincode::config::standard()).unwrap();
-        server.send_message(ClientId::from_raw(message.client_id), I, payload);
+        if muted_client_events.is_type_muted_to(message.client_id, TypeId::of::<T>()) {
+            continue;
+        }
+        let content_key = outbox.content_key(channel_config, &message.content);
+        outbox.push::<T>(
+            message.client_id,
+            channel_config,
+            QueuedMessage {
+                channel_id: I,
+                payload: payload_cache.0.encode(channel_config, &message.content),
+                queued_at: time.elapsed(),
+            },
+            content_key.as_ref(),
+            SendKind::Client,
+        );
     }
+    payload_cache.0.clear();
 }
 
 pub fn server_broadcasts_messages_to_clients<
     const I: u8,
     T: Event + Serialize + DeserializeOwned,
 >(
-    mut server: ResMut<RenetServer>,
+    mut outbox: DeduplicatedOutbox,
+    connected_clients: Res<ConnectedClients>,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    muted_client_events: Res<MutedClientEvents>,
     mut broadcast_message_events: EventReader<SendToClients<T>>,
 ) {
+    let channel_config = &channel_configs.0[I as usize];
     for message in broadcast_message_events.read() {
-        let payload =
-            bincode::serde::encode_to_vec(&message.content, bincode::config::standard()).unwrap();
-        server.broadcast_message(I, payload);
+        let content_key = outbox.content_key(channel_config, &message.content);
+        let message = QueuedMessage {
+            channel_id: I,
+            payload: codec::encode(channel_config, &message.content),
+            queued_at: time.elapsed(),
+        };
+        for client_id in connected_clients.0.keys() {
+            if !muted_client_events.is_type_muted_to(*client_id, TypeId::of::<T>()) {
+                outbox.push::<T>(
+                    *client_id,
+                    channel_config,
+                    message.clone(),
+                    content_key.as_ref(),
+                    SendKind::Clients,
+                );
+            }
+        }
+    }
+}
+
+pub fn server_sets_client_bandwidth(
+    mut set_client_bandwidth_events: EventReader<SetClientBandwidth>,
+    mut bandwidth: ResMut<ClientBandwidth>,
+) {
+    for SetClientBandwidth {
+        client_id,
+        bytes_per_tick,
+    } in set_client_bandwidth_events.read()
+    {
+        match bytes_per_tick {
+            Some(bytes_per_tick) => bandwidth.0.insert(*client_id, *bytes_per_tick),
+            None => bandwidth.0.remove(client_id),
+        };
+    }
+}
+
+pub fn server_sets_session_budgets(
+    mut set_session_budget_events: EventReader<SetSessionBudget>,
+    sessions: Res<Sessions>,
+    mut session_budgets: ResMut<SessionBudgets>,
+) {
+    for SetSessionBudget { session, budget } in set_session_budget_events.read() {
+        match budget {
+            Some(budget) => session_budgets.0.insert(*session, budget.clone()),
+            None => session_budgets.0.remove(session),
+        };
+    }
+    session_budgets
+        .0
+        .retain(|session, _| sessions.state(*session).is_some());
+}
+
+/// Bytes handed to renet this tick for a session, in total and per channel.
+#[derive(Default)]
+struct SessionSentBytes {
+    total: u64,
+    channels: HashMap<u8, u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_flushes_outbox(
+    mut server: ResMut<RenetServer>,
+    mut outbox: ResMut<ServerOutbox>,
+    mut dropped_stale_messages: ResMut<DroppedStaleMessages>,
+    mut delta_baselines: ResMut<ServerDeltaBaselines>,
+    bandwidth: Res<ClientBandwidth>,
+    sessions: Res<Sessions>,
+    session_budgets: Res<SessionBudgets>,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    warm_up: Res<ClientWarmUp>,
+    mut telemetry: ResMut<ConnectionTelemetry>,
+    mut pending_deliveries: ResMut<ServerPendingDeliveries>,
+    mut streams: ResMut<ServerStreams>,
+    mut sequences: ResMut<ServerSequences>,
+    mut network_errors: EventWriter<ServerNetworkError>,
+) {
+    let now = time.elapsed();
+    let mut session_sent: HashMap<SessionId, SessionSentBytes> = HashMap::new();
+    let ServerOutbox {
+        queues,
+        coalescer,
+        queued_kinds,
+    } = &mut *outbox;
+    queued_kinds.clear();
+    let send_interval = |channel_id: u8| {
+        channel_configs.0[channel_id as usize]
+            .send_interval()
+            .unwrap_or_default()
+    };
+    for ((client_id, _), message) in coalescer.release(now, send_interval) {
+        queues.entry(client_id).or_default().push_back(message);
     }
+    for (client_id, queue) in queues.iter_mut() {
+        queue.retain(|message| {
+            let ttl = channel_configs
+                .0
+                .get(message.channel_id as usize)
+                .and_then(NetworkConfig::unreliable_ttl);
+            let stale = ttl.is_some_and(|ttl| now.saturating_sub(message.queued_at) > ttl);
+            if stale {
+                *dropped_stale_messages
+                    .0
+                    .entry(message.channel_id)
+                    .or_default() += 1;
+            }
+            !stale
+        });
+        if warm_up.is_warming_up(*client_id) {
+            continue;
+        }
+        queue.make_contiguous().sort_by_key(|message| {
+            (
+                channel_configs.is_background(message.channel_id),
+                std::cmp::Reverse(channel_configs.priority(message.channel_id)),
+            )
+        });
+        let limit = bandwidth.get(*client_id);
+        let session = sessions.session_of(*client_id);
+        let session_budget = session.and_then(|session| session_budgets.get(session));
+        let mut sent_bytes = 0;
+        // Channels over their session limit, their messages stay queued in order.
+        let mut full_channels: Vec<u8> = Vec::new();
+        // Whether other types are held back this tick, leaving no spare budget for background ones.
+        let mut held_back = false;
+        let mut index = 0;
+        while let Some(message) = queue.get(index) {
+            let size = message.payload.len() as u64;
+            // Always let one message through so oversized ones can't block the queue.
+            let over = |limit: Option<u64>, sent: u64| {
+                limit.is_some_and(|limit| sent > 0 && sent + size > limit)
+            };
+            if over(limit, sent_bytes) {
+                break;
+            }
+            let background = channel_configs.is_background(message.channel_id);
+            if background {
+                if held_back {
+                    break;
+                }
+                if full_channels.contains(&message.channel_id)
+                    || !server.can_send_message(
+                        (*client_id).into(),
+                        message.channel_id,
+                        message.payload.len(),
+                    )
+                {
+                    full_channels.push(message.channel_id);
+                    index += 1;
+                    continue;
+                }
+            }
+            if let (Some(session), Some(budget)) = (session, session_budget) {
+                let sent = session_sent.entry(session).or_default();
+                if over(budget.bytes_per_tick, sent.total) {
+                    break;
+                }
+                let channel_sent = sent.channels.get(&message.channel_id).copied();
+                if full_channels.contains(&message.channel_id)
+                    || over(
+                        budget.channels.get(&message.channel_id).copied(),
+                        channel_sent.unwrap_or_default(),
+                    )
+                {
+                    full_channels.push(message.channel_id);
+                    held_back |= !background;
+                    index += 1;
+                    continue;
+                }
+            }
+            let message = queue.remove(index).unwrap();
+            let renet_client_id = (*client_id).into();
+            if !server.can_send_message(renet_client_id, message.channel_id, message.payload.len())
+            {
+                network_errors.send(ServerNetworkError(NetworkError::Send {
+                    client_id: Some(*client_id),
+                    channel_id: message.channel_id,
+                    reason: SendErrorReason::ChannelFull,
+                }));
+                continue;
+            }
+            let Some(channel_config) = channel_configs.0.get(message.channel_id as usize) else {
+                continue;
+            };
+            let payload = if channel_config.uses_delta() {
+                delta_baselines
+                    .0
+                    .encode((*client_id, message.channel_id), message.payload.to_vec())
+                    .into()
+            } else if channel_config.uses_resumption() {
+                streams
+                    .0
+                    .stamp(*client_id, message.channel_id, &message.payload)
+            } else if channel_config.uses_sequencing() {
+                sequences
+                    .0
+                    .stamp((*client_id, message.channel_id), &message.payload)
+            } else {
+                message.payload
+            };
+            pending_deliveries.0.track(
+                *client_id,
+                message.channel_id,
+                channel_config,
+                &payload,
+                now,
+            );
+            telemetry.sent(*client_id, message.channel_id, payload.len());
+            server.send_message(renet_client_id, message.channel_id, payload);
+            // Only what renet took counts against the budgets.
+            sent_bytes += size;
+            if let Some(sent) = session.and_then(|session| session_sent.get_mut(&session)) {
+                *sent.channels.entry(message.channel_id).or_default() += size;
+                sent.total += size;
+            }
+        }
+    }
+    queues.retain(|_, queue| !queue.is_empty());
 }
 
-pub fn cleanup_transport(mut commands: Commands) {
+pub fn server_warms_up_clients(
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    connected_clients: Res<ConnectedClients>,
+    time: Res<Time>,
+    mut warm_up: ResMut<ClientWarmUp>,
+) {
+    let now = time.elapsed();
+    let new_client_ids = client_connected_events
+        .read()
+        .map(|client_connected| client_connected.client_id)
+        .chain(
+            client_reconnected_events
+                .read()
+                .map(|client_reconnected| client_reconnected.client_id),
+        );
+    for new_client_id in new_client_ids {
+        warm_up.start(new_client_id, now);
+    }
+    warm_up.warming_up.retain(|client_id, expires_at| {
+        now < *expires_at && connected_clients.get(*client_id).is_some()
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_receives_control_messages(
+    mut server: ResMut<RenetServer>,
+    mut warm_up: ResMut<ClientWarmUp>,
+    mut ready_checks: ResMut<ReadyChecks>,
+    mut matchmaking_queues: ResMut<MatchmakingQueues>,
+    mut matchmaking_requested_events: EventWriter<MatchmakingRequested>,
+    mut matchmaking_cancelled_events: EventWriter<MatchmakingCancelled>,
+    mut pending_deliveries: ResMut<ServerPendingDeliveries>,
+    mut streams: ResMut<ServerStreams>,
+    mut handshake: Handshake,
+) {
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, CONTROL_CHANNEL_ID) {
+            match ControlMessage::decode(&message) {
+                Some(ControlMessage::ClientReady) => warm_up.ready(client_id.into()),
+                Some(ControlMessage::ReadyCheckAnswer { id, ready }) => {
+                    ready_checks.answer(client_id.into(), id, ready);
+                },
+                Some(ControlMessage::Enqueue { queue }) => {
+                    if let Some(previous) =
+                        matchmaking_queues.enqueue(client_id.into(), queue.clone())
+                    {
+                        matchmaking_cancelled_events.send(MatchmakingCancelled {
+                            client_id: client_id.into(),
+                            queue: previous,
+                        });
+                    }
+                    matchmaking_requested_events.send(MatchmakingRequested {
+                        client_id: client_id.into(),
+                        queue,
+                    });
+                },
+                Some(ControlMessage::Dequeue) => {
+                    if let Some(queue) = matchmaking_queues.dequeue(client_id.into()) {
+                        matchmaking_cancelled_events.send(MatchmakingCancelled {
+                            client_id: client_id.into(),
+                            queue,
+                        });
+                    }
+                },
+                Some(ControlMessage::Acknowledge {
+                    channel_id,
+                    message_id,
+                }) => {
+                    pending_deliveries
+                        .0
+                        .acknowledge(client_id.into(), channel_id, message_id);
+                },
+                Some(ControlMessage::StreamReceived {
+                    channel_id,
+                    sequence,
+                }) => {
+                    streams
+                        .0
+                        .acknowledge(client_id.into(), channel_id, sequence);
+                },
+                Some(ControlMessage::SchemaHash(hash))
+                    if hash != handshake.schema_check.schema_hash() =>
+                {
+                    server.send_message(
+                        client_id,
+                        CONTROL_CHANNEL_ID,
+                        ControlMessage::SchemaTypes(handshake.schema_check.type_schemas()).encode(),
+                    );
+                },
+                Some(ControlMessage::SchemaTypes(type_schemas)) => {
+                    handshake
+                        .schema_check
+                        .compare(Some(client_id.into()), type_schemas);
+                },
+                Some(ControlMessage::Capabilities(advertised)) => {
+                    if let Some(negotiated) =
+                        handshake.server_negotiates(client_id.into(), advertised)
+                    {
+                        server.send_message(
+                            client_id,
+                            CONTROL_CHANNEL_ID,
+                            ControlMessage::NegotiatedCapabilities(negotiated).encode(),
+                        );
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn cleanup_transport(
+    mut outbox: ResMut<ServerOutbox>,
+    mut bandwidth: ResMut<ClientBandwidth>,
+    mut session_budgets: ResMut<SessionBudgets>,
+    mut delta_baselines: ResMut<ServerDeltaBaselines>,
+    mut sequences: ResMut<ServerSequences>,
+    mut receive_backlog: ResMut<ReceiveBacklog>,
+    mut server_state: ResMut<ServerState>,
+    mut teams: ResMut<Teams>,
+    mut sessions: ResMut<Sessions>,
+    mut server_stopped_events: EventWriter<ServerStopped>,
+    mut commands: Commands,
+) {
+    *server_state = ServerState::Stopped;
+    teams.clear();
+    sessions.clear();
+    server_stopped_events.send(ServerStopped);
+    outbox.clear();
+    bandwidth.0.clear();
+    session_budgets.0.clear();
+    delta_baselines.0.clear();
+    sequences.0.clear();
+    receive_backlog.0.clear();
     commands.remove_resource::<renet::transport::NetcodeServerTransport>();
 }
+
+pub fn server_receives_unknown_channel_messages(
+    mut server: ResMut<RenetServer>,
+    channel_configs: Res<NetworkConfigs>,
+    channel_registry: Res<ChannelRegistry>,
+    mut unknown_channel_message_events: EventWriter<UnknownChannelMessage>,
+) {
+    for channel_id in channel_registry.unregistered(&channel_configs) {
+        for client_id in server.clients_id() {
+            while let Some(bytes) = server.receive_message(client_id, channel_id) {
+                unknown_channel_message_events.send(UnknownChannelMessage {
+                    client_id: Some(client_id.into()),
+                    channel_id,
+                    bytes,
+                });
+            }
+        }
+    }
+}
+
+pub fn server_sends_messages_to_clients_where<
+    const I: u8,
+    T: Event + Serialize + DeserializeOwned,
+>(
+    mut outbox: DeduplicatedOutbox,
+    connected_clients: Res<ConnectedClients>,
+    teams: Res<Teams>,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    muted_client_events: Res<MutedClientEvents>,
+    mut send_message_where_events: EventReader<SendToClientsWhere<T>>,
+) {
+    let channel_config = &channel_configs.0[I as usize];
+    for message_where in send_message_where_events.read() {
+        let content_key = outbox.content_key(channel_config, &message_where.content);
+        let message = QueuedMessage {
+            channel_id: I,
+            payload: codec::encode(channel_config, &message_where.content),
+            queued_at: time.elapsed(),
+        };
+        for (client_id, client) in connected_clients.iter() {
+            if message_where.filter.matches(*client_id, client, &teams)
+                && !muted_client_events.is_type_muted_to(*client_id, TypeId::of::<T>())
+            {
+                outbox.push::<T>(
+                    *client_id,
+                    channel_config,
+                    message.clone(),
+                    content_key.as_ref(),
+                    SendKind::ClientsWhere,
+                );
+            }
+        }
+    }
+}
+
+pub fn server_sends_messages_to_team<const I: u8, T: Event + Serialize + DeserializeOwned>(
+    mut outbox: DeduplicatedOutbox,
+    connected_clients: Res<ConnectedClients>,
+    teams: Res<Teams>,
+    channel_configs: Res<NetworkConfigs>,
+    time: Res<Time>,
+    muted_client_events: Res<MutedClientEvents>,
+    mut send_message_to_team_events: EventReader<SendToTeam<T>>,
+) {
+    let channel_config = &channel_configs.0[I as usize];
+    for message_to_team in send_message_to_team_events.read() {
+        let content_key = outbox.content_key(channel_config, &message_to_team.content);
+        let message = QueuedMessage {
+            channel_id: I,
+            payload: codec::encode(channel_config, &message_to_team.content),
+            queued_at: time.elapsed(),
+        };
+        for client_id in teams.members(message_to_team.team) {
+            if connected_clients.get(client_id).is_some()
+                && !muted_client_events.is_type_muted_to(client_id, TypeId::of::<T>())
+            {
+                outbox.push::<T>(
+                    client_id,
+                    channel_config,
+                    message.clone(),
+                    content_key.as_ref(),
+                    SendKind::Team,
+                );
+            }
+        }
+    }
+}
+
+pub fn server_replicates_teams(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    mut teams: ResMut<Teams>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    mut client_team_changed_events: EventWriter<ClientTeamChanged>,
+) {
+    let new_client_ids = client_connected_events
+        .read()
+        .map(|client_connected| client_connected.client_id)
+        .chain(
+            client_reconnected_events
+                .read()
+                .map(|client_reconnected| client_reconnected.client_id),
+        );
+    for new_client_id in new_client_ids {
+        for (client_id, team) in teams.iter() {
+            let team_changed = ControlMessage::TeamChanged {
+                client_id: *client_id,
+                team: Some(*team),
+            };
+            server.send_message(
+                new_client_id.into(),
+                CONTROL_CHANNEL_ID,
+                team_changed.encode(),
+            );
+        }
+    }
+    for (client_id, team) in teams.drain_changes() {
+        let team_changed = Bytes::from(ControlMessage::TeamChanged { client_id, team }.encode());
+        for (connected_client_id, _) in connected_clients.iter() {
+            server.send_message(
+                (*connected_client_id).into(),
+                CONTROL_CHANNEL_ID,
+                team_changed.clone(),
+            );
+        }
+        client_team_changed_events.send(ClientTeamChanged { client_id, team });
+    }
+}
+
+pub fn server_replicates_sessions(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    mut sessions: ResMut<Sessions>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    mut session_events: SessionEvents,
+) {
+    let new_client_ids = client_connected_events
+        .read()
+        .map(|client_connected| client_connected.client_id)
+        .chain(
+            client_reconnected_events
+                .read()
+                .map(|client_reconnected| client_reconnected.client_id),
+        );
+    for new_client_id in new_client_ids {
+        for change in sessions.snapshot() {
+            server.send_message(
+                new_client_id.into(),
+                CONTROL_CHANNEL_ID,
+                ControlMessage::Session(change).encode(),
+            );
+        }
+    }
+    for change in sessions.drain_changes() {
+        let session_changed = Bytes::from(ControlMessage::Session(change).encode());
+        for (connected_client_id, _) in connected_clients.iter() {
+            server.send_message(
+                (*connected_client_id).into(),
+                CONTROL_CHANNEL_ID,
+                session_changed.clone(),
+            );
+        }
+        session_events.send(change);
+    }
+}
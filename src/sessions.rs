@@ -0,0 +1,189 @@
+//!
+//! Match/session lifecycle, declared by the server and replicated to the clients.
+//!
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{Event, EventWriter, Resource};
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::ClientId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SessionId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SessionState {
+    Lobby,
+    InProgress,
+    Ended,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum SessionChange {
+    /// A session was created, changed state, or was removed (`None`).
+    State(SessionId, Option<SessionState>),
+    /// A client joined, changed or left a session (`None`).
+    Member(ClientId, Option<SessionId>),
+}
+
+///
+/// The sessions (matches) running on the server, their state and which clients are in them.
+/// Modified by the server, clients get a read-only copy kept up to date by the plugin.
+///
+#[derive(Debug, Default, Resource)]
+pub struct Sessions {
+    states: HashMap<SessionId, SessionState>,
+    members: HashMap<ClientId, SessionId>,
+    changes: Vec<SessionChange>,
+}
+
+impl Sessions {
+    /// Moves the session to `state`, creating it if needed.
+    pub fn set_state(&mut self, session: SessionId, state: SessionState) {
+        if self.states.insert(session, state) != Some(state) {
+            self.changes
+                .push(SessionChange::State(session, Some(state)));
+        }
+    }
+
+    /// Creates the session in the `Lobby` state.
+    pub fn create(&mut self, session: SessionId) {
+        self.set_state(session, SessionState::Lobby);
+    }
+
+    /// Removes the session, its members leave it.
+    pub fn remove(&mut self, session: SessionId) {
+        let members: Vec<ClientId> = self.members(session).collect();
+        for client_id in members {
+            self.leave(client_id);
+        }
+        if self.states.remove(&session).is_some() {
+            self.changes.push(SessionChange::State(session, None));
+        }
+    }
+
+    /// Moves the client to `session` (created if needed), a client is in at most one session.
+    pub fn join(&mut self, client_id: ClientId, session: SessionId) {
+        if !self.states.contains_key(&session) {
+            self.create(session);
+        }
+        if self.members.insert(client_id, session) != Some(session) {
+            self.changes
+                .push(SessionChange::Member(client_id, Some(session)));
+        }
+    }
+
+    pub fn leave(&mut self, client_id: ClientId) {
+        if self.members.remove(&client_id).is_some() {
+            self.changes.push(SessionChange::Member(client_id, None));
+        }
+    }
+
+    pub fn state(&self, session: SessionId) -> Option<SessionState> {
+        self.states.get(&session).copied()
+    }
+
+    pub fn session_of(&self, client_id: ClientId) -> Option<SessionId> {
+        self.members.get(&client_id).copied()
+    }
+
+    pub fn members(&self, session: SessionId) -> impl Iterator<Item = ClientId> + '_ {
+        self.members
+            .iter()
+            .filter(move |(_, member_session)| **member_session == session)
+            .map(|(client_id, _)| *client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SessionId, &SessionState)> {
+        self.states.iter()
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.members.keys().copied()
+    }
+
+    /// Every session and membership, as changes to replay on a newly connected client.
+    pub(crate) fn snapshot(&self) -> Vec<SessionChange> {
+        let states = self
+            .states
+            .iter()
+            .map(|(session, state)| SessionChange::State(*session, Some(*state)));
+        let members = self
+            .members
+            .iter()
+            .map(|(client_id, session)| SessionChange::Member(*client_id, Some(*session)));
+        states.chain(members).collect()
+    }
+
+    /// Applies a change received from the server, without recording it.
+    pub(crate) fn apply(&mut self, change: SessionChange) {
+        match change {
+            SessionChange::State(session, Some(state)) => {
+                self.states.insert(session, state);
+            },
+            SessionChange::State(session, None) => {
+                self.states.remove(&session);
+            },
+            SessionChange::Member(client_id, Some(session)) => {
+                self.members.insert(client_id, session);
+            },
+            SessionChange::Member(client_id, None) => {
+                self.members.remove(&client_id);
+            },
+        }
+    }
+
+    pub(crate) fn drain_changes(&mut self) -> Vec<SessionChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.states.clear();
+        self.members.clear();
+        self.changes.clear();
+    }
+}
+
+///
+/// A session was created, changed state or was removed (`state` is `None`), received on both
+/// the server and the clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct SessionStateChanged {
+    pub session: SessionId,
+    pub state: Option<SessionState>,
+}
+
+///
+/// A client joined, changed or left a session (`session` is `None`), received on both the server
+/// and the clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClientSessionChanged {
+    pub client_id: ClientId,
+    pub session: Option<SessionId>,
+}
+
+///
+/// Writers for both session events, which are sent from the same changes.
+///
+#[derive(SystemParam)]
+pub struct SessionEvents<'w> {
+    state_changed: EventWriter<'w, SessionStateChanged>,
+    client_changed: EventWriter<'w, ClientSessionChanged>,
+}
+
+impl SessionEvents<'_> {
+    pub(crate) fn send(&mut self, change: SessionChange) {
+        match change {
+            SessionChange::State(session, state) => {
+                self.state_changed
+                    .send(SessionStateChanged { session, state });
+            },
+            SessionChange::Member(client_id, session) => {
+                self.client_changed
+                    .send(ClientSessionChanged { client_id, session });
+            },
+        }
+    }
+}
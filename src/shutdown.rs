@@ -0,0 +1,83 @@
+//!
+//! Ends connections cleanly when the app exits, so peers see a regular disconnection instead of
+//! a timeout.
+//!
+//! Once an `AppExit` event is sent, the server tells its clients it is shutting down
+//! (`DisconnectReason::ServerShutdown`) and the client tells the server it is leaving. Messages
+//! already handed to renet are sent along with the disconnection packets in the same tick,
+//! messages still waiting in the outboxes (bandwidth limits, warm-up, send intervals) are not.
+//!
+use bevy::app::AppExit;
+use bevy::prelude::{
+    resource_exists, App, EventReader, IntoSystemConfigs, Last, Res, ResMut, Resource,
+};
+use bevy_renet::renet::{
+    transport::{NetcodeClientTransport, NetcodeServerTransport},
+    RenetClient, RenetServer,
+};
+
+use crate::{control::ControlMessage, DisconnectReason, CONTROL_CHANNEL_ID};
+
+///
+/// Whether connections are ended cleanly when the app exits, enabled by default.
+///
+#[derive(Debug, Resource)]
+pub struct GracefulShutdown(pub bool);
+
+impl Default for GracefulShutdown {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+pub fn server_shuts_down_on_exit(
+    graceful_shutdown: Res<GracefulShutdown>,
+    mut app_exit_events: EventReader<AppExit>,
+    mut server: ResMut<RenetServer>,
+    mut transport: ResMut<NetcodeServerTransport>,
+) {
+    if app_exit_events.read().count() == 0 || !graceful_shutdown.0 {
+        return;
+    }
+    disconnect_all(&mut server, &mut transport);
+}
+
+/// Tells every client the server is shutting down and disconnects them right away.
+pub(crate) fn disconnect_all(server: &mut RenetServer, transport: &mut NetcodeServerTransport) {
+    let shutting_down = ControlMessage::Disconnecting(DisconnectReason::ServerShutdown).encode();
+    for client_id in server.clients_id() {
+        server.send_message(client_id, CONTROL_CHANNEL_ID, shutting_down.clone());
+    }
+    transport.send_packets(server);
+    server.disconnect_all();
+    transport.disconnect_all(server);
+}
+
+pub fn client_disconnects_on_exit(
+    graceful_shutdown: Res<GracefulShutdown>,
+    mut app_exit_events: EventReader<AppExit>,
+    mut client: ResMut<RenetClient>,
+    mut transport: ResMut<NetcodeClientTransport>,
+) {
+    if app_exit_events.read().count() == 0 || !graceful_shutdown.0 {
+        return;
+    }
+    // Errors can't be reported anymore, the disconnection is sent regardless.
+    let _ = transport.send_packets(&mut client);
+    client.disconnect();
+    transport.disconnect();
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<GracefulShutdown>().add_systems(
+        Last,
+        (
+            server_shuts_down_on_exit
+                .run_if(resource_exists::<RenetServer>)
+                .run_if(resource_exists::<NetcodeServerTransport>),
+            client_disconnects_on_exit
+                .run_if(resource_exists::<RenetClient>)
+                .run_if(resource_exists::<NetcodeClientTransport>),
+        ),
+    );
+}
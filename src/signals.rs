@@ -0,0 +1,90 @@
+//!
+//! Shuts headless servers down cleanly on SIGINT (Ctrl-C), SIGTERM and SIGHUP (e.g. when their
+//! container is stopped), which would otherwise kill the process mid-send.
+//!
+//! On the first signal the server tells its clients it is shutting down and stops (see
+//! `StopServer`), the client disconnects from its server, and `AppExit::Success` is sent once both
+//! are done. A second signal exits right away.
+//!
+//! Only one signal handler can be installed per process, apps installing their own (e.g. with the
+//! `ctrlc` crate) shouldn't enable this feature.
+//!
+use bevy::app::AppExit;
+use bevy::log::warn;
+use bevy::prelude::{App, EventWriter, First, Res, ResMut, Resource, Startup};
+use bevy_renet::renet::{transport::NetcodeServerTransport, RenetClient, RenetServer};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::{
+    client::DisconnectFromServer,
+    server::{ServerState, StopServer},
+    shutdown,
+};
+
+///
+/// Shutdown signals received by the process, see the `signals` module.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ShutdownSignals {
+    received: Arc<AtomicUsize>,
+    shutting_down: bool,
+}
+
+impl ShutdownSignals {
+    /// Whether a signal was received and the app is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+}
+
+pub fn installs_signal_handler(shutdown_signals: Res<ShutdownSignals>) {
+    let received = shutdown_signals.received.clone();
+    if let Err(error) = ctrlc::set_handler(move || {
+        received.fetch_add(1, Ordering::Relaxed);
+    }) {
+        warn!("Could not install the shutdown signal handler: {}", error);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn shuts_down_on_signal(
+    mut shutdown_signals: ResMut<ShutdownSignals>,
+    server: Option<ResMut<RenetServer>>,
+    transport: Option<ResMut<NetcodeServerTransport>>,
+    client: Option<Res<RenetClient>>,
+    server_state: Res<ServerState>,
+    mut stop_server_events: EventWriter<StopServer>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+    mut app_exit_events: EventWriter<AppExit>,
+) {
+    let received = shutdown_signals.received.load(Ordering::Relaxed);
+    if received == 0 {
+        return;
+    }
+    if received > 1 {
+        app_exit_events.send(AppExit::Success);
+        return;
+    }
+    if !shutdown_signals.shutting_down {
+        shutdown_signals.shutting_down = true;
+        if let (Some(mut server), Some(mut transport)) = (server, transport) {
+            shutdown::disconnect_all(&mut server, &mut transport);
+            stop_server_events.send(StopServer);
+        }
+        if client.is_some() {
+            disconnect_from_server_events.send(DisconnectFromServer);
+        }
+        return;
+    }
+    if *server_state == ServerState::Stopped && client.is_none() {
+        app_exit_events.send(AppExit::Success);
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ShutdownSignals>()
+        .add_systems(Startup, installs_signal_handler)
+        .add_systems(First, shuts_down_on_signal);
+}
@@ -0,0 +1,380 @@
+//!
+//! Reliable-ordered streams resumed across a quick reconnect, for the types with
+//! `NetworkConfig::resumable` enabled.
+//!
+//! Renet's channels start over with every connection, so messages in flight when the connection
+//! dropped are lost. Resumable messages are numbered per receiver and channel, and kept by the
+//! sender until the receiver acknowledges them. When the server resumes a session (see
+//! `SessionResumption`, or `PlayerReconnected` for a new client id), both ends resend what the
+//! other didn't acknowledge, and receivers skip the messages they already delivered, so the
+//! stream carries on without duplicates or reordering. Fresh connections start new streams.
+//!
+//! Clients hold their resumable messages back after connecting until the server told them whether
+//! the session was resumed, so that new messages can't overtake the resent ones.
+//!
+use bevy::prelude::{
+    resource_exists, App, EventReader, IntoSystemConfigs, PostUpdate, PreUpdate, Res, ResMut,
+    Resource,
+};
+use bevy_renet::renet::{RenetClient, RenetServer};
+use renet::Bytes;
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    client::ClientStopped,
+    control::ControlMessage,
+    server::{
+        server_expires_suspended_clients, ClientConnected, ClientDisconnected, ClientReconnected,
+        PlayerReconnected, ServerStopped,
+    },
+    ClientId, DecodeErrorKind, NetworkConfig, NetworkConfigs, NetworkSet, CONTROL_CHANNEL_ID,
+};
+
+/// The stream id (`u32`) and sequence number (`u64`) preceding every resumable payload.
+const HEADER_BYTES: usize = 12;
+
+#[derive(Debug)]
+struct Outgoing {
+    /// Random id of the stream, so that receivers tell a new stream from a resumed one.
+    stream_id: u32,
+    next: u64,
+    unacknowledged: VecDeque<(u64, Bytes)>,
+}
+
+#[derive(Debug)]
+struct Incoming {
+    stream_id: u32,
+    last: u64,
+    acknowledged: u64,
+}
+
+///
+/// Outgoing and incoming streams per peer (the client on the server, `()` on clients) and channel.
+///
+#[derive(Debug)]
+pub(crate) struct Streams<K> {
+    outgoing: BTreeMap<(K, u8), Outgoing>,
+    incoming: BTreeMap<(K, u8), Incoming>,
+}
+
+impl<K> Default for Streams<K> {
+    fn default() -> Self {
+        Self {
+            outgoing: BTreeMap::new(),
+            incoming: BTreeMap::new(),
+        }
+    }
+}
+
+fn new_stream_id() -> u32 {
+    let mut stream_id = [0; 4];
+    // A failure leaves the id at zero, which only matters if the previous stream got zero too.
+    let _ = getrandom::getrandom(&mut stream_id);
+    u32::from_le_bytes(stream_id)
+}
+
+impl<K: Copy + Ord> Streams<K> {
+    /// Numbers `payload` and keeps it until the receiver acknowledges it.
+    pub(crate) fn stamp(&mut self, key: K, channel_id: u8, payload: &[u8]) -> Bytes {
+        let outgoing = self
+            .outgoing
+            .entry((key, channel_id))
+            .or_insert_with(|| Outgoing {
+                stream_id: new_stream_id(),
+                next: 1,
+                unacknowledged: VecDeque::new(),
+            });
+        let sequence = outgoing.next;
+        outgoing.next += 1;
+        let mut frame = Vec::with_capacity(HEADER_BYTES + payload.len());
+        frame.extend_from_slice(&outgoing.stream_id.to_le_bytes());
+        frame.extend_from_slice(&sequence.to_le_bytes());
+        frame.extend_from_slice(payload);
+        let frame = Bytes::from(frame);
+        outgoing.unacknowledged.push_back((sequence, frame.clone()));
+        frame
+    }
+
+    /// Strips the stream header, `None` for a message delivered before.
+    pub(crate) fn receive(
+        &mut self,
+        key: K,
+        channel_id: u8,
+        frame: &Bytes,
+    ) -> Result<Option<Bytes>, DecodeErrorKind> {
+        if frame.len() < HEADER_BYTES {
+            return Err(DecodeErrorKind::Invalid(
+                "missing stream header".to_string(),
+            ));
+        }
+        let stream_id = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        let sequence = u64::from_le_bytes(frame[4..HEADER_BYTES].try_into().unwrap());
+        match self.incoming.get_mut(&(key, channel_id)) {
+            Some(incoming) if incoming.stream_id == stream_id => {
+                if sequence <= incoming.last {
+                    return Ok(None);
+                }
+                incoming.last = sequence;
+            },
+            _ => {
+                self.incoming.insert(
+                    (key, channel_id),
+                    Incoming {
+                        stream_id,
+                        last: sequence,
+                        acknowledged: 0,
+                    },
+                );
+            },
+        }
+        Ok(Some(frame.slice(HEADER_BYTES..)))
+    }
+
+    /// The latest sequence received per peer and channel, for those not acknowledged yet.
+    fn acknowledgements(&mut self) -> Vec<(K, u8, u64)> {
+        self.incoming
+            .iter_mut()
+            .filter(|(_, incoming)| incoming.last > incoming.acknowledged)
+            .map(|((key, channel_id), incoming)| {
+                incoming.acknowledged = incoming.last;
+                (*key, *channel_id, incoming.last)
+            })
+            .collect()
+    }
+
+    /// Drops the messages the receiver acknowledged, up to `sequence`.
+    pub(crate) fn acknowledge(&mut self, key: K, channel_id: u8, sequence: u64) {
+        if let Some(outgoing) = self.outgoing.get_mut(&(key, channel_id)) {
+            outgoing
+                .unacknowledged
+                .retain(|(unacknowledged, _)| *unacknowledged > sequence);
+        }
+    }
+
+    /// The messages `key` didn't acknowledge, in order, and acknowledges again what `key` sent
+    /// in case the previous acknowledgements were lost.
+    pub(crate) fn resume(&mut self, key: K) -> Vec<(u8, Bytes)> {
+        for ((_, _), incoming) in self
+            .incoming
+            .iter_mut()
+            .filter(|((incoming_key, _), _)| *incoming_key == key)
+        {
+            incoming.acknowledged = 0;
+        }
+        self.outgoing
+            .iter()
+            .filter(|((outgoing_key, _), _)| *outgoing_key == key)
+            .flat_map(|((_, channel_id), outgoing)| {
+                outgoing
+                    .unacknowledged
+                    .iter()
+                    .map(|(_, frame)| (*channel_id, frame.clone()))
+            })
+            .collect()
+    }
+
+    fn rekey(&mut self, from: K, to: K) {
+        self.outgoing = std::mem::take(&mut self.outgoing)
+            .into_iter()
+            .map(|((key, channel_id), outgoing)| {
+                ((if key == from { to } else { key }, channel_id), outgoing)
+            })
+            .collect();
+        self.incoming = std::mem::take(&mut self.incoming)
+            .into_iter()
+            .map(|((key, channel_id), incoming)| {
+                ((if key == from { to } else { key }, channel_id), incoming)
+            })
+            .collect();
+    }
+
+    fn forget(&mut self, key: K) {
+        self.outgoing
+            .retain(|(outgoing_key, _), _| *outgoing_key != key);
+        self.incoming
+            .retain(|(incoming_key, _), _| *incoming_key != key);
+    }
+
+    fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.outgoing
+            .keys()
+            .chain(self.incoming.keys())
+            .map(|(key, _)| *key)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.outgoing.clear();
+        self.incoming.clear();
+    }
+}
+
+///
+/// The resumable streams of every client, kept while their session is suspended.
+///
+#[derive(Debug, Default, Resource)]
+pub struct ServerStreams(pub(crate) Streams<ClientId>);
+
+impl ServerStreams {
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys()
+    }
+}
+
+///
+/// The client's resumable streams, kept after disconnecting until the server resumed the session
+/// or started a new one.
+///
+#[derive(Debug, Resource)]
+pub struct ClientStreams {
+    pub(crate) streams: Streams<()>,
+    /// Whether the server didn't tell yet if the session was resumed.
+    pub(crate) awaiting_verdict: bool,
+}
+
+impl Default for ClientStreams {
+    fn default() -> Self {
+        Self {
+            streams: Streams::default(),
+            awaiting_verdict: true,
+        }
+    }
+}
+
+impl ClientStreams {
+    /// Whether resumable messages are held back, see the module documentation.
+    pub fn is_awaiting_verdict(&self) -> bool {
+        self.awaiting_verdict
+    }
+}
+
+pub fn server_resumes_streams(
+    mut server: ResMut<RenetServer>,
+    channel_configs: Res<NetworkConfigs>,
+    mut streams: ResMut<ServerStreams>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_reconnected_events: EventReader<ClientReconnected>,
+    mut player_reconnected_events: EventReader<PlayerReconnected>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+) {
+    let mut resumed: Vec<ClientId> = client_reconnected_events
+        .read()
+        .map(|client_reconnected| client_reconnected.client_id)
+        .collect();
+    // Carried over before the previous client id's `ClientDisconnected` forgets them.
+    for PlayerReconnected {
+        client_id,
+        previous_client_id,
+        ..
+    } in player_reconnected_events.read()
+    {
+        streams.0.rekey(*previous_client_id, *client_id);
+        resumed.push(*client_id);
+    }
+    for client_disconnected in client_disconnected_events.read() {
+        streams.0.forget(client_disconnected.client_id);
+    }
+    if !channel_configs.0.iter().any(NetworkConfig::uses_resumption) {
+        client_connected_events.clear();
+        return;
+    }
+    for ClientConnected { client_id, .. } in client_connected_events.read() {
+        if !resumed.contains(client_id) {
+            streams.0.forget(*client_id);
+            server.send_message(
+                (*client_id).into(),
+                CONTROL_CHANNEL_ID,
+                ControlMessage::ResetStreams.encode(),
+            );
+        }
+    }
+    for client_id in resumed {
+        server.send_message(
+            client_id.into(),
+            CONTROL_CHANNEL_ID,
+            ControlMessage::ResumeStreams.encode(),
+        );
+        for (channel_id, frame) in streams.0.resume(client_id) {
+            server.send_message(client_id.into(), channel_id, frame);
+        }
+    }
+}
+
+pub fn server_acknowledges_streams(
+    mut server: ResMut<RenetServer>,
+    mut streams: ResMut<ServerStreams>,
+) {
+    for (client_id, channel_id, sequence) in streams.0.acknowledgements() {
+        server.send_message(
+            client_id.into(),
+            CONTROL_CHANNEL_ID,
+            ControlMessage::StreamReceived {
+                channel_id,
+                sequence,
+            }
+            .encode(),
+        );
+    }
+}
+
+pub fn server_resets_streams(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut streams: ResMut<ServerStreams>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        streams.0.clear();
+    }
+}
+
+pub fn client_acknowledges_streams(
+    mut client: ResMut<RenetClient>,
+    mut streams: ResMut<ClientStreams>,
+) {
+    if !client.is_connected() {
+        return;
+    }
+    for ((), channel_id, sequence) in streams.streams.acknowledgements() {
+        client.send_message(
+            CONTROL_CHANNEL_ID,
+            ControlMessage::StreamReceived {
+                channel_id,
+                sequence,
+            }
+            .encode(),
+        );
+    }
+}
+
+pub fn client_awaits_stream_verdict(
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut streams: ResMut<ClientStreams>,
+) {
+    if client_stopped_events.read().count() > 0 {
+        streams.awaiting_verdict = true;
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ServerStreams>()
+        .init_resource::<ClientStreams>()
+        .add_systems(
+            PreUpdate,
+            server_resumes_streams
+                .after(server_expires_suspended_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            server_acknowledges_streams
+                .in_set(NetworkSet::Flush)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_resets_streams)
+        .add_systems(
+            PostUpdate,
+            client_acknowledges_streams
+                .in_set(NetworkSet::Flush)
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(PostUpdate, client_awaits_stream_verdict);
+}
@@ -0,0 +1,78 @@
+//!
+//! Team membership of connected clients, assigned by the server and replicated to the clients.
+//!
+use bevy::prelude::{Event, Resource};
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::ClientId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TeamId(pub u32);
+
+///
+/// Which team each client belongs to.
+/// Modified by the server, clients get a read-only copy kept up to date by the plugin.
+///
+#[derive(Debug, Default, Resource)]
+pub struct Teams {
+    members: HashMap<ClientId, TeamId>,
+    changes: Vec<(ClientId, Option<TeamId>)>,
+}
+
+impl Teams {
+    /// Moves the client to `team`, a client belongs to at most one team.
+    pub fn assign(&mut self, client_id: ClientId, team: TeamId) {
+        if self.members.insert(client_id, team) != Some(team) {
+            self.changes.push((client_id, Some(team)));
+        }
+    }
+
+    pub fn remove(&mut self, client_id: ClientId) {
+        if self.members.remove(&client_id).is_some() {
+            self.changes.push((client_id, None));
+        }
+    }
+
+    pub fn team_of(&self, client_id: ClientId) -> Option<TeamId> {
+        self.members.get(&client_id).copied()
+    }
+
+    pub fn members(&self, team: TeamId) -> impl Iterator<Item = ClientId> + '_ {
+        self.members
+            .iter()
+            .filter(move |(_, member_team)| **member_team == team)
+            .map(|(client_id, _)| *client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &TeamId)> {
+        self.members.iter()
+    }
+
+    /// Applies a change received from the server, without recording it.
+    pub(crate) fn apply(&mut self, client_id: ClientId, team: Option<TeamId>) {
+        match team {
+            Some(team) => self.members.insert(client_id, team),
+            None => self.members.remove(&client_id),
+        };
+    }
+
+    pub(crate) fn drain_changes(&mut self) -> Vec<(ClientId, Option<TeamId>)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.members.clear();
+        self.changes.clear();
+    }
+}
+
+///
+/// A client joined, changed or left a team (`team` is `None`), received on both the server and
+/// the clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClientTeamChanged {
+    pub client_id: ClientId,
+    pub team: Option<TeamId>,
+}
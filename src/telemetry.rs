@@ -0,0 +1,215 @@
+//!
+//! Per-connection traffic summaries on the server, for analytics without instrumenting the
+//! plugin's internals.
+//!
+//! Every client is tracked from its `ClientConnected` to its `ClientDisconnected` (a resumed
+//! session counts as one connection), then a `ConnectionSummary` event is sent with the session
+//! duration, the bytes and messages of each registered type sent and received, and the average
+//! round-trip time. Clients still connected when the server stops are summarized with
+//! `DisconnectReason::ServerShutdown`.
+//!
+//! Bytes are the payloads handed to and received from renet for the registered types, the
+//! plugin's own traffic (control messages, ticks, relayed messages) and packet headers aren't
+//! counted.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, PostUpdate,
+    PreUpdate, Res, ResMut, Resource, Time,
+};
+#[cfg(feature = "inspector")]
+use bevy::prelude::{Reflect, ReflectResource};
+use bevy::utils::HashMap;
+use bevy_renet::renet::RenetServer;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::{
+    server::{
+        server_expires_suspended_clients, ClientConnected, ClientDisconnected, ConnectedClients,
+        ServerStopped,
+    },
+    ChannelRegistry, ClientId, DisconnectReason,
+};
+
+///
+/// Sent on the server once a client is gone, with a summary of its connection.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ConnectionSummary {
+    pub client_id: ClientId,
+    pub reason: DisconnectReason,
+    pub duration: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// `None` when the connection didn't last a single tick.
+    pub average_rtt: Option<Duration>,
+    /// The registered types the client sent or was sent, ordered by channel.
+    pub messages: Vec<TypeTraffic>,
+}
+
+///
+/// Messages of one registered type exchanged with a client, see `ConnectionSummary`.
+///
+#[derive(Debug, Clone)]
+pub struct TypeTraffic {
+    pub channel_id: u8,
+    pub type_name: &'static str,
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+struct ChannelTraffic {
+    sent: u64,
+    received: u64,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "inspector", derive(Reflect))]
+struct ConnectionStats {
+    connected_at: Duration,
+    bytes_sent: u64,
+    bytes_received: u64,
+    rtt_total: f64,
+    rtt_samples: u32,
+    channels: BTreeMap<u8, ChannelTraffic>,
+}
+
+///
+/// The traffic of the connected (and suspended) clients, summarized as `ConnectionSummary` events.
+///
+#[derive(Debug, Default, Resource)]
+#[cfg_attr(feature = "inspector", derive(Reflect), reflect(Resource))]
+pub struct ConnectionTelemetry(HashMap<ClientId, ConnectionStats>);
+
+impl ConnectionTelemetry {
+    /// Bytes sent to and received from the client so far.
+    pub fn bytes(&self, client_id: ClientId) -> Option<(u64, u64)> {
+        self.0
+            .get(&client_id)
+            .map(|stats| (stats.bytes_sent, stats.bytes_received))
+    }
+
+    pub(crate) fn sent(&mut self, client_id: ClientId, channel_id: u8, bytes: usize) {
+        if let Some(stats) = self.0.get_mut(&client_id) {
+            stats.bytes_sent += bytes as u64;
+            stats.channels.entry(channel_id).or_default().sent += 1;
+        }
+    }
+
+    pub(crate) fn received(&mut self, client_id: ClientId, channel_id: u8, bytes: usize) {
+        if let Some(stats) = self.0.get_mut(&client_id) {
+            stats.bytes_received += bytes as u64;
+            stats.channels.entry(channel_id).or_default().received += 1;
+        }
+    }
+
+    pub(crate) fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.0.keys().copied()
+    }
+
+    fn summarize(
+        &mut self,
+        client_id: ClientId,
+        reason: DisconnectReason,
+        now: Duration,
+        channel_registry: &ChannelRegistry,
+    ) -> Option<ConnectionSummary> {
+        let stats = self.0.remove(&client_id)?;
+        Some(ConnectionSummary {
+            client_id,
+            reason,
+            duration: now.saturating_sub(stats.connected_at),
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            average_rtt: (stats.rtt_samples > 0)
+                .then(|| Duration::from_secs_f64(stats.rtt_total / stats.rtt_samples as f64)),
+            messages: stats
+                .channels
+                .into_iter()
+                .map(|(channel_id, traffic)| TypeTraffic {
+                    channel_id,
+                    type_name: channel_registry.type_name(channel_id).unwrap_or_default(),
+                    sent: traffic.sent,
+                    received: traffic.received,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn server_tracks_connection_telemetry(
+    server: Res<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    channel_registry: Res<ChannelRegistry>,
+    time: Res<Time>,
+    mut telemetry: ResMut<ConnectionTelemetry>,
+    mut client_connected_events: EventReader<ClientConnected>,
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut connection_summary_events: EventWriter<ConnectionSummary>,
+) {
+    for ClientConnected { client_id, .. } in client_connected_events.read() {
+        telemetry.0.insert(
+            *client_id,
+            ConnectionStats {
+                connected_at: time.elapsed(),
+                bytes_sent: 0,
+                bytes_received: 0,
+                rtt_total: 0.0,
+                rtt_samples: 0,
+                channels: BTreeMap::new(),
+            },
+        );
+    }
+    for ClientDisconnected { client_id, reason } in client_disconnected_events.read() {
+        if let Some(summary) =
+            telemetry.summarize(*client_id, *reason, time.elapsed(), &channel_registry)
+        {
+            connection_summary_events.send(summary);
+        }
+    }
+    for (client_id, stats) in telemetry.0.iter_mut() {
+        if connected_clients.get(*client_id).is_some() {
+            stats.rtt_total += server.rtt((*client_id).into());
+            stats.rtt_samples += 1;
+        }
+    }
+}
+
+pub fn server_summarizes_connections_on_stop(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    channel_registry: Res<ChannelRegistry>,
+    time: Res<Time>,
+    mut telemetry: ResMut<ConnectionTelemetry>,
+    mut connection_summary_events: EventWriter<ConnectionSummary>,
+) {
+    if server_stopped_events.read().count() == 0 {
+        return;
+    }
+    let client_ids: Vec<ClientId> = telemetry.client_ids().collect();
+    for client_id in client_ids {
+        if let Some(summary) = telemetry.summarize(
+            client_id,
+            DisconnectReason::ServerShutdown,
+            time.elapsed(),
+            &channel_registry,
+        ) {
+            connection_summary_events.send(summary);
+        }
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ConnectionTelemetry>()
+        .add_event::<ConnectionSummary>()
+        .add_systems(
+            PreUpdate,
+            server_tracks_connection_telemetry
+                .after(server_expires_suspended_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_summarizes_connections_on_stop);
+}
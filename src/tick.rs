@@ -0,0 +1,174 @@
+//!
+//! Authoritative server tick, broadcast to the clients which keep an estimate of it.
+//!
+//! The server counts its frames in `ServerTick` and sends the count on a reserved unreliable
+//! channel every frame. Clients maintain `EstimatedServerTick`, advancing it with their own clock
+//! and correcting it as ticks arrive, so it can be used to timestamp inputs or drive
+//! interpolation even when the two ends run at different frame rates.
+//!
+use bevy::prelude::{
+    resource_exists, App, EventReader, IntoSystemConfigs, PostUpdate, PreUpdate, Res, ResMut,
+    Resource, Time,
+};
+use bevy_renet::renet::{RenetClient, RenetServer};
+use bevy_renet::RenetReceive;
+
+use std::time::Duration;
+
+use crate::{
+    client::ClientStopped,
+    server::{ConnectedClients, ServerStopped},
+    NetworkSet, TICK_CHANNEL_ID,
+};
+
+/// Estimate errors larger than this many ticks are corrected at once instead of smoothed.
+const SNAP_THRESHOLD: f64 = 10.0;
+
+/// Fraction of the estimate error corrected per received tick.
+const CORRECTION_RATE: f64 = 0.1;
+
+/// Weight of the latest measurement in the smoothed tick rate.
+const TICK_RATE_SMOOTHING: f64 = 0.1;
+
+///
+/// Number of frames the server has run since it started.
+///
+#[derive(Debug, Default, Clone, Copy, Resource)]
+pub struct ServerTick(u64);
+
+impl ServerTick {
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+///
+/// The client's estimate of the server's current `ServerTick`, accounting for latency.
+/// Unavailable until the server's tick rate is known (two ticks received), and reset when
+/// disconnecting.
+///
+#[derive(Debug, Default, Clone, Resource)]
+pub struct EstimatedServerTick {
+    estimate: Option<f64>,
+    tick_rate: Option<f64>,
+    last_received: Option<(u64, Duration)>,
+}
+
+impl EstimatedServerTick {
+    pub fn tick(&self) -> Option<u64> {
+        self.estimate().map(|estimate| estimate.max(0.0) as u64)
+    }
+
+    /// The estimate with its fractional part, handy for interpolating between ticks.
+    pub fn estimate(&self) -> Option<f64> {
+        self.tick_rate.and(self.estimate)
+    }
+
+    /// Server ticks per second, as measured from the received ticks.
+    pub fn tick_rate(&self) -> Option<f64> {
+        self.tick_rate
+    }
+
+    fn advance(&mut self, delta: Duration) {
+        if let (Some(estimate), Some(tick_rate)) = (&mut self.estimate, self.tick_rate) {
+            *estimate += delta.as_secs_f64() * tick_rate;
+        }
+    }
+
+    fn receive(&mut self, tick: u64, received_at: Duration, latency: f64) {
+        if let Some((last_tick, last_received_at)) = self.last_received {
+            if tick <= last_tick {
+                return; // Out of order.
+            }
+            let elapsed = received_at.saturating_sub(last_received_at).as_secs_f64();
+            if elapsed > 0.0 {
+                let measured = (tick - last_tick) as f64 / elapsed;
+                self.tick_rate = Some(match self.tick_rate {
+                    Some(tick_rate) => tick_rate + (measured - tick_rate) * TICK_RATE_SMOOTHING,
+                    None => measured,
+                });
+            }
+        }
+        self.last_received = Some((tick, received_at));
+        let target = tick as f64 + latency * self.tick_rate.unwrap_or_default();
+        self.estimate = Some(match self.estimate {
+            Some(estimate) if (target - estimate).abs() <= SNAP_THRESHOLD => {
+                estimate + (target - estimate) * CORRECTION_RATE
+            },
+            _ => target,
+        });
+    }
+}
+
+pub fn server_advances_tick(mut server_tick: ResMut<ServerTick>) {
+    server_tick.0 += 1;
+}
+
+pub fn server_broadcasts_tick(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    server_tick: Res<ServerTick>,
+) {
+    let payload =
+        bincode::serde::encode_to_vec(server_tick.0, bincode::config::standard()).unwrap();
+    for (client_id, _) in connected_clients.iter() {
+        server.send_message((*client_id).into(), TICK_CHANNEL_ID, payload.clone());
+    }
+}
+
+pub fn server_resets_tick(
+    mut server_stopped_events: EventReader<ServerStopped>,
+    mut server_tick: ResMut<ServerTick>,
+) {
+    if server_stopped_events.read().count() > 0 {
+        *server_tick = ServerTick::default();
+    }
+}
+
+pub fn client_estimates_server_tick(
+    mut client: ResMut<RenetClient>,
+    time: Res<Time>,
+    mut estimated_server_tick: ResMut<EstimatedServerTick>,
+) {
+    estimated_server_tick.advance(time.delta());
+    let latency = client.rtt() / 2.0;
+    while let Some(message) = client.receive_message(TICK_CHANNEL_ID) {
+        if let Ok((tick, _)) =
+            bincode::serde::decode_from_slice::<u64, _>(&message, bincode::config::standard())
+        {
+            estimated_server_tick.receive(tick, time.elapsed(), latency);
+        }
+    }
+}
+
+pub fn client_resets_estimated_server_tick(
+    mut client_stopped_events: EventReader<ClientStopped>,
+    mut estimated_server_tick: ResMut<EstimatedServerTick>,
+) {
+    if client_stopped_events.read().count() > 0 {
+        *estimated_server_tick = EstimatedServerTick::default();
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<ServerTick>()
+        .init_resource::<EstimatedServerTick>()
+        .add_systems(
+            PreUpdate,
+            server_advances_tick.run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            server_broadcasts_tick
+                .in_set(NetworkSet::Send)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(PostUpdate, server_resets_tick)
+        .add_systems(
+            PreUpdate,
+            client_estimates_server_tick
+                .after(RenetReceive)
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(PostUpdate, client_resets_estimated_server_tick);
+}
@@ -0,0 +1,173 @@
+//!
+//! The netcode transport systems, run in place of bevy_renet's `NetcodeServerPlugin` and
+//! `NetcodeClientPlugin` so that a panic inside the transport (e.g. on an unexpected packet)
+//! doesn't take the whole app down.
+//!
+//! A panicking update or packet send is reported as `NetworkError::TransportPanic`, then the
+//! server is stopped (`StopServer`) or the client disconnected (`DisconnectFromServer`), since
+//! the transport's state can't be trusted anymore. Panics can only be caught when the app is
+//! built with `panic = "unwind"` (the default), the panic hook still prints them.
+//!
+use bevy::app::AppExit;
+use bevy::prelude::{
+    resource_exists, App, EventReader, EventWriter, IntoSystemConfigs, PostUpdate, PreUpdate, Res,
+    ResMut, Time,
+};
+use bevy_renet::{
+    renet::{
+        transport::{NetcodeClientTransport, NetcodeServerTransport, NetcodeTransportError},
+        RenetClient, RenetServer,
+    },
+    transport::client_should_update,
+    RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin,
+};
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::{
+    client::DisconnectFromServer,
+    error::{ClientNetworkError, NetworkError, ServerNetworkError},
+    server::StopServer,
+};
+
+/// Runs `f`, turning a panic into its message.
+pub(crate) fn guarded<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(payload.as_ref()))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+pub fn server_updates_transport(
+    mut transport: ResMut<NetcodeServerTransport>,
+    mut server: ResMut<RenetServer>,
+    time: Res<Time>,
+    mut transport_errors: EventWriter<NetcodeTransportError>,
+    mut network_errors: EventWriter<ServerNetworkError>,
+    mut stop_server_events: EventWriter<StopServer>,
+) {
+    match guarded(|| transport.update(time.delta(), &mut server)) {
+        Ok(Ok(())) => {},
+        Ok(Err(error)) => {
+            transport_errors.send(error);
+        },
+        Err(message) => {
+            network_errors.send(ServerNetworkError(NetworkError::TransportPanic(message)));
+            stop_server_events.send(StopServer);
+        },
+    }
+}
+
+pub fn server_sends_packets(
+    mut transport: ResMut<NetcodeServerTransport>,
+    mut server: ResMut<RenetServer>,
+    mut network_errors: EventWriter<ServerNetworkError>,
+    mut stop_server_events: EventWriter<StopServer>,
+) {
+    if let Err(message) = guarded(|| transport.send_packets(&mut server)) {
+        network_errors.send(ServerNetworkError(NetworkError::TransportPanic(message)));
+        stop_server_events.send(StopServer);
+    }
+}
+
+pub fn server_disconnects_transport_on_exit(
+    mut app_exit_events: EventReader<AppExit>,
+    mut transport: ResMut<NetcodeServerTransport>,
+    mut server: ResMut<RenetServer>,
+) {
+    if app_exit_events.read().count() > 0 {
+        let _ = guarded(|| transport.disconnect_all(&mut server));
+    }
+}
+
+pub fn client_updates_transport(
+    mut transport: ResMut<NetcodeClientTransport>,
+    mut client: ResMut<RenetClient>,
+    time: Res<Time>,
+    mut transport_errors: EventWriter<NetcodeTransportError>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+) {
+    match guarded(|| transport.update(time.delta(), &mut client)) {
+        Ok(Ok(())) => {},
+        Ok(Err(error)) => {
+            transport_errors.send(error);
+        },
+        Err(message) => {
+            network_errors.send(ClientNetworkError(NetworkError::TransportPanic(message)));
+            disconnect_from_server_events.send(DisconnectFromServer);
+        },
+    }
+}
+
+pub fn client_sends_packets(
+    mut transport: ResMut<NetcodeClientTransport>,
+    mut client: ResMut<RenetClient>,
+    mut transport_errors: EventWriter<NetcodeTransportError>,
+    mut network_errors: EventWriter<ClientNetworkError>,
+    mut disconnect_from_server_events: EventWriter<DisconnectFromServer>,
+) {
+    match guarded(|| transport.send_packets(&mut client)) {
+        Ok(Ok(())) => {},
+        Ok(Err(error)) => {
+            transport_errors.send(error);
+        },
+        Err(message) => {
+            network_errors.send(ClientNetworkError(NetworkError::TransportPanic(message)));
+            disconnect_from_server_events.send(DisconnectFromServer);
+        },
+    }
+}
+
+pub fn client_disconnects_transport_on_exit(
+    mut app_exit_events: EventReader<AppExit>,
+    mut transport: ResMut<NetcodeClientTransport>,
+) {
+    if app_exit_events.read().count() > 0 {
+        let _ = guarded(|| transport.disconnect());
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.add_event::<NetcodeTransportError>()
+        .add_systems(
+            PreUpdate,
+            server_updates_transport
+                .in_set(RenetReceive)
+                .after(RenetServerPlugin::update_system)
+                .before(RenetServerPlugin::emit_server_events_system)
+                .run_if(resource_exists::<NetcodeServerTransport>)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                server_sends_packets.in_set(RenetSend),
+                server_disconnects_transport_on_exit,
+            )
+                .run_if(resource_exists::<NetcodeServerTransport>)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PreUpdate,
+            client_updates_transport
+                .in_set(RenetReceive)
+                .after(RenetClientPlugin::update_system)
+                .run_if(resource_exists::<NetcodeClientTransport>)
+                .run_if(resource_exists::<RenetClient>),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                client_sends_packets.in_set(RenetSend),
+                client_disconnects_transport_on_exit,
+            )
+                .run_if(client_should_update),
+        );
+}
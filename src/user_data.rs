@@ -0,0 +1,161 @@
+//!
+//! Layout of the netcode user data bytes reserved by this crate.
+//! Users own the leading `NETCODE_USER_DATA_BYTES - RESERVED_BYTES` bytes, the trailing bytes
+//! carry connection metadata and the redirect token.
+//!
+use renet::transport::NETCODE_USER_DATA_BYTES;
+use sha2::{Digest, Sha256};
+
+use crate::{ChannelsFingerprint, ConnectionMode, PlayerId};
+
+/// Index of the byte holding this crate's connection flags.
+const FLAGS_INDEX: usize = NETCODE_USER_DATA_BYTES - 1;
+
+/// Index of the byte holding the number of registered types.
+const CHANNEL_COUNT_INDEX: usize = NETCODE_USER_DATA_BYTES - 2;
+
+/// Start of the 4 bytes holding the hash of the registered type names.
+const TYPES_HASH_INDEX: usize = NETCODE_USER_DATA_BYTES - 6;
+
+/// Start of the 16 bytes holding the hash of the server password.
+const PASSWORD_HASH_INDEX: usize = NETCODE_USER_DATA_BYTES - 22;
+
+/// Start of the 16 bytes holding the player id.
+const PLAYER_ID_INDEX: usize = NETCODE_USER_DATA_BYTES - 38;
+
+/// Redirect tokens are at most this long, see `REDIRECT_TOKEN_INDEX`.
+pub(crate) const MAX_REDIRECT_TOKEN_BYTES: usize = 128;
+
+/// Start of the byte holding the length of the redirect token, followed by the token itself.
+const REDIRECT_TOKEN_INDEX: usize = PLAYER_ID_INDEX - 1 - MAX_REDIRECT_TOKEN_BYTES;
+
+/// Trailing bytes reserved by this crate, whether or not they are used.
+pub(crate) const RESERVED_BYTES: usize = NETCODE_USER_DATA_BYTES - REDIRECT_TOKEN_INDEX;
+
+const SPECTATOR_FLAG: u8 = 0b0000_0001;
+const REDIRECT_TOKEN_FLAG: u8 = 0b0000_0010;
+const PLAYER_ID_FLAG: u8 = 0b0000_0100;
+
+pub(crate) fn write_connection_mode(
+    user_data: &mut [u8; NETCODE_USER_DATA_BYTES],
+    mode: ConnectionMode,
+) {
+    match mode {
+        ConnectionMode::Player => user_data[FLAGS_INDEX] &= !SPECTATOR_FLAG,
+        ConnectionMode::Spectator => user_data[FLAGS_INDEX] |= SPECTATOR_FLAG,
+    }
+}
+
+pub(crate) fn read_connection_mode(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> ConnectionMode {
+    if user_data[FLAGS_INDEX] & SPECTATOR_FLAG != 0 {
+        ConnectionMode::Spectator
+    } else {
+        ConnectionMode::Player
+    }
+}
+
+/// Returns `false` when the token is longer than `MAX_REDIRECT_TOKEN_BYTES`.
+pub(crate) fn write_redirect_token(
+    user_data: &mut [u8; NETCODE_USER_DATA_BYTES],
+    token: &[u8],
+) -> bool {
+    if token.len() > MAX_REDIRECT_TOKEN_BYTES {
+        return false;
+    }
+    let token_start = REDIRECT_TOKEN_INDEX + 1;
+    user_data[REDIRECT_TOKEN_INDEX] = token.len() as u8;
+    user_data[token_start..token_start + token.len()].copy_from_slice(token);
+    user_data[FLAGS_INDEX] |= REDIRECT_TOKEN_FLAG;
+    true
+}
+
+pub(crate) fn read_redirect_token(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<Vec<u8>> {
+    if user_data[FLAGS_INDEX] & REDIRECT_TOKEN_FLAG == 0 {
+        return None;
+    }
+    let token_start = REDIRECT_TOKEN_INDEX + 1;
+    let len = (user_data[REDIRECT_TOKEN_INDEX] as usize).min(MAX_REDIRECT_TOKEN_BYTES);
+    Some(user_data[token_start..token_start + len].to_vec())
+}
+
+pub(crate) fn write_player_id(user_data: &mut [u8; NETCODE_USER_DATA_BYTES], player_id: PlayerId) {
+    user_data[PLAYER_ID_INDEX..PASSWORD_HASH_INDEX].copy_from_slice(&player_id.to_bytes());
+    user_data[FLAGS_INDEX] |= PLAYER_ID_FLAG;
+}
+
+pub(crate) fn read_player_id(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<PlayerId> {
+    if user_data[FLAGS_INDEX] & PLAYER_ID_FLAG == 0 {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&user_data[PLAYER_ID_INDEX..PASSWORD_HASH_INDEX]);
+    Some(PlayerId::from_bytes(bytes))
+}
+
+pub(crate) fn write_channels_fingerprint(
+    user_data: &mut [u8; NETCODE_USER_DATA_BYTES],
+    fingerprint: ChannelsFingerprint,
+) {
+    user_data[CHANNEL_COUNT_INDEX] = fingerprint.count;
+    user_data[TYPES_HASH_INDEX..CHANNEL_COUNT_INDEX]
+        .copy_from_slice(&fingerprint.types_hash.to_le_bytes());
+}
+
+pub(crate) fn read_channels_fingerprint(
+    user_data: &[u8; NETCODE_USER_DATA_BYTES],
+) -> ChannelsFingerprint {
+    let mut types_hash = [0; 4];
+    types_hash.copy_from_slice(&user_data[TYPES_HASH_INDEX..CHANNEL_COUNT_INDEX]);
+    ChannelsFingerprint {
+        count: user_data[CHANNEL_COUNT_INDEX],
+        types_hash: u32::from_le_bytes(types_hash),
+    }
+}
+
+pub(crate) type PasswordHash = [u8; 16];
+
+/// Salted with the protocol id so the hash can't be replayed against servers of other games.
+pub(crate) fn password_hash(password: &str, protocol_id: u64) -> PasswordHash {
+    let digest = Sha256::new()
+        .chain_update(b"bevy_client_server_events password")
+        .chain_update(protocol_id.to_le_bytes())
+        .chain_update(password.as_bytes())
+        .finalize();
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&digest[..16]);
+    hash
+}
+
+pub(crate) fn write_password_hash(
+    user_data: &mut [u8; NETCODE_USER_DATA_BYTES],
+    hash: PasswordHash,
+) {
+    user_data[PASSWORD_HASH_INDEX..TYPES_HASH_INDEX].copy_from_slice(&hash);
+}
+
+pub(crate) fn read_password_hash(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> PasswordHash {
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&user_data[PASSWORD_HASH_INDEX..TYPES_HASH_INDEX]);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_token_leaves_the_user_bytes_alone() {
+        let user_bytes = NETCODE_USER_DATA_BYTES - RESERVED_BYTES;
+        let mut user_data = [0xAB; NETCODE_USER_DATA_BYTES];
+        let token = [7; MAX_REDIRECT_TOKEN_BYTES];
+        assert!(write_redirect_token(&mut user_data, &token));
+        write_player_id(&mut user_data, PlayerId(42));
+        assert!(user_data[..user_bytes].iter().all(|byte| *byte == 0xAB));
+        assert_eq!(read_redirect_token(&user_data), Some(token.to_vec()));
+        assert_eq!(read_player_id(&user_data), Some(PlayerId(42)));
+        assert!(!write_redirect_token(
+            &mut user_data,
+            &[7; MAX_REDIRECT_TOKEN_BYTES + 1]
+        ));
+    }
+}
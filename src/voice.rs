@@ -0,0 +1,525 @@
+//!
+//! Voice payload transport, enabled with the `voice` feature.
+//!
+//! Audio is encoded and decoded outside of this crate (e.g. with Opus), this module only moves the
+//! encoded frames: clients send `SendVoiceFrame` events, the server relays them to the other
+//! connected clients, and every receiver gets `VoiceFrame` events. Frames travel on an unreliable
+//! sequenced channel: the server drops duplicated frames and frames older than the latest one
+//! from the same sender before relaying. Frames can still be reordered or lost on their way to
+//! the other clients, so receivers should feed them into a `JitterBuffer` (one per sender) to
+//! smooth that out before playback.
+//!
+//! Listen servers get the frames of the other clients twice as `VoiceFrame`, once received by the
+//! server and once delivered to the client, `ServerVoiceFrame` and `ClientVoiceFrame` tell them
+//! apart. Only the frames the server received are relayed.
+//!
+use bevy::prelude::{
+    resource_exists, App, Event, EventReader, EventWriter, IntoSystemConfigs, Local, PostUpdate,
+    PreUpdate, Res, ResMut, Resource,
+};
+use bevy_renet::renet::{Bytes, RenetClient, RenetServer};
+use bevy_renet::RenetReceive;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+use crate::{
+    sequence::{Arrival, Sequences},
+    server::{server_tracks_connected_and_disconnected_clients, ConnectedClients},
+    ClientId, ConnectionMode, VOICE_CHANNEL_ID,
+};
+
+/// Upper bound on the memory decoding a voice message may claim, so that a crafted length prefix
+/// can't make the server allocate huge buffers. Larger frames are dropped.
+const MAX_VOICE_MESSAGE_BYTES: usize = 1 << 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum VoicePayload {
+    Send {
+        seq: u32,
+        bytes: Vec<u8>,
+    },
+    Deliver {
+        client_id: ClientId,
+        seq: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+impl VoicePayload {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard().with_limit::<MAX_VOICE_MESSAGE_BYTES>();
+        bincode::serde::decode_from_slice(bytes, config)
+            .ok()
+            .map(|(payload, _)| payload)
+    }
+}
+
+///
+/// Send this event from a client with an encoded audio frame, sequence numbers are assigned
+/// automatically.
+///
+#[derive(Debug, Event)]
+pub struct SendVoiceFrame {
+    pub bytes: Vec<u8>,
+}
+
+///
+/// An encoded audio frame from `client_id`, received on the server and by the other clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct VoiceFrame {
+    pub client_id: ClientId,
+    pub seq: u32,
+    pub bytes: Vec<u8>,
+}
+
+///
+/// A `VoiceFrame` received by the server (including the server of a listen server), the frames
+/// relayed to the other clients.
+///
+#[derive(Debug, Clone, Event)]
+pub struct ServerVoiceFrame(pub VoiceFrame);
+
+///
+/// A `VoiceFrame` delivered to the client (including the client of a listen server).
+///
+#[derive(Debug, Clone, Event)]
+pub struct ClientVoiceFrame(pub VoiceFrame);
+
+///
+/// Server side voice settings.
+///
+#[derive(Debug, Resource)]
+pub struct VoiceConfig {
+    /// Whether the server forwards frames to the other clients, disable it to route voice
+    /// yourself (proximity, teams) with `VoiceFrame` events.
+    pub relay: bool,
+    /// Whether frames from spectators are accepted.
+    pub accept_from_spectators: bool,
+    /// Frames larger than this are dropped by the server.
+    pub max_frame_bytes: usize,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        Self {
+            relay: true,
+            accept_from_spectators: false,
+            max_frame_bytes: 1024,
+        }
+    }
+}
+
+///
+/// What a `JitterBuffer` yields at each playback tick.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JitterFrame {
+    Frame(Vec<u8>),
+    /// The expected frame was lost or is too late, the decoder should conceal it.
+    Missing,
+}
+
+///
+/// Reorders the frames of a single sender and releases them at a steady pace.
+///
+/// Call `push` for every received frame and `pop` once per playback frame (e.g. every 20ms).
+/// Playback starts once `depth` frames are buffered, and buffering starts over when it runs dry.
+///
+#[derive(Debug)]
+pub struct JitterBuffer {
+    frames: BTreeMap<u32, Vec<u8>>,
+    next_seq: Option<u32>,
+    depth: usize,
+}
+
+impl JitterBuffer {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            next_seq: None,
+            depth: depth.max(1),
+        }
+    }
+
+    /// Buffers a frame, frames older than the one currently expected are dropped.
+    pub fn push(&mut self, seq: u32, bytes: Vec<u8>) {
+        if let Some(next_seq) = self.next_seq {
+            if seq < next_seq {
+                if next_seq - seq <= self.capacity() as u32 {
+                    return;
+                }
+                // Far behind, the sender restarted its sequence.
+                self.clear();
+            }
+        }
+        self.frames.insert(seq, bytes);
+        while self.frames.len() > self.capacity() {
+            self.frames.pop_first();
+        }
+    }
+
+    /// The next frame to play, `None` while buffering.
+    pub fn pop(&mut self) -> Option<JitterFrame> {
+        let Some(first_seq) = self.frames.keys().next().copied() else {
+            // Ran dry, wait for `depth` frames again.
+            self.next_seq = None;
+            return None;
+        };
+        let next_seq = match self.next_seq {
+            Some(next_seq) if first_seq - next_seq <= self.depth as u32 => next_seq,
+            _ if self.frames.len() < self.depth => return None,
+            // Start playing, or resynchronize after a gap too long to conceal.
+            _ => first_seq,
+        };
+        self.next_seq = Some(next_seq.wrapping_add(1));
+        Some(match self.frames.remove(&next_seq) {
+            Some(bytes) => JitterFrame::Frame(bytes),
+            None => JitterFrame::Missing,
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.depth * 4
+    }
+
+    /// Number of frames currently buffered.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.next_seq = None;
+    }
+}
+
+impl Default for JitterBuffer {
+    /// Three frames, 60ms with the usual 20ms audio frames.
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+pub(crate) fn server_receives_voice_frames(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    voice_config: Res<VoiceConfig>,
+    mut sequences: Local<Sequences<ClientId>>,
+    mut voice_frame_events: EventWriter<VoiceFrame>,
+    mut server_voice_frame_events: EventWriter<ServerVoiceFrame>,
+) {
+    // Reconnecting clients start their sequence over.
+    sequences.retain(|client_id| connected_clients.get(*client_id).is_some());
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, VOICE_CHANNEL_ID) {
+            let Some(client) = connected_clients.get(client_id.into()) else {
+                continue;
+            };
+            if client.mode == ConnectionMode::Spectator && !voice_config.accept_from_spectators {
+                continue;
+            }
+            let Some(VoicePayload::Send { seq, bytes }) = VoicePayload::decode(&message) else {
+                continue;
+            };
+            if bytes.len() > voice_config.max_frame_bytes
+                || sequences.arrival(client_id.into(), seq) != Arrival::InOrder
+            {
+                continue;
+            }
+            let voice_frame = VoiceFrame {
+                client_id: client_id.into(),
+                seq,
+                bytes,
+            };
+            server_voice_frame_events.send(ServerVoiceFrame(voice_frame.clone()));
+            voice_frame_events.send(voice_frame);
+        }
+    }
+}
+
+pub fn server_relays_voice_frames(
+    mut server: ResMut<RenetServer>,
+    connected_clients: Res<ConnectedClients>,
+    voice_config: Res<VoiceConfig>,
+    mut server_voice_frame_events: EventReader<ServerVoiceFrame>,
+) {
+    if !voice_config.relay {
+        server_voice_frame_events.clear();
+        return;
+    }
+    for ServerVoiceFrame(VoiceFrame {
+        client_id: sender,
+        seq,
+        bytes,
+    }) in server_voice_frame_events.read()
+    {
+        let payload = Bytes::from(
+            VoicePayload::Deliver {
+                client_id: *sender,
+                seq: *seq,
+                bytes: bytes.clone(),
+            }
+            .encode(),
+        );
+        for (client_id, _) in connected_clients.iter() {
+            if client_id != sender {
+                server.send_message((*client_id).into(), VOICE_CHANNEL_ID, payload.clone());
+            }
+        }
+    }
+}
+
+pub fn client_sends_voice_frames(
+    mut client: ResMut<RenetClient>,
+    mut seq: Local<u32>,
+    mut send_voice_frame_events: EventReader<SendVoiceFrame>,
+) {
+    for SendVoiceFrame { bytes } in send_voice_frame_events.read() {
+        let payload = VoicePayload::Send {
+            seq: *seq,
+            bytes: bytes.clone(),
+        };
+        client.send_message(VOICE_CHANNEL_ID, payload.encode());
+        *seq = seq.wrapping_add(1);
+    }
+}
+
+pub fn client_receives_voice_frames(
+    mut client: ResMut<RenetClient>,
+    mut voice_frame_events: EventWriter<VoiceFrame>,
+    mut client_voice_frame_events: EventWriter<ClientVoiceFrame>,
+) {
+    while let Some(message) = client.receive_message(VOICE_CHANNEL_ID) {
+        if let Some(VoicePayload::Deliver {
+            client_id,
+            seq,
+            bytes,
+        }) = VoicePayload::decode(&message)
+        {
+            let voice_frame = VoiceFrame {
+                client_id,
+                seq,
+                bytes,
+            };
+            client_voice_frame_events.send(ClientVoiceFrame(voice_frame.clone()));
+            voice_frame_events.send(voice_frame);
+        }
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<VoiceConfig>()
+        .add_event::<SendVoiceFrame>()
+        .add_event::<VoiceFrame>()
+        .add_event::<ServerVoiceFrame>()
+        .add_event::<ClientVoiceFrame>()
+        .add_systems(
+            PreUpdate,
+            server_receives_voice_frames
+                .after(RenetReceive)
+                .after(server_tracks_connected_and_disconnected_clients)
+                .run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            server_relays_voice_frames.run_if(resource_exists::<RenetServer>),
+        )
+        .add_systems(
+            PostUpdate,
+            (client_sends_voice_frames, client_receives_voice_frames)
+                .run_if(resource_exists::<RenetClient>),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{Mut, Update};
+    use renet::{ChannelConfig, ConnectionConfig, SendType};
+
+    use super::*;
+    use crate::server::ConnectedClient;
+
+    fn frame(seq: u32) -> JitterFrame {
+        JitterFrame::Frame(vec![seq as u8])
+    }
+
+    fn push(buffer: &mut JitterBuffer, seqs: &[u32]) {
+        for seq in seqs {
+            buffer.push(*seq, vec![*seq as u8]);
+        }
+    }
+
+    #[test]
+    fn reordered_frames_are_played_in_order() {
+        let mut buffer = JitterBuffer::new(3);
+        push(&mut buffer, &[2, 0, 1]);
+        assert_eq!(buffer.pop(), Some(frame(0)));
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), Some(frame(2)));
+    }
+
+    #[test]
+    fn lost_frames_are_missing() {
+        let mut buffer = JitterBuffer::new(3);
+        push(&mut buffer, &[0, 1, 3]);
+        assert_eq!(buffer.pop(), Some(frame(0)));
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), Some(JitterFrame::Missing));
+        assert_eq!(buffer.pop(), Some(frame(3)));
+    }
+
+    #[test]
+    fn long_gaps_resynchronize() {
+        let mut buffer = JitterBuffer::new(2);
+        push(&mut buffer, &[0, 1, 2]);
+        assert_eq!(buffer.pop(), Some(frame(0)));
+        push(&mut buffer, &[10, 11]);
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), Some(frame(2)));
+        assert_eq!(buffer.pop(), Some(frame(10)));
+        assert_eq!(buffer.pop(), Some(frame(11)));
+    }
+
+    #[test]
+    fn buffering_starts_over_when_dry() {
+        let mut buffer = JitterBuffer::new(2);
+        push(&mut buffer, &[0, 1]);
+        assert_eq!(buffer.pop(), Some(frame(0)));
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), None);
+        push(&mut buffer, &[2]);
+        assert_eq!(buffer.pop(), None);
+        push(&mut buffer, &[3]);
+        assert_eq!(buffer.pop(), Some(frame(2)));
+        assert_eq!(buffer.pop(), Some(frame(3)));
+    }
+
+    #[test]
+    fn crafted_length_prefix_is_rejected() {
+        let mut bytes = vec![0, 0, 253];
+        bytes.extend_from_slice(&(1u64 << 62).to_le_bytes());
+        assert!(VoicePayload::decode(&bytes).is_none());
+    }
+
+    #[derive(Default, Resource)]
+    struct Received {
+        server: usize,
+        client: usize,
+    }
+
+    fn count_received(
+        mut server_voice_frame_events: EventReader<ServerVoiceFrame>,
+        mut client_voice_frame_events: EventReader<ClientVoiceFrame>,
+        mut received: ResMut<Received>,
+    ) {
+        received.server += server_voice_frame_events.read().count();
+        received.client += client_voice_frame_events.read().count();
+    }
+
+    #[test]
+    fn listen_server_relays_frames_once() {
+        let channels_config = vec![ChannelConfig {
+            channel_id: VOICE_CHANNEL_ID,
+            max_memory_usage_bytes: 1 << 20,
+            send_type: SendType::Unreliable,
+        }];
+        let mut server = RenetServer::new(ConnectionConfig {
+            server_channels_config: channels_config.clone(),
+            client_channels_config: channels_config,
+            ..Default::default()
+        });
+        let (local_id, remote_id) = (ClientId(1), ClientId(2));
+        let local_client = server.new_local_client(local_id.into());
+        let mut remote_client = server.new_local_client(remote_id.into());
+        let mut connected_clients = ConnectedClients::default();
+        for client_id in [local_id, remote_id] {
+            connected_clients.insert(
+                client_id,
+                ConnectedClient {
+                    mode: ConnectionMode::Player,
+                    player_id: None,
+                },
+            );
+        }
+        let mut app = App::new();
+        app.init_resource::<Received>()
+            .insert_resource(connected_clients)
+            .insert_resource(server)
+            .insert_resource(local_client)
+            .add_systems(Update, count_received);
+        build(&mut app);
+        let payload = VoicePayload::Send {
+            seq: 0,
+            bytes: vec![1, 2, 3],
+        };
+        remote_client.send_message(VOICE_CHANNEL_ID, payload.encode());
+        let mut remote_received = 0;
+        for _ in 0..10 {
+            app.world_mut()
+                .resource_scope(|world, mut server: Mut<RenetServer>| {
+                    let mut local_client = world.resource_mut::<RenetClient>();
+                    server.process_local_client(local_id.into(), &mut local_client)?;
+                    server.process_local_client(remote_id.into(), &mut remote_client)
+                })
+                .unwrap();
+            while remote_client.receive_message(VOICE_CHANNEL_ID).is_some() {
+                remote_received += 1;
+            }
+            app.update();
+        }
+        let received = app.world().resource::<Received>();
+        assert_eq!(received.server, 1);
+        assert_eq!(received.client, 1);
+        assert_eq!(remote_received, 0);
+    }
+
+    #[test]
+    fn late_and_duplicated_frames_are_dropped() {
+        let mut app = App::new();
+        let mut server = RenetServer::new(ConnectionConfig {
+            client_channels_config: vec![ChannelConfig {
+                channel_id: VOICE_CHANNEL_ID,
+                max_memory_usage_bytes: 1 << 20,
+                send_type: SendType::Unreliable,
+            }],
+            ..Default::default()
+        });
+        let client_id = ClientId(1);
+        let mut client = server.new_local_client(client_id.into());
+        let mut connected_clients = ConnectedClients::default();
+        connected_clients.insert(
+            client_id,
+            ConnectedClient {
+                mode: ConnectionMode::Player,
+                player_id: None,
+            },
+        );
+        app.init_resource::<Received>()
+            .insert_resource(connected_clients)
+            .add_systems(Update, count_received);
+        build(&mut app);
+        for seq in [1, 0, 1, 2] {
+            let payload = VoicePayload::Send {
+                seq,
+                bytes: vec![seq as u8],
+            };
+            client.send_message(VOICE_CHANNEL_ID, payload.encode());
+        }
+        server
+            .process_local_client(client_id.into(), &mut client)
+            .unwrap();
+        app.insert_resource(server);
+        app.update();
+        assert_eq!(app.world().resource::<Received>().server, 2);
+    }
+}
@@ -0,0 +1,116 @@
+//!
+//! Detects transports that stopped making progress, which otherwise goes unnoticed: the app keeps
+//! running, but no message is sent or received anymore.
+//!
+//! The netcode transports are updated by the `transport` module's systems every frame. When a transport
+//! resource exists but wasn't updated for `TransportWatchdog::frames` frames in a row (its
+//! systems are missing from the schedule, the renet resources don't match, ...), a
+//! `NetworkStalled` event is sent, once per stall, and logged at the `NetworkLogConfig::stalls`
+//! level.
+//!
+use bevy::prelude::{App, DetectChanges, Event, EventWriter, Last, Res, ResMut, Resource};
+use bevy_renet::renet::transport::{NetcodeClientTransport, NetcodeServerTransport};
+
+///
+/// The transport that stalled, see `NetworkStalled`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StalledTransport {
+    Server,
+    Client,
+}
+
+impl StalledTransport {
+    /// Likely causes, to be shown along with the stall.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::Server => {
+                "The server transport systems didn't run: check that PreUpdate and \
+                 PostUpdate run (custom runners, schedules stepped by hand), that RenetServer \
+                 exists next to NetcodeServerTransport, and that the renet resources come from the \
+                 renet version bevy_client_server_events uses"
+            },
+            Self::Client => {
+                "The client transport systems didn't run: check that PreUpdate and \
+                 PostUpdate run (custom runners, schedules stepped by hand), that RenetClient \
+                 exists next to NetcodeClientTransport, and that the renet resources come from the \
+                 renet version bevy_client_server_events uses"
+            },
+        }
+    }
+}
+
+///
+/// Sent when a transport resource exists but wasn't updated for `frames` frames.
+///
+#[derive(Debug, Clone, Event)]
+pub struct NetworkStalled {
+    pub transport: StalledTransport,
+    pub frames: u32,
+}
+
+///
+/// How many frames in a row a transport may go without an update before `NetworkStalled` is
+/// sent, `None` to disable the watchdog. 30 frames by default.
+///
+#[derive(Debug, Resource)]
+pub struct TransportWatchdog {
+    pub frames: Option<u32>,
+    server_idle_frames: u32,
+    client_idle_frames: u32,
+}
+
+impl Default for TransportWatchdog {
+    fn default() -> Self {
+        Self {
+            frames: Some(30),
+            server_idle_frames: 0,
+            client_idle_frames: 0,
+        }
+    }
+}
+
+/// Counts the frames without an update, returns whether the transport just stalled.
+fn idle(idle_frames: &mut u32, updated: Option<bool>, frames: u32) -> bool {
+    match updated {
+        Some(false) => {
+            *idle_frames += 1;
+            *idle_frames == frames
+        },
+        Some(true) | None => {
+            *idle_frames = 0;
+            false
+        },
+    }
+}
+
+pub fn watches_transport_progress(
+    mut watchdog: ResMut<TransportWatchdog>,
+    server_transport: Option<Res<NetcodeServerTransport>>,
+    client_transport: Option<Res<NetcodeClientTransport>>,
+    mut network_stalled_events: EventWriter<NetworkStalled>,
+) {
+    let Some(frames) = watchdog.frames else {
+        return;
+    };
+    let server_updated = server_transport.map(|transport| transport.is_changed());
+    if idle(&mut watchdog.server_idle_frames, server_updated, frames) {
+        network_stalled_events.send(NetworkStalled {
+            transport: StalledTransport::Server,
+            frames,
+        });
+    }
+    let client_updated = client_transport.map(|transport| transport.is_changed());
+    if idle(&mut watchdog.client_idle_frames, client_updated, frames) {
+        network_stalled_events.send(NetworkStalled {
+            transport: StalledTransport::Client,
+            frames,
+        });
+    }
+}
+
+pub(crate) fn build(app: &mut App) {
+    app.init_resource::<TransportWatchdog>()
+        .add_event::<NetworkStalled>()
+        .add_systems(Last, watches_transport_progress);
+}